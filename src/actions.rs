@@ -0,0 +1,179 @@
+use crate::dispenser::DispenseAction;
+use crate::retry::Retry;
+use async_trait::async_trait;
+use fedimint_core::anyhow::{self, Context, bail, ensure};
+use reqwest::Url;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::warn;
+
+/// Environment variable with a URL that gets POSTed to instead of running the local mechanism
+pub const DISPENSE_HTTP_URL_ENV: &str = "CANDYPI_DISPENSE_HTTP_URL";
+/// Environment variable with an `mqtt://[user:password@]host[:port]/topic` URL that gets a
+/// message published instead of running the local mechanism
+pub const DISPENSE_MQTT_URL_ENV: &str = "CANDYPI_DISPENSE_MQTT_URL";
+
+const MQTT_DEFAULT_PORT: u16 = 1883;
+const MQTT_TIMEOUT: Duration = Duration::from_secs(5);
+const MQTT_PAYLOAD: &[u8] = b"dispense";
+
+/// Triggers another device with an HTTP POST, e.g. a tap controller or a door opener
+pub struct HttpTrigger {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl HttpTrigger {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl DispenseAction for HttpTrigger {
+    async fn dispense(&mut self) -> bool {
+        // Only retry if the request never made it out, otherwise the device may trigger twice
+        let result = Retry::NETWORK
+            .run(
+                "trigger dispense",
+                |e: &reqwest::Error| e.is_connect(),
+                async || {
+                    self.client
+                        .post(&self.url)
+                        .body("dispense")
+                        .send()
+                        .await?
+                        .error_for_status()
+                },
+            )
+            .await;
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Failed to trigger dispense at {}: {}", self.url, e);
+                false
+            }
+        }
+    }
+}
+
+/// Publishes a `dispense` message with QoS 0 on every payment. Opens a fresh connection each
+/// time, which is slower but can't go stale between rare sales.
+pub struct MqttTrigger {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    topic: String,
+}
+
+impl MqttTrigger {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("Invalid {DISPENSE_MQTT_URL_ENV}"))?;
+        ensure!(
+            url.scheme() == "mqtt",
+            "Only plain mqtt:// URLs are supported"
+        );
+
+        let topic = url.path().trim_start_matches('/').to_string();
+        ensure!(!topic.is_empty(), "{DISPENSE_MQTT_URL_ENV} has no topic");
+        let credentials = match (url.username(), url.password()) {
+            ("", _) => None,
+            (user, password) => Some((user.to_string(), password.unwrap_or("").to_string())),
+        };
+
+        Ok(Self {
+            host: url.host_str().context("MQTT URL has no host")?.to_string(),
+            port: url.port().unwrap_or(MQTT_DEFAULT_PORT),
+            credentials,
+            topic,
+        })
+    }
+
+    async fn publish(&self) -> anyhow::Result<()> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        // MQTT 3.1.1 CONNECT with a clean session and no keep alive
+        let mut connect = Vec::new();
+        put_string(&mut connect, "MQTT");
+        connect.push(4);
+        let mut flags = 0x02;
+        if self.credentials.is_some() {
+            flags |= 0xC0;
+        }
+        connect.push(flags);
+        connect.extend_from_slice(&0u16.to_be_bytes());
+        put_string(&mut connect, "candypi");
+        if let Some((user, password)) = &self.credentials {
+            put_string(&mut connect, user);
+            put_string(&mut connect, password);
+        }
+        write_packet(&mut stream, 0x10, &connect).await?;
+
+        let mut connack = [0u8; 4];
+        stream.read_exact(&mut connack).await?;
+        if connack[0] != 0x20 {
+            bail!(
+                "Unexpected MQTT packet {:#x} instead of CONNACK",
+                connack[0]
+            );
+        }
+        ensure!(
+            connack[3] == 0,
+            "MQTT broker refused connection ({})",
+            connack[3]
+        );
+
+        let mut publish = Vec::new();
+        put_string(&mut publish, &self.topic);
+        publish.extend_from_slice(MQTT_PAYLOAD);
+        write_packet(&mut stream, 0x30, &publish).await?;
+
+        write_packet(&mut stream, 0xE0, &[]).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DispenseAction for MqttTrigger {
+    async fn dispense(&mut self) -> bool {
+        match tokio::time::timeout(MQTT_TIMEOUT, self.publish()).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                warn!("Failed to publish dispense to MQTT: {:#}", e);
+                false
+            }
+            Err(_) => {
+                warn!("Timed out publishing dispense to MQTT");
+                false
+            }
+        }
+    }
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+async fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> std::io::Result<()> {
+    let mut packet = vec![header];
+    // Remaining length is a varint with 7 bits per byte
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    stream.write_all(&packet).await
+}
@@ -0,0 +1,225 @@
+use crate::api_auth::{API_TOKEN_ENV, ApiAuth, AuthError};
+use crate::control::{ControlCommand, ControlRequest, ControlResponse};
+use crate::mdns;
+use crate::remote_dispense::{RemoteAuthorizer, SignedAction, SignedRequest};
+use crate::signed_config;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{StatusCode, header};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use fedimint_core::anyhow::{self, Context, anyhow};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// `[api]` table of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Address to listen on, e.g. `0.0.0.0:8080`
+    pub listen: SocketAddr,
+    /// Bearer token required on every request, read from [`API_TOKEN_ENV`] if left out
+    pub token: Option<String>,
+    /// Advertised on the LAN as `<mdns_hostname>.local`, `candypi` unless set
+    pub mdns_hostname: Option<String>,
+}
+
+impl ApiConfig {
+    pub fn mdns_hostname(&self) -> &str {
+        self.mdns_hostname
+            .as_deref()
+            .unwrap_or(mdns::DEFAULT_HOSTNAME)
+    }
+}
+
+struct ApiState {
+    auth: ApiAuth,
+    /// Unset without an operator key or device ID, remote dispenses and federation switches are
+    /// refused then
+    authorizer: Option<RemoteAuthorizer>,
+    requests: mpsc::Sender<ControlRequest>,
+}
+
+#[derive(Deserialize)]
+struct SetPrice {
+    sats: u64,
+}
+
+#[derive(Deserialize)]
+struct Refill {
+    count: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct JoinFederation {
+    invite: String,
+    #[serde(flatten)]
+    request: SignedRequest,
+}
+
+/// JSON HTTP API for kiosks and signage, answering with the same JSON as the control socket
+pub struct ApiServer {
+    listener: TcpListener,
+    auth: ApiAuth,
+    authorizer: Option<RemoteAuthorizer>,
+}
+
+impl ApiServer {
+    pub async fn bind(config: &ApiConfig) -> anyhow::Result<Self> {
+        let token = match &config.token {
+            Some(token) => token.clone(),
+            None => std::env::var(API_TOKEN_ENV)
+                .map_err(|_| anyhow!("The API needs a token in the config or {}", API_TOKEN_ENV))?,
+        };
+        let auth = ApiAuth::new(token).map_err(anyhow::Error::msg)?;
+        let authorizer = signed_config::operator_key_from_env()?
+            .zip(signed_config::device_id_from_env())
+            .map(|(operator_key, device_id)| RemoteAuthorizer::new(operator_key, device_id));
+        let listener = TcpListener::bind(config.listen)
+            .await
+            .with_context(|| format!("Failed to listen on {}", config.listen))?;
+
+        Ok(Self {
+            listener,
+            auth,
+            authorizer,
+        })
+    }
+
+    /// Serves requests in a background task, forwarding commands to `requests`
+    pub fn spawn(self, requests: mpsc::Sender<ControlRequest>) {
+        if let Ok(addr) = self.listener.local_addr() {
+            info!("API listening on {}", addr);
+        }
+
+        let state = Arc::new(ApiState {
+            auth: self.auth,
+            authorizer: self.authorizer,
+            requests,
+        });
+        let router = Router::new()
+            .route("/status", get(status))
+            .route("/invoice", get(invoice))
+            .route("/price", post(set_price))
+            .route("/dispense", post(dispense))
+            .route("/refill", post(refill))
+            .route("/federation", post(join_federation))
+            .layer(middleware::from_fn_with_state(state.clone(), require_token))
+            .with_state(state);
+
+        tokio::spawn(async move {
+            let service = router.into_make_service_with_connect_info::<SocketAddr>();
+            if let Err(e) = axum::serve(self.listener, service).await {
+                warn!("API server stopped: {}", e);
+            }
+        });
+    }
+}
+
+async fn require_token(
+    State(api): State<Arc<ApiState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    match api.auth.check(peer.ip(), authorization) {
+        Ok(()) => next.run(request).await,
+        Err(AuthError::RateLimited) => StatusCode::TOO_MANY_REQUESTS.into_response(),
+        Err(AuthError::Unauthorized) => StatusCode::UNAUTHORIZED.into_response(),
+    }
+}
+
+async fn status(State(api): State<Arc<ApiState>>) -> Response {
+    forward(&api, ControlCommand::Status).await
+}
+
+async fn invoice(State(api): State<Arc<ApiState>>) -> Response {
+    forward(&api, ControlCommand::Invoice).await
+}
+
+async fn set_price(State(api): State<Arc<ApiState>>, Json(price): Json<SetPrice>) -> Response {
+    forward(&api, ControlCommand::SetPrice { sats: price.sats }).await
+}
+
+async fn refill(State(api): State<Arc<ApiState>>, Json(refill): Json<Refill>) -> Response {
+    forward(
+        &api,
+        ControlCommand::Refill {
+            count: refill.count,
+        },
+    )
+    .await
+}
+
+/// Needs a request signed by the operator on top of the token, a leaked token alone must not
+/// sweep the funds to another federation
+async fn join_federation(
+    State(api): State<Arc<ApiState>>,
+    Json(join): Json<JoinFederation>,
+) -> Response {
+    let action = SignedAction::JoinFederation {
+        invite: &join.invite,
+    };
+    if let Some(refused) = refuse_unsigned(&api, &join.request, action) {
+        return refused;
+    }
+    forward(
+        &api,
+        ControlCommand::JoinFederation {
+            invite: join.invite,
+        },
+    )
+    .await
+}
+
+/// Needs a request signed by the operator on top of the token, a leaked token alone must not
+/// empty the machine
+async fn dispense(
+    State(api): State<Arc<ApiState>>,
+    Json(request): Json<SignedRequest>,
+) -> Response {
+    if let Some(refused) = refuse_unsigned(&api, &request, SignedAction::Dispense) {
+        return refused;
+    }
+    forward(&api, ControlCommand::Dispense).await
+}
+
+/// Response refusing the request, `None` if the operator signed it for this machine
+fn refuse_unsigned(
+    api: &ApiState,
+    request: &SignedRequest,
+    action: SignedAction,
+) -> Option<Response> {
+    let Some(authorizer) = &api.authorizer else {
+        return Some(error(
+            StatusCode::FORBIDDEN,
+            "Remote requests need an operator key and a device ID",
+        ));
+    };
+    authorizer
+        .authorize(request, action)
+        .err()
+        .map(|e| error(StatusCode::FORBIDDEN, &format!("{:#}", e)))
+}
+
+async fn forward(api: &ApiState, command: ControlCommand) -> Response {
+    match ControlRequest::send(&api.requests, command).await {
+        Some(ControlResponse::Error(e)) => error(StatusCode::BAD_REQUEST, &e),
+        Some(response) => Json(response.to_json()).into_response(),
+        None => error(StatusCode::SERVICE_UNAVAILABLE, "Shutting down"),
+    }
+}
+
+fn error(status: StatusCode, message: &str) -> Response {
+    let body = ControlResponse::Error(message.to_string()).to_json();
+    (status, Json(body)).into_response()
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+
+/// Environment variable holding the bearer token for admin endpoints. Admin endpoints refuse all
+/// requests if it is unset.
+pub const API_TOKEN_ENV: &str = "CANDYPI_API_TOKEN";
+
+/// Tokens shorter than this are trivially brute-forced even with rate limiting
+const MIN_TOKEN_LENGTH: usize = 16;
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const RATE_LIMIT_MAX_REQUESTS: u32 = 30;
+
+/// Upper bound on tracked clients so a flood of spoofed addresses can't exhaust memory
+const RATE_LIMIT_MAX_CLIENTS: usize = 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    RateLimited,
+    Unauthorized,
+}
+
+struct RateWindow {
+    started: Instant,
+    requests: u32,
+}
+
+/// Guards admin endpoints with a bearer token and per-IP rate limiting, since the machine
+/// usually shares a network with everyone at the venue.
+pub struct ApiAuth {
+    token: String,
+    windows: Mutex<HashMap<IpAddr, RateWindow>>,
+}
+
+impl ApiAuth {
+    /// Reads the token from [`API_TOKEN_ENV`], returns `None` if none was configured
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var(API_TOKEN_ENV) {
+            Ok(token) => Self::new(token).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn new(token: String) -> Result<Self, String> {
+        if token.len() < MIN_TOKEN_LENGTH {
+            return Err(format!(
+                "API token must be at least {MIN_TOKEN_LENGTH} characters long"
+            ));
+        }
+
+        Ok(Self {
+            token,
+            windows: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Checks a request from `peer` carrying the given `Authorization` header value. Rate
+    /// limiting applies before the token check so it also throttles guessing.
+    pub fn check(&self, peer: IpAddr, authorization: Option<&str>) -> Result<(), AuthError> {
+        self.count_request(peer)?;
+
+        let provided = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::Unauthorized)?;
+        if bool::from(provided.as_bytes().ct_eq(self.token.as_bytes())) {
+            Ok(())
+        } else {
+            Err(AuthError::Unauthorized)
+        }
+    }
+
+    fn count_request(&self, peer: IpAddr) -> Result<(), AuthError> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().expect("Rate limit lock poisoned");
+
+        if windows.len() >= RATE_LIMIT_MAX_CLIENTS {
+            windows.retain(|_, window| now.duration_since(window.started) < RATE_LIMIT_WINDOW);
+        }
+        if windows.len() >= RATE_LIMIT_MAX_CLIENTS && !windows.contains_key(&peer) {
+            return Err(AuthError::RateLimited);
+        }
+
+        let window = windows.entry(peer).or_insert(RateWindow {
+            started: now,
+            requests: 0,
+        });
+        if now.duration_since(window.started) >= RATE_LIMIT_WINDOW {
+            window.started = now;
+            window.requests = 0;
+        }
+
+        window.requests += 1;
+        if window.requests > RATE_LIMIT_MAX_REQUESTS {
+            return Err(AuthError::RateLimited);
+        }
+        Ok(())
+    }
+}
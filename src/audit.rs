@@ -0,0 +1,293 @@
+use chrono::Local;
+use fedimint_core::anyhow::{self, bail};
+use fedimint_core::bitcoin::hashes::{Hash, sha256};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::error;
+
+/// How long entries may sit in memory before they are synced to the SD card
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+struct AuditState {
+    file: File,
+    head: sha256::Hash,
+    /// Chained lines not yet written to disk, kept in order until a write succeeds
+    pending: String,
+}
+
+impl AuditState {
+    fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(self.pending.as_bytes())?;
+        self.file.sync_data()?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl Drop for AuditState {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to flush audit log: {}", e);
+        }
+    }
+}
+
+/// Append-only log of security relevant events, one timestamped event per line.
+///
+/// Every line ends in `sha256(<previous hash><line without hash>)`, so editing or deleting
+/// historical entries breaks the chain unless everything after them is rewritten as well, which
+/// [`verify`] together with a copy of a recent head hash detects.
+#[derive(Clone)]
+pub struct AuditLog {
+    state: Arc<Mutex<AuditState>>,
+}
+
+impl AuditLog {
+    /// Defaults to `$XDG_DATA_HOME/candypi/audit.log`
+    pub fn default_path() -> PathBuf {
+        xdg::BaseDirectories::new()
+            .data_home
+            .expect("Could not determine XDG data home")
+            .join("candypi/audit.log")
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let existing = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        // Entries written before chaining was introduced are covered by hashing them as a whole
+        let head = match existing.lines().last().and_then(split_hash) {
+            Some((_, hash)) => hash,
+            None if existing.is_empty() => sha256::Hash::all_zeros(),
+            None => sha256::Hash::hash(existing.as_bytes()),
+        };
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(AuditState {
+                file,
+                head,
+                pending: String::new(),
+            })),
+        })
+    }
+
+    /// Appends an event. It is written to disk with everything else recorded in the meantime by
+    /// the next [`Self::flush`], which runs every few seconds once [`Self::spawn_flusher`] was
+    /// called and when the last handle is dropped.
+    pub fn record(&self, event: &str) {
+        let mut state = self.state.lock().expect("Audit log lock poisoned");
+        Self::append(&mut state, event);
+    }
+
+    /// Appends an event and syncs it right away, for security events like door openings and
+    /// refunds that have to survive a power cut moments later
+    pub fn record_now(&self, event: &str) {
+        let mut state = self.state.lock().expect("Audit log lock poisoned");
+        Self::append(&mut state, event);
+        if let Err(e) = state.flush() {
+            error!("Failed to write audit log: {}", e);
+        }
+    }
+
+    fn append(state: &mut AuditState, event: &str) {
+        let body = format!("{} {}", Local::now().to_rfc3339(), event);
+        let hash = chain_hash(&state.head, &body);
+        state.pending.push_str(&format!("{} {}\n", body, hash));
+        state.head = hash;
+    }
+
+    /// Writes and syncs all pending entries. Failures are only logged and the entries retried
+    /// with the next flush, a broken SD card shouldn't stop the machine from vending.
+    pub fn flush(&self) {
+        let mut state = self.state.lock().expect("Audit log lock poisoned");
+        if let Err(e) = state.flush() {
+            error!("Failed to write audit log: {}", e);
+        }
+    }
+
+    /// Flushes pending entries in the background, batching bursts of events into a single sync
+    /// to spare the SD card. Stops once every handle is dropped, which flushes the rest.
+    pub fn spawn_flusher(&self) {
+        let state = Arc::downgrade(&self.state);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(state) = state.upgrade() else {
+                    return;
+                };
+                AuditLog { state }.flush();
+            }
+        });
+    }
+
+    /// Hash of the latest entry, operators can note it down to detect later rewrites of the log
+    pub fn head_hash(&self) -> String {
+        self.state
+            .lock()
+            .expect("Audit log lock poisoned")
+            .head
+            .to_string()
+    }
+}
+
+fn chain_hash(previous: &sha256::Hash, body: &str) -> sha256::Hash {
+    sha256::Hash::hash(format!("{}{}", previous, body).as_bytes())
+}
+
+fn split_hash(line: &str) -> Option<(&str, sha256::Hash)> {
+    let (body, hash) = line.rsplit_once(' ')?;
+    Some((body, hash.parse().ok()?))
+}
+
+/// Checks the hash chain of the log at `path` and returns the head hash
+pub fn verify(path: &Path) -> anyhow::Result<String> {
+    let content = fs::read_to_string(path)?;
+
+    // Unchained entries are only allowed at the very beginning, from before chaining existed
+    let legacy_len: usize = content
+        .lines()
+        .take_while(|line| split_hash(line).is_none())
+        .map(|line| line.len() + 1)
+        .sum();
+    let legacy = &content[..legacy_len.min(content.len())];
+    let mut head = if legacy.is_empty() {
+        sha256::Hash::all_zeros()
+    } else {
+        sha256::Hash::hash(legacy.as_bytes())
+    };
+
+    for (idx, line) in content.lines().enumerate().skip(legacy.lines().count()) {
+        let Some((body, hash)) = split_hash(line) else {
+            bail!("Audit log line {} is missing its hash", idx + 1);
+        };
+        if hash != chain_hash(&head, body) {
+            bail!("Audit log chain broken at line {}", idx + 1);
+        }
+        head = hash;
+    }
+
+    Ok(head.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn write_log(path: &Path, events: &[&str]) -> String {
+        let log = AuditLog::open(path).unwrap();
+        for event in events {
+            log.record(event);
+        }
+        let head = log.head_hash();
+        drop(log);
+        head
+    }
+
+    #[test]
+    fn intact_log_verifies() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let head = write_log(&path, &["boot", "sale", "door opened"]);
+        assert_eq!(verify(&path).unwrap(), head);
+
+        // Reopening continues the chain
+        let head = write_log(&path, &["shutdown"]);
+        assert_eq!(verify(&path).unwrap(), head);
+    }
+
+    #[test]
+    fn recorded_now_is_on_disk_before_drop() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path).unwrap();
+        log.record("sale");
+        log.record_now("door_open");
+        assert_eq!(verify(&path).unwrap(), log.head_hash());
+
+        // Batched entries wait for the next flush
+        log.record("sale");
+        assert_ne!(verify(&path).unwrap(), log.head_hash());
+    }
+
+    #[test]
+    fn edited_entry_breaks_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, &["boot", "sale", "door opened"]);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("sale", "idle", 1)).unwrap();
+
+        let error = verify(&path).unwrap_err().to_string();
+        assert!(error.contains("line 2"), "{error}");
+    }
+
+    #[test]
+    fn deleted_entry_breaks_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, &["boot", "sale", "door opened"]);
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+
+        assert!(verify(&path).is_err());
+    }
+
+    #[test]
+    fn truncated_log_verifies_to_older_head() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let older = write_log(&path, &["boot", "sale"]);
+        let newer = write_log(&path, &["door opened"]);
+        let content = fs::read_to_string(&path).unwrap();
+        let kept: usize = content.lines().take(2).map(|line| line.len() + 1).sum();
+        fs::write(&path, &content[..kept]).unwrap();
+
+        // Only a noted head hash tells that entries are missing at the end
+        let head = verify(&path).unwrap();
+        assert_eq!(head, older);
+        assert_ne!(head, newer);
+    }
+
+    #[test]
+    fn legacy_prefix_is_covered_by_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        fs::write(&path, "2024-01-01T00:00:00+00:00 boot\n").unwrap();
+        let head = write_log(&path, &["sale"]);
+        assert_eq!(verify(&path).unwrap(), head);
+
+        // Editing an unchained entry changes the hash the chain starts from
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("boot", "idle", 1)).unwrap();
+        assert!(verify(&path).is_err());
+    }
+
+    #[test]
+    fn unchained_line_after_chain_is_rejected() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        write_log(&path, &["boot"]);
+        let mut content = fs::read_to_string(&path).unwrap();
+        content.push_str("2024-01-01T00:00:00+00:00 sale\n");
+        fs::write(&path, content).unwrap();
+
+        let error = verify(&path).unwrap_err().to_string();
+        assert!(error.contains("missing its hash"), "{error}");
+    }
+}
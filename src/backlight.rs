@@ -0,0 +1,172 @@
+use crate::events::{Event, EventSubscriber};
+use rppal::gpio::OutputPin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Ambient light at which the backlight reaches full brightness, about an overcast day outside
+const DAYLIGHT_LUX: f64 = 10_000.0;
+/// Keeps the screen readable in the dark even if it is switched off while idle
+const MIN_ACTIVE_LEVEL: f64 = 0.05;
+/// Smaller ambient changes aren't worth restarting the PWM for
+const MIN_LEVEL_CHANGE: f64 = 0.02;
+
+/// Brightness levels and when to dim, levels go from 0 to 1
+#[derive(Debug, Clone, Copy)]
+pub struct BacklightSettings {
+    pub brightness: f64,
+    /// Level while nobody used the machine for `dim_after`
+    pub dimmed: f64,
+    /// Never dims if `None`
+    pub dim_after: Option<Duration>,
+}
+
+impl Default for BacklightSettings {
+    fn default() -> Self {
+        Self {
+            brightness: 1.0,
+            dimmed: 0.1,
+            dim_after: None,
+        }
+    }
+}
+
+impl BacklightSettings {
+    /// Level while the machine is in use: `brightness` without a light sensor, otherwise
+    /// between `dimmed` in the dark and `brightness` in daylight on a logarithmic scale, the
+    /// way eyes perceive it
+    fn active_level(&self, lux: Option<f32>) -> f64 {
+        let Some(lux) = lux else {
+            return self.brightness;
+        };
+        let daylight = (f64::from(lux).max(0.0) + 1.0).log10() / (DAYLIGHT_LUX + 1.0).log10();
+        let darkest = self.dimmed.max(MIN_ACTIVE_LEVEL).min(self.brightness);
+        darkest + (self.brightness - darkest) * daylight.clamp(0.0, 1.0)
+    }
+}
+
+struct State {
+    pin: OutputPin,
+    pwm_frequency: f64,
+    /// Switched off for good, e.g. on shutdown
+    off: bool,
+}
+
+impl State {
+    fn set_level(&mut self, level: f64) {
+        if self.off {
+            return;
+        }
+        if level >= 1.0 {
+            let _ = self.pin.clear_pwm();
+            self.pin.set_high();
+            return;
+        }
+        if let Err(e) = self
+            .pin
+            .set_pwm_frequency(self.pwm_frequency, level.max(0.0))
+        {
+            warn!("Failed to dim backlight: {}", e);
+            self.pin.set_high();
+        }
+    }
+}
+
+/// The display's LED backlight, dimmed through software PWM. Clones control the same pin.
+#[derive(Clone)]
+pub struct Backlight(Arc<Mutex<State>>);
+
+impl Backlight {
+    /// Starts at full brightness, as the display left it
+    pub fn new(pin: OutputPin, pwm_frequency: f64) -> Self {
+        Self(Arc::new(Mutex::new(State {
+            pin,
+            pwm_frequency,
+            off: false,
+        })))
+    }
+
+    pub fn set_level(&self, level: f64) {
+        self.0
+            .lock()
+            .expect("backlight lock poisoned")
+            .set_level(level);
+    }
+
+    /// Switches the backlight off for good, later level changes are ignored
+    pub fn off(&self) {
+        let mut state = self.0.lock().expect("backlight lock poisoned");
+        let _ = state.pin.clear_pwm();
+        state.pin.set_low();
+        state.off = true;
+    }
+
+    /// Applies `settings` in a background task: the backlight dims once nothing happened for a
+    /// while and wakes up on button presses, payments and alarms. While in use it follows the
+    /// `ambient` light in lux, which stays `None` without a light sensor.
+    pub fn spawn(
+        self,
+        settings: BacklightSettings,
+        mut events: EventSubscriber,
+        mut ambient: watch::Receiver<Option<f32>>,
+    ) {
+        let mut level = settings.active_level(*ambient.borrow_and_update());
+        self.set_level(level);
+
+        tokio::spawn(async move {
+            let mut dim_at = settings.dim_after.map(|after| Instant::now() + after);
+            let mut dimmed = false;
+            loop {
+                let dim = async {
+                    match dim_at {
+                        Some(at) => tokio::time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    event = events.recv() => match event {
+                        None | Some(Event::ShuttingDown) => {
+                            self.off();
+                            return;
+                        }
+                        Some(
+                            Event::ButtonPressed(_)
+                            | Event::ButtonHeld(_)
+                            | Event::PaymentReceived { .. }
+                            | Event::DispenseStarted
+                            | Event::DispenseJammed
+                            | Event::TamperAlarm
+                            | Event::EmergencyStop,
+                        ) => {
+                            if dimmed {
+                                debug!("Waking up backlight");
+                                self.set_level(level);
+                                dimmed = false;
+                            }
+                            dim_at = settings.dim_after.map(|after| Instant::now() + after);
+                        }
+                        Some(_) => {}
+                    },
+                    Ok(()) = ambient.changed() => {
+                        let adjusted = settings.active_level(*ambient.borrow_and_update());
+                        if (adjusted - level).abs() < MIN_LEVEL_CHANGE {
+                            continue;
+                        }
+                        level = adjusted;
+                        if !dimmed {
+                            self.set_level(level);
+                        }
+                    }
+                    _ = dim => {
+                        debug!("Dimming backlight");
+                        self.set_level(settings.dimmed);
+                        dimmed = true;
+                        dim_at = None;
+                    }
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,137 @@
+//! [BBQr](https://github.com/coinkite/BBQr) framing of data too long for a single QR code. The
+//! parts are shown one after the other and reassembled by the scanning wallet.
+
+/// RFC 4648 base32, only characters of the QR code's alphanumeric mode
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const BASE36: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Part numbers are two base 36 digits
+pub const MAX_PARTS: usize = 36 * 36 - 1;
+
+/// Eight base32 characters encode five bytes, parts are split on these boundaries
+const BASE32_BLOCK: usize = 8;
+
+/// Splits the UTF-8 `text` into at most `count` base32 encoded parts with BBQr headers
+pub fn split(text: &str, count: usize) -> Vec<String> {
+    let encoded = base32(text.as_bytes());
+    let count = count.clamp(1, MAX_PARTS);
+    let part_len = encoded
+        .len()
+        .div_ceil(count)
+        .next_multiple_of(BASE32_BLOCK)
+        .max(BASE32_BLOCK);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(part_len).collect();
+    let total = chunks.len();
+
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            // Base32 ('2') encoded Unicode text ('U')
+            let mut part = format!("B$2U{}{}", base36(total), base36(index));
+            part.push_str(std::str::from_utf8(chunk).expect("Base32 is ASCII"));
+            part
+        })
+        .collect()
+}
+
+fn base32(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * BASE32_BLOCK);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8 | byte as u32) & 0xFFF;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    encoded
+}
+
+fn base36(value: usize) -> String {
+    [value / 36, value % 36]
+        .iter()
+        .map(|&digit| BASE36[digit] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digit(alphabet: &[u8], c: u8) -> usize {
+        alphabet.iter().position(|&d| d == c).unwrap()
+    }
+
+    /// Reassembles the parts like a scanning wallet, checking their headers
+    fn join(parts: &[String]) -> String {
+        let mut data = Vec::new();
+        let mut buffer = 0u32;
+        let mut bits = 0;
+        for (index, part) in parts.iter().enumerate() {
+            let part = part.as_bytes();
+            assert_eq!(&part[..4], b"B$2U");
+            assert_eq!(
+                digit(BASE36, part[4]) * 36 + digit(BASE36, part[5]),
+                parts.len()
+            );
+            assert_eq!(digit(BASE36, part[6]) * 36 + digit(BASE36, part[7]), index);
+            for &c in &part[8..] {
+                buffer = (buffer << 5 | digit(BASE32, c) as u32) & 0xFFF;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    data.push((buffer >> bits) as u8);
+                }
+            }
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    #[test]
+    fn round_trips() {
+        let text = "lnbc1pvjluezsp5zyg3zyg3zyg3zyg3 Süßigkeiten 🍬";
+        for count in [1, 2, 3, 7] {
+            let parts = split(text, count);
+            assert!(parts.len() <= count);
+            assert_eq!(join(&parts), text);
+        }
+    }
+
+    #[test]
+    fn at_least_one_part() {
+        let parts = split("candy", 0);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(join(&parts), "candy");
+    }
+
+    #[test]
+    fn short_text_needs_fewer_parts() {
+        // Parts hold at least one block of five bytes
+        let parts = split("candy", 5);
+        assert_eq!(parts.len(), 1);
+        assert_eq!(join(&parts), "candy");
+    }
+
+    #[test]
+    fn round_trips_at_the_part_limit() {
+        // Exactly one block per part
+        let text = "x".repeat(MAX_PARTS * 5);
+        let parts = split(&text, MAX_PARTS);
+        assert_eq!(parts.len(), MAX_PARTS);
+        assert!(parts[MAX_PARTS - 1].starts_with("B$2UZZZY"));
+        assert_eq!(join(&parts), text);
+
+        // More parts than the header can number
+        assert_eq!(split(&text, MAX_PARTS + 100).len(), MAX_PARTS);
+        let longer = "x".repeat(MAX_PARTS * 5 + 1);
+        let parts = split(&longer, usize::MAX);
+        assert!(parts.len() <= MAX_PARTS);
+        assert_eq!(join(&parts), longer);
+    }
+}
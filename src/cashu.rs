@@ -0,0 +1,270 @@
+use crate::retry::{self, Retry};
+use crate::tpm::{self, SeedKey};
+use crate::wallet::PaymentProvider;
+use async_trait::async_trait;
+use cdk::Amount;
+use cdk::amount::SplitTarget;
+use cdk::nuts::{CurrencyUnit, MintQuoteState, Token};
+use cdk::wallet::ReceiveOptions;
+use cdk_redb::WalletRedbDatabase;
+use fedimint_bip39::Mnemonic;
+use fedimint_core::anyhow::{self, Context, ensure};
+use lightning_invoice::Bolt11Invoice;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Mints don't push payment notifications, quotes have to be polled
+const QUOTE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Length of the unencrypted entropy of a 12 word mnemonic, sealed secrets are longer
+const PLAIN_ENTROPY_LEN: usize = 16;
+
+/// `[cashu]` table of the config file, replaces the Fedimint client with a Cashu wallet
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CashuConfig {
+    /// e.g. `https://mint.example.com`
+    pub mint_url: String,
+    /// Defaults to `$XDG_DATA_HOME/candypi/cashu`
+    pub datadir: Option<PathBuf>,
+}
+
+impl CashuConfig {
+    pub fn datadir(&self) -> PathBuf {
+        self.datadir.clone().unwrap_or_else(|| {
+            xdg::BaseDirectories::new()
+                .data_home
+                .expect("Could not determine XDG data home")
+                .join("candypi/cashu")
+        })
+    }
+}
+
+/// Wallet of a single Cashu mint: invoices are mint quotes, payouts are melts
+pub struct CashuWallet {
+    wallet: cdk::Wallet,
+    mnemonic: Mnemonic,
+    /// Mint quote of every invoice handed out, by payment hash
+    quotes: Mutex<HashMap<String, String>>,
+}
+
+impl CashuWallet {
+    /// Opens the wallet in the configured data directory, creating the seed on first start.
+    /// The seed is sealed with `seed_key` if given.
+    pub async fn open(config: &CashuConfig, seed_key: Option<&SeedKey>) -> anyhow::Result<Self> {
+        let datadir = config.datadir();
+        fs::create_dir_all(&datadir)
+            .with_context(|| format!("Failed to create {}", datadir.display()))?;
+        let mnemonic = load_or_generate_mnemonic(&datadir.join("seed"), seed_key)?;
+
+        // Not SQLite, the Tor client of the `tor` feature links another version of it
+        let localstore = WalletRedbDatabase::new(&datadir.join("wallet.redb"))
+            .context("Failed to open Cashu wallet database")?;
+        let wallet = cdk::Wallet::new(
+            &config.mint_url,
+            CurrencyUnit::Sat,
+            Arc::new(localstore),
+            mnemonic.to_seed_normalized(""),
+            None,
+        )
+        .context("Invalid Cashu mint URL")?;
+        wallet
+            .get_mint_info()
+            .await
+            .context("Failed to reach Cashu mint")?;
+        info!(mint = %config.mint_url, "Opened Cashu wallet");
+
+        // Quotes are kept in the database, ones paid before a failed mint or a restart are
+        // still owed to us
+        match wallet.check_all_mint_quotes().await {
+            Ok(minted) if minted > Amount::ZERO => info!(%minted, "Minted paid quotes"),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to mint paid quotes: {}", e),
+        }
+
+        Ok(Self {
+            wallet,
+            mnemonic,
+            quotes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Melts ecash to pay the invoice
+    pub async fn pay_invoice(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        let quote = self.wallet.melt_quote(invoice.to_string(), None).await?;
+        let melted = self.wallet.melt(&quote.id).await?;
+        info!(
+            amount = %melted.amount,
+            fee_paid = %melted.fee_paid,
+            "Melted ecash"
+        );
+        Ok(())
+    }
+
+    /// Swaps a Cashu token of this mint worth at least `min_sats` into the wallet, returns what
+    /// it was worth
+    pub async fn receive(&self, token: &str, min_sats: u64) -> anyhow::Result<u64> {
+        let parsed = Token::from_str(token.trim()).context("Invalid Cashu token")?;
+        let value: u64 = parsed.value()?.into();
+        ensure!(
+            value >= min_sats,
+            "Token is worth {} sats, less than the price",
+            value
+        );
+        let received = self
+            .wallet
+            .receive(token.trim(), ReceiveOptions::default())
+            .await?;
+        Ok(received.into())
+    }
+
+    /// Balance in sats
+    pub async fn balance(&self) -> anyhow::Result<u64> {
+        Ok(self.wallet.total_balance().await?.into())
+    }
+
+    /// Backup of the wallet's ecash
+    pub fn mnemonic(&self) -> &Mnemonic {
+        &self.mnemonic
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for CashuWallet {
+    /// Requests a mint quote, the mint only issues whole sats so the amount is rounded up
+    async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let quote = self
+            .wallet
+            .mint_quote(
+                amount_msats.div_ceil(1000).into(),
+                Some(description.to_owned()),
+            )
+            .await?;
+        let invoice = Bolt11Invoice::from_str(&quote.request)?;
+        self.quotes
+            .lock()
+            .expect("Quote lock poisoned")
+            .insert(invoice.payment_hash().to_string(), quote.id);
+        Ok(invoice)
+    }
+
+    /// Polls the mint until the quote was paid, then mints the ecash so it's held by the machine
+    async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        let payment_hash = invoice.payment_hash().to_string();
+        let quote_id = self
+            .quotes
+            .lock()
+            .expect("Quote lock poisoned")
+            .get(&payment_hash)
+            .cloned()
+            .context("Invoice wasn't created by this wallet")?;
+
+        let started = Instant::now();
+        loop {
+            match self.wallet.mint_quote_state(&quote_id).await {
+                Ok(response) if response.state == MintQuoteState::Paid => break,
+                Ok(response) if response.state == MintQuoteState::Issued => {
+                    anyhow::bail!("Mint quote was already issued")
+                }
+                Ok(_) => {}
+                // Keep trying through short outages of the mint until the invoice expires
+                Err(e) => warn!("Failed to check mint quote: {}", e),
+            }
+
+            ensure!(!invoice.is_expired(), "Invoice expired before being paid");
+            tokio::time::sleep(QUOTE_POLL_INTERVAL).await;
+        }
+        metrics::histogram!("candypi_payment_detection_seconds")
+            .record(started.elapsed().as_secs_f64());
+
+        // The customer paid, even if minting fails the mint owes us. The quote stays in the
+        // database and is minted on the next start.
+        let minted = Retry::NETWORK
+            .run("mint paid quote", retry::always, async || {
+                self.wallet
+                    .mint(&quote_id, SplitTarget::default(), None)
+                    .await
+            })
+            .await;
+        if let Err(e) = minted {
+            warn!(quote = %quote_id, "Failed to mint paid quote: {}", e);
+        }
+        self.quotes
+            .lock()
+            .expect("Quote lock poisoned")
+            .remove(&payment_hash);
+        Ok(())
+    }
+}
+
+fn load_or_generate_mnemonic(path: &Path, seed_key: Option<&SeedKey>) -> anyhow::Result<Mnemonic> {
+    match fs::read(path) {
+        Ok(stored) => {
+            let entropy = match seed_key {
+                Some(key) if stored.len() != PLAIN_ENTROPY_LEN => key.unseal(&stored)?,
+                Some(key) => {
+                    warn!(
+                        "Cashu wallet secret is stored unencrypted although a TPM key is set, encrypting it"
+                    );
+                    write_secret(path, &key.seal(&stored)?)
+                        .with_context(|| format!("Failed to encrypt {}", path.display()))?;
+                    stored
+                }
+                None => {
+                    ensure!(
+                        stored.len() == PLAIN_ENTROPY_LEN,
+                        "Cashu wallet secret is encrypted, set {} to decrypt it",
+                        tpm::TPM_NV_INDEX_ENV
+                    );
+                    stored
+                }
+            };
+            Ok(Mnemonic::from_entropy(&entropy)?)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mnemonic = Mnemonic::generate(12)?;
+            let stored = match seed_key {
+                Some(key) => key.seal(&mnemonic.to_entropy())?,
+                None => mnemonic.to_entropy(),
+            };
+            write_secret(path, &stored)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(mnemonic)
+        }
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Replaces the file at `path` with `secret`, readable by the owner only. Everything is synced
+/// before returning, a power cut must not leave an empty seed behind.
+fn write_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    // A leftover of an interrupted write would keep its permissions
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(secret)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    // The rename itself is only durable once the directory is synced
+    File::open(path.parent().unwrap_or(Path::new(".")))?.sync_all()
+}
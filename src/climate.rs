@@ -0,0 +1,196 @@
+use crate::notify::Notifier;
+use crate::retry::{self, Retry};
+use fedimint_core::anyhow::{self, Context, bail};
+use rppal::i2c::I2c;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Environment variable selecting the compartment climate sensor, `sht31` or `dht22`
+pub const CLIMATE_SENSOR_ENV: &str = "CANDYPI_CLIMATE_SENSOR";
+/// Environment variable with the temperature in °C above which the operator gets alerted
+pub const MAX_TEMPERATURE_ENV: &str = "CANDYPI_MAX_TEMPERATURE";
+
+/// Chocolate starts to soften around here
+const DEFAULT_MAX_TEMPERATURE: f32 = 28.0;
+const TEMPERATURE_HYSTERESIS: f32 = 2.0;
+
+const SHT31_ADDRESS: u16 = 0x44;
+/// Single shot, high repeatability, no clock stretching
+const SHT31_MEASURE: [u8; 2] = [0x24, 0x00];
+const SHT31_MEASUREMENT_TIME: Duration = Duration::from_millis(20);
+
+/// The DHT22 is read by the kernel's `dht11` driver (`dtoverlay=dht11,gpiopin=..`), bit-banging
+/// its single wire protocol from userspace is too unreliable
+const DHT22_IIO_DEVICE: &str = "/sys/bus/iio/devices/iio:device0";
+/// The driver fails a good share of reads with EIO due to missed edges, and the sensor needs two
+/// seconds between conversions
+const DHT22_RETRY: Retry = Retry {
+    attempts: 5,
+    initial_delay: Duration::from_secs(2),
+    max_delay: Duration::from_secs(2),
+    jitter: 0.0,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Only every n-th reading is logged to keep the journal readable
+const LOG_EVERY: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ClimateReading {
+    pub temperature_celsius: f32,
+    pub humidity_percent: f32,
+}
+
+pub enum ClimateSensor {
+    Sht31(I2c),
+    Dht22(PathBuf),
+}
+
+impl ClimateSensor {
+    /// Opens the sensor selected by [`CLIMATE_SENSOR_ENV`], returns `None` if none is configured
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var(CLIMATE_SENSOR_ENV).as_deref() {
+            Ok("sht31") => {
+                let mut i2c = I2c::new().context("Failed to open I2C bus")?;
+                i2c.set_slave_address(SHT31_ADDRESS)?;
+                Ok(Some(ClimateSensor::Sht31(i2c)))
+            }
+            Ok("dht22") => Ok(Some(ClimateSensor::Dht22(PathBuf::from(DHT22_IIO_DEVICE)))),
+            Ok(other) => bail!("Unknown {CLIMATE_SENSOR_ENV} '{other}', expected sht31 or dht22"),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub async fn read(&mut self) -> anyhow::Result<ClimateReading> {
+        match self {
+            ClimateSensor::Sht31(i2c) => {
+                Retry::SENSOR
+                    .run("read SHT31", retry::always, async || read_sht31(i2c).await)
+                    .await
+            }
+            ClimateSensor::Dht22(device) => {
+                DHT22_RETRY
+                    .run(
+                        "read DHT22",
+                        |e: &anyhow::Error| {
+                            e.downcast_ref::<std::io::Error>()
+                                .is_some_and(retry::is_transient_io)
+                        },
+                        async || read_dht22(device),
+                    )
+                    .await
+            }
+        }
+    }
+
+    /// Polls the sensor in a background task, exporting readings as metrics and alerting the
+    /// operator when the compartment gets too warm
+    pub fn spawn(mut self, notifier: Notifier) -> anyhow::Result<()> {
+        let max_temperature = match std::env::var(MAX_TEMPERATURE_ENV) {
+            Ok(value) => value
+                .parse::<f32>()
+                .with_context(|| format!("Invalid {MAX_TEMPERATURE_ENV}"))?,
+            Err(_) => DEFAULT_MAX_TEMPERATURE,
+        };
+
+        tokio::spawn(async move {
+            let mut alerted = false;
+            let mut readings = 0usize;
+
+            loop {
+                match self.read().await {
+                    Ok(reading) => {
+                        metrics::gauge!("candypi_temperature_celsius")
+                            .set(f64::from(reading.temperature_celsius));
+                        metrics::gauge!("candypi_humidity_percent")
+                            .set(f64::from(reading.humidity_percent));
+
+                        if readings % LOG_EVERY == 0 {
+                            info!(
+                                "Compartment climate: {:.1} °C, {:.0}% RH",
+                                reading.temperature_celsius, reading.humidity_percent
+                            );
+                        }
+                        readings += 1;
+
+                        if !alerted && reading.temperature_celsius > max_temperature {
+                            alerted = true;
+                            notifier
+                                .notify(&format!(
+                                    "Candy compartment is at {:.1} °C, above {:.1} °C",
+                                    reading.temperature_celsius, max_temperature
+                                ))
+                                .await;
+                        } else if alerted
+                            && reading.temperature_celsius
+                                < max_temperature - TEMPERATURE_HYSTERESIS
+                        {
+                            alerted = false;
+                            notifier
+                                .notify(&format!(
+                                    "Candy compartment cooled down to {:.1} °C",
+                                    reading.temperature_celsius
+                                ))
+                                .await;
+                        }
+                    }
+                    Err(e) => warn!("Failed to read climate sensor: {:#}", e),
+                }
+
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+
+        Ok(())
+    }
+}
+
+async fn read_sht31(i2c: &mut I2c) -> anyhow::Result<ClimateReading> {
+    i2c.write(&SHT31_MEASURE)?;
+    tokio::time::sleep(SHT31_MEASUREMENT_TIME).await;
+
+    let mut data = [0u8; 6];
+    i2c.read(&mut data)?;
+    if sht31_crc(&data[0..2]) != data[2] || sht31_crc(&data[3..5]) != data[5] {
+        bail!("SHT31 checksum mismatch");
+    }
+
+    let raw_temperature = f32::from(u16::from_be_bytes([data[0], data[1]]));
+    let raw_humidity = f32::from(u16::from_be_bytes([data[3], data[4]]));
+    Ok(ClimateReading {
+        temperature_celsius: -45.0 + 175.0 * raw_temperature / 65535.0,
+        humidity_percent: 100.0 * raw_humidity / 65535.0,
+    })
+}
+
+fn read_dht22(device: &Path) -> anyhow::Result<ClimateReading> {
+    // The IIO driver reports milli-degrees and milli-percent
+    let read_milli = |name: &str| -> anyhow::Result<f32> {
+        let value = fs::read_to_string(device.join(name))
+            .with_context(|| format!("Failed to read {}", name))?;
+        Ok(value.trim().parse::<i32>()? as f32 / 1000.0)
+    };
+
+    Ok(ClimateReading {
+        temperature_celsius: read_milli("in_temp_input")?,
+        humidity_percent: read_milli("in_humidityrelative_input")?,
+    })
+}
+
+/// CRC-8 with polynomial 0x31 and initial value 0xFF, as specified in the SHT3x datasheet
+fn sht31_crc(data: &[u8]) -> u8 {
+    let mut crc = 0xFFu8;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,104 @@
+use crate::pins::Input;
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+
+/// Pulses are 20-100 ms long on common acceptors (CH-926 and clones), polled well below that
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+const DEFAULT_MIN_PULSE: Duration = Duration::from_millis(10);
+/// Acceptors pause about 100 ms between the pulses of one coin
+const DEFAULT_COIN_GAP: Duration = Duration::from_millis(250);
+
+/// `[coins]` table of the config file, a pulse-output coin acceptor taking fiat coins
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoinConfig {
+    /// BCM GPIO number of the acceptor's open collector output, pulled to ground per pulse
+    pub pin: u8,
+    /// Value of one pulse in the `[fiat]` currency, e.g. `0.10` with the acceptor programmed
+    /// to send one pulse per 10 cents
+    pub pulse_value: f64,
+    /// Shorter pulses are noise, 10 ms unless set
+    pub min_pulse_ms: Option<u64>,
+    /// Silence ending the pulses of one coin, 250 ms unless set
+    pub coin_gap_ms: Option<u64>,
+}
+
+impl CoinConfig {
+    pub fn timing(&self) -> PulseTiming {
+        PulseTiming {
+            min_pulse: self
+                .min_pulse_ms
+                .map_or(DEFAULT_MIN_PULSE, Duration::from_millis),
+            coin_gap: self
+                .coin_gap_ms
+                .map_or(DEFAULT_COIN_GAP, Duration::from_millis),
+        }
+    }
+}
+
+/// How the pulses of a coin are told apart
+#[derive(Debug, Clone, Copy)]
+pub struct PulseTiming {
+    pub min_pulse: Duration,
+    pub coin_gap: Duration,
+}
+
+/// Coin acceptor signaling each coin by a train of pulses, more pulses for more valuable coins
+pub struct CoinAcceptor {
+    pin: Input,
+    timing: PulseTiming,
+}
+
+impl CoinAcceptor {
+    pub fn new(pin: Input, timing: PulseTiming) -> Self {
+        Self { pin, timing }
+    }
+
+    /// Counts pulses in a background task, yields the number of pulses of every inserted coin
+    pub fn spawn(self) -> mpsc::UnboundedReceiver<u32> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POLL_INTERVAL);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+            // Start of the pulse in progress
+            let mut pulse_start: Option<Instant> = None;
+            let mut pulses = 0;
+            let mut last_pulse_end = Instant::now();
+
+            loop {
+                interval.tick().await;
+                match (self.pin.is_low(), pulse_start) {
+                    (true, None) => pulse_start = Some(Instant::now()),
+                    (false, Some(start)) => {
+                        pulse_start = None;
+                        if start.elapsed() >= self.timing.min_pulse {
+                            pulses += 1;
+                            last_pulse_end = Instant::now();
+                        } else {
+                            debug!("Ignoring {:?} coin acceptor glitch", start.elapsed());
+                        }
+                    }
+                    _ => {}
+                }
+
+                if pulses > 0
+                    && pulse_start.is_none()
+                    && last_pulse_end.elapsed() >= self.timing.coin_gap
+                {
+                    info!(pulses, "Coin inserted");
+                    metrics::counter!("candypi_coins_total").increment(1);
+                    if tx.send(pulses).is_err() {
+                        return;
+                    }
+                    pulses = 0;
+                }
+            }
+        });
+
+        rx
+    }
+}
@@ -0,0 +1,608 @@
+use crate::api::ApiConfig;
+use crate::backlight::BacklightSettings;
+use crate::cashu::CashuConfig;
+use crate::coins::CoinConfig;
+use crate::dispenser::{Mechanism, MotorRamp, RampCurve, StepperDriver};
+use crate::federation;
+use crate::fedimint::{DepositPolicy, FedimintBuilder};
+use crate::gateway::GatewayPolicy;
+use crate::hardware::DisplayPins;
+use crate::hopper::HopperSensorConfig;
+use crate::input::ButtonTiming;
+use crate::jam::JamSensorConfig;
+use crate::light_sensor::LightSensorKind;
+use crate::lights::{self, Choreography};
+use crate::lnd::LndConfig;
+use crate::mqtt::MqttConfig;
+use crate::nfc::NfcBus;
+use crate::pins::{OutputSpec, PinRef};
+use crate::rates::{FiatConfig, FiatPrice, Rate};
+use crate::wifi_setup::WifiSetupConfig;
+use fedimint_core::anyhow::{self, Context, ensure};
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Read at startup unless another file is passed with `--config`
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/candypi.toml";
+
+/// Price of the single product sold unless the config file says otherwise
+pub const DEFAULT_PRICE_SATS: u64 = 42;
+
+/// Settings from the config file, anything left out keeps the built-in default
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Price of one dispense, 42 sats unless set. Ignored if `products` are listed.
+    pub price_sats: Option<u64>,
+    /// Price of one dispense in fiat, e.g. `0.50 EUR`, converted to sats for every invoice.
+    /// `price_sats` is charged while no recent exchange rate is available.
+    pub price: Option<FiatPrice>,
+    /// Price tiers the tier button cycles through, the first one is shown at startup
+    pub products: Vec<Product>,
+    /// BCM GPIO number of the button cycling through `products`
+    pub tier_button_pin: Option<u8>,
+    /// Replaces the built-in button pins and timing
+    pub buttons: Option<ButtonConfig>,
+    /// Rotary encoder navigating the menus in addition to the buttons
+    pub encoder: Option<EncoderConfig>,
+    pub display: DisplayPins,
+    pub backlight: BacklightConfig,
+    /// Replaces the built-in dispense mechanism with a single motor
+    pub motor: Option<MotorConfig>,
+    /// Replaces the built-in dispense mechanism with a hobby servo
+    pub servo: Option<ServoConfig>,
+    /// Replaces the built-in dispense mechanism with a stepper motor
+    pub stepper: Option<StepperConfig>,
+    pub fedimint: FedimintConfig,
+    /// Takes payments with a Cashu mint instead of Fedimint, disabled unless set
+    pub cashu: Option<CashuConfig>,
+    /// Takes payments with the operator's LND node instead of Fedimint, disabled unless set
+    pub lnd: Option<LndConfig>,
+    /// Shows prices in this currency as well
+    pub fiat: Option<FiatConfig>,
+    /// JSON HTTP API, disabled unless set
+    pub api: Option<ApiConfig>,
+    /// Offers an on-chain address in the invoice QR code, disabled unless set
+    pub onchain: Option<OnchainConfig>,
+    /// Takes fiat coins as well, needs `fiat`
+    pub coins: Option<CoinConfig>,
+    /// Setup hotspot if no network comes up at boot, disabled unless set
+    pub wifi_setup: Option<WifiSetupConfig>,
+    /// Fleet telemetry, `CANDYPI_MQTT_URL` overrides the broker
+    pub mqtt: Option<MqttConfig>,
+    /// Warns before the hopper runs empty, `CANDYPI_HOPPER_SENSOR` overrides it
+    pub hopper_sensor: Option<HopperSensorConfig>,
+    /// Notices jammed dispenses, `CANDYPI_JAM_SENSOR` overrides it
+    pub jam_sensor: Option<JamSensorConfig>,
+    /// WS2812 strip on SPI1, `CANDYPI_LED_STRIP` and `CANDYPI_LED_BRIGHTNESS` override it
+    pub led_strip: Option<LedStripConfig>,
+    /// Lets the backlight follow the ambient light, `CANDYPI_LIGHT_SENSOR` overrides it
+    pub light_sensor: Option<LightSensorKind>,
+    /// SSD1306 OLED for the operator, `CANDYPI_STATUS_DISPLAY` overrides it
+    pub status_display: bool,
+    /// PN532 for tap to pay, `CANDYPI_NFC_READER` overrides it
+    pub nfc_reader: Option<NfcBus>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ButtonConfig {
+    /// BCM GPIO numbers, the buttons connect them to ground
+    pub next_pin: u8,
+    pub select_pin: u8,
+    /// Button replacing the invoice with a fresh one
+    pub refresh_pin: Option<u8>,
+    pub debounce_ms: u64,
+    /// Presses held this long count as long presses
+    pub long_press_ms: u64,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        let timing = ButtonTiming::default();
+        Self {
+            next_pin: 5,
+            select_pin: 6,
+            refresh_pin: None,
+            debounce_ms: timing.debounce.as_millis() as u64,
+            long_press_ms: timing.long_press.as_millis() as u64,
+        }
+    }
+}
+
+impl ButtonConfig {
+    pub fn timing(&self) -> ButtonTiming {
+        ButtonTiming {
+            debounce: Duration::from_millis(self.debounce_ms),
+            long_press: Duration::from_millis(self.long_press_ms),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BacklightConfig {
+    pub brightness_percent: u8,
+    /// Brightness after `dim_after_secs` without anyone using the machine
+    pub dimmed_percent: u8,
+    /// Never dims if unset
+    pub dim_after_secs: Option<u64>,
+}
+
+impl Default for BacklightConfig {
+    fn default() -> Self {
+        Self {
+            brightness_percent: 100,
+            dimmed_percent: 10,
+            dim_after_secs: None,
+        }
+    }
+}
+
+impl BacklightConfig {
+    pub fn settings(&self) -> BacklightSettings {
+        BacklightSettings {
+            brightness: f64::from(self.brightness_percent) / 100.0,
+            dimmed: f64::from(self.dimmed_percent) / 100.0,
+            dim_after: self.dim_after_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LedStripConfig {
+    /// Number of LEDs
+    pub length: usize,
+    /// 30 unless set, a long strip at full brightness draws more than a phone charger delivers
+    pub brightness_percent: Option<u8>,
+}
+
+impl LedStripConfig {
+    /// The configured strip with [`lights::LED_STRIP_ENV`] and [`lights::LED_BRIGHTNESS_ENV`]
+    /// applied, `None` without either a table or a length
+    pub fn from_env_or(config: Option<Self>) -> anyhow::Result<Option<Self>> {
+        let length = match std::env::var(lights::LED_STRIP_ENV) {
+            Ok(length) => length
+                .parse()
+                .with_context(|| format!("Invalid {}", lights::LED_STRIP_ENV))?,
+            Err(_) => match config {
+                Some(config) => config.length,
+                None => return Ok(None),
+            },
+        };
+        let brightness_percent = match std::env::var(lights::LED_BRIGHTNESS_ENV) {
+            Ok(percent) => Some(
+                percent
+                    .parse()
+                    .ok()
+                    .filter(|percent| *percent <= 100)
+                    .with_context(|| format!("Invalid {}", lights::LED_BRIGHTNESS_ENV))?,
+            ),
+            Err(_) => config.and_then(|config| config.brightness_percent),
+        };
+        Ok(Some(Self {
+            length,
+            brightness_percent,
+        }))
+    }
+
+    pub fn choreography(&self) -> Choreography {
+        let mut choreography = Choreography::default();
+        if let Some(percent) = self.brightness_percent {
+            choreography.brightness = f32::from(percent) / 100.0;
+        }
+        choreography
+    }
+}
+
+/// BCM GPIO numbers of a rotary encoder, all connecting to ground when active
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EncoderConfig {
+    /// Swap `a_pin` and `b_pin` if it turns the wrong way
+    pub a_pin: u8,
+    pub b_pin: u8,
+    pub push_pin: u8,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotorConfig {
+    /// BCM GPIO number, switched active high
+    pub pin: u8,
+    pub run_ms: u64,
+    /// Motor speed through PWM, full speed without PWM if 100
+    pub duty_percent: u8,
+    /// Soft start before `run_ms`
+    pub ramp_up_ms: u64,
+    /// Soft stop after `run_ms`
+    pub ramp_down_ms: u64,
+    pub ramp_curve: RampCurve,
+}
+
+impl Default for MotorConfig {
+    fn default() -> Self {
+        Self {
+            pin: 4,
+            run_ms: 500,
+            duty_percent: 100,
+            ramp_up_ms: 0,
+            ramp_down_ms: 0,
+            ramp_curve: RampCurve::Linear,
+        }
+    }
+}
+
+impl MotorConfig {
+    fn mechanism(&self) -> Mechanism {
+        Mechanism::Motor {
+            output: OutputSpec::active_high(PinRef::Native(self.pin)),
+            run: Duration::from_millis(self.run_ms),
+            ramp: self.ramp(),
+        }
+    }
+
+    /// `None` for a motor that is simply switched on and off
+    fn ramp(&self) -> Option<MotorRamp> {
+        if self.duty_percent == 100 && self.ramp_up_ms == 0 && self.ramp_down_ms == 0 {
+            return None;
+        }
+        Some(MotorRamp {
+            duty_cycle: f64::from(self.duty_percent) / 100.0,
+            up: Duration::from_millis(self.ramp_up_ms),
+            down: Duration::from_millis(self.ramp_down_ms),
+            curve: self.ramp_curve,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServoConfig {
+    /// BCM GPIO number, must be a native pin
+    pub pin: u8,
+    /// Angle in degrees the gate rests at
+    pub closed_deg: u16,
+    /// Angle in degrees that lets the candy out
+    pub open_deg: u16,
+    /// How long the gate stays open
+    pub dwell_ms: u64,
+    /// Pulse width at 0°
+    pub min_pulse_us: u64,
+    /// Pulse width at 180°
+    pub max_pulse_us: u64,
+}
+
+impl Default for ServoConfig {
+    fn default() -> Self {
+        Self {
+            pin: 18,
+            closed_deg: 0,
+            open_deg: 90,
+            dwell_ms: 1000,
+            min_pulse_us: 500,
+            max_pulse_us: 2500,
+        }
+    }
+}
+
+impl ServoConfig {
+    fn pulse_width(&self, angle_deg: u16) -> Duration {
+        let range = self.max_pulse_us - self.min_pulse_us;
+        Duration::from_micros(self.min_pulse_us + range * u64::from(angle_deg) / 180)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StepperDriverKind {
+    StepDir,
+    Uln2003,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StepperConfig {
+    pub driver: StepperDriverKind,
+    /// BCM GPIO numbers, `[step, dir]` for step-dir drivers and `[IN1, IN2, IN3, IN4]` for the
+    /// ULN2003
+    pub pins: Vec<u8>,
+    /// Steps per dispense, half steps with the ULN2003 (4096 per turn of a 28BYJ-48)
+    pub steps: u32,
+    /// Time between steps, sets the speed
+    #[serde(default = "default_step_us")]
+    pub step_us: u64,
+    /// Turns the other way
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+fn default_step_us() -> u64 {
+    2000
+}
+
+impl StepperConfig {
+    fn driver(&self) -> StepperDriver {
+        let output = |idx: usize| OutputSpec::active_high(PinRef::Native(self.pins[idx]));
+        match self.driver {
+            StepperDriverKind::StepDir => StepperDriver::StepDir {
+                step: output(0),
+                dir: output(1),
+                reverse: self.reverse,
+            },
+            // Reversing the coil order reverses the sequence
+            StepperDriverKind::Uln2003 if self.reverse => StepperDriver::Uln2003 {
+                coils: [output(3), output(2), output(1), output(0)],
+            },
+            StepperDriverKind::Uln2003 => StepperDriver::Uln2003 {
+                coils: [output(0), output(1), output(2), output(3)],
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Product {
+    /// Shown on the invoice screen and used as the invoice description
+    pub name: String,
+    /// Charged as is, or while no recent exchange rate is available if `price` is set
+    pub price_sats: u64,
+    /// Price in fiat, converted to sats for every invoice
+    pub price: Option<FiatPrice>,
+    /// Dispense time for this tier, the mechanism's own timing if unset
+    pub run_ms: Option<u64>,
+    /// Motor of this product's own hopper, either all products have one or none
+    pub motor: Option<MotorConfig>,
+}
+
+impl Product {
+    pub fn run(&self) -> Option<Duration> {
+        self.run_ms.map(Duration::from_millis)
+    }
+
+    /// Price in sats at `rate`, `price_sats` for products without a fiat price or without a
+    /// fresh rate
+    pub fn current_price_sats(&self, rate: Option<Rate>) -> u64 {
+        match (&self.price, rate) {
+            (Some(price), Some(rate)) => rate.fiat_to_sats(price.amount).max(1),
+            _ => self.price_sats,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FedimintConfig {
+    /// Invite code of the federation to join on first start
+    pub invite: Option<String>,
+    /// Defaults to `$XDG_DATA_HOME/fedimint/default`
+    pub datadir: Option<PathBuf>,
+    /// Any gateway of the federation, vetted ones first, unless set
+    pub gateway: GatewayPolicy,
+    /// Reaches the federation and its gateways through Tor, needs the `tor` feature
+    pub tor: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnchainConfig {
+    /// Claimed unless set, unconfirmed payments dispense right away but can be double spent
+    pub dispense_after: DepositPolicy,
+}
+
+impl Config {
+    /// Loads the config file, a missing file at the default path means all defaults
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        let explicit = path.is_some();
+        let path = path.unwrap_or(Path::new(DEFAULT_CONFIG_PATH));
+
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => {
+                return Ok(Self::default());
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Self::parse(&content).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    /// Parses and checks the contents of a config file
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let mut config: Self = toml::from_str(content)?;
+        ensure!(
+            config.price_sats != Some(0),
+            "price_sats must be at least 1"
+        );
+        for motor in config
+            .motor
+            .iter()
+            .chain(config.products.iter().flat_map(|p| &p.motor))
+        {
+            ensure!(
+                (1..=100).contains(&motor.duty_percent),
+                "duty_percent must be between 1 and 100"
+            );
+        }
+        let product_motors = config.products.iter().filter(|p| p.motor.is_some()).count();
+        ensure!(
+            product_motors == 0 || product_motors == config.products.len(),
+            "Either all products need a motor or none"
+        );
+        let mechanisms = [
+            product_motors > 0,
+            config.motor.is_some(),
+            config.servo.is_some(),
+            config.stepper.is_some(),
+        ];
+        ensure!(
+            mechanisms.iter().filter(|configured| **configured).count() <= 1,
+            "Configure only one of motor, servo, stepper and product motors"
+        );
+        if let Some(servo) = &config.servo {
+            ensure!(
+                servo.closed_deg <= 180 && servo.open_deg <= 180,
+                "Servo angles must be between 0 and 180 degrees"
+            );
+            ensure!(
+                servo.min_pulse_us < servo.max_pulse_us,
+                "min_pulse_us must be below max_pulse_us"
+            );
+        }
+        if let Some(stepper) = &config.stepper {
+            let pins = match stepper.driver {
+                StepperDriverKind::StepDir => 2,
+                StepperDriverKind::Uln2003 => 4,
+            };
+            ensure!(
+                stepper.pins.len() == pins,
+                "The {:?} stepper driver needs {} pins",
+                stepper.driver,
+                pins
+            );
+            ensure!(stepper.step_us >= 1000, "step_us must be at least 1000");
+        }
+        ensure!(
+            config.cashu.is_none() || config.lnd.is_none(),
+            "Configure only one of cashu and lnd"
+        );
+        ensure!(
+            config.backlight.brightness_percent <= 100 && config.backlight.dimmed_percent <= 100,
+            "Backlight brightness must be between 0 and 100 percent"
+        );
+        if let Some(led_strip) = &config.led_strip {
+            ensure!(
+                led_strip.brightness_percent.unwrap_or_default() <= 100,
+                "LED strip brightness must be between 0 and 100 percent"
+            );
+        }
+        if let Some(buttons) = &config.buttons {
+            ensure!(
+                buttons.debounce_ms < buttons.long_press_ms,
+                "long_press_ms must be longer than debounce_ms"
+            );
+        }
+        for product in &config.products {
+            ensure!(
+                product.price_sats != 0,
+                "price_sats of {} must be at least 1",
+                product.name
+            );
+        }
+
+        // Fiat prices need the exchange rate of their currency
+        let fiat_prices = config
+            .price
+            .iter()
+            .chain(config.products.iter().flat_map(|p| &p.price));
+        for price in fiat_prices {
+            let fiat = config.fiat.get_or_insert_with(|| FiatConfig {
+                currency: price.currency.clone(),
+                rate_url: None,
+                max_rate_age_secs: None,
+            });
+            ensure!(
+                fiat.currency == price.currency,
+                "Price in {} but exchange rates are fetched for {}",
+                price.currency,
+                fiat.currency
+            );
+        }
+        if let Some(coins) = &config.coins {
+            ensure!(
+                config.fiat.is_some(),
+                "The coin acceptor needs a [fiat] currency"
+            );
+            ensure!(coins.pulse_value > 0.0, "pulse_value must be positive");
+        }
+        if let Some(HopperSensorConfig::Ultrasonic { low_cm, .. }) = config.hopper_sensor {
+            ensure!(low_cm > 0.0, "low_cm must be positive");
+        }
+        ensure!(
+            !config.fedimint.tor || cfg!(feature = "tor"),
+            "tor is set but candypi was built without the tor feature"
+        );
+        if let Some(wifi_setup) = &config.wifi_setup {
+            ensure!(
+                (8..=63).contains(&wifi_setup.password.len()),
+                "The WiFi setup password must be 8 to 63 characters"
+            );
+        }
+        Ok(config)
+    }
+
+    /// The configured products, or a single one at `price_sats` if none are listed
+    pub fn products(&self) -> Vec<Product> {
+        if !self.products.is_empty() {
+            return self.products.clone();
+        }
+        vec![Product {
+            name: "M&Ms".to_string(),
+            price_sats: self.price_sats.unwrap_or(DEFAULT_PRICE_SATS),
+            price: self.price.clone(),
+            run_ms: None,
+            motor: None,
+        }]
+    }
+
+    /// One mechanism per product if the products have their own motors, in product order
+    pub fn channels(&self) -> Option<Vec<Mechanism>> {
+        if self.products.is_empty() || self.products.iter().any(|p| p.motor.is_none()) {
+            return None;
+        }
+        let channels = self
+            .products
+            .iter()
+            .flat_map(|p| &p.motor)
+            .map(MotorConfig::mechanism)
+            .collect();
+        Some(channels)
+    }
+
+    pub fn mechanism(&self) -> Option<Mechanism> {
+        if let Some(stepper) = &self.stepper {
+            return Some(Mechanism::Stepper {
+                driver: stepper.driver(),
+                steps: stepper.steps,
+                step_interval: Duration::from_micros(stepper.step_us),
+            });
+        }
+        if let Some(servo) = &self.servo {
+            return Some(Mechanism::Servo {
+                output: OutputSpec::active_high(PinRef::Native(servo.pin)),
+                open: servo.pulse_width(servo.open_deg),
+                closed: servo.pulse_width(servo.closed_deg),
+                hold: Duration::from_millis(servo.dwell_ms),
+            });
+        }
+        self.motor.as_ref().map(MotorConfig::mechanism)
+    }
+
+    pub fn datadir(&self) -> PathBuf {
+        self.fedimint
+            .datadir
+            .clone()
+            .unwrap_or_else(FedimintBuilder::default_datadir)
+    }
+
+    /// Builder for the configured federation and datadir. A federation joined at runtime
+    /// replaces the configured one.
+    pub fn fedimint_builder(&self) -> anyhow::Result<FedimintBuilder> {
+        let mut builder = FedimintBuilder::default()
+            .datadir(self.datadir())
+            .gateway_policy(self.fedimint.gateway.clone())
+            .tor(self.fedimint.tor);
+        if let Some(invite) = federation::load(&federation::default_path())? {
+            builder = builder.federation_invite(invite);
+        } else if let Some(invite) = &self.fedimint.invite {
+            builder = builder
+                .federation(invite)
+                .context("Invalid federation invite code")?;
+        }
+        Ok(builder)
+    }
+}
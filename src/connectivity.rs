@@ -0,0 +1,97 @@
+use crate::screen::ConnectionStatus;
+use crate::wallet::Wallet;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Environment variable with the `host:port` connected to over TCP to check for internet access
+pub const PROBE_ADDRESS_ENV: &str = "CANDYPI_PROBE_ADDRESS";
+
+const DEFAULT_PROBE_ADDRESS: &str = "1.1.1.1:443";
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A single lost probe on flaky venue Wi-Fi shouldn't take the machine offline
+const FAILURES_BEFORE_OFFLINE: u32 = 2;
+
+/// Checks the internet connection and the federation in the background
+pub struct ConnectionMonitor {
+    /// Only upgraded while probing, so shutting down the wallet isn't held up for long
+    wallet: Weak<Wallet>,
+    address: String,
+}
+
+impl ConnectionMonitor {
+    pub fn new(wallet: &Arc<Wallet>) -> Self {
+        Self {
+            wallet: Arc::downgrade(wallet),
+            address: std::env::var(PROBE_ADDRESS_ENV)
+                .unwrap_or_else(|_| DEFAULT_PROBE_ADDRESS.to_string()),
+        }
+    }
+
+    /// Starts probing, the wallet just connected so the status starts out as connected. Stops
+    /// once the wallet was shut down.
+    pub fn spawn(self) -> watch::Receiver<ConnectionStatus> {
+        let (status_tx, status_rx) = watch::channel(ConnectionStatus::Connected);
+        tokio::spawn(async move {
+            let mut failures = 0;
+            loop {
+                tokio::time::sleep(PROBE_INTERVAL).await;
+                let Some(result) = self.probe().await else {
+                    break;
+                };
+
+                let status = match result {
+                    Ok(()) => {
+                        failures = 0;
+                        ConnectionStatus::Connected
+                    }
+                    Err(e) => {
+                        failures += 1;
+                        warn!("Connectivity check failed ({}): {}", failures, e);
+                        if failures < FAILURES_BEFORE_OFFLINE {
+                            continue;
+                        }
+                        ConnectionStatus::Disconnected
+                    }
+                };
+                status_tx.send_if_modified(|current| {
+                    if *current == status {
+                        return false;
+                    }
+                    info!("Connection status changed to {:?}", status);
+                    *current = status;
+                    true
+                });
+            }
+        });
+        status_rx
+    }
+
+    /// Reaches the internet and then the federation, `None` once the wallet is gone
+    async fn probe(&self) -> Option<Result<(), String>> {
+        match tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(&self.address)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Some(Err(format!("no internet: {}", e))),
+            Err(_) => return Some(Err("no internet: timed out".to_string())),
+        }
+
+        let wallet = self.wallet.upgrade()?;
+        // Watch-only wallets have no federation to ask, their relay is on the internet
+        let Some(fedimint) = wallet.fedimint() else {
+            return Some(Ok(()));
+        };
+        Some(
+            match tokio::time::timeout(PROBE_TIMEOUT, fedimint.ping()).await {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(e)) => Err(format!("federation unreachable: {:#}", e)),
+                Err(_) => Err("federation unreachable: timed out".to_string()),
+            },
+        )
+    }
+}
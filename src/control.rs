@@ -0,0 +1,352 @@
+use crate::vending::VendingState;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::UnixListener;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Environment variable with the path of the control socket, enables it if set
+pub const CONTROL_SOCKET_ENV: &str = "CANDYPI_CONTROL_SOCKET";
+
+/// Seconds a message from `show-message` stays on screen unless specified
+const DEFAULT_MESSAGE_SECS: u64 = 10;
+/// Longest a message may stay on screen, longer breaks are what maintenance mode is for
+const MAX_MESSAGE_SECS: u64 = 60 * 60;
+
+/// Commands accepted on the control socket and stdin, one JSON object per line, e.g.
+/// `{"command": "set-price", "sats": 21}`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlCommand {
+    Status,
+    /// The invoice currently on screen
+    Invoice,
+    /// Dispenses once without payment
+    Dispense,
+    SetPrice {
+        sats: u64,
+    },
+    /// Sets the candy count after a refill, to the previous refill's count if left out
+    Refill {
+        #[serde(default)]
+        count: Option<u32>,
+    },
+    /// Takes the machine out of service, no invoices are shown while enabled
+    Maintenance {
+        enabled: bool,
+    },
+    /// Shows a text to customers for a while, then returns to the invoice
+    ShowMessage {
+        text: String,
+        #[serde(default = "default_message_secs", deserialize_with = "message_secs")]
+        seconds: u64,
+    },
+    /// Pays for one dispense with Fedimint ecash notes, e.g. forwarded from an NFC reader. Notes
+    /// worth more than the price are accepted, the change is kept.
+    RedeemNotes {
+        notes: String,
+    },
+    /// Sweeps the funds, deletes the wallet and restarts into the federation of `invite`
+    JoinFederation {
+        invite: String,
+    },
+    /// Shuts the application down cleanly
+    Quit,
+}
+
+fn default_message_secs() -> u64 {
+    DEFAULT_MESSAGE_SECS
+}
+
+fn message_secs<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let seconds = u64::deserialize(deserializer)?;
+    if seconds > MAX_MESSAGE_SECS {
+        return Err(serde::de::Error::custom(format!(
+            "a message can stay for at most {} seconds",
+            MAX_MESSAGE_SECS
+        )));
+    }
+    Ok(seconds)
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MachineStatus {
+    pub price_sats: u64,
+    pub maintenance: bool,
+    pub state: VendingState,
+    pub emergency_stop: bool,
+    /// `null` without a load cell
+    pub stock_grams: Option<u32>,
+    /// Pieces left, `null` while candy isn't counted
+    pub candy_left: Option<u32>,
+    pub ip: String,
+    /// Hash of the audit log's latest entry, noted down remotely it reveals a truncated log
+    pub audit_head: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrentInvoice {
+    /// `null` while no invoice is shown, e.g. in maintenance mode or while offline
+    pub invoice: Option<String>,
+    pub amount_sats: u64,
+}
+
+/// Answer to a command, sent back as one JSON line: `{"ok": true}`,
+/// `{"ok": true, "status": {..}}` or `{"ok": false, "error": ".."}`
+#[derive(Debug, Clone)]
+pub enum ControlResponse {
+    Ok,
+    Status(MachineStatus),
+    Invoice(CurrentInvoice),
+    Error(String),
+}
+
+impl ControlResponse {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            ControlResponse::Ok => serde_json::json!({ "ok": true }),
+            ControlResponse::Status(status) => serde_json::json!({ "ok": true, "status": status }),
+            ControlResponse::Invoice(invoice) => {
+                serde_json::json!({ "ok": true, "invoice": invoice })
+            }
+            ControlResponse::Error(error) => serde_json::json!({ "ok": false, "error": error }),
+        }
+    }
+}
+
+/// A command waiting for the main loop to execute it
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    reply: oneshot::Sender<ControlResponse>,
+}
+
+impl ControlRequest {
+    /// Passes `command` to the main loop and waits for its answer, `None` if the main loop is
+    /// gone
+    pub async fn send(
+        requests: &mpsc::Sender<ControlRequest>,
+        command: ControlCommand,
+    ) -> Option<ControlResponse> {
+        let (reply, response) = oneshot::channel();
+        requests
+            .send(ControlRequest { command, reply })
+            .await
+            .ok()?;
+        Some(
+            response
+                .await
+                .unwrap_or_else(|_| ControlResponse::Error("Command was dropped".to_string())),
+        )
+    }
+
+    pub fn reply(self, response: ControlResponse) {
+        // The client may have hung up already
+        let _ = self.reply.send(response);
+    }
+}
+
+/// Local control interface for scripts and GUIs, only the owner of the socket (i.e. the user
+/// candypi runs as) may connect
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlServer {
+    /// Binds the socket from [`CONTROL_SOCKET_ENV`], returns `None` if it isn't set
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match std::env::var(CONTROL_SOCKET_ENV) {
+            Ok(path) => Self::bind(Path::new(&path)).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Binds the socket at `path`, only connectable by our user. It is bound in a directory only
+    /// we can enter and moved into place once restricted, so nobody can connect in between.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        // A socket left behind by an unclean shutdown would make bind fail
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        let mut staging = path.as_os_str().to_owned();
+        staging.push(".bind");
+        let staging = PathBuf::from(staging);
+        match fs::remove_dir_all(&staging) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+        let bound = staging.join("socket");
+        let listener = UnixListener::bind(&bound)?;
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        fs::remove_dir(&staging)?;
+
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Accepts connections in a background task and forwards their commands to `requests`
+    pub fn spawn(self, requests: mpsc::Sender<ControlRequest>) {
+        info!("Control socket listening on {}", self.path.display());
+
+        tokio::spawn(async move {
+            loop {
+                match self.listener.accept().await {
+                    Ok((stream, _)) => {
+                        let (reader, writer) = stream.into_split();
+                        tokio::spawn(serve(BufReader::new(reader), writer, requests.clone()));
+                    }
+                    Err(e) => warn!("Failed to accept control connection: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Accepts the same commands on stdin as on the control socket, answering on stdout. Anything
+/// that isn't a valid command is rejected, so stray input can't dispense.
+pub fn spawn_stdin(requests: mpsc::Sender<ControlRequest>) {
+    tokio::spawn(serve(
+        BufReader::new(tokio::io::stdin()),
+        tokio::io::stdout(),
+        requests,
+    ));
+}
+
+async fn serve(
+    reader: impl AsyncBufRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    requests: mpsc::Sender<ControlRequest>,
+) {
+    let mut lines = reader.lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<ControlCommand>(&line) {
+            Ok(command) => match ControlRequest::send(&requests, command).await {
+                Some(response) => response,
+                None => return,
+            },
+            Err(e) => ControlResponse::Error(format!("Invalid command: {}", e)),
+        };
+
+        let mut json = response.to_json().to_string();
+        json.push('\n');
+        if writer.write_all(json.as_bytes()).await.is_err() || writer.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tokio::net::UnixStream;
+
+    fn parse(line: &str) -> serde_json::Result<ControlCommand> {
+        serde_json::from_str(line)
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(
+            parse(r#"{"command": "set-price", "sats": 21}"#).unwrap(),
+            ControlCommand::SetPrice { sats: 21 }
+        ));
+        assert!(matches!(
+            parse(r#"{"command": "refill"}"#).unwrap(),
+            ControlCommand::Refill { count: None }
+        ));
+        let ControlCommand::ShowMessage { text, seconds } =
+            parse(r#"{"command": "show-message", "text": "Back soon"}"#).unwrap()
+        else {
+            panic!("Not a message");
+        };
+        assert_eq!(text, "Back soon");
+        assert_eq!(seconds, DEFAULT_MESSAGE_SECS);
+        assert!(matches!(
+            parse(r#"{"command": "show-message", "text": "x", "seconds": 3600}"#).unwrap(),
+            ControlCommand::ShowMessage { seconds: 3600, .. }
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        let lines = [
+            "dispense",
+            "{}",
+            r#"{"sats": 21}"#,
+            r#"{"command": "dispense""#,
+            r#"{"command": "Dispense"}"#,
+            r#"{"command": "give-me-candy"}"#,
+            r#"{"command": "set-price"}"#,
+            r#"{"command": "set-price", "sats": -1}"#,
+            r#"{"command": "set-price", "sats": "21"}"#,
+            r#"{"command": "maintenance", "enabled": "yes"}"#,
+            r#"["dispense"]"#,
+            r#"{"command": "show-message", "text": "x", "seconds": 3601}"#,
+            r#"{"command": "show-message", "text": "x", "seconds": 18446744073709551615}"#,
+        ];
+        for line in lines {
+            assert!(parse(line).is_err(), "{line}");
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_malformed_lines_without_forwarding() {
+        let (requests, mut received) = mpsc::channel(1);
+        let mut output = Vec::new();
+        let input = [
+            "dispense",
+            "",
+            r#"{"command": 1}"#,
+            r#"{"command": "show-message", "text": "x", "seconds": 18446744073709551615}"#,
+        ]
+        .join("\n");
+        serve(input.as_bytes(), &mut output, requests).await;
+
+        assert!(received.try_recv().is_err());
+        let answers: Vec<serde_json::Value> = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(answers.len(), 3);
+        for answer in answers {
+            assert_eq!(answer["ok"], false);
+            assert!(
+                answer["error"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("Invalid command")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_socket_for_the_owner_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        fs::write(&path, "left over").unwrap();
+
+        let _server = ControlServer::bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Nothing is left of the staging directory
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        UnixStream::connect(&path).await.unwrap();
+    }
+}
@@ -0,0 +1,105 @@
+use crate::fedimint::DepositPolicy;
+use crate::wallet::Wallet;
+use fedimint_core::anyhow::{self, Context};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{Instrument, info, info_span};
+
+/// Progress of a payment to one of the watched addresses
+#[derive(Debug)]
+pub enum DepositEvent {
+    /// Seen unconfirmed, the policy waits for the federation to claim it
+    Detected { address: String, sats: u64 },
+    /// Counts as paid under the policy
+    Received { address: String, sats: u64 },
+    Failed {
+        address: String,
+        error: anyhow::Error,
+    },
+}
+
+/// Allocates on-chain addresses of the federation and watches each of them in a background task
+/// until it is paid, so a payment waiting for confirmations doesn't hold up the next customer
+pub struct DepositWatcher {
+    wallet: Arc<Wallet>,
+    policy: DepositPolicy,
+    events: mpsc::UnboundedSender<DepositEvent>,
+    watches: HashMap<String, JoinHandle<()>>,
+}
+
+impl DepositWatcher {
+    /// Fails in watch-only mode, where there is no federation wallet to pay into
+    pub fn new(
+        wallet: &Arc<Wallet>,
+        policy: DepositPolicy,
+    ) -> anyhow::Result<(Self, mpsc::UnboundedReceiver<DepositEvent>)> {
+        wallet
+            .fedimint()
+            .context("On-chain payments need a Fedimint wallet")?;
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let watcher = Self {
+            wallet: wallet.clone(),
+            policy,
+            events: events_tx,
+            watches: HashMap::new(),
+        };
+        Ok((watcher, events_rx))
+    }
+
+    /// Allocates a fresh address and starts watching it
+    pub async fn allocate(&mut self) -> anyhow::Result<String> {
+        let fedimint = self.wallet.fedimint().expect("Checked on creation");
+        let deposit = fedimint.deposit_address().await?;
+        let address = deposit.address.to_string();
+
+        let wallet = self.wallet.clone();
+        let policy = self.policy;
+        let events = self.events.clone();
+        let watched = address.clone();
+        let watch = tokio::spawn(
+            async move {
+                let fedimint = wallet.fedimint().expect("Checked on creation");
+                let detected = |amount: fedimint_core::bitcoin::Amount| {
+                    info!("Deposit detected, waiting for confirmation");
+                    let _ = events.send(DepositEvent::Detected {
+                        address: watched.clone(),
+                        sats: amount.to_sat(),
+                    });
+                };
+                let event = match fedimint.await_deposit(&deposit, policy, detected).await {
+                    Ok(amount) => {
+                        info!("Deposit received");
+                        DepositEvent::Received {
+                            address: watched,
+                            sats: amount.to_sat(),
+                        }
+                    }
+                    Err(error) => DepositEvent::Failed {
+                        address: watched,
+                        error,
+                    },
+                };
+                let _ = events.send(event);
+            }
+            .instrument(info_span!("deposit", %address)),
+        );
+        self.watches.insert(address.clone(), watch);
+        Ok(address)
+    }
+
+    /// Forgets about `address` once it was paid or its watch failed
+    pub fn finished(&mut self, address: &str) {
+        self.watches.remove(address);
+    }
+
+    /// Stops all watches. The federation client still claims payments waiting for confirmation
+    /// after a restart, but they no longer dispense.
+    pub async fn stop(self) {
+        for (_, watch) in self.watches {
+            watch.abort();
+            let _ = watch.await;
+        }
+    }
+}
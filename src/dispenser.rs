@@ -0,0 +1,708 @@
+use crate::jam::JamSignal;
+use crate::pins::{Output, OutputSpec, Pins};
+use async_trait::async_trait;
+use fedimint_core::anyhow;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc, oneshot, watch};
+use tracing::{Instrument, info, info_span, warn};
+
+/// Hobby servos expect a pulse every 20 ms
+const SERVO_PERIOD: Duration = Duration::from_millis(20);
+/// Time for a servo to reach its position before the pulses stop
+const SERVO_TRAVEL: Duration = Duration::from_millis(500);
+/// Coil patterns of the ULN2003 half-step sequence, IN1 to IN4
+const HALF_STEP_SEQUENCE: [[bool; 4]; 8] = [
+    [true, false, false, false],
+    [true, true, false, false],
+    [false, true, false, false],
+    [false, true, true, false],
+    [false, false, true, false],
+    [false, false, true, true],
+    [false, false, false, true],
+    [true, false, false, true],
+];
+/// Minimum step pulse width of common step/dir drivers (A4988, DRV8825), with some margin
+const STEP_PULSE: Duration = Duration::from_micros(10);
+
+/// The motor speed is updated this often while ramping
+const RAMP_STEP: Duration = Duration::from_millis(20);
+/// Time for the last piece to fall past the drop switch after the mechanism stopped
+const DROP_GRACE: Duration = Duration::from_secs(1);
+
+/// What happens once a payment arrived, e.g. running a motor, opening a door lock or telling
+/// another device to pour a beer
+#[async_trait]
+pub trait DispenseAction: Send {
+    /// Performs the action once and returns whether it completed
+    async fn dispense(&mut self) -> bool;
+
+    /// Like [`dispense`](Self::dispense), but runs for `run` instead of the configured time if
+    /// the action has one, e.g. for the larger portion of a more expensive tier
+    async fn dispense_for(&mut self, run: Duration) -> bool {
+        let _ = run;
+        self.dispense().await
+    }
+
+    /// Returns any hardware to its safe state, e.g. after the emergency stop tripped
+    fn set_idle(&mut self) {}
+
+    /// Picks the hopper the next dispenses come from, ignored by actions with a single one
+    fn select_channel(&mut self, channel: usize) {
+        let _ = channel;
+    }
+}
+
+/// Refuses to trigger the wrapped action while the emergency stop is tripped, for actions that
+/// don't watch it themselves. An action still running when it trips, e.g. retrying a request,
+/// is abandoned and set idle.
+pub struct Interlocked {
+    action: Box<dyn DispenseAction>,
+    estop: watch::Receiver<bool>,
+}
+
+impl Interlocked {
+    pub fn new(action: Box<dyn DispenseAction>, estop: watch::Receiver<bool>) -> Self {
+        Self { action, estop }
+    }
+
+    async fn run(&mut self, run: Option<Duration>) -> bool {
+        if *self.estop.borrow_and_update() {
+            warn!("Emergency stop active, not dispensing");
+            return false;
+        }
+        let completed = tokio::select! {
+            completed = run_action(self.action.as_mut(), run) => Some(completed),
+            Ok(_) = self.estop.wait_for(|tripped| *tripped) => None,
+        };
+        completed.unwrap_or_else(|| {
+            warn!("Emergency stop tripped, abandoning the dispense");
+            self.action.set_idle();
+            false
+        })
+    }
+}
+
+#[async_trait]
+impl DispenseAction for Interlocked {
+    async fn dispense(&mut self) -> bool {
+        self.run(None).await
+    }
+
+    async fn dispense_for(&mut self, run: Duration) -> bool {
+        self.run(Some(run)).await
+    }
+
+    fn set_idle(&mut self) {
+        self.action.set_idle();
+    }
+
+    fn select_channel(&mut self, channel: usize) {
+        self.action.select_channel(channel);
+    }
+}
+
+enum DispenserCommand {
+    /// Runs for the given time instead of the configured one if set
+    Dispense(Option<Duration>, oneshot::Sender<bool>),
+    SetIdle,
+    SelectChannel(usize),
+}
+
+/// Runs `action` for `run` if given, or its configured time
+async fn run_action(action: &mut dyn DispenseAction, run: Option<Duration>) -> bool {
+    match run {
+        Some(run) => action.dispense_for(run).await,
+        None => action.dispense().await,
+    }
+}
+
+/// Drives a [`DispenseAction`] from its own task, so a started dispense runs to completion no
+/// matter what the caller is busy with. Only [`set_idle`](DispenseAction::set_idle) cuts it
+/// short, it doesn't queue up behind the running dispense.
+#[derive(Clone)]
+pub struct DispenserHandle {
+    commands: mpsc::UnboundedSender<DispenserCommand>,
+    idle: Arc<Notify>,
+}
+
+impl DispenserHandle {
+    pub fn spawn(mut action: Box<dyn DispenseAction>) -> Self {
+        let (commands_tx, mut commands) = mpsc::unbounded_channel();
+        let idle = Arc::new(Notify::new());
+        let idle_requests = idle.clone();
+        tokio::spawn(async move {
+            while let Some(command) = commands.recv().await {
+                match command {
+                    DispenserCommand::Dispense(run, reply) => {
+                        let span = info_span!("dispense", run_ms = run.map(|run| run.as_millis()));
+                        let completed = tokio::select! {
+                            completed = run_action(action.as_mut(), run).instrument(span) => {
+                                Some(completed)
+                            }
+                            () = idle_requests.notified() => None,
+                        };
+                        let completed = completed.unwrap_or_else(|| {
+                            warn!("Dispense cut short");
+                            action.set_idle();
+                            false
+                        });
+                        let _ = reply.send(completed);
+                    }
+                    DispenserCommand::SetIdle => action.set_idle(),
+                    DispenserCommand::SelectChannel(channel) => action.select_channel(channel),
+                }
+            }
+            action.set_idle();
+        });
+        Self {
+            commands: commands_tx,
+            idle,
+        }
+    }
+
+    async fn request(&self, run: Option<Duration>) -> bool {
+        let (reply, completed) = oneshot::channel();
+        if self
+            .commands
+            .send(DispenserCommand::Dispense(run, reply))
+            .is_err()
+        {
+            return false;
+        }
+        completed.await.unwrap_or(false)
+    }
+}
+
+#[async_trait]
+impl DispenseAction for DispenserHandle {
+    async fn dispense(&mut self) -> bool {
+        self.request(None).await
+    }
+
+    async fn dispense_for(&mut self, run: Duration) -> bool {
+        self.request(Some(run)).await
+    }
+
+    /// Stops a running dispense right away, without waiting for it to finish
+    fn set_idle(&mut self) {
+        // Only wakes a running dispense, a later one must not be cut short
+        self.idle.notify_waiters();
+        let _ = self.commands.send(DispenserCommand::SetIdle);
+    }
+
+    /// Applies to all dispenses requested afterwards
+    fn select_channel(&mut self, channel: usize) {
+        let _ = self.commands.send(DispenserCommand::SelectChannel(channel));
+    }
+}
+
+/// Several hoppers with their own mechanism each, e.g. one per candy sold
+pub struct Channels {
+    channels: Vec<Box<dyn DispenseAction>>,
+    selected: usize,
+}
+
+impl Channels {
+    pub fn new(channels: Vec<Box<dyn DispenseAction>>) -> Self {
+        Self {
+            channels,
+            selected: 0,
+        }
+    }
+}
+
+#[async_trait]
+impl DispenseAction for Channels {
+    async fn dispense(&mut self) -> bool {
+        self.channels[self.selected].dispense().await
+    }
+
+    async fn dispense_for(&mut self, run: Duration) -> bool {
+        self.channels[self.selected].dispense_for(run).await
+    }
+
+    fn set_idle(&mut self) {
+        for channel in &mut self.channels {
+            channel.set_idle();
+        }
+    }
+
+    fn select_channel(&mut self, channel: usize) {
+        if channel < self.channels.len() {
+            self.selected = channel;
+        } else {
+            warn!(
+                "No dispense channel {}, keeping channel {}",
+                channel, self.selected
+            );
+        }
+    }
+}
+
+/// How a product channel releases its candy
+#[derive(Debug, Clone, Copy)]
+pub enum Mechanism {
+    /// DC motor, e.g. driving a spiral, that runs for a fixed time. Switched fully on and off
+    /// unless it has a `ramp`.
+    Motor {
+        output: OutputSpec,
+        run: Duration,
+        ramp: Option<MotorRamp>,
+    },
+    /// Solenoid latch of a flap-style dispenser. It is pulled in at full power, then held open at
+    /// a reduced duty cycle so the coil doesn't overheat, and finally released with some time for
+    /// the flap to close before the next dispense.
+    Solenoid {
+        output: OutputSpec,
+        energize: Duration,
+        hold: Duration,
+        hold_duty_cycle: f64,
+        release: Duration,
+    },
+    /// Two motors for large dual-auger dispensers, driven together or one after the other
+    DualMotor {
+        outputs: [OutputSpec; 2],
+        run: Duration,
+        drive: DualDrive,
+    },
+    /// Hobby servo on a native pin, e.g. turning a gate or a latch. It moves to the `open` pulse
+    /// width, waits `hold` and moves back to `closed`.
+    Servo {
+        output: OutputSpec,
+        open: Duration,
+        closed: Duration,
+        hold: Duration,
+    },
+    /// Stepper motor turning an auger by an exact number of steps, metering out the same portion
+    /// every time. Steps are timed by the async runtime, about 1 ms is the shortest interval.
+    Stepper {
+        driver: StepperDriver,
+        steps: u32,
+        step_interval: Duration,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum StepperDriver {
+    /// Driver boards like the A4988 or DRV8825, one pulse on `step` per (micro)step
+    StepDir {
+        step: OutputSpec,
+        dir: OutputSpec,
+        /// Turns the other way by activating `dir`
+        reverse: bool,
+    },
+    /// ULN2003 board of a 28BYJ-48, the coils are switched directly in half steps
+    Uln2003 { coils: [OutputSpec; 4] },
+}
+
+/// Soft start and stop of a motor through software PWM, so heavy candy loads don't stall it and
+/// the gears wear less. Needs a native pin, expander outputs only switch fully on.
+#[derive(Debug, Clone, Copy)]
+pub struct MotorRamp {
+    /// Speed while running, from 0.0 to 1.0
+    pub duty_cycle: f64,
+    /// Time from standstill to `duty_cycle`, before the run time
+    pub up: Duration,
+    /// Time from `duty_cycle` to standstill, after the run time
+    pub down: Duration,
+    pub curve: RampCurve,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RampCurve {
+    #[default]
+    Linear,
+    /// Eases in and out, gentlest on the gears at both ends of the ramp
+    SCurve,
+}
+
+impl RampCurve {
+    /// Share of the full speed after `progress` (0.0 to 1.0) of the ramp
+    fn speed(self, progress: f64) -> f64 {
+        match self {
+            RampCurve::Linear => progress,
+            RampCurve::SCurve => progress * progress * (3.0 - 2.0 * progress),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DualDrive {
+    /// Both motors start and stop together
+    Simultaneous,
+    /// The second motor starts after the first one stopped and the given gap, which halves the
+    /// inrush current on small power supplies
+    Sequential { gap: Duration },
+}
+
+impl Mechanism {
+    /// The same mechanism running or holding open for `run` instead
+    fn with_run(self, run: Duration) -> Self {
+        match self {
+            Mechanism::Motor { output, ramp, .. } => Mechanism::Motor { output, run, ramp },
+            Mechanism::Solenoid {
+                output,
+                energize,
+                hold_duty_cycle,
+                release,
+                ..
+            } => Mechanism::Solenoid {
+                output,
+                energize,
+                hold: run.saturating_sub(energize),
+                hold_duty_cycle,
+                release,
+            },
+            Mechanism::DualMotor { outputs, drive, .. } => Mechanism::DualMotor {
+                outputs,
+                run,
+                drive,
+            },
+            Mechanism::Servo {
+                output,
+                open,
+                closed,
+                ..
+            } => Mechanism::Servo {
+                output,
+                open,
+                closed,
+                hold: run,
+            },
+            // Products are portioned by run time, which the step rate turns into steps
+            Mechanism::Stepper {
+                driver,
+                step_interval,
+                ..
+            } => Mechanism::Stepper {
+                driver,
+                steps: (run.as_micros() / step_interval.as_micros().max(1)) as u32,
+                step_interval,
+            },
+        }
+    }
+}
+
+/// Why the mechanism stopped before it was done
+enum Interrupted {
+    EmergencyStop,
+    /// The current sense saw the motor stall
+    Stalled,
+    /// The mechanism ran, but no candy passed the drop switch
+    NothingDropped,
+}
+
+/// Sleeps while an output is switched on, returning early if the emergency stop trips or the
+/// motor stalls
+async fn wait(
+    estop: &mut watch::Receiver<bool>,
+    jam: &mut Option<JamSignal>,
+    duration: Duration,
+) -> Result<(), Interrupted> {
+    tokio::select! {
+        _ = tokio::time::sleep(duration) => Ok(()),
+        Ok(_) = estop.wait_for(|tripped| *tripped) => Err(Interrupted::EmergencyStop),
+        _ = stall(jam) => Err(Interrupted::Stalled),
+    }
+}
+
+/// Changes the motor speed step by step from `from` to `to` (shares of the ramp's duty cycle)
+async fn ramp_motor(
+    output: &mut Output,
+    estop: &mut watch::Receiver<bool>,
+    jam: &mut Option<JamSignal>,
+    ramp: &MotorRamp,
+    (from, to): (f64, f64),
+    duration: Duration,
+) -> Result<(), Interrupted> {
+    let steps = (duration.as_millis() / RAMP_STEP.as_millis()).max(1) as u32;
+    for step in 1..=steps {
+        let progress = from + (to - from) * step as f64 / steps as f64;
+        let duty_cycle = ramp.curve.speed(progress) * ramp.duty_cycle;
+        if duty_cycle > 0.0 {
+            output.activate_partial(duty_cycle);
+        } else {
+            output.deactivate();
+        }
+        wait(estop, jam, duration / steps).await?;
+    }
+    Ok(())
+}
+
+/// Completes once the motor stalls, never without a current sense
+async fn stall(jam: &mut Option<JamSignal>) {
+    match jam {
+        Some(JamSignal::Stalled(stalled)) => {
+            if stalled.wait_for(|stalled| *stalled).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+        _ => std::future::pending().await,
+    }
+}
+
+/// Actuator of a single product channel
+pub struct Dispenser {
+    mechanism: Mechanism,
+    outputs: Vec<Output>,
+    estop: watch::Receiver<bool>,
+    jam: Option<JamSignal>,
+    /// Told about every jam, so the payment can be refunded
+    jams: Option<mpsc::UnboundedSender<()>>,
+}
+
+impl Dispenser {
+    pub fn new(
+        pins: &Pins,
+        mechanism: Mechanism,
+        estop: watch::Receiver<bool>,
+    ) -> anyhow::Result<Self> {
+        let specs = match mechanism {
+            Mechanism::Motor { output, .. }
+            | Mechanism::Solenoid { output, .. }
+            | Mechanism::Servo { output, .. } => vec![output],
+            Mechanism::DualMotor { outputs, .. } => outputs.to_vec(),
+            Mechanism::Stepper {
+                driver: StepperDriver::StepDir { step, dir, .. },
+                ..
+            } => vec![step, dir],
+            Mechanism::Stepper {
+                driver: StepperDriver::Uln2003 { coils },
+                ..
+            } => coils.to_vec(),
+        };
+        let outputs = specs
+            .into_iter()
+            .map(|spec| pins.output(spec))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            mechanism,
+            outputs,
+            estop,
+            jam: None,
+            jams: None,
+        })
+    }
+
+    /// Stops the mechanism when `signal` shows a jam and reports it on `jams`. A dispense
+    /// without any candy passing the drop switch counts as jammed.
+    pub fn detect_jams(mut self, signal: JamSignal, jams: mpsc::UnboundedSender<()>) -> Self {
+        self.jam = Some(signal);
+        self.jams = Some(jams);
+        self
+    }
+
+    /// Waits for a piece to pass the drop switch if there is one
+    async fn candy_dropped(&mut self) -> bool {
+        match &mut self.jam {
+            Some(JamSignal::Drops(drops)) => tokio::time::timeout(DROP_GRACE, drops.changed())
+                .await
+                .is_ok_and(|changed| changed.is_ok()),
+            _ => true,
+        }
+    }
+
+    fn report_jam(&mut self) {
+        self.set_idle();
+        warn!("Dispenser jammed");
+        if let Some(jams) = &self.jams {
+            let _ = jams.send(());
+        }
+    }
+
+    async fn run(&mut self) -> Result<(), Interrupted> {
+        match self.mechanism {
+            Mechanism::Motor {
+                run, ramp: None, ..
+            } => {
+                info!("Dispensing candy for {} ms...", run.as_millis());
+                self.outputs[0].activate();
+                wait(&mut self.estop, &mut self.jam, run).await?;
+                self.outputs[0].deactivate();
+            }
+            Mechanism::Motor {
+                run,
+                ramp: Some(ramp),
+                ..
+            } => {
+                info!(
+                    "Dispensing candy for {} ms at {:.0}% speed...",
+                    run.as_millis(),
+                    ramp.duty_cycle * 100.0
+                );
+                let output = &mut self.outputs[0];
+                ramp_motor(
+                    output,
+                    &mut self.estop,
+                    &mut self.jam,
+                    &ramp,
+                    (0.0, 1.0),
+                    ramp.up,
+                )
+                .await?;
+                wait(&mut self.estop, &mut self.jam, run).await?;
+                ramp_motor(
+                    output,
+                    &mut self.estop,
+                    &mut self.jam,
+                    &ramp,
+                    (1.0, 0.0),
+                    ramp.down,
+                )
+                .await?;
+                output.deactivate();
+            }
+            Mechanism::Solenoid {
+                energize,
+                hold,
+                hold_duty_cycle,
+                release,
+                ..
+            } => {
+                info!("Opening flap for {} ms...", (energize + hold).as_millis());
+                self.outputs[0].activate();
+                wait(&mut self.estop, &mut self.jam, energize).await?;
+                self.outputs[0].activate_partial(hold_duty_cycle);
+                wait(&mut self.estop, &mut self.jam, hold).await?;
+                self.outputs[0].deactivate();
+                wait(&mut self.estop, &mut self.jam, release).await?;
+            }
+            Mechanism::DualMotor {
+                run,
+                drive: DualDrive::Simultaneous,
+                ..
+            } => {
+                info!(
+                    "Dispensing candy with both motors for {} ms...",
+                    run.as_millis()
+                );
+                for output in &mut self.outputs {
+                    output.activate();
+                }
+                wait(&mut self.estop, &mut self.jam, run).await?;
+                for output in &mut self.outputs {
+                    output.deactivate();
+                }
+            }
+            Mechanism::DualMotor {
+                run,
+                drive: DualDrive::Sequential { gap },
+                ..
+            } => {
+                info!(
+                    "Dispensing candy with each motor for {} ms...",
+                    run.as_millis()
+                );
+                for (idx, output) in self.outputs.iter_mut().enumerate() {
+                    if idx > 0 {
+                        wait(&mut self.estop, &mut self.jam, gap).await?;
+                    }
+                    output.activate();
+                    wait(&mut self.estop, &mut self.jam, run).await?;
+                    output.deactivate();
+                }
+            }
+            Mechanism::Servo {
+                open, closed, hold, ..
+            } => {
+                info!("Opening gate for {} ms...", hold.as_millis());
+                self.outputs[0].pulse(SERVO_PERIOD, open);
+                wait(&mut self.estop, &mut self.jam, SERVO_TRAVEL + hold).await?;
+                self.outputs[0].pulse(SERVO_PERIOD, closed);
+                wait(&mut self.estop, &mut self.jam, SERVO_TRAVEL).await?;
+                // Unpowered servos hold their position well enough and stop buzzing
+                self.outputs[0].set_idle();
+            }
+            Mechanism::Stepper {
+                driver,
+                steps,
+                step_interval,
+            } => {
+                info!("Turning the auger by {} steps...", steps);
+                if let StepperDriver::StepDir { reverse: true, .. } = driver {
+                    self.outputs[1].activate();
+                }
+                for step in 0..steps as usize {
+                    match driver {
+                        StepperDriver::StepDir { .. } => {
+                            self.outputs[0].activate();
+                            std::thread::sleep(STEP_PULSE);
+                            self.outputs[0].deactivate();
+                        }
+                        StepperDriver::Uln2003 { .. } => {
+                            let pattern = HALF_STEP_SEQUENCE[step % HALF_STEP_SEQUENCE.len()];
+                            for (coil, active) in self.outputs.iter_mut().zip(pattern) {
+                                if active {
+                                    coil.activate();
+                                } else {
+                                    coil.deactivate();
+                                }
+                            }
+                        }
+                    }
+                    wait(&mut self.estop, &mut self.jam, step_interval).await?;
+                }
+                // Energized coils only heat up the motor, the auger doesn't turn back by itself
+                self.set_idle();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DispenseAction for Dispenser {
+    /// Runs the mechanism once. Refuses to move while the emergency stop is tripped and cuts all
+    /// outputs as soon as it trips mid-dispense.
+    async fn dispense(&mut self) -> bool {
+        if *self.estop.borrow() {
+            warn!("Emergency stop active, not dispensing");
+            return false;
+        }
+
+        // Only pieces dropping from now on count
+        if let Some(JamSignal::Drops(drops)) = &mut self.jam {
+            drops.mark_unchanged();
+        }
+
+        let mut result = self.run().await;
+        if result.is_ok() && !self.candy_dropped().await {
+            result = Err(Interrupted::NothingDropped);
+        }
+
+        match result {
+            Ok(()) => {
+                info!("Candy dispensed!");
+                true
+            }
+            Err(Interrupted::Stalled | Interrupted::NothingDropped) => {
+                self.report_jam();
+                false
+            }
+            Err(Interrupted::EmergencyStop) => {
+                self.set_idle();
+                warn!("Dispense aborted by emergency stop");
+                false
+            }
+        }
+    }
+
+    async fn dispense_for(&mut self, run: Duration) -> bool {
+        let configured = self.mechanism;
+        self.mechanism = configured.with_run(run);
+        let completed = self.dispense().await;
+        self.mechanism = configured;
+        completed
+    }
+
+    /// Returns all outputs to their safe state
+    fn set_idle(&mut self) {
+        for output in &mut self.outputs {
+            output.set_idle();
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::audit::AuditLog;
+use crate::pins::{Input, PinRef, Pins};
+use fedimint_core::anyhow;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::info;
+
+/// Environment variable that, if set to `1`, locks the screen until the operator PIN is entered
+/// whenever the cabinet door is opened.
+pub const DOOR_PIN_ACK_ENV: &str = "CANDYPI_DOOR_PIN_ACK";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorEvent {
+    Opened,
+    Closed,
+}
+
+impl DoorEvent {
+    fn audit_name(self) -> &'static str {
+        match self {
+            DoorEvent::Opened => "door_opened",
+            DoorEvent::Closed => "door_closed",
+        }
+    }
+}
+
+/// Returns whether [`DOOR_PIN_ACK_ENV`] asks for a PIN acknowledgment when the door opens
+pub fn pin_ack_required() -> bool {
+    std::env::var(DOOR_PIN_ACK_ENV).is_ok_and(|value| value == "1")
+}
+
+/// Cabinet door switch connecting the GPIO to ground while the door is closed
+pub struct DoorSensor {
+    pin: Input,
+    audit_log: AuditLog,
+}
+
+impl DoorSensor {
+    pub fn new(pins: &Pins, pin: PinRef, audit_log: AuditLog) -> anyhow::Result<Self> {
+        Ok(Self {
+            pin: pins.input_pullup(pin)?,
+            audit_log,
+        })
+    }
+
+    fn is_open(&self) -> bool {
+        self.pin.is_high()
+    }
+
+    /// Polls the switch in a background task, recording every change in the audit log before
+    /// passing it on
+    pub fn spawn(self) -> mpsc::UnboundedReceiver<DoorEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut was_open = self.is_open();
+            if was_open {
+                self.audit_log.record_now("door_open_at_startup");
+            }
+
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+
+                let is_open = self.is_open();
+                if is_open == was_open {
+                    continue;
+                }
+                was_open = is_open;
+
+                let event = if is_open {
+                    DoorEvent::Opened
+                } else {
+                    DoorEvent::Closed
+                };
+                info!("Cabinet door event: {:?}", event);
+                self.audit_log.record_now(event.audit_name());
+
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+
+        rx
+    }
+}
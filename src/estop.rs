@@ -0,0 +1,74 @@
+use crate::pins::{Input, PinRef, Pins};
+use fedimint_core::anyhow;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Environment variable that, if set to `1`, enables the emergency stop input
+pub const ESTOP_ENV: &str = "CANDYPI_ESTOP";
+
+/// Short enough that a running motor stops before the next candy drops
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Latched emergency stop state shared by everything that moves. Once tripped it stays tripped
+/// until an operator resets it, even if the button is released again.
+#[derive(Clone)]
+pub struct EStopLatch(Arc<watch::Sender<bool>>);
+
+impl Default for EStopLatch {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl EStopLatch {
+    pub fn is_tripped(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.0.subscribe()
+    }
+
+    fn trip(&self) {
+        self.0
+            .send_if_modified(|tripped| !std::mem::replace(tripped, true));
+    }
+
+    /// Clears the latch. If the button is still pressed the monitor trips it again right away.
+    pub fn reset(&self) {
+        self.0.send_replace(false);
+    }
+}
+
+/// Emergency stop button with a normally closed contact between the GPIO and ground, so a cut
+/// wire stops the machine as well
+pub struct EmergencyStop {
+    pin: Input,
+}
+
+impl EmergencyStop {
+    pub fn new(pins: &Pins, pin: PinRef) -> anyhow::Result<Self> {
+        Ok(Self {
+            pin: pins.input_pullup(pin)?,
+        })
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.pin.is_high()
+    }
+
+    /// Polls the button in a background task and trips the latch while it is pressed
+    pub fn spawn(self, latch: EStopLatch) {
+        tokio::spawn(async move {
+            loop {
+                if self.is_pressed() && !latch.is_tripped() {
+                    warn!("Emergency stop pressed");
+                    latch.trip();
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+    }
+}
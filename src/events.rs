@@ -0,0 +1,80 @@
+use crate::input::Button;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+
+/// Subscribers that fall further behind than this miss events
+const CAPACITY: usize = 64;
+
+/// Something that happened on the machine, published for everyone interested
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    InvoiceCreated {
+        amount_msat: u64,
+    },
+    PaymentReceived {
+        amount_msat: u64,
+    },
+    DispenseStarted,
+    /// `completed` is false if the dispense was cut short, e.g. by the emergency stop
+    DispenseDone {
+        completed: bool,
+    },
+    /// The mechanism jammed during a dispense and was stopped, it may still be retried
+    DispenseJammed,
+    NetworkChanged {
+        ip: String,
+    },
+    ButtonPressed(Button),
+    /// The button was held down for a long press
+    ButtonHeld(Button),
+    TamperAlarm,
+    EmergencyStop,
+    /// A tamper alarm or emergency stop was dealt with and the machine is back in service
+    AlarmCleared,
+    /// The hopper level sensor saw stock run low, or refilled if `low` is false
+    HopperLow {
+        low: bool,
+    },
+    ShuttingDown,
+}
+
+/// Fans out [`Event`]s to any number of subscribers, e.g. lights, metrics and notifications.
+/// Publishing never blocks, slow subscribers lose old events instead.
+#[derive(Clone)]
+pub struct EventBus(broadcast::Sender<Event>);
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // Having no subscribers is fine
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> EventSubscriber {
+        EventSubscriber(self.0.subscribe())
+    }
+}
+
+pub struct EventSubscriber(broadcast::Receiver<Event>);
+
+impl EventSubscriber {
+    /// Waits for the next event, returns `None` once the bus is gone. Lost events are logged and
+    /// skipped.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Event subscriber lagging, missed {} events", missed)
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
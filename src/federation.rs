@@ -0,0 +1,38 @@
+use fedimint_core::anyhow::{self, Context};
+use fedimint_core::invite_code::InviteCode;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Defaults to `$XDG_DATA_HOME/candypi/federation`, holds the invite code of a federation
+/// joined at runtime
+pub fn default_path() -> PathBuf {
+    xdg::BaseDirectories::new()
+        .data_home
+        .expect("Could not determine XDG data home")
+        .join("candypi/federation")
+}
+
+/// Reads the invite code chosen at runtime, `None` if the operator never picked one
+pub fn load(path: &Path) -> anyhow::Result<Option<InviteCode>> {
+    match fs::read_to_string(path) {
+        Ok(content) => InviteCode::from_str(content.trim())
+            .map(Some)
+            .with_context(|| format!("Invalid invite code in {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Replaces the invite code on disk, a power cut leaves either the old or the new one
+pub fn save(path: &Path, invite: &InviteCode) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    writeln!(file, "{}", invite)?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)
+}
@@ -1,48 +1,81 @@
+use crate::gateway::{self, GatewayPolicy, GatewaySelector};
+use crate::prometheus;
+use crate::tpm::{self, SeedKey};
+use crate::wallet::PaymentProvider;
+use async_trait::async_trait;
 use fedimint_bip39::{Bip39RootSecretStrategy, Mnemonic};
+use fedimint_client::db::EncodedClientSecretKey;
 use fedimint_client::meta::MetaService;
 use fedimint_client::module::meta::LegacyMetaSource;
 use fedimint_client::secret::RootSecretStrategy;
-use fedimint_client::{Client, ClientHandle, ClientModuleInstance, RootSecret};
+use fedimint_client::{Client, ClientBuilder, ClientHandle, ClientModuleInstance, RootSecret};
 use fedimint_core::anyhow::{Context, anyhow, bail, ensure};
 use fedimint_core::bitcoin::hashes::sha256;
+use fedimint_core::bitcoin::{self, Address};
 use fedimint_core::core::OperationId;
-use fedimint_core::db::{Database, IRawDatabaseExt};
+use fedimint_core::db::{Database, IDatabaseTransactionOpsCoreTyped, IRawDatabaseExt};
+use fedimint_core::encoding::Encodable;
 use fedimint_core::invite_code::InviteCode;
+use fedimint_core::secp256k1::PublicKey;
 use fedimint_core::{Amount, anyhow};
 use fedimint_ln_client::{
-    LightningClientInit, LightningClientModule, LightningOperationMeta,
-    LightningOperationMetaVariant, LnReceiveState,
+    InternalPayState, LightningClientInit, LightningClientModule, LightningOperationMeta,
+    LightningOperationMetaVariant, LnPayState, LnReceiveState, PayType,
 };
+use fedimint_ln_common::LightningGateway;
 use fedimint_meta_client::MetaModuleMetaSourceWithFallback;
-use fedimint_mint_client::MintClientInit;
+use fedimint_mint_client::{
+    MintClientInit, MintClientModule, OOBNotes, ReissueExternalNotesState,
+    SelectNotesWithAtleastAmount, SelectNotesWithExactAmount,
+};
+use fedimint_wallet_client::{DepositStateV2, WalletClientInit, WalletClientModule};
 use futures_lite::stream::StreamExt;
-use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Description};
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Description, RoutingFees};
+use serde::Deserialize;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// Length of the unencrypted entropy of a 12 word mnemonic, sealed secrets are longer
+const PLAIN_ENTROPY_LEN: usize = 16;
 
 const ECASH_CLUB_INVITE: &str = "fed11qgqzggnhwden5te0v9cxjtn9vd3jue3wvfkxjmnyva6kzunyd9skutnwv46z7qqpyzhv5mxgpl79xz7j649sj6qldmde5s2uxchy4uh7840qgymsqmazzp6sn43";
 
 pub struct FedimintBuilder {
     datadir: PathBuf,
     federation: InviteCode,
+    seed_key: Option<SeedKey>,
+    progress: Option<watch::Sender<&'static str>>,
+    gateway_policy: GatewayPolicy,
+    tor: bool,
 }
 
 impl Default for FedimintBuilder {
     fn default() -> Self {
-        let xdg = xdg::BaseDirectories::new();
-
         Self {
-            datadir: xdg
-                .data_home
-                .expect("Could not determine XDG data home")
-                .join("fedimint/default"),
+            datadir: Self::default_datadir(),
             federation: InviteCode::from_str(ECASH_CLUB_INVITE).expect("can be parsed"),
+            seed_key: None,
+            progress: None,
+            gateway_policy: GatewayPolicy::default(),
+            tor: false,
         }
     }
 }
 
 impl FedimintBuilder {
+    /// Returns `$XDG_DATA_HOME/fedimint/default`
+    pub fn default_datadir() -> PathBuf {
+        xdg::BaseDirectories::new()
+            .data_home
+            .expect("Could not determine XDG data home")
+            .join("fedimint/default")
+    }
+
     /// Sets the directory where Fedimint data will be stored. Defaults to `$XDG_DATA_HOME/fedimint/default`
     pub fn datadir(mut self, path: PathBuf) -> Self {
         self.datadir = path;
@@ -62,24 +95,84 @@ impl FedimintBuilder {
         Ok(self)
     }
 
-    pub async fn build(self) -> anyhow::Result<Fedimint> {
+    /// Encrypts the wallet secret at rest with the given key. An existing unencrypted secret is
+    /// encrypted when the wallet is opened.
+    pub fn seed_key(mut self, key: SeedKey) -> Self {
+        self.seed_key = Some(key);
+        self
+    }
+
+    /// Reports each startup step, e.g. to show it on screen while joining takes a while
+    pub fn progress(mut self, progress: watch::Sender<&'static str>) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Restricts and orders the Lightning gateways invoices are created with
+    pub fn gateway_policy(mut self, policy: GatewayPolicy) -> Self {
+        self.gateway_policy = policy;
+        self
+    }
+
+    /// Connects to the federation's guardians and gateways through an embedded Tor client, so
+    /// the network the machine is on doesn't see which endpoints it talks to. Needs the `tor`
+    /// feature.
+    pub fn tor(mut self, enabled: bool) -> Self {
+        self.tor = enabled;
+        self
+    }
+
+    fn report(&self, step: &'static str) {
+        if let Some(progress) = &self.progress {
+            progress.send_replace(step);
+        }
+    }
+
+    /// Downloads the federation's config without joining it, to check an invite code before
+    /// giving up the current federation. Returns the federation's name if it has one.
+    pub async fn preview(&self) -> anyhow::Result<Option<String>> {
+        let preview = self
+            .client_builder()
+            .await?
+            .preview(&self.federation)
+            .await?;
+        Ok(preview.config().global.federation_name().map(str::to_owned))
+    }
+
+    async fn client_builder(&self) -> anyhow::Result<ClientBuilder> {
         let mut client_builder = fedimint_client::Client::builder().await?;
         client_builder.with_module(MintClientInit);
         client_builder.with_module(LightningClientInit::default());
+        client_builder.with_module(WalletClientInit::default());
+        if self.tor {
+            #[cfg(feature = "tor")]
+            client_builder.with_connector(fedimint_api_client::api::net::Connector::Tor);
+            #[cfg(not(feature = "tor"))]
+            bail!("Tor was requested but candypi was built without the tor feature");
+        }
         let mut client_builder = client_builder.with_iroh_enable_next(false);
         client_builder.with_meta_service(MetaService::new(MetaModuleMetaSourceWithFallback::<
             LegacyMetaSource,
         >::default()));
+        Ok(client_builder)
+    }
+
+    pub async fn build(self) -> anyhow::Result<Fedimint> {
+        let client_builder = self.client_builder().await?;
 
-        let db = fedimint_rocksdb::RocksDb::open(self.datadir)
+        self.report("Opening database");
+        let db = fedimint_rocksdb::RocksDb::open(&self.datadir)
             .await?
             .into_database();
 
         // TODO: use config being present to decide if to open or join
-        let client = if let Some(root_secret) = try_load_root_secret(&db).await? {
+        let seed_key = self.seed_key.as_ref();
+        let client = if let Some(root_secret) = try_load_root_secret(&db, seed_key).await? {
+            self.report("Opening wallet");
             client_builder.open(db, root_secret).await?
         } else {
-            let root_secret = generate_root_secret(&db).await?;
+            self.report("Joining federation");
+            let root_secret = generate_root_secret(&db, seed_key).await?;
             client_builder
                 .preview(&self.federation)
                 .await?
@@ -87,25 +180,73 @@ impl FedimintBuilder {
                 .await?
         };
 
-        Ok(Fedimint { client })
+        Ok(Fedimint {
+            client: Arc::new(client),
+            seed_key: self.seed_key,
+            gateway: Arc::new(GatewaySelector::new(self.gateway_policy)),
+            gateway_refresh: None,
+        })
     }
 }
 
-async fn try_load_root_secret(db: &Database) -> anyhow::Result<Option<RootSecret>> {
-    let Some(entropy) = Client::load_decodable_client_secret_opt::<Vec<u8>>(&db).await? else {
+async fn try_load_mnemonic(
+    db: &Database,
+    seed_key: Option<&SeedKey>,
+) -> anyhow::Result<Option<Mnemonic>> {
+    let Some(stored) = Client::load_decodable_client_secret_opt::<Vec<u8>>(&db).await? else {
         return Ok(None);
     };
 
-    let mnemonic = Mnemonic::from_entropy(&entropy)?;
+    let entropy = match seed_key {
+        Some(key) if stored.len() != PLAIN_ENTROPY_LEN => key.unseal(&stored)?,
+        Some(key) => {
+            warn!("Wallet secret is stored unencrypted although a TPM key is set, encrypting it");
+            reseal_secret(db, key.seal(&stored)?).await?;
+            stored
+        }
+        None => {
+            ensure!(
+                stored.len() == PLAIN_ENTROPY_LEN,
+                "Wallet secret is encrypted, set {} to decrypt it",
+                tpm::TPM_NV_INDEX_ENV
+            );
+            stored
+        }
+    };
+
+    Ok(Some(Mnemonic::from_entropy(&entropy)?))
+}
+
+/// Replaces the unencrypted secret of a wallet created before a TPM key was set
+async fn reseal_secret(db: &Database, sealed: Vec<u8>) -> anyhow::Result<()> {
+    let mut dbtx = db.begin_transaction().await;
+    dbtx.insert_entry(&EncodedClientSecretKey, &sealed.consensus_encode_to_vec())
+        .await;
+    dbtx.commit_tx_result().await
+}
+
+async fn try_load_root_secret(
+    db: &Database,
+    seed_key: Option<&SeedKey>,
+) -> anyhow::Result<Option<RootSecret>> {
+    let Some(mnemonic) = try_load_mnemonic(db, seed_key).await? else {
+        return Ok(None);
+    };
 
     Ok(Some(RootSecret::StandardDoubleDerive(
         Bip39RootSecretStrategy::<12>::to_root_secret(&mnemonic),
     )))
 }
 
-async fn generate_root_secret(db: &Database) -> anyhow::Result<RootSecret> {
+async fn generate_root_secret(
+    db: &Database,
+    seed_key: Option<&SeedKey>,
+) -> anyhow::Result<RootSecret> {
     let mnemonic = Mnemonic::generate(12)?;
-    let entropy = mnemonic.to_entropy();
+    let entropy = match seed_key {
+        Some(key) => key.seal(&mnemonic.to_entropy())?,
+        None => mnemonic.to_entropy(),
+    };
 
     Client::store_encodable_client_secret(&db, &entropy).await?;
 
@@ -116,12 +257,42 @@ async fn generate_root_secret(db: &Database) -> anyhow::Result<RootSecret> {
     )))
 }
 
+/// When an on-chain payment counts as received
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DepositPolicy {
+    /// Seen unconfirmed, fast but the customer could still double spend it
+    Unconfirmed,
+    /// Claimed as ecash, after as many confirmations as the federation requires
+    #[default]
+    Claimed,
+}
+
+/// Where a refund is sent
+pub enum RefundTo {
+    /// Pays the customer's invoice, which may ask for at most what they paid
+    Invoice(Bolt11Invoice),
+    /// Ecash notes for the customer to redeem, returning to the balance if nobody does within
+    /// `claim_time`
+    Notes { claim_time: Duration },
+}
+
+/// On-chain address of the federation, deposits to it are pegged in as ecash
+pub struct Deposit {
+    operation_id: OperationId,
+    pub address: Address,
+}
+
 pub struct Fedimint {
-    client: ClientHandle,
+    /// Only shared with the gateway refresh task, which is stopped before shutting down
+    client: Arc<ClientHandle>,
+    seed_key: Option<SeedKey>,
+    /// Selected ahead of time so creating an invoice doesn't have to look one up
+    gateway: Arc<GatewaySelector>,
+    gateway_refresh: Option<JoinHandle<()>>,
 }
 
 impl Fedimint {
-
     pub fn builder() -> FedimintBuilder {
         FedimintBuilder::default()
     }
@@ -134,6 +305,247 @@ impl Fedimint {
         &self.client
     }
 
+    /// Fetches the federation's gateways and selects one, so the first invoice of the day doesn't
+    /// wait for a cold lookup
+    pub async fn warm_up_gateway(&self) -> anyhow::Result<()> {
+        self.gateway.reselect(&self.client, None).await?;
+        Ok(())
+    }
+
+    /// Keeps checking the selected gateway in the background and re-selects one, e.g. once a
+    /// preferred gateway is back or the selected one went away
+    pub fn spawn_gateway_refresh(&mut self) {
+        let client = self.client.clone();
+        let selector = self.gateway.clone();
+        self.gateway_refresh = Some(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(selector.policy().health_check_interval()).await;
+                let unhealthy = match selector.check_health(&client).await {
+                    Ok(()) => None,
+                    Err(e) => {
+                        warn!("LN gateway health check failed: {:#}", e);
+                        selector.selected()
+                    }
+                };
+                if let Err(e) = selector.reselect(&client, unhealthy.as_ref()).await {
+                    warn!("Failed to refresh LN gateway: {:#}", e);
+                }
+            }
+        }));
+    }
+
+    /// Fees of the selected gateway, which it deducts from every payment received through it.
+    /// `None` until a gateway was selected.
+    pub fn gateway_fees(&self) -> Option<RoutingFees> {
+        self.gateway.selected().map(|gateway| gateway.fees)
+    }
+
+    async fn gateway(&self) -> anyhow::Result<LightningGateway> {
+        match self.gateway.selected() {
+            Some(gateway) => Ok(gateway),
+            None => self.gateway.reselect(&self.client, None).await,
+        }
+    }
+
+    /// Stops the client's background tasks and closes the database
+    pub async fn shutdown(self) {
+        if let Some(refresh) = self.gateway_refresh {
+            refresh.abort();
+            let _ = refresh.await;
+        }
+        Arc::into_inner(self.client)
+            .expect("Client is only shared with the gateway refresh")
+            .shutdown()
+            .await;
+    }
+
+    /// Returns the wallet's seed words, only meant to be shown to the operator for backups
+    pub async fn mnemonic(&self) -> anyhow::Result<Mnemonic> {
+        try_load_mnemonic(self.client.db(), self.seed_key.as_ref())
+            .await?
+            .context("Client secret missing from database")
+    }
+
+    /// Checks that the federation answers by fetching its gateway list
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        self.ln_module().update_gateway_cache().await?;
+        Ok(())
+    }
+
+    pub async fn balance(&self) -> anyhow::Result<Amount> {
+        Ok(self.client.get_balance().await)
+    }
+
+    /// Pays a Lightning invoice from the ecash balance, returns once the payment succeeded
+    pub async fn pay_invoice(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        // Withdrawing "whatever the payee asks for" is never what the operator meant
+        ensure!(
+            invoice.amount_milli_satoshis().is_some(),
+            "Invoice has no amount"
+        );
+        ensure!(!invoice.is_expired(), "Invoice expired");
+        let ln_client = self.ln_module();
+
+        let ln_gateway = self.gateway().await?;
+        let payment = ln_client
+            .pay_bolt11_invoice(Some(ln_gateway), invoice.clone(), ())
+            .await?;
+
+        match payment.payment_type {
+            PayType::Lightning(operation_id) => {
+                let mut update_stream = ln_client
+                    .subscribe_ln_pay(operation_id)
+                    .await
+                    .context("Unexpected error subscribing to operation")?
+                    .into_stream();
+                while let Some(update) = update_stream.next().await {
+                    match update {
+                        LnPayState::Success { .. } => return Ok(()),
+                        LnPayState::Canceled
+                        | LnPayState::Refunded { .. }
+                        | LnPayState::UnexpectedError { .. } => {
+                            bail!("Payment failed: {:?}", update);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            PayType::Internal(operation_id) => {
+                // Invoice was issued by another user of the same federation
+                let mut update_stream = ln_client
+                    .subscribe_internal_pay(operation_id)
+                    .await
+                    .context("Unexpected error subscribing to operation")?
+                    .into_stream();
+                while let Some(update) = update_stream.next().await {
+                    match update {
+                        InternalPayState::Preimage(_) => return Ok(()),
+                        InternalPayState::Funding => {}
+                        _ => bail!("Payment failed: {:?}", update),
+                    }
+                }
+            }
+        }
+
+        unreachable!("Stream ended unexpectedly");
+    }
+
+    /// Takes ecash notes from a customer of the same federation, returns their value once they
+    /// have been reissued to us and can't be spent by anyone else anymore
+    pub async fn redeem_notes(&self, notes: OOBNotes) -> anyhow::Result<Amount> {
+        let mint_client = self.mint_module();
+
+        let amount = notes.total_amount();
+        // Fails for notes of other federations
+        let operation_id = mint_client.reissue_external_notes(notes, ()).await?;
+        let mut update_stream = mint_client
+            .subscribe_reissue_external_notes(operation_id)
+            .await
+            .context("Unexpected error subscribing to operation")?
+            .into_stream();
+        while let Some(update) = update_stream.next().await {
+            match update {
+                ReissueExternalNotesState::Done => return Ok(amount),
+                ReissueExternalNotesState::Failed(e) => bail!("Redeeming notes failed: {}", e),
+                _ => {}
+            }
+        }
+
+        unreachable!("Stream ended unexpectedly");
+    }
+
+    /// Takes exactly `amount` out of the balance as ecash notes, e.g. to refund a customer.
+    /// Notes nobody redeemed within `claim_time` return to the balance.
+    pub async fn spend_notes(
+        &self,
+        amount: Amount,
+        claim_time: Duration,
+    ) -> anyhow::Result<OOBNotes> {
+        if let Ok(notes) = self.spend_exact_notes(amount, claim_time).await {
+            return Ok(notes);
+        }
+
+        // The notes on hand don't add up to the amount. Reissuing a larger note to ourselves
+        // splits it into smaller denominations to make change from.
+        let (_, change) = self
+            .mint_module()
+            .spend_notes_with_selector(&SelectNotesWithAtleastAmount, amount, claim_time, false, ())
+            .await?;
+        self.redeem_notes(change)
+            .await
+            .context("Failed to make change")?;
+        self.spend_exact_notes(amount, claim_time).await
+    }
+
+    async fn spend_exact_notes(
+        &self,
+        amount: Amount,
+        claim_time: Duration,
+    ) -> anyhow::Result<OOBNotes> {
+        // The invite lets wallets that don't know the federation yet redeem the notes
+        let (_, notes) = self
+            .mint_module()
+            .spend_notes_with_selector(&SelectNotesWithExactAmount, amount, claim_time, true, ())
+            .await?;
+        Ok(notes)
+    }
+
+    /// Allocates a fresh on-chain address, fails if the federation has no on-chain wallet
+    pub async fn deposit_address(&self) -> anyhow::Result<Deposit> {
+        let (operation_id, address, _) = self
+            .wallet_module()?
+            .safe_allocate_deposit_address(())
+            .await?;
+        Ok(Deposit {
+            operation_id,
+            address,
+        })
+    }
+
+    /// Waits until a payment to `deposit` counts as received under `policy`, returns the amount
+    /// sent before the federation's peg-in fee. `detected` is called when the payment is seen
+    /// unconfirmed but `policy` asks to wait for the federation.
+    pub async fn await_deposit(
+        &self,
+        deposit: &Deposit,
+        policy: DepositPolicy,
+        mut detected: impl FnMut(bitcoin::Amount),
+    ) -> anyhow::Result<bitcoin::Amount> {
+        let mut update_stream = self
+            .wallet_module()?
+            .subscribe_deposit(deposit.operation_id)
+            .await
+            .context("Unexpected error subscribing to operation")?
+            .into_stream();
+        while let Some(update) = update_stream.next().await {
+            match update {
+                DepositStateV2::WaitingForConfirmation { btc_deposited, .. } => {
+                    if policy == DepositPolicy::Unconfirmed {
+                        return Ok(btc_deposited);
+                    }
+                    detected(btc_deposited);
+                }
+                DepositStateV2::Claimed { btc_deposited, .. } => return Ok(btc_deposited),
+                DepositStateV2::Failed(e) => bail!("Deposit failed: {}", e),
+                _ => {}
+            }
+        }
+
+        unreachable!("Stream ended unexpectedly");
+    }
+
+    fn mint_module(&self) -> ClientModuleInstance<'_, MintClientModule> {
+        self.client
+            .get_first_module::<MintClientModule>()
+            .expect("Mint module not found")
+    }
+
+    fn wallet_module(&self) -> anyhow::Result<ClientModuleInstance<'_, WalletClientModule>> {
+        self.client
+            .get_first_module::<WalletClientModule>()
+            .context("Federation has no on-chain wallet")
+    }
+
     fn ln_module(&self) -> ClientModuleInstance<'_, LightningClientModule> {
         self.client
             .get_first_module::<LightningClientModule>()
@@ -147,34 +559,150 @@ impl Fedimint {
     ) -> anyhow::Result<Bolt11Invoice> {
         let ln_client = self.ln_module();
 
-        let ln_gateway = ln_client
-            .get_gateway(None, false)
-            .await?
-            .ok_or_else(|| anyhow!("No LN gateway available"))?;
-        let (_, invoice, _) = ln_client
+        let ln_gateway = self.gateway().await?;
+        let created = ln_client
             .create_bolt11_invoice(
                 Amount::from_msats(amount_msats),
                 Bolt11InvoiceDescription::Direct(Description::new(description.into())?),
                 None,
                 (),
-                Some(ln_gateway),
+                Some(ln_gateway.clone()),
             )
-            .await?;
+            .await;
 
-        Ok(invoice)
+        match created {
+            Ok((_, invoice, _)) => {
+                self.gateway.invoice_created();
+                Ok(invoice)
+            }
+            Err(e) => {
+                if self.gateway.invoice_failed() {
+                    warn!("Invoices keep failing, selecting another LN gateway");
+                    if let Err(e) = self.gateway.reselect(&self.client, Some(&ln_gateway)).await {
+                        warn!("Failed to select another LN gateway: {:#}", e);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Invoices issued by us since `since` with their creation time, newest first
+    pub async fn recent_receives(&self, since: SystemTime) -> Vec<(SystemTime, Bolt11Invoice)> {
+        const PAGE_SIZE: usize = 100;
+
+        let mut receives = Vec::new();
+        let mut last_seen = None;
+        loop {
+            let page = self
+                .client
+                .operation_log()
+                .paginate_operations_rev(PAGE_SIZE, last_seen)
+                .await;
+            let complete = page.len() < PAGE_SIZE;
+            for (key, operation) in &page {
+                if key.creation_time < since {
+                    return receives;
+                }
+                if operation.operation_module_kind() != "ln" {
+                    continue;
+                }
+                if let LightningOperationMetaVariant::Receive { invoice, .. } =
+                    operation.meta::<LightningOperationMeta>().variant
+                {
+                    receives.push((key.creation_time, invoice));
+                }
+            }
+            match page.last() {
+                Some((key, _)) if !complete => last_seen = Some(key.clone()),
+                _ => return receives,
+            }
+        }
     }
 
     pub async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
         self.await_payment_by_hash(invoice.payment_hash()).await
     }
 
-    pub async fn await_payment_by_hash(&self, payment_hash: &sha256::Hash) -> anyhow::Result<()> {
-        let operation_id = OperationId(*payment_hash.as_ref());
+    /// Amount received for the invoice with `payment_hash`, fails unless it was issued by us and
+    /// paid. The gateway's fee is part of the invoice amount but never reached the wallet.
+    pub async fn received_msat(&self, payment_hash: &sha256::Hash) -> anyhow::Result<u64> {
+        let (invoice, gateway_id, outcome) = self.receive_operation(payment_hash).await?;
+        ensure!(
+            matches!(outcome, Some(LnReceiveState::Claimed)),
+            "Invoice was not paid"
+        );
+        let invoice_msat = invoice
+            .amount_milli_satoshis()
+            .context("Invoice has no amount")?;
+
+        // Without a gateway the payment came from within the federation, free of fees
+        let Some(gateway_id) = gateway_id else {
+            return Ok(invoice_msat);
+        };
+        let fees = self
+            .ln_module()
+            .list_gateways()
+            .await
+            .into_iter()
+            .find(|announcement| announcement.info.gateway_id == gateway_id)
+            .map(|announcement| announcement.info.fees)
+            .or_else(|| self.gateway_fees())
+            .context("Fees of the gateway the invoice was paid through are unknown")?;
+        Ok(gateway::net_amount_msat(&fees, invoice_msat))
+    }
+
+    /// Sends back what a customer paid for the invoice with `payment_hash`, e.g. after a failed
+    /// dispense. Returns the notes when refunding as ecash.
+    pub async fn refund(
+        &self,
+        payment_hash: &sha256::Hash,
+        to: RefundTo,
+    ) -> anyhow::Result<Option<OOBNotes>> {
+        let received_msat = self.received_msat(payment_hash).await?;
+        match to {
+            RefundTo::Invoice(invoice) => {
+                let amount_msat = invoice
+                    .amount_milli_satoshis()
+                    .context("Invoice has no amount")?;
+                ensure!(
+                    amount_msat <= received_msat,
+                    "Invoice asks for {} msat, more than the {} msat received",
+                    amount_msat,
+                    received_msat
+                );
+                self.pay_invoice(&invoice).await?;
+                Ok(None)
+            }
+            RefundTo::Notes { claim_time } => {
+                let notes = self
+                    .spend_notes(Amount::from_msats(received_msat), claim_time)
+                    .await?;
+                Ok(Some(notes))
+            }
+        }
+    }
+
+    /// Whether the invoice with `payment_hash` was paid, `None` while that isn't settled yet.
+    /// Unlike [`Self::await_payment_by_hash`] this never waits.
+    pub async fn receive_outcome(
+        &self,
+        payment_hash: &sha256::Hash,
+    ) -> anyhow::Result<Option<bool>> {
+        let (_, _, outcome) = self.receive_operation(payment_hash).await?;
+        Ok(outcome.map(|state| matches!(state, LnReceiveState::Claimed)))
+    }
 
+    /// Invoice of an incoming payment, the gateway it was issued through and its final state,
+    /// `None` while it is still pending
+    async fn receive_operation(
+        &self,
+        payment_hash: &sha256::Hash,
+    ) -> anyhow::Result<(Bolt11Invoice, Option<PublicKey>, Option<LnReceiveState>)> {
         let operation = self
             .client
             .operation_log()
-            .get_operation(operation_id)
+            .get_operation(OperationId(*payment_hash.as_ref()))
             .await
             .context(
                 "No operation found for payment hash, was the invoice issued by us?".to_string(),
@@ -184,14 +712,20 @@ impl Fedimint {
             "Operation associated with payment hash is not an LN operation"
         );
 
-        let operation_meta = operation.meta::<LightningOperationMeta>();
-        ensure!(
-            matches!(
-                operation_meta.variant,
-                LightningOperationMetaVariant::Receive { .. }
-            ),
-            "Operation associated with the payment hash is not an incoming payment"
-        );
+        let LightningOperationMetaVariant::Receive {
+            invoice,
+            gateway_id,
+            ..
+        } = operation.meta::<LightningOperationMeta>().variant
+        else {
+            bail!("Operation associated with the payment hash is not an incoming payment");
+        };
+        Ok((invoice, gateway_id, operation.outcome::<LnReceiveState>()))
+    }
+
+    pub async fn await_payment_by_hash(&self, payment_hash: &sha256::Hash) -> anyhow::Result<()> {
+        let operation_id = OperationId(*payment_hash.as_ref());
+        self.receive_operation(payment_hash).await?;
 
         let ln_module = self.ln_module();
         let mut update_stream = ln_module
@@ -199,12 +733,21 @@ impl Fedimint {
             .await
             .context("Unexpected error subscribing to operation")?
             .into_stream();
+        // Time from the gateway funding the contract until the ecash is ours
+        let mut funded_at = None;
         while let Some(update) = update_stream.next().await {
             match update {
                 LnReceiveState::Canceled { reason } => {
                     return Err(anyhow!("Payment was canceled: {}", reason));
                 }
+                LnReceiveState::Funded => funded_at = Some(Instant::now()),
                 LnReceiveState::Claimed => {
+                    if let Some(funded_at) = funded_at {
+                        prometheus::record_duration(
+                            "candypi_payment_detection_seconds",
+                            funded_at.elapsed(),
+                        );
+                    }
                     return Ok(());
                 }
                 _ => {}
@@ -214,3 +757,18 @@ impl Fedimint {
         unreachable!("Stream ended unexpectedly");
     }
 }
+
+#[async_trait]
+impl PaymentProvider for Fedimint {
+    async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        Fedimint::lightning_invoice(self, amount_msats, description).await
+    }
+
+    async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        Fedimint::await_payment(self, invoice).await
+    }
+}
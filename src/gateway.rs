@@ -0,0 +1,233 @@
+use fedimint_client::{ClientHandle, ClientModuleInstance};
+use fedimint_core::anyhow::{self, Context, ensure};
+use fedimint_ln_client::LightningClientModule;
+use fedimint_ln_common::LightningGateway;
+use lightning_invoice::RoutingFees;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tracing::info;
+
+/// How often the selected gateway is checked and a gateway re-selected in the background
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A single failed invoice may well be the federation's fault rather than the gateway's
+const DEFAULT_FAILURES_BEFORE_RESELECT: u32 = 2;
+
+/// Which of the federation's Lightning gateways invoices are created with
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GatewayPolicy {
+    /// Gateway IDs (hex public keys) tried in this order before any other gateway
+    pub preferred: Vec<String>,
+    /// Gateways charging a higher base fee are never used
+    pub max_base_msat: Option<u32>,
+    /// Gateways charging a higher proportional fee, in parts per million, are never used
+    pub max_ppm: Option<u32>,
+    /// Ten minutes unless set
+    pub health_check_secs: Option<u64>,
+    /// Invoices failing in a row before another gateway is selected, two unless set
+    pub failures_before_reselect: Option<u32>,
+    /// Charges customers paying by Lightning the gateway's receive fee on top of the price
+    pub add_fee: bool,
+}
+
+impl GatewayPolicy {
+    pub fn health_check_interval(&self) -> Duration {
+        self.health_check_secs
+            .map_or(DEFAULT_HEALTH_CHECK_INTERVAL, Duration::from_secs)
+    }
+
+    fn within_fee_caps(&self, gateway: &LightningGateway) -> bool {
+        self.max_base_msat
+            .is_none_or(|max| gateway.fees.base_msat <= max)
+            && self
+                .max_ppm
+                .is_none_or(|max| gateway.fees.proportional_millionths <= max)
+    }
+
+    /// Position in the preferred list, gateways that aren't listed come last
+    fn rank(&self, gateway: &LightningGateway) -> usize {
+        let id = gateway.gateway_id.to_string();
+        self.preferred
+            .iter()
+            .position(|preferred| preferred.eq_ignore_ascii_case(&id))
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// Keeps the gateway selected under a [`GatewayPolicy`], shared with the background health check
+pub struct GatewaySelector {
+    policy: GatewayPolicy,
+    selected: Mutex<Option<LightningGateway>>,
+    /// Invoices that failed in a row with the selected gateway
+    failures: AtomicU32,
+}
+
+impl GatewaySelector {
+    pub fn new(policy: GatewayPolicy) -> Self {
+        Self {
+            policy,
+            selected: Mutex::new(None),
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> &GatewayPolicy {
+        &self.policy
+    }
+
+    pub fn selected(&self) -> Option<LightningGateway> {
+        self.selected.lock().expect("Gateway lock poisoned").clone()
+    }
+
+    /// Fetches the federation's gateways and selects the best one under the policy: preferred
+    /// gateways in order, then vetted ones, then the cheapest. `avoid` is only selected if no
+    /// other gateway is within the fee caps.
+    pub async fn reselect(
+        &self,
+        client: &ClientHandle,
+        avoid: Option<&LightningGateway>,
+    ) -> anyhow::Result<LightningGateway> {
+        let ln_client = ln_module(client);
+        ln_client.update_gateway_cache().await?;
+        let mut candidates: Vec<_> = ln_client
+            .list_gateways()
+            .await
+            .into_iter()
+            .filter(|announcement| self.policy.within_fee_caps(&announcement.info))
+            .collect();
+        ensure!(!candidates.is_empty(), "No LN gateway within the fee caps");
+        if let Some(avoid) = avoid.filter(|_| candidates.len() > 1) {
+            candidates.retain(|announcement| announcement.info.gateway_id != avoid.gateway_id);
+        }
+        // Small candy payments make the base fee matter most
+        candidates.sort_by_key(|announcement| {
+            let gateway = &announcement.info;
+            (
+                self.policy.rank(gateway),
+                !announcement.vetted,
+                gateway.fees.base_msat,
+                gateway.fees.proportional_millionths,
+            )
+        });
+        let gateway = candidates.swap_remove(0).info;
+
+        let previous = self
+            .selected
+            .lock()
+            .expect("Gateway lock poisoned")
+            .replace(gateway.clone());
+        if previous.is_none_or(|previous| previous.gateway_id != gateway.gateway_id) {
+            info!(
+                gateway = %gateway.gateway_id,
+                base_msat = gateway.fees.base_msat,
+                ppm = gateway.fees.proportional_millionths,
+                "Selected LN gateway"
+            );
+        }
+        self.failures.store(0, Ordering::Relaxed);
+        Ok(gateway)
+    }
+
+    /// Checks that the selected gateway is still announced by the federation and answers on its
+    /// API
+    pub async fn check_health(&self, client: &ClientHandle) -> anyhow::Result<()> {
+        let gateway = self.selected().context("No LN gateway selected")?;
+        let ln_client = ln_module(client);
+        ln_client.update_gateway_cache().await?;
+        ensure!(
+            ln_client
+                .list_gateways()
+                .await
+                .iter()
+                .any(|announcement| announcement.info.gateway_id == gateway.gateway_id),
+            "LN gateway is no longer announced"
+        );
+
+        let url = gateway.api.join("id").context("Invalid gateway URL")?;
+        reqwest::Client::new()
+            .get(url.to_string())
+            .timeout(HEALTH_CHECK_TIMEOUT)
+            .send()
+            .await
+            .context("LN gateway unreachable")?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub fn invoice_created(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Counts a failed invoice, returns true once enough failed in a row to select another
+    /// gateway
+    pub fn invoice_failed(&self) -> bool {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        failures
+            >= self
+                .policy
+                .failures_before_reselect
+                .unwrap_or(DEFAULT_FAILURES_BEFORE_RESELECT)
+    }
+}
+
+/// Invoice amount leaving `net_msat` once a gateway charging `fees` took its cut
+pub fn gross_amount_msat(fees: &RoutingFees, net_msat: u64) -> u64 {
+    let ppm = u128::from(fees.proportional_millionths).min(999_999);
+    let gross = (u128::from(net_msat) + u128::from(fees.base_msat)) * 1_000_000;
+    gross.div_ceil(1_000_000 - ppm) as u64
+}
+
+/// What reaches the wallet of an invoice over `gross_msat` once a gateway charging `fees` took its
+/// cut, the fee is rounded up
+pub fn net_amount_msat(fees: &RoutingFees, gross_msat: u64) -> u64 {
+    let proportional = (u128::from(gross_msat) * u128::from(fees.proportional_millionths))
+        .div_ceil(1_000_000) as u64;
+    gross_msat.saturating_sub(u64::from(fees.base_msat) + proportional)
+}
+
+fn ln_module(client: &ClientHandle) -> ClientModuleInstance<'_, LightningClientModule> {
+    client
+        .get_first_module::<LightningClientModule>()
+        .expect("LN module not found")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FEES: RoutingFees = RoutingFees {
+        base_msat: 1000,
+        proportional_millionths: 5000,
+    };
+
+    #[test]
+    fn net_amount_deducts_base_and_proportional_fee() {
+        assert_eq!(net_amount_msat(&FEES, 100_000), 98_500);
+    }
+
+    #[test]
+    fn net_amount_rounds_fee_up() {
+        let fees = RoutingFees {
+            base_msat: 0,
+            proportional_millionths: 1,
+        };
+        assert_eq!(net_amount_msat(&fees, 1_000_001), 999_999);
+    }
+
+    #[test]
+    fn net_amount_never_goes_negative() {
+        assert_eq!(net_amount_msat(&FEES, 500), 0);
+    }
+
+    #[test]
+    fn net_amount_of_gross_amount_is_the_price() {
+        for net in [1_000, 42_000, 2_100_000] {
+            assert_eq!(net_amount_msat(&FEES, gross_amount_msat(&FEES, net)), net);
+        }
+    }
+}
@@ -0,0 +1,655 @@
+use crate::actions::{self, HttpTrigger, MqttTrigger};
+use crate::audit::AuditLog;
+use crate::backlight::{Backlight, BacklightSettings};
+use crate::climate::ClimateSensor;
+use crate::coins::{CoinAcceptor, PulseTiming};
+use crate::dispenser::{Channels, DispenseAction, Dispenser, Interlocked, Mechanism};
+use crate::door::{DoorEvent, DoorSensor};
+use crate::estop::{EStopLatch, EmergencyStop};
+use crate::events::EventBus;
+use crate::hopper::{HopperSensor, HopperSensorConfig};
+use crate::ili9341::Ili9341;
+use crate::input::{Button, ButtonTiming, Buttons, PanelButton, RotaryEncoder};
+use crate::jam::{JamSensorConfig, JamSignal};
+use crate::light_sensor::LightSensor;
+use crate::lights::{Choreography, LedStrip};
+use crate::load_cell::{Hx711, LoadCellCalibration};
+use crate::nfc::{NfcTap, Pn532};
+use crate::notify::Notifier;
+use crate::pins::{self, OutputSpec, PinRef, Pins};
+use crate::screen::{
+    DISPLAY_HEIGHT, DISPLAY_WIDTH, Display, DisplayOrientation, Panel, QrErrorCorrection, QrStyle,
+    init_panel,
+};
+use crate::soft_spi::{DisplaySpi, SoftSpi};
+use crate::status_display::StatusDisplay;
+use crate::tamper::{BusinessHours, TamperAlarm, TamperMonitor};
+use crate::ups::{Ups, UpsEvent};
+use crate::{estop, ups};
+use fedimint_core::anyhow::{self, Context, anyhow};
+use rppal::gpio::{Gpio, OutputPin};
+use rppal::spi::{Bus, Mode, SimpleHalSpiDevice, SlaveSelect, Spi};
+use serde::Deserialize;
+use st7735_lcd::ST7735;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::warn;
+
+pub const DISPENSE_MECHANISM: Mechanism = Mechanism::Motor {
+    output: OutputSpec::active_high(PinRef::Native(4)),
+    run: Duration::from_millis(500),
+    ramp: None,
+};
+
+pub const BUTTON_NEXT_PIN: PinRef = PinRef::Native(5);
+pub const BUTTON_SELECT_PIN: PinRef = PinRef::Native(6);
+
+pub const TAMPER_SENSOR_PIN: PinRef = PinRef::Native(17);
+pub const BUZZER_OUTPUT: OutputSpec = OutputSpec::active_high(PinRef::Native(27));
+
+pub const DOOR_SENSOR_PIN: PinRef = PinRef::Native(16);
+
+pub const ESTOP_PIN: PinRef = PinRef::Native(12);
+
+// The HX711 is bit-banged and has to sit on native pins
+pub const LOAD_CELL_DOUT_PIN: u8 = 23;
+pub const LOAD_CELL_SCK_PIN: u8 = 26;
+
+/// Controller of the display, both are wired the same way
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayDriver {
+    /// 128x160
+    #[default]
+    St7735,
+    /// 240x320, leaves room for larger QR modules on long invoices
+    Ili9341,
+}
+
+/// Display controller and control lines, BCM GPIO numbers. The data lines are fixed to SPI0.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisplayPins {
+    pub driver: DisplayDriver,
+    pub orientation: DisplayOrientation,
+    /// Flips the picture left to right
+    pub mirrored: bool,
+    pub qr_error_correction: QrErrorCorrection,
+    /// Width of the light border around QR codes, in modules
+    pub qr_quiet_zone: u32,
+    pub qr_lightning_uri: bool,
+    /// Uppercase invoice QR codes for larger modules, off for wallets that reject them
+    pub qr_uppercase: bool,
+    pub backlight: u8,
+    pub dc: u8,
+    pub reset: u8,
+}
+
+impl Default for DisplayPins {
+    fn default() -> Self {
+        Self {
+            driver: DisplayDriver::default(),
+            orientation: DisplayOrientation::default(),
+            mirrored: false,
+            qr_error_correction: QrErrorCorrection::default(),
+            qr_quiet_zone: 0,
+            qr_lightning_uri: false,
+            qr_uppercase: true,
+            backlight: 22,
+            dc: 24,
+            reset: 25,
+        }
+    }
+}
+
+// SPI0 pins, only driven directly when the display SPI is bit-banged
+const LCD_SCLK_PIN: u8 = 11;
+const LCD_MOSI_PIN: u8 = 10;
+const LCD_CS_PIN: u8 = 8;
+
+/// Environment variable selecting timings for older boards, `armv6` for the Pi 1 and the first
+/// Pi Zero
+pub const HARDWARE_PROFILE_ENV: &str = "CANDYPI_HARDWARE_PROFILE";
+/// Environment variable to bit-bang the display SPI if set to `1`, e.g. when the SPI driver isn't
+/// available
+pub const DISPLAY_SOFT_SPI_ENV: &str = "CANDYPI_DISPLAY_SOFT_SPI";
+
+/// Board generation the timings are tuned for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Profile {
+    #[default]
+    Default,
+    /// Single core ARMv6 boards (Pi 1, Pi Zero), which can't keep up with the faster defaults
+    Armv6,
+}
+
+impl Profile {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(HARDWARE_PROFILE_ENV).as_deref() {
+            Err(_) | Ok("default") => Ok(Profile::Default),
+            Ok("armv6") => Ok(Profile::Armv6),
+            Ok(other) => Err(anyhow!("Unknown {}: {}", HARDWARE_PROFILE_ENV, other)),
+        }
+    }
+
+    fn display_spi_clock_hz(self) -> u32 {
+        match self {
+            Profile::Default => 16_000_000,
+            // Long jumper wires and the older SPI block corrupt pixels at higher clocks
+            Profile::Armv6 => 4_000_000,
+        }
+    }
+
+    fn soft_pwm_frequency(self) -> f64 {
+        match self {
+            Profile::Default => pins::SOFT_PWM_FREQUENCY,
+            // The PWM thread competes with everything else for the only core
+            Profile::Armv6 => 100.0,
+        }
+    }
+}
+
+/// Peripherals assembled by [`HardwareBuilder`]. Sensors are already polled in the background,
+/// their events arrive on the contained channels.
+pub struct Hardware {
+    pub display: Display,
+    /// `None` in the simulator
+    pub backlight: Option<Backlight>,
+    /// Operator status display, not started yet as it shows the status bar kept by the UI
+    pub status_display: Option<StatusDisplay>,
+    pub dispenser: Box<dyn DispenseAction>,
+    /// Yields once for every jammed dispense, never without a jam sensor
+    pub jams: mpsc::UnboundedReceiver<()>,
+    pub estop: EStopLatch,
+    /// Short presses of the next and select buttons
+    pub buttons: mpsc::UnboundedReceiver<Button>,
+    /// Long presses of the next and select buttons
+    pub long_presses: mpsc::UnboundedReceiver<Button>,
+    /// Presses of the price tier button, never yields anything without one
+    pub tier_button: mpsc::UnboundedReceiver<()>,
+    /// Presses of the invoice refresh button, never yields anything without one
+    pub refresh_button: mpsc::UnboundedReceiver<()>,
+    pub tamper_alarms: mpsc::UnboundedReceiver<TamperAlarm>,
+    pub door_events: mpsc::UnboundedReceiver<DoorEvent>,
+    /// Never yields anything if no UPS is attached
+    pub ups_events: mpsc::UnboundedReceiver<UpsEvent>,
+    /// Payments tapped on the NFC reader, never yields anything without one
+    pub nfc_taps: mpsc::UnboundedReceiver<NfcTap>,
+    /// Pulses of every inserted coin, never yields anything without a coin acceptor
+    pub coins: mpsc::UnboundedReceiver<u32>,
+    /// Hopper weight in grams, stays `None` without a load cell
+    pub stock: watch::Receiver<Option<u32>>,
+    /// Whether the hopper level sensor reports low stock, stays false without one
+    pub hopper_low: watch::Receiver<bool>,
+}
+
+/// Describes which peripherals are attached and where. Defaults to the pins documented in the
+/// README with all optional peripherals disabled.
+pub struct HardwareBuilder {
+    profile: Profile,
+    display_pins: DisplayPins,
+    display_soft_spi: bool,
+    backlight: BacklightSettings,
+    dispense_mechanism: Mechanism,
+    dispense_channels: Vec<Mechanism>,
+    dispense_action: Option<Box<dyn DispenseAction>>,
+    jam_sensor: Option<JamSensorConfig>,
+    button_pins: (PinRef, PinRef),
+    button_timing: ButtonTiming,
+    encoder_pins: Option<(PinRef, PinRef, PinRef)>,
+    tier_button_pin: Option<PinRef>,
+    refresh_button_pin: Option<PinRef>,
+    coin_acceptor: Option<(PinRef, PulseTiming)>,
+    tamper_sensor_pin: PinRef,
+    buzzer: OutputSpec,
+    business_hours: Option<BusinessHours>,
+    door_sensor_pin: PinRef,
+    estop_pin: Option<PinRef>,
+    load_cell: Option<LoadCellCalibration>,
+    hopper_sensor: Option<HopperSensorConfig>,
+    ups: bool,
+    led_strip: Option<(usize, Choreography)>,
+    climate_sensor: Option<ClimateSensor>,
+    light_sensor: Option<LightSensor>,
+    status_display: Option<StatusDisplay>,
+    nfc_reader: Option<Pn532>,
+}
+
+impl Default for HardwareBuilder {
+    fn default() -> Self {
+        Self {
+            profile: Profile::Default,
+            display_pins: DisplayPins::default(),
+            display_soft_spi: false,
+            backlight: BacklightSettings::default(),
+            dispense_mechanism: DISPENSE_MECHANISM,
+            dispense_channels: Vec::new(),
+            dispense_action: None,
+            jam_sensor: None,
+            button_pins: (BUTTON_NEXT_PIN, BUTTON_SELECT_PIN),
+            button_timing: ButtonTiming::default(),
+            encoder_pins: None,
+            tier_button_pin: None,
+            refresh_button_pin: None,
+            coin_acceptor: None,
+            tamper_sensor_pin: TAMPER_SENSOR_PIN,
+            buzzer: BUZZER_OUTPUT,
+            business_hours: None,
+            door_sensor_pin: DOOR_SENSOR_PIN,
+            estop_pin: None,
+            load_cell: None,
+            hopper_sensor: None,
+            ups: false,
+            led_strip: None,
+            climate_sensor: None,
+            light_sensor: None,
+            status_display: None,
+            nfc_reader: None,
+        }
+    }
+}
+
+impl HardwareBuilder {
+    /// Enables the optional peripherals configured through environment variables
+    pub fn from_env() -> anyhow::Result<Self> {
+        let enabled = |var: &str| std::env::var(var).is_ok_and(|value| value == "1");
+
+        let mut builder = Self::default()
+            .profile(Profile::from_env()?)
+            .display_soft_spi(enabled(DISPLAY_SOFT_SPI_ENV))
+            .business_hours(BusinessHours::from_env().map_err(|e| anyhow!(e))?)
+            .ups(enabled(ups::UPS_ENV));
+        if enabled(estop::ESTOP_ENV) {
+            builder = builder.estop(ESTOP_PIN);
+        }
+        if let Some(calibration) = LoadCellCalibration::from_env()? {
+            builder = builder.load_cell(calibration);
+        }
+        if let Some(sensor) = ClimateSensor::from_env()? {
+            builder = builder.climate_sensor(sensor);
+        }
+        if let Ok(url) = std::env::var(actions::DISPENSE_HTTP_URL_ENV) {
+            builder = builder.dispense_action(HttpTrigger::new(url));
+        } else if let Ok(url) = std::env::var(actions::DISPENSE_MQTT_URL_ENV) {
+            builder = builder.dispense_action(MqttTrigger::new(&url)?);
+        }
+
+        Ok(builder)
+    }
+
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    pub fn display_pins(mut self, pins: DisplayPins) -> Self {
+        self.display_pins = pins;
+        self
+    }
+
+    /// Bit-bangs the display SPI on the SPI0 pins instead of using the kernel driver
+    pub fn display_soft_spi(mut self, enabled: bool) -> Self {
+        self.display_soft_spi = enabled;
+        self
+    }
+
+    pub fn backlight(mut self, settings: BacklightSettings) -> Self {
+        self.backlight = settings;
+        self
+    }
+
+    pub fn dispense_mechanism(mut self, mechanism: Mechanism) -> Self {
+        self.dispense_mechanism = mechanism;
+        self
+    }
+
+    /// One mechanism per product, replaces the single dispense mechanism. The product's index
+    /// selects the channel with [`DispenseAction::select_channel`].
+    pub fn dispense_channels(mut self, channels: Vec<Mechanism>) -> Self {
+        self.dispense_channels = channels;
+        self
+    }
+
+    /// Runs `action` on payment instead of a local mechanism, e.g. to drive non-candy hardware
+    pub fn dispense_action(mut self, action: impl DispenseAction + 'static) -> Self {
+        self.dispense_action = Some(Box::new(action));
+        self
+    }
+
+    /// Stops the dispense mechanism when it jams, ignored with a custom dispense action
+    pub fn jam_sensor(mut self, sensor: JamSensorConfig) -> Self {
+        self.jam_sensor = Some(sensor);
+        self
+    }
+
+    /// Only raise the tamper alarm outside these hours
+    pub fn business_hours(mut self, hours: Option<BusinessHours>) -> Self {
+        self.business_hours = hours;
+        self
+    }
+
+    /// Button cycling through the price tiers on the invoice screen
+    pub fn button_pins(mut self, next: PinRef, select: PinRef) -> Self {
+        self.button_pins = (next, select);
+        self
+    }
+
+    /// Applies to all buttons
+    pub fn button_timing(mut self, timing: ButtonTiming) -> Self {
+        self.button_timing = timing;
+        self
+    }
+
+    /// Rotary encoder with push button for the menus, in addition to the buttons
+    pub fn rotary_encoder(mut self, a: PinRef, b: PinRef, push: PinRef) -> Self {
+        self.encoder_pins = Some((a, b, push));
+        self
+    }
+
+    pub fn tier_button(mut self, pin: PinRef) -> Self {
+        self.tier_button_pin = Some(pin);
+        self
+    }
+
+    /// Replaces the invoice with a fresh one when pressed
+    pub fn refresh_button(mut self, pin: PinRef) -> Self {
+        self.refresh_button_pin = Some(pin);
+        self
+    }
+
+    /// Pulse-output coin acceptor, the pin is pulled to ground for every pulse
+    pub fn coin_acceptor(mut self, pin: PinRef, timing: PulseTiming) -> Self {
+        self.coin_acceptor = Some((pin, timing));
+        self
+    }
+
+    pub fn estop(mut self, pin: PinRef) -> Self {
+        self.estop_pin = Some(pin);
+        self
+    }
+
+    pub fn load_cell(mut self, calibration: LoadCellCalibration) -> Self {
+        self.load_cell = Some(calibration);
+        self
+    }
+
+    pub fn hopper_sensor(mut self, sensor: HopperSensorConfig) -> Self {
+        self.hopper_sensor = Some(sensor);
+        self
+    }
+
+    pub fn ups(mut self, enabled: bool) -> Self {
+        self.ups = enabled;
+        self
+    }
+
+    pub fn led_strip(mut self, length: usize, choreography: Choreography) -> Self {
+        self.led_strip = Some((length, choreography));
+        self
+    }
+
+    pub fn climate_sensor(mut self, sensor: ClimateSensor) -> Self {
+        self.climate_sensor = Some(sensor);
+        self
+    }
+
+    /// Adjusts the backlight to the ambient light
+    pub fn light_sensor(mut self, sensor: LightSensor) -> Self {
+        self.light_sensor = Some(sensor);
+        self
+    }
+
+    pub fn status_display(mut self, display: StatusDisplay) -> Self {
+        self.status_display = Some(display);
+        self
+    }
+
+    pub fn nfc_reader(mut self, reader: Pn532) -> Self {
+        self.nfc_reader = Some(reader);
+        self
+    }
+
+    /// Initializes only the display and its backlight, e.g. to test the wiring
+    pub fn build_display(&self, gpio: &Gpio) -> anyhow::Result<(Display, OutputPin)> {
+        let spi = if self.display_soft_spi {
+            DisplaySpi::Software(SoftSpi::new(
+                gpio.get(LCD_SCLK_PIN)?.into_output(),
+                gpio.get(LCD_MOSI_PIN)?.into_output(),
+                gpio.get(LCD_CS_PIN)?.into_output(),
+            ))
+        } else {
+            DisplaySpi::Hardware(SimpleHalSpiDevice::new(Spi::new(
+                Bus::Spi0,
+                SlaveSelect::Ss0,
+                self.profile.display_spi_clock_hz(),
+                Mode::Mode0,
+            )?))
+        };
+        init_display(gpio, self.display_pins, spi)
+    }
+
+    /// Initializes all peripherals and starts their background tasks
+    pub fn build(
+        self,
+        notifier: Notifier,
+        audit_log: AuditLog,
+        bus: &EventBus,
+    ) -> anyhow::Result<Hardware> {
+        let gpio = Gpio::new()?;
+        let (display, backlight_pin) = self.build_display(&gpio)?;
+        let ambient_light = match self.light_sensor {
+            Some(sensor) => sensor.spawn(),
+            None => watch::channel(None).1,
+        };
+        let backlight = Backlight::new(backlight_pin, self.profile.soft_pwm_frequency());
+        backlight
+            .clone()
+            .spawn(self.backlight, bus.subscribe(), ambient_light);
+
+        // The HX711 is bit-banged and needs native pins, so it gets them before the rest
+        let stock = match self.load_cell {
+            Some(calibration) => Hx711::new(&gpio, LOAD_CELL_DOUT_PIN, LOAD_CELL_SCK_PIN)?
+                .spawn(calibration, notifier.clone()),
+            None => watch::channel(None).1,
+        };
+        // The ultrasonic echo is timed in microseconds, too slow through the expander
+        let hopper_low = match self.hopper_sensor {
+            Some(config) => HopperSensor::new(&gpio, config)?.spawn(notifier.clone(), bus.clone()),
+            None => watch::channel(false).1,
+        };
+
+        // Display pins are timing critical and always native, everything else may sit on the
+        // expander
+        let pins = Pins::new(gpio).soft_pwm_frequency(self.profile.soft_pwm_frequency());
+
+        // The emergency stop comes before anything that can move
+        let estop = EStopLatch::default();
+        if let Some(pin) = self.estop_pin {
+            EmergencyStop::new(&pins, pin)?.spawn(estop.clone());
+        }
+        let (jams_tx, jams) = mpsc::unbounded_channel();
+        let dispenser: Box<dyn DispenseAction> = match self.dispense_action {
+            Some(action) => Box::new(Interlocked::new(action, estop.subscribe())),
+            None => {
+                let jam_signal = match self.jam_sensor {
+                    Some(sensor) => Some(JamSignal::spawn(&pins, sensor)?),
+                    None => None,
+                };
+                let mechanisms = if self.dispense_channels.is_empty() {
+                    vec![self.dispense_mechanism]
+                } else {
+                    self.dispense_channels
+                };
+                let mut channels = Vec::new();
+                for mechanism in mechanisms {
+                    let mut dispenser = Dispenser::new(&pins, mechanism, estop.subscribe())?;
+                    // Only one hopper runs at a time, so they can share the jam sensor
+                    if let Some(signal) = &jam_signal {
+                        dispenser = dispenser.detect_jams(signal.clone(), jams_tx.clone());
+                    }
+                    channels.push(Box::new(dispenser) as Box<dyn DispenseAction>);
+                }
+                match channels.len() {
+                    1 => channels.remove(0),
+                    _ => Box::new(Channels::new(channels)),
+                }
+            }
+        };
+
+        let (next_pin, select_pin) = self.button_pins;
+        let mut buttons = Buttons::new(&pins, next_pin, select_pin, self.button_timing)?;
+        if let Some((a, b, push)) = self.encoder_pins {
+            buttons = buttons.encoder(RotaryEncoder::new(&pins, a, b, push, self.button_timing)?);
+        }
+        let (buttons, long_presses) = buttons.spawn(bus.clone());
+        let panel_button = |pin: Option<PinRef>| -> anyhow::Result<_> {
+            Ok(match pin {
+                Some(pin) => PanelButton::new(&pins, pin, self.button_timing)?.spawn(),
+                None => mpsc::unbounded_channel().1,
+            })
+        };
+        let tier_button = panel_button(self.tier_button_pin)?;
+        let refresh_button = panel_button(self.refresh_button_pin)?;
+        let coins = match self.coin_acceptor {
+            Some((pin, timing)) => CoinAcceptor::new(pins.input_pullup(pin)?, timing).spawn(),
+            None => mpsc::unbounded_channel().1,
+        };
+
+        let tamper_alarms = TamperMonitor::new(
+            &pins,
+            self.tamper_sensor_pin,
+            self.buzzer,
+            self.business_hours,
+            notifier.clone(),
+        )?
+        .spawn();
+
+        let door_events = DoorSensor::new(&pins, self.door_sensor_pin, audit_log)?.spawn();
+
+        // A missing UPS shouldn't keep the machine from vending
+        let (_no_ups, mut ups_events) = mpsc::unbounded_channel();
+        if self.ups {
+            match Ups::new() {
+                Ok(ups) => ups_events = ups.spawn(notifier.clone()),
+                Err(e) => warn!("Failed to open UPS: {}", e),
+            }
+        }
+
+        if let Some((length, choreography)) = self.led_strip {
+            LedStrip::new(length, choreography)?.spawn(bus.subscribe());
+        }
+
+        if let Some(sensor) = self.climate_sensor {
+            sensor.spawn(notifier)?;
+        }
+
+        let nfc_taps = match self.nfc_reader {
+            Some(reader) => reader.spawn(),
+            None => mpsc::unbounded_channel().1,
+        };
+
+        Ok(Hardware {
+            display,
+            backlight: Some(backlight),
+            status_display: self.status_display,
+            dispenser,
+            jams,
+            estop,
+            buttons,
+            long_presses,
+            tier_button,
+            refresh_button,
+            tamper_alarms,
+            door_events,
+            ups_events,
+            nfc_taps,
+            coins,
+            stock,
+            hopper_low,
+        })
+    }
+}
+
+#[cfg(feature = "simulate")]
+impl HardwareBuilder {
+    /// Stands in for [`build`](Self::build) on machines without GPIO or SPI: the screen is saved
+    /// as an image and dispenses are only logged. No sensors or buttons are attached, the control
+    /// socket drives everything else. Network dispense actions are still triggered.
+    pub fn build_simulated(self) -> Hardware {
+        use crate::simulator::{SimulatedDispenser, SimulatedDisplay};
+
+        let estop = EStopLatch::default();
+        let dispenser: Box<dyn DispenseAction> = match self.dispense_action {
+            Some(action) => Box::new(Interlocked::new(action, estop.subscribe())),
+            None if self.dispense_channels.is_empty() => {
+                Box::new(SimulatedDispenser::new(self.dispense_mechanism))
+            }
+            None => Box::new(Channels::new(
+                self.dispense_channels
+                    .into_iter()
+                    .map(|mechanism| {
+                        Box::new(SimulatedDispenser::new(mechanism)) as Box<dyn DispenseAction>
+                    })
+                    .collect(),
+            )),
+        };
+
+        Hardware {
+            display: Display::new(
+                Panel::Simulated(SimulatedDisplay::spawn()),
+                DisplayOrientation::default(),
+            ),
+            backlight: None,
+            status_display: None,
+            dispenser,
+            jams: mpsc::unbounded_channel().1,
+            estop,
+            buttons: mpsc::unbounded_channel().1,
+            long_presses: mpsc::unbounded_channel().1,
+            tier_button: mpsc::unbounded_channel().1,
+            refresh_button: mpsc::unbounded_channel().1,
+            tamper_alarms: mpsc::unbounded_channel().1,
+            door_events: mpsc::unbounded_channel().1,
+            ups_events: mpsc::unbounded_channel().1,
+            nfc_taps: mpsc::unbounded_channel().1,
+            coins: mpsc::unbounded_channel().1,
+            stock: watch::channel(None).1,
+            hopper_low: watch::channel(false).1,
+        }
+    }
+}
+
+fn init_display(
+    gpio: &Gpio,
+    pins: DisplayPins,
+    spi: DisplaySpi,
+) -> anyhow::Result<(Display, OutputPin)> {
+    let dc_pin = gpio.get(pins.dc)?.into_output();
+    let rst_pin = gpio.get(pins.reset)?.into_output();
+    let mut backlight = gpio.get(pins.backlight)?.into_output();
+    backlight.set_high();
+
+    let panel = match pins.driver {
+        DisplayDriver::St7735 => {
+            let (width, height) = pins.orientation.size(DISPLAY_WIDTH, DISPLAY_HEIGHT);
+            Panel::St7735(ST7735::new(
+                spi, dc_pin, rst_pin, false, false, width, height,
+            ))
+        }
+        DisplayDriver::Ili9341 => {
+            Panel::Ili9341(Ili9341::new(spi, dc_pin, rst_pin, pins.orientation))
+        }
+    };
+    let mut display = Display::new(panel, pins.orientation)
+        .mirrored(pins.mirrored)
+        .qr_style(QrStyle {
+            error_correction: pins.qr_error_correction,
+            quiet_zone: pins.qr_quiet_zone,
+            lightning_uri: pins.qr_lightning_uri,
+            uppercase: pins.qr_uppercase,
+        });
+
+    init_panel(&mut display).context("Failed to initialize display")?;
+
+    Ok((display, backlight))
+}
@@ -0,0 +1,208 @@
+use crate::events::{Event, EventBus};
+use crate::notify::Notifier;
+use fedimint_core::anyhow::{self, Context, bail};
+use rppal::gpio::{Gpio, InputPin, Level, OutputPin};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Environment variable overriding the `[hopper_sensor]` table: `ir:<pin>` for an IR obstacle
+/// module looking across the hopper, `ultrasonic:<trigger pin>:<echo pin>` for an HC-SR04 looking
+/// down onto the candy. Pins are BCM GPIO numbers.
+pub const HOPPER_SENSOR_ENV: &str = "CANDYPI_HOPPER_SENSOR";
+/// Environment variable overriding the distance in cm from the ultrasonic sensor to the candy
+/// above which stock is low
+pub const HOPPER_LOW_CM_ENV: &str = "CANDYPI_HOPPER_LOW_CM";
+
+const DEFAULT_LOW_CM: f32 = 15.0;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Candy shifts around while dispensing, a level has to be seen this many times in a row
+const STABLE_READINGS: u32 = 3;
+
+const TRIGGER_PULSE: Duration = Duration::from_micros(10);
+/// Echoes from further than about 5 m never arrive
+const ECHO_TIMEOUT: Duration = Duration::from_millis(30);
+/// Round trip time of sound per cm of distance
+const MICROS_PER_CM: f32 = 58.0;
+
+/// `[hopper_sensor]` table of the config file, pins are BCM GPIO numbers
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum HopperSensorConfig {
+    /// IR obstacle module looking across the hopper
+    Ir { pin: u8 },
+    /// HC-SR04 looking down onto the candy
+    Ultrasonic {
+        trigger: u8,
+        echo: u8,
+        /// Distance to the candy above which stock is low, 15 cm unless set
+        #[serde(default = "default_low_cm")]
+        low_cm: f32,
+    },
+}
+
+fn default_low_cm() -> f32 {
+    DEFAULT_LOW_CM
+}
+
+impl HopperSensorConfig {
+    /// The sensor from [`HOPPER_SENSOR_ENV`] if set, `config` otherwise. [`HOPPER_LOW_CM_ENV`]
+    /// overrides the threshold of either. Returns `None` without a sensor.
+    pub fn from_env_or(config: Option<Self>) -> anyhow::Result<Option<Self>> {
+        let mut config = match std::env::var(HOPPER_SENSOR_ENV) {
+            Ok(value) => Self::parse(&value)?,
+            Err(_) => match config {
+                Some(config) => config,
+                None => return Ok(None),
+            },
+        };
+        if let HopperSensorConfig::Ultrasonic { low_cm, .. } = &mut config
+            && let Ok(cm) = std::env::var(HOPPER_LOW_CM_ENV)
+        {
+            *low_cm = cm
+                .parse()
+                .with_context(|| format!("Invalid {HOPPER_LOW_CM_ENV}"))?;
+        }
+        Ok(Some(config))
+    }
+
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        let parse_pin = |pin: &str| {
+            pin.parse::<u8>()
+                .with_context(|| format!("Invalid pin '{pin}' in {HOPPER_SENSOR_ENV}"))
+        };
+
+        let config = match value.split(':').collect::<Vec<_>>().as_slice() {
+            ["ir", pin] => HopperSensorConfig::Ir {
+                pin: parse_pin(pin)?,
+            },
+            ["ultrasonic", trigger, echo] => HopperSensorConfig::Ultrasonic {
+                trigger: parse_pin(trigger)?,
+                echo: parse_pin(echo)?,
+                low_cm: DEFAULT_LOW_CM,
+            },
+            _ => bail!(
+                "Invalid {HOPPER_SENSOR_ENV} '{value}', expected ir:<pin> or \
+                 ultrasonic:<trigger>:<echo>"
+            ),
+        };
+        Ok(config)
+    }
+}
+
+/// Tells whether the hopper needs a refill, so customers don't pay for an empty machine
+pub enum HopperSensor {
+    /// Output is low while the beam hits candy
+    Ir(InputPin),
+    Ultrasonic {
+        trigger: OutputPin,
+        echo: InputPin,
+        low_cm: f32,
+    },
+}
+
+impl HopperSensor {
+    /// Needs native pins, the ultrasonic echo is timed in microseconds
+    pub fn new(gpio: &Gpio, config: HopperSensorConfig) -> anyhow::Result<Self> {
+        Ok(match config {
+            HopperSensorConfig::Ir { pin } => HopperSensor::Ir(gpio.get(pin)?.into_input_pullup()),
+            HopperSensorConfig::Ultrasonic {
+                trigger,
+                echo,
+                low_cm,
+            } => HopperSensor::Ultrasonic {
+                trigger: gpio.get(trigger)?.into_output_low(),
+                echo: gpio.get(echo)?.into_input(),
+                low_cm,
+            },
+        })
+    }
+
+    /// Blocks for up to two echo timeouts with the ultrasonic sensor
+    fn is_low(&mut self) -> anyhow::Result<bool> {
+        match self {
+            HopperSensor::Ir(pin) => Ok(pin.read() == Level::High),
+            HopperSensor::Ultrasonic {
+                trigger,
+                echo,
+                low_cm,
+            } => Ok(measure_cm(trigger, echo)? > *low_cm),
+        }
+    }
+
+    /// Polls the sensor in a background task. Changes are published as [`Event::HopperLow`] and
+    /// the operator is notified when stock runs low.
+    pub fn spawn(self, notifier: Notifier, bus: EventBus) -> watch::Receiver<bool> {
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            let mut sensor = self;
+            let mut candidate = false;
+            let mut seen = 0;
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+
+                let Ok((returned, reading)) = tokio::task::spawn_blocking(move || {
+                    let reading = sensor.is_low();
+                    (sensor, reading)
+                })
+                .await
+                else {
+                    warn!("Hopper sensor task panicked");
+                    return;
+                };
+                sensor = returned;
+                let low = match reading {
+                    Ok(low) => low,
+                    Err(e) => {
+                        warn!("Failed to read hopper sensor: {:#}", e);
+                        continue;
+                    }
+                };
+
+                if low != candidate {
+                    candidate = low;
+                    seen = 0;
+                }
+                seen += 1;
+                if seen < STABLE_READINGS || low == *tx.borrow() {
+                    continue;
+                }
+
+                info!("Hopper level {}", if low { "low" } else { "refilled" });
+                bus.publish(Event::HopperLow { low });
+                tx.send_replace(low);
+                if low {
+                    notifier
+                        .notify("Hopper level is low, please refill the candy")
+                        .await;
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+/// Distance to the candy surface from an HC-SR04
+fn measure_cm(trigger: &mut OutputPin, echo: &InputPin) -> anyhow::Result<f32> {
+    trigger.set_high();
+    std::thread::sleep(TRIGGER_PULSE);
+    trigger.set_low();
+
+    let wait_for = |level: Level| {
+        let started = Instant::now();
+        while echo.read() != level {
+            if started.elapsed() > ECHO_TIMEOUT {
+                return None;
+            }
+        }
+        Some(Instant::now())
+    };
+    let sent = wait_for(Level::High).context("Ultrasonic sensor did not answer")?;
+    let received = wait_for(Level::Low).context("No echo from the hopper")?;
+
+    Ok((received - sent).as_micros() as f32 / MICROS_PER_CM)
+}
@@ -0,0 +1,64 @@
+use fedimint_core::anyhow::{self, Context, anyhow};
+use fluent_bundle::FluentResource;
+use fluent_bundle::concurrent::FluentBundle;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use unic_langid::LanguageIdentifier;
+
+/// Environment variable with the language of the customer screens, e.g. `de`. Can be switched at
+/// runtime through `language` in the theme file.
+pub const LANGUAGE_ENV: &str = "CANDYPI_LANGUAGE";
+
+/// Built into the binary, needs no catalog
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Directory with one `<language>.ftl` file per translation
+pub fn locale_dir() -> PathBuf {
+    xdg::BaseDirectories::new()
+        .data_home
+        .expect("Could not determine XDG data home")
+        .join("candypi/locales")
+}
+
+pub fn language_from_env() -> String {
+    std::env::var(LANGUAGE_ENV).unwrap_or_else(|_| DEFAULT_LANGUAGE.to_string())
+}
+
+/// Translated UI strings from a Fluent file
+pub struct Catalog(FluentBundle<FluentResource>);
+
+impl Catalog {
+    /// Loads `<dir>/<language>.ftl`
+    pub fn load(dir: &Path, language: &str) -> anyhow::Result<Self> {
+        let language_id: LanguageIdentifier = language
+            .parse()
+            .map_err(|_| anyhow!("Invalid language '{}'", language))?;
+
+        let path = dir.join(format!("{}.ftl", language));
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let resource = FluentResource::try_new(source)
+            .map_err(|(_, errors)| anyhow!("Invalid {}: {:?}", path.display(), errors))?;
+
+        let mut bundle = FluentBundle::new_concurrent(vec![language_id]);
+        // Bidi isolation marks would show up as garbage, the display font has no glyphs for them
+        bundle.set_use_isolating(false);
+        bundle
+            .add_resource(resource)
+            .map_err(|errors| anyhow!("Invalid {}: {:?}", path.display(), errors))?;
+
+        Ok(Self(bundle))
+    }
+
+    /// Returns the translation of message `id`, `None` if the catalog doesn't have it
+    pub fn get(&self, id: &str) -> Option<String> {
+        let pattern = self.0.get_message(id)?.value()?;
+        let mut errors = Vec::new();
+        let text = self.0.format_pattern(pattern, None, &mut errors);
+        if !errors.is_empty() {
+            warn!("Failed to format message '{}': {:?}", id, errors);
+        }
+        Some(text.into_owned())
+    }
+}
@@ -0,0 +1,183 @@
+use crate::screen::DisplayOrientation;
+use crate::soft_spi::DisplaySpi;
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*, primitives::Rectangle};
+use embedded_hal::spi::SpiDevice;
+use rppal::gpio::OutputPin;
+use std::thread;
+use std::time::Duration;
+
+pub const ILI9341_WIDTH: u32 = 240;
+pub const ILI9341_HEIGHT: u32 = 320;
+
+const SWRESET: u8 = 0x01;
+const SLPOUT: u8 = 0x11;
+const DISPON: u8 = 0x29;
+const CASET: u8 = 0x2A;
+const PASET: u8 = 0x2B;
+const RAMWR: u8 = 0x2C;
+const MADCTL: u8 = 0x36;
+const COLMOD: u8 = 0x3A;
+
+/// Row and column address order and their exchange, together they rotate the picture
+const MADCTL_MY: u8 = 0x80;
+const MADCTL_MX: u8 = 0x40;
+const MADCTL_MV: u8 = 0x20;
+/// Most modules are wired BGR
+const MADCTL_BGR: u8 = 0x08;
+/// 16 bits per pixel
+const COLMOD_RGB565: u8 = 0x55;
+
+/// The kernel SPI driver rejects longer transfers with its default buffer size
+const MAX_TRANSFER: usize = 4096;
+
+/// 240x320 ILI9341 panel, written to over the same SPI and control lines as the ST7735
+pub struct Ili9341 {
+    spi: DisplaySpi,
+    dc: OutputPin,
+    reset: OutputPin,
+    orientation: DisplayOrientation,
+}
+
+impl Ili9341 {
+    pub fn new(
+        spi: DisplaySpi,
+        dc: OutputPin,
+        reset: OutputPin,
+        orientation: DisplayOrientation,
+    ) -> Self {
+        Self {
+            spi,
+            dc,
+            reset,
+            orientation,
+        }
+    }
+
+    /// Hardware and software reset followed by the minimal init sequence, the power and gamma
+    /// defaults are fine for the common modules
+    pub fn init(&mut self) -> Result<(), rppal::spi::Error> {
+        self.reset.set_low();
+        thread::sleep(Duration::from_millis(10));
+        self.reset.set_high();
+        thread::sleep(Duration::from_millis(120));
+
+        self.command(SWRESET, &[])?;
+        thread::sleep(Duration::from_millis(120));
+        self.command(SLPOUT, &[])?;
+        thread::sleep(Duration::from_millis(120));
+        self.command(COLMOD, &[COLMOD_RGB565])?;
+        // The panel's own portrait mode is mirrored
+        let rotation = match self.orientation {
+            DisplayOrientation::Portrait => MADCTL_MX,
+            DisplayOrientation::Landscape => MADCTL_MV,
+            DisplayOrientation::PortraitSwapped => MADCTL_MY,
+            DisplayOrientation::LandscapeSwapped => MADCTL_MY | MADCTL_MX | MADCTL_MV,
+        };
+        self.command(MADCTL, &[rotation | MADCTL_BGR])?;
+        self.command(DISPON, &[])
+    }
+
+    fn command(&mut self, command: u8, params: &[u8]) -> Result<(), rppal::spi::Error> {
+        self.dc.set_low();
+        self.spi.write(&[command])?;
+        self.dc.set_high();
+        if !params.is_empty() {
+            self.spi.write(params)?;
+        }
+        Ok(())
+    }
+
+    /// Selects `area` for the pixel data that follows, which has to lie on the panel
+    fn start_write(&mut self, area: &Rectangle) -> Result<(), rppal::spi::Error> {
+        let Some(bottom_right) = area.bottom_right() else {
+            return Ok(());
+        };
+        let [x0, y0, x1, y1] = [
+            area.top_left.x,
+            area.top_left.y,
+            bottom_right.x,
+            bottom_right.y,
+        ]
+        .map(|coordinate| (coordinate as u16).to_be_bytes());
+        self.command(CASET, &[x0[0], x0[1], x1[0], x1[1]])?;
+        self.command(PASET, &[y0[0], y0[1], y1[0], y1[1]])?;
+        self.command(RAMWR, &[])
+    }
+
+    /// Streams pixels in as few transfers as the SPI driver allows
+    fn write_pixels(
+        &mut self,
+        colors: impl IntoIterator<Item = Rgb565>,
+    ) -> Result<(), rppal::spi::Error> {
+        let mut buffer = Vec::with_capacity(MAX_TRANSFER);
+        for color in colors {
+            buffer.extend_from_slice(&color.into_storage().to_be_bytes());
+            if buffer.len() == MAX_TRANSFER {
+                self.spi.write(&buffer)?;
+                buffer.clear();
+            }
+        }
+        if !buffer.is_empty() {
+            self.spi.write(&buffer)?;
+        }
+        Ok(())
+    }
+}
+
+impl OriginDimensions for Ili9341 {
+    fn size(&self) -> Size {
+        let (width, height) = self.orientation.size(ILI9341_WIDTH, ILI9341_HEIGHT);
+        Size::new(width, height)
+    }
+}
+
+impl DrawTarget for Ili9341 {
+    type Color = Rgb565;
+    type Error = rppal::spi::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let bounds = self.bounding_box();
+        for Pixel(point, color) in pixels {
+            if bounds.contains(point) {
+                self.start_write(&Rectangle::new(point, Size::new(1, 1)))?;
+                self.write_pixels([color])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        let visible = area.intersection(&self.bounding_box());
+        if visible == *area {
+            self.start_write(area)?;
+            return self.write_pixels(
+                colors
+                    .into_iter()
+                    .take(area.size.width as usize * area.size.height as usize),
+            );
+        }
+        // Partly off screen, e.g. a logo too large for the panel
+        self.draw_iter(
+            area.points()
+                .zip(colors)
+                .filter(|(point, _)| visible.contains(*point))
+                .map(|(point, color)| Pixel(point, color)),
+        )
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let visible = area.intersection(&self.bounding_box());
+        if visible.is_zero_sized() {
+            return Ok(());
+        }
+        self.start_write(&visible)?;
+        let pixels = visible.size.width as usize * visible.size.height as usize;
+        self.write_pixels(std::iter::repeat_n(color, pixels))
+    }
+}
@@ -0,0 +1,276 @@
+use crate::events::{Event, EventBus};
+use crate::pins::{Input, PinRef, Pins};
+use fedimint_core::anyhow;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Hand turning an encoder doesn't produce more than a few hundred transitions per second
+const ENCODER_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Quadrature transitions from one detent of a KY-040 style encoder to the next
+const TRANSITIONS_PER_DETENT: i8 = 4;
+/// Direction of a change from the previous to the current A/B state, indexed by
+/// `previous << 2 | current`. Bounces and skipped states count as no movement.
+const QUADRATURE: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Next,
+    /// Only sent by the rotary encoder
+    Previous,
+    Select,
+}
+
+/// How presses are told apart from contact bounce and from each other
+#[derive(Debug, Clone, Copy)]
+pub struct ButtonTiming {
+    /// A new level has to be read for this long before it counts
+    pub debounce: Duration,
+    /// Held at least this long a press counts as long, and is reported without waiting for the
+    /// release
+    pub long_press: Duration,
+}
+
+impl Default for ButtonTiming {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(30),
+            long_press: Duration::from_millis(1500),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Press {
+    Short,
+    Long,
+}
+
+/// Debounces a button wired between a GPIO and ground and tells short presses from long ones
+struct PressDetector {
+    input: Input,
+    timing: ButtonTiming,
+    pressed: bool,
+    /// How long the input has read differently from `pressed`
+    bouncing: Duration,
+    held: Duration,
+    long_reported: bool,
+}
+
+impl PressDetector {
+    fn new(input: Input, timing: ButtonTiming) -> Self {
+        Self {
+            input,
+            timing,
+            pressed: false,
+            bouncing: Duration::ZERO,
+            held: Duration::ZERO,
+            long_reported: false,
+        }
+    }
+
+    /// Called every `interval`, short presses are reported on release
+    fn poll(&mut self, interval: Duration) -> Option<Press> {
+        if self.input.is_low() == self.pressed {
+            self.bouncing = Duration::ZERO;
+        } else {
+            self.bouncing += interval;
+            if self.bouncing >= self.timing.debounce {
+                self.pressed = !self.pressed;
+                self.bouncing = Duration::ZERO;
+                self.held = Duration::ZERO;
+                if !self.pressed && !self.long_reported {
+                    return Some(Press::Short);
+                }
+                self.long_reported = false;
+            }
+        }
+
+        if self.pressed && !self.long_reported {
+            self.held += interval;
+            if self.held >= self.timing.long_press {
+                self.long_reported = true;
+                return Some(Press::Long);
+            }
+        }
+        None
+    }
+}
+
+/// Two push buttons wired between a GPIO and ground, using the internal pull-ups. A rotary
+/// encoder can be added to navigate menus more comfortably.
+pub struct Buttons {
+    next: PressDetector,
+    select: PressDetector,
+    encoder: Option<RotaryEncoder>,
+}
+
+impl Buttons {
+    pub fn new(
+        pins: &Pins,
+        next_pin: PinRef,
+        select_pin: PinRef,
+        timing: ButtonTiming,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            next: PressDetector::new(pins.input_pullup(next_pin)?, timing),
+            select: PressDetector::new(pins.input_pullup(select_pin)?, timing),
+            encoder: None,
+        })
+    }
+
+    pub fn encoder(mut self, encoder: RotaryEncoder) -> Self {
+        self.encoder = Some(encoder);
+        self
+    }
+
+    /// Polls the buttons in a background task. Short presses are sent on the first returned
+    /// channel, long presses on the second. Presses are also published on the bus, but the UI
+    /// reads the channels so a menu has exclusive focus.
+    pub fn spawn(
+        mut self,
+        bus: EventBus,
+    ) -> (
+        mpsc::UnboundedReceiver<Button>,
+        mpsc::UnboundedReceiver<Button>,
+    ) {
+        let (short_tx, short_rx) = mpsc::unbounded_channel();
+        let (long_tx, long_rx) = mpsc::unbounded_channel();
+        if let Some(encoder) = self.encoder.take() {
+            encoder.spawn(bus.clone(), short_tx.clone(), long_tx.clone());
+        }
+
+        tokio::spawn(async move {
+            loop {
+                for (button, detector) in [
+                    (Button::Next, &mut self.next),
+                    (Button::Select, &mut self.select),
+                ] {
+                    let press = detector.poll(POLL_INTERVAL);
+                    if !report(press, button, &bus, &short_tx, &long_tx) {
+                        return;
+                    }
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+
+        (short_rx, long_rx)
+    }
+}
+
+/// Publishes a press and sends it to the UI, returns false once the UI is gone
+fn report(
+    press: Option<Press>,
+    button: Button,
+    bus: &EventBus,
+    short_tx: &mpsc::UnboundedSender<Button>,
+    long_tx: &mpsc::UnboundedSender<Button>,
+) -> bool {
+    let sent = match press {
+        Some(Press::Short) => {
+            bus.publish(Event::ButtonPressed(button));
+            short_tx.send(button)
+        }
+        Some(Press::Long) => {
+            bus.publish(Event::ButtonHeld(button));
+            long_tx.send(button)
+        }
+        None => Ok(()),
+    };
+    sent.is_ok()
+}
+
+/// Rotary encoder with a push button, e.g. a KY-040. Turning clockwise acts like the next
+/// button, counter-clockwise goes back, pushing acts like the select button. A, B and the push
+/// button connect to ground when active, the internal pull-ups are used.
+pub struct RotaryEncoder {
+    a: Input,
+    b: Input,
+    push: PressDetector,
+}
+
+impl RotaryEncoder {
+    /// A and B are polled every millisecond, they should be native pins
+    pub fn new(
+        pins: &Pins,
+        a_pin: PinRef,
+        b_pin: PinRef,
+        push_pin: PinRef,
+        timing: ButtonTiming,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            a: pins.input_pullup(a_pin)?,
+            b: pins.input_pullup(b_pin)?,
+            push: PressDetector::new(pins.input_pullup(push_pin)?, timing),
+        })
+    }
+
+    fn state(&self) -> usize {
+        (usize::from(self.a.is_high()) << 1) | usize::from(self.b.is_high())
+    }
+
+    fn spawn(
+        mut self,
+        bus: EventBus,
+        short_tx: mpsc::UnboundedSender<Button>,
+        long_tx: mpsc::UnboundedSender<Button>,
+    ) {
+        tokio::spawn(async move {
+            let mut previous = self.state();
+            let mut transitions = 0;
+            let mut interval = tokio::time::interval(ENCODER_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+
+                let current = self.state();
+                transitions += QUADRATURE[(previous << 2) | current];
+                previous = current;
+                let turned = if transitions >= TRANSITIONS_PER_DETENT {
+                    Some(Button::Next)
+                } else if transitions <= -TRANSITIONS_PER_DETENT {
+                    Some(Button::Previous)
+                } else {
+                    None
+                };
+                if let Some(button) = turned {
+                    transitions = 0;
+                    if !report(Some(Press::Short), button, &bus, &short_tx, &long_tx) {
+                        return;
+                    }
+                }
+
+                let press = self.push.poll(ENCODER_POLL_INTERVAL);
+                if !report(press, Button::Select, &bus, &short_tx, &long_tx) {
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Front panel button with a single job, e.g. cycling through the price tiers or replacing the
+/// invoice, wired like the other two. It is read separately so menus never see its presses.
+pub struct PanelButton(PressDetector);
+
+impl PanelButton {
+    pub fn new(pins: &Pins, pin: PinRef, timing: ButtonTiming) -> anyhow::Result<Self> {
+        Ok(Self(PressDetector::new(pins.input_pullup(pin)?, timing)))
+    }
+
+    /// Polls the button in a background task and sends one event per press, short or long
+    pub fn spawn(mut self) -> mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                if self.0.poll(POLL_INTERVAL).is_some() && tx.send(()).is_err() {
+                    return;
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+
+        rx
+    }
+}
@@ -0,0 +1,146 @@
+use fedimint_core::anyhow::{self, Context};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// `candypi refill` may run while the machine is vending, its changes are picked up this fast
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Candy left in the hopper, counted down with every dispense
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CandyCount {
+    pub left: u32,
+    /// Count of the last refill, restored by [`Inventory::refill`] without a count
+    pub capacity: u32,
+}
+
+impl CandyCount {
+    pub fn is_sold_out(&self) -> bool {
+        self.left == 0
+    }
+}
+
+/// Defaults to `$XDG_DATA_HOME/candypi/inventory.json`
+pub fn default_path() -> PathBuf {
+    xdg::BaseDirectories::new()
+        .data_home
+        .expect("Could not determine XDG data home")
+        .join("candypi/inventory.json")
+}
+
+/// Reads the count, `None` if the machine was never refilled and candy isn't counted
+pub fn load(path: &Path) -> anyhow::Result<Option<CandyCount>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .with_context(|| format!("Invalid {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Replaces the count on disk, a power cut leaves either the old or the new one
+pub fn save(path: &Path, count: CandyCount) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(serde_json::to_string(&count)?.as_bytes())?;
+    file.sync_data()?;
+    fs::rename(&tmp, path)
+}
+
+/// Tracks the candy count of the running machine, shared by everything that dispenses
+pub struct Inventory {
+    path: PathBuf,
+    count: watch::Sender<Option<CandyCount>>,
+}
+
+impl Inventory {
+    /// Loads the count and keeps watching the file for `candypi refill`
+    pub fn open(path: PathBuf) -> Self {
+        let initial = load(&path).unwrap_or_else(|e| {
+            warn!("Failed to load inventory, not counting candy: {:#}", e);
+            None
+        });
+        let (count, _) = watch::channel(initial);
+
+        let watched = count.clone();
+        let watched_path = path.clone();
+        tokio::spawn(async move {
+            let mut last_modified = modification_time(&watched_path);
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let modified = modification_time(&watched_path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                match load(&watched_path) {
+                    Ok(loaded) => {
+                        watched
+                            .send_if_modified(|count| std::mem::replace(count, loaded) != loaded);
+                    }
+                    Err(e) => warn!("Failed to reload inventory: {:#}", e),
+                }
+            }
+        });
+
+        Self { path, count }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Option<CandyCount>> {
+        self.count.subscribe()
+    }
+
+    pub fn count(&self) -> Option<CandyCount> {
+        *self.count.borrow()
+    }
+
+    /// Counts down one dispense, nothing happens while candy isn't counted
+    pub fn dispensed(&self) {
+        let Some(mut count) = self.count() else {
+            return;
+        };
+        count.left = count.left.saturating_sub(1);
+        if count.is_sold_out() {
+            info!("Sold out");
+        }
+        self.store(count);
+    }
+
+    /// Sets the count after a refill, back to the previous refill's count if `left` is `None`.
+    /// Returns the new count, `None` if no count was ever set.
+    pub fn refill(&self, left: Option<u32>) -> Option<CandyCount> {
+        let count = match (left, self.count()) {
+            (Some(left), _) => CandyCount {
+                left,
+                capacity: left,
+            },
+            (None, Some(count)) => CandyCount {
+                left: count.capacity,
+                ..count
+            },
+            (None, None) => return None,
+        };
+        info!("Refilled to {} pieces", count.left);
+        self.store(count);
+        Some(count)
+    }
+
+    fn store(&self, count: CandyCount) {
+        if let Err(e) = save(&self.path, count) {
+            warn!("Failed to save inventory: {}", e);
+        }
+        self.count.send_replace(Some(count));
+    }
+}
+
+fn modification_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
@@ -0,0 +1,119 @@
+use crate::pins::{Input, PinRef, Pins};
+use fedimint_core::anyhow::{self, Context, bail};
+use serde::{Deserialize, Deserializer};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Environment variable overriding the `[jam_sensor]` table: `current:<pin>` for a current sense
+/// comparator whose output goes high while the motor stalls, `drop:<pin>` for a micro-switch in
+/// the chute that candy hits on its way out. Pins are BCM GPIO numbers.
+pub const JAM_SENSOR_ENV: &str = "CANDYPI_JAM_SENSOR";
+
+/// Short enough to catch a piece falling past the drop switch
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Motors draw stall current for a moment when starting, only a longer stall is a jam
+const STALL_TIME: Duration = Duration::from_millis(300);
+
+/// `[jam_sensor]` table of the config file, which takes a BCM GPIO number as `pin`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(
+    tag = "kind",
+    content = "pin",
+    rename_all = "kebab-case",
+    deny_unknown_fields
+)]
+pub enum JamSensorConfig {
+    /// Current sense comparator whose output goes high while the motor stalls
+    Current(#[serde(deserialize_with = "native_pin")] PinRef),
+    /// Micro-switch or light barrier in the chute
+    Drop(#[serde(deserialize_with = "native_pin")] PinRef),
+}
+
+fn native_pin<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PinRef, D::Error> {
+    u8::deserialize(deserializer).map(PinRef::Native)
+}
+
+impl JamSensorConfig {
+    /// The sensor from [`JAM_SENSOR_ENV`] if set, `config` otherwise. Returns `None` without a
+    /// sensor.
+    pub fn from_env_or(config: Option<Self>) -> anyhow::Result<Option<Self>> {
+        let Ok(value) = std::env::var(JAM_SENSOR_ENV) else {
+            return Ok(config);
+        };
+        let Some((kind, pin)) = value.split_once(':') else {
+            bail!("Invalid {JAM_SENSOR_ENV} '{value}', expected current:<pin> or drop:<pin>");
+        };
+        let pin = PinRef::Native(
+            pin.parse()
+                .with_context(|| format!("Invalid pin '{pin}' in {JAM_SENSOR_ENV}"))?,
+        );
+        match kind {
+            "current" => Ok(Some(JamSensorConfig::Current(pin))),
+            "drop" => Ok(Some(JamSensorConfig::Drop(pin))),
+            _ => bail!("Unknown jam sensor '{kind}' in {JAM_SENSOR_ENV}"),
+        }
+    }
+}
+
+/// What the dispenser watches to tell whether candy actually came out
+#[derive(Clone)]
+pub enum JamSignal {
+    /// True while the motor has been stalling for longer than a start-up spike
+    Stalled(watch::Receiver<bool>),
+    /// Number of pieces that hit the drop switch so far
+    Drops(watch::Receiver<u64>),
+}
+
+impl JamSignal {
+    /// Polls the sensor in a background task
+    pub fn spawn(pins: &Pins, config: JamSensorConfig) -> anyhow::Result<Self> {
+        // Comparator outputs are open collector and need the pull-up as well
+        Ok(match config {
+            JamSensorConfig::Current(pin) => {
+                JamSignal::Stalled(spawn_stall_watch(pins.input_pullup(pin)?))
+            }
+            JamSensorConfig::Drop(pin) => {
+                JamSignal::Drops(spawn_drop_counter(pins.input_pullup(pin)?))
+            }
+        })
+    }
+}
+
+fn spawn_stall_watch(input: Input) -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        let mut high_for = Duration::ZERO;
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            high_for = if input.is_high() {
+                high_for + POLL_INTERVAL
+            } else {
+                Duration::ZERO
+            };
+            let stalled = high_for >= STALL_TIME;
+            if stalled && !*tx.borrow() {
+                warn!("Motor stalled");
+            }
+            tx.send_if_modified(|current| std::mem::replace(current, stalled) != stalled);
+        }
+    });
+    rx
+}
+
+/// The switch closes to ground while a piece passes
+fn spawn_drop_counter(input: Input) -> watch::Receiver<u64> {
+    let (tx, rx) = watch::channel(0);
+    tokio::spawn(async move {
+        let mut was_closed = input.is_low();
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let closed = input.is_low();
+            if closed && !was_closed {
+                tx.send_modify(|drops| *drops += 1);
+            }
+            was_closed = closed;
+        }
+    });
+    rx
+}
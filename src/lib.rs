@@ -0,0 +1,129 @@
+//! Lightning-paid candy dispenser for the Raspberry Pi.
+//!
+//! The `candypi` binary wires everything together, but the building blocks can be reused to
+//! drive other vending hardware. The [`prelude`] contains the types most integrations need.
+//! Modules hidden from the documentation are plumbing of the binary. They are only public
+//! because the binary is built against this library, they aren't part of its API and may change
+//! in any release.
+//!
+//! Receiving a payment and dispensing once:
+//!
+//! ```no_run
+//! use candypi::prelude::*;
+//!
+//! # async fn vend() -> Result<(), Box<dyn std::error::Error>> {
+//! let fedimint = FedimintBuilder::default().build().await?;
+//! let bus = EventBus::default();
+//! let audit_log = AuditLog::open(&AuditLog::default_path())?;
+//! let mut hardware = HardwareBuilder::default().build(Notifier::from_env(), audit_log, &bus)?;
+//!
+//! let invoice = fedimint.lightning_invoice(21_000, "Gummy bears").await?;
+//! let status_bar = StatusBar::new("192.168.1.23".to_string());
+//! Screen::Invoice {
+//!     invoice: &invoice.to_string(),
+//!     amount: "21 sats",
+//!     onchain: None,
+//! }
+//! .draw(&mut hardware.display, &status_bar, &Theme::default())?;
+//!
+//! fedimint.await_payment(&invoice).await?;
+//! hardware.dispenser.dispense().await;
+//! # Ok(())
+//! # }
+//! ```
+
+pub(crate) mod actions;
+#[doc(hidden)]
+pub mod api;
+pub(crate) mod api_auth;
+pub mod audit;
+pub mod backlight;
+pub(crate) mod bbqr;
+pub mod cashu;
+pub mod climate;
+pub mod coins;
+pub mod config;
+pub mod connectivity;
+#[doc(hidden)]
+pub mod control;
+pub mod deposit;
+pub mod dispenser;
+pub mod door;
+pub mod estop;
+pub mod events;
+pub(crate) mod federation;
+pub mod fedimint;
+pub mod gateway;
+pub mod hardware;
+pub mod hopper;
+pub mod i18n;
+pub mod ili9341;
+pub mod input;
+pub mod inventory;
+pub mod jam;
+pub mod light_sensor;
+pub mod lights;
+pub mod lnd;
+pub mod lnurl;
+pub mod load_cell;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod mdns;
+#[doc(hidden)]
+pub mod memory;
+#[doc(hidden)]
+pub mod mqtt;
+pub mod nfc;
+pub mod notify;
+#[doc(hidden)]
+pub mod operator;
+pub mod pins;
+#[doc(hidden)]
+pub mod prometheus;
+pub mod rates;
+#[doc(hidden)]
+pub mod recovery;
+pub(crate) mod remote_dispense;
+#[doc(hidden)]
+pub mod retry;
+#[doc(hidden)]
+pub mod rtc;
+pub mod sales;
+pub mod screen;
+#[doc(hidden)]
+pub mod signed_config;
+#[cfg(feature = "simulate")]
+pub mod simulator;
+#[doc(hidden)]
+pub mod soft_spi;
+pub mod status_display;
+pub mod tamper;
+#[cfg(test)]
+mod testing;
+pub mod theme;
+pub mod tpm;
+pub mod ups;
+pub mod vending;
+pub mod wallet;
+pub mod watch_only;
+#[doc(hidden)]
+pub mod watchdog;
+#[doc(hidden)]
+pub mod wifi_setup;
+#[doc(hidden)]
+pub mod wipe;
+
+/// The types most integrations need
+pub mod prelude {
+    pub use crate::audit::AuditLog;
+    pub use crate::dispenser::{DispenseAction, Dispenser, Mechanism};
+    pub use crate::events::{Event, EventBus, EventSubscriber};
+    pub use crate::fedimint::{Fedimint, FedimintBuilder};
+    pub use crate::hardware::{Hardware, HardwareBuilder};
+    pub use crate::notify::Notifier;
+    pub use crate::pins::{OutputSpec, PinRef, Polarity};
+    pub use crate::screen::{Display, Screen, StatusBar};
+    pub use crate::theme::Theme;
+    pub use crate::wallet::{PaymentProvider, Wallet};
+}
@@ -0,0 +1,159 @@
+use crate::retry::{self, Retry};
+use fedimint_core::anyhow::{self, Context, bail};
+use rppal::i2c::I2c;
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, warn};
+
+/// Environment variable overriding `light_sensor` of the config file, `bh1750` or `tsl2561`
+pub const LIGHT_SENSOR_ENV: &str = "CANDYPI_LIGHT_SENSOR";
+
+const BH1750_ADDRESS: u16 = 0x23;
+const BH1750_POWER_ON: u8 = 0x01;
+/// One lux resolution, repeated every 120 ms
+const BH1750_CONTINUOUS_HIGH_RES: u8 = 0x10;
+
+/// ADDR pin floating
+const TSL2561_ADDRESS: u16 = 0x39;
+/// Command bit, combined with the register address
+const TSL2561_COMMAND: u8 = 0x80;
+/// Read two bytes in one go
+const TSL2561_WORD: u8 = 0x20;
+const TSL2561_REG_CONTROL: u8 = 0x00;
+const TSL2561_REG_TIMING: u8 = 0x01;
+const TSL2561_REG_DATA0: u8 = 0x0C;
+const TSL2561_REG_DATA1: u8 = 0x0E;
+const TSL2561_POWER_ON: u8 = 0x03;
+/// 402 ms integration at 1x gain, direct sunlight saturates the 16x gain
+const TSL2561_TIMING_402MS_LOW_GAIN: u8 = 0x02;
+/// The datasheet's lux formula assumes 16x gain
+const TSL2561_GAIN_SCALE: f32 = 16.0;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Weight of a new reading, so a passing shadow doesn't make the screen flicker
+const SMOOTHING: f32 = 0.2;
+
+/// `light_sensor` of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LightSensorKind {
+    Bh1750,
+    Tsl2561,
+}
+
+pub enum LightSensor {
+    Bh1750(I2c),
+    Tsl2561(I2c),
+}
+
+impl LightSensor {
+    /// Opens the sensor selected by [`LIGHT_SENSOR_ENV`] if set, `kind` otherwise. Returns `None`
+    /// without either.
+    pub fn from_config(kind: Option<LightSensorKind>) -> anyhow::Result<Option<Self>> {
+        let kind = match std::env::var(LIGHT_SENSOR_ENV).as_deref() {
+            Ok("bh1750") => LightSensorKind::Bh1750,
+            Ok("tsl2561") => LightSensorKind::Tsl2561,
+            Ok(other) => bail!("Unknown {LIGHT_SENSOR_ENV} '{other}', expected bh1750 or tsl2561"),
+            Err(_) => match kind {
+                Some(kind) => kind,
+                None => return Ok(None),
+            },
+        };
+        let open = |address| -> anyhow::Result<I2c> {
+            let mut i2c = I2c::new().context("Failed to open I2C bus")?;
+            i2c.set_slave_address(address)?;
+            Ok(i2c)
+        };
+        let sensor = match kind {
+            LightSensorKind::Bh1750 => {
+                let i2c = open(BH1750_ADDRESS)?;
+                i2c.write(&[BH1750_POWER_ON])?;
+                i2c.write(&[BH1750_CONTINUOUS_HIGH_RES])?;
+                LightSensor::Bh1750(i2c)
+            }
+            LightSensorKind::Tsl2561 => {
+                let i2c = open(TSL2561_ADDRESS)?;
+                i2c.write(&[TSL2561_COMMAND | TSL2561_REG_CONTROL, TSL2561_POWER_ON])?;
+                i2c.write(&[
+                    TSL2561_COMMAND | TSL2561_REG_TIMING,
+                    TSL2561_TIMING_402MS_LOW_GAIN,
+                ])?;
+                LightSensor::Tsl2561(i2c)
+            }
+        };
+        Ok(Some(sensor))
+    }
+
+    fn read_lux(&mut self) -> anyhow::Result<f32> {
+        match self {
+            LightSensor::Bh1750(i2c) => {
+                let mut data = [0u8; 2];
+                i2c.read(&mut data)?;
+                Ok(f32::from(u16::from_be_bytes(data)) / 1.2)
+            }
+            LightSensor::Tsl2561(i2c) => {
+                let read_channel = |register: u8| -> anyhow::Result<f32> {
+                    let mut data = [0u8; 2];
+                    i2c.write_read(&[TSL2561_COMMAND | TSL2561_WORD | register], &mut data)?;
+                    Ok(f32::from(u16::from_le_bytes(data)) * TSL2561_GAIN_SCALE)
+                };
+                let full_spectrum = read_channel(TSL2561_REG_DATA0)?;
+                let infrared = read_channel(TSL2561_REG_DATA1)?;
+                Ok(tsl2561_lux(full_spectrum, infrared))
+            }
+        }
+    }
+
+    /// Polls the sensor in a background task, the returned lux value is smoothed and stays
+    /// `None` until the first successful reading
+    pub fn spawn(mut self) -> watch::Receiver<Option<f32>> {
+        let (tx, rx) = watch::channel(None);
+
+        tokio::spawn(async move {
+            loop {
+                let reading = Retry::SENSOR
+                    .run("read light sensor", retry::always, async || self.read_lux())
+                    .await;
+                match reading {
+                    Ok(lux) => {
+                        let smoothed = match *tx.borrow() {
+                            Some(previous) => previous + SMOOTHING * (lux - previous),
+                            None => lux,
+                        };
+                        debug!("Ambient light {:.0} lux", smoothed);
+                        metrics::gauge!("candypi_ambient_light_lux").set(f64::from(smoothed));
+                        if tx.send(Some(smoothed)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Failed to read light sensor: {:#}", e),
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+
+        rx
+    }
+}
+
+/// Lux from the two TSL2561 channels, following the datasheet's approximation for the T, FN and
+/// CL packages
+fn tsl2561_lux(full_spectrum: f32, infrared: f32) -> f32 {
+    if full_spectrum <= 0.0 {
+        return 0.0;
+    }
+    let ratio = infrared / full_spectrum;
+    let lux = if ratio <= 0.5 {
+        0.0304 * full_spectrum - 0.062 * full_spectrum * ratio.powf(1.4)
+    } else if ratio <= 0.61 {
+        0.0224 * full_spectrum - 0.031 * infrared
+    } else if ratio <= 0.8 {
+        0.0128 * full_spectrum - 0.0153 * infrared
+    } else if ratio <= 1.3 {
+        0.00146 * full_spectrum - 0.00112 * infrared
+    } else {
+        0.0
+    };
+    lux.max(0.0)
+}
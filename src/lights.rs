@@ -0,0 +1,311 @@
+use crate::events::{Event, EventSubscriber};
+use crate::retry::{self, Retry};
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Environment variable overriding the number of LEDs of the `[led_strip]` table, enables the
+/// strip without one
+pub const LED_STRIP_ENV: &str = "CANDYPI_LED_STRIP";
+/// Environment variable overriding the strip brightness in percent
+pub const LED_BRIGHTNESS_ENV: &str = "CANDYPI_LED_BRIGHTNESS";
+
+/// Three SPI bits per WS2812 bit give the required 0.4/0.8 µs pulse widths at 2.4 MHz
+const SPI_CLOCK_HZ: u32 = 2_400_000;
+const SPI_ONE: u32 = 0b110;
+const SPI_ZERO: u32 = 0b100;
+/// Newer WS2812B revisions latch after 280 µs of low
+const RESET_BYTES: usize = 90;
+
+const FRAME_INTERVAL: Duration = Duration::from_millis(30);
+/// The payment cue is followed right away by the dispense, it stays up this long regardless
+const PAYMENT_CUE_TIME: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color::rgb(0, 0, 0);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// Fully saturated color at `hue` turns around the color wheel, starting at red
+    fn from_hue(hue: f32) -> Self {
+        let sector = hue.rem_euclid(1.0) * 6.0;
+        let rising = ((sector % 1.0) * 255.0) as u8;
+        let falling = 255 - rising;
+        match sector as u8 {
+            0 => Self::rgb(255, rising, 0),
+            1 => Self::rgb(falling, 255, 0),
+            2 => Self::rgb(0, 255, rising),
+            3 => Self::rgb(0, falling, 255),
+            4 => Self::rgb(rising, 0, 255),
+            _ => Self::rgb(255, 0, falling),
+        }
+    }
+
+    fn scale(self, factor: f32) -> Self {
+        let scale = |channel: u8| (f32::from(channel) * factor.clamp(0.0, 1.0)) as u8;
+        Self::rgb(scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+/// Animation shown on the whole strip
+#[derive(Debug, Clone, Copy)]
+pub enum Effect {
+    Off,
+    /// Slowly breathes in and out
+    Pulse {
+        color: Color,
+        period: Duration,
+    },
+    /// Random LEDs light up and fade out
+    Sparkle {
+        color: Color,
+    },
+    /// A short lit segment runs along the strip
+    Chase {
+        color: Color,
+        step: Duration,
+    },
+    /// The whole strip blinks
+    Flash {
+        color: Color,
+        period: Duration,
+    },
+    /// A rainbow spread over the strip turns once per period, to draw attention from afar
+    Rainbow {
+        period: Duration,
+    },
+}
+
+/// Which effect plays at which point of the vending flow
+#[derive(Debug, Clone, Copy)]
+pub struct Choreography {
+    /// Attracts customers while waiting for a payment
+    pub idle: Effect,
+    /// Shown as soon as a payment arrives, for at least 1.5 s
+    pub payment: Effect,
+    pub dispense: Effect,
+    /// After a completed dispense, until the next invoice
+    pub success: Effect,
+    pub error: Effect,
+    /// Global brightness from 0 to 1, full brightness is blinding and overloads small supplies
+    pub brightness: f32,
+}
+
+impl Default for Choreography {
+    fn default() -> Self {
+        Self {
+            idle: Effect::Rainbow {
+                period: Duration::from_secs(8),
+            },
+            payment: Effect::Flash {
+                color: Color::rgb(0, 255, 60),
+                period: Duration::from_millis(300),
+            },
+            dispense: Effect::Chase {
+                color: Color::rgb(255, 255, 255),
+                step: Duration::from_millis(40),
+            },
+            success: Effect::Sparkle {
+                color: Color::rgb(0, 255, 60),
+            },
+            error: Effect::Flash {
+                color: Color::rgb(255, 0, 0),
+                period: Duration::from_millis(500),
+            },
+            brightness: 0.3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LightingCue {
+    Idle,
+    Payment,
+    Dispense,
+    Success,
+    Error,
+    Off,
+}
+
+impl LightingCue {
+    fn for_event(event: &Event) -> Option<Self> {
+        match event {
+            Event::InvoiceCreated { .. } | Event::AlarmCleared => Some(LightingCue::Idle),
+            Event::PaymentReceived { .. } => Some(LightingCue::Payment),
+            Event::DispenseStarted => Some(LightingCue::Dispense),
+            Event::DispenseDone { completed: true } => Some(LightingCue::Success),
+            Event::DispenseDone { completed: false }
+            | Event::DispenseJammed
+            | Event::TamperAlarm
+            | Event::EmergencyStop => Some(LightingCue::Error),
+            Event::ShuttingDown => Some(LightingCue::Off),
+            Event::NetworkChanged { .. }
+            | Event::HopperLow { .. }
+            | Event::ButtonPressed(_)
+            | Event::ButtonHeld(_) => None,
+        }
+    }
+}
+
+/// WS2812 (NeoPixel) strip driven by SPI1's MOSI (GPIO 20), SPI0 is taken by the display
+pub struct LedStrip {
+    spi: Spi,
+    frame: Vec<Color>,
+    choreography: Choreography,
+}
+
+impl LedStrip {
+    pub fn new(length: usize, choreography: Choreography) -> rppal::spi::Result<Self> {
+        Ok(Self {
+            spi: Spi::new(Bus::Spi1, SlaveSelect::Ss0, SPI_CLOCK_HZ, Mode::Mode0)?,
+            frame: vec![Color::OFF; length],
+            choreography,
+        })
+    }
+
+    fn show(&mut self) -> rppal::spi::Result<()> {
+        let mut data = Vec::with_capacity(self.frame.len() * 9 + RESET_BYTES);
+        for color in &self.frame {
+            let color = color.scale(self.choreography.brightness);
+            // WS2812 expects green first
+            for byte in [color.g, color.r, color.b] {
+                let mut bits = 0u32;
+                for bit in (0..8).rev() {
+                    let encoded = if byte & (1 << bit) != 0 {
+                        SPI_ONE
+                    } else {
+                        SPI_ZERO
+                    };
+                    bits = (bits << 3) | encoded;
+                }
+                data.extend_from_slice(&bits.to_be_bytes()[1..]);
+            }
+        }
+        data.resize(data.len() + RESET_BYTES, 0);
+
+        self.spi.write(&data)?;
+        Ok(())
+    }
+
+    fn effect(&self, cue: LightingCue) -> Effect {
+        match cue {
+            LightingCue::Idle => self.choreography.idle,
+            LightingCue::Payment => self.choreography.payment,
+            LightingCue::Dispense => self.choreography.dispense,
+            LightingCue::Success => self.choreography.success,
+            LightingCue::Error => self.choreography.error,
+            LightingCue::Off => Effect::Off,
+        }
+    }
+
+    fn render(&mut self, effect: Effect, elapsed: Duration, rng: &mut u32) {
+        let len = self.frame.len();
+        if len == 0 {
+            return;
+        }
+
+        match effect {
+            Effect::Off => self.frame.fill(Color::OFF),
+            Effect::Pulse { color, period } => {
+                let phase = elapsed.as_secs_f32() / period.as_secs_f32() * 2.0 * PI;
+                self.frame.fill(color.scale((1.0 - phase.cos()) / 2.0));
+            }
+            Effect::Sparkle { color } => {
+                for led in &mut self.frame {
+                    *led = led.scale(0.85);
+                }
+                // xorshift is plenty random for blinking lights
+                *rng ^= *rng << 13;
+                *rng ^= *rng >> 17;
+                *rng ^= *rng << 5;
+                self.frame[*rng as usize % len] = color;
+            }
+            Effect::Chase { color, step } => {
+                let head = (elapsed.as_millis() / step.as_millis().max(1)) as usize % len;
+                for (idx, led) in self.frame.iter_mut().enumerate() {
+                    *led = match (head + len - idx) % len {
+                        0 => color,
+                        1 => color.scale(0.4),
+                        2 => color.scale(0.1),
+                        _ => Color::OFF,
+                    };
+                }
+            }
+            Effect::Flash { color, period } => {
+                let on = elapsed.as_millis() % period.as_millis().max(1) < period.as_millis() / 2;
+                self.frame.fill(if on { color } else { Color::OFF });
+            }
+            Effect::Rainbow { period } => {
+                let turn = elapsed.as_secs_f32() / period.as_secs_f32();
+                for (idx, led) in self.frame.iter_mut().enumerate() {
+                    *led = Color::from_hue(turn + idx as f32 / len as f32);
+                }
+            }
+        }
+    }
+
+    /// Animates the strip in a background task, switching effects as the machine's state changes
+    pub fn spawn(mut self, mut events: EventSubscriber) {
+        tokio::spawn(async move {
+            let mut cue = LightingCue::Idle;
+            let mut started = Instant::now();
+            // Cue to switch to once the payment cue was shown long enough
+            let mut queued = None;
+            let mut rng = 0x2545_f491u32;
+            let mut interval = tokio::time::interval(FRAME_INTERVAL);
+
+            loop {
+                tokio::select! {
+                    event = events.recv() => {
+                        let Some(event) = event else {
+                            // The machine is going away, leave the strip dark
+                            self.frame.fill(Color::OFF);
+                            let cleared = Retry::SPI
+                                .run("clear LED strip", retry::always, async || self.show())
+                                .await;
+                            if let Err(e) = cleared {
+                                warn!("Failed to clear LED strip: {}", e);
+                            }
+                            return;
+                        };
+                        let Some(next) = LightingCue::for_event(&event) else {
+                            continue;
+                        };
+                        let holding = cue == LightingCue::Payment
+                            && started.elapsed() < PAYMENT_CUE_TIME
+                            && !matches!(next, LightingCue::Error | LightingCue::Off);
+                        if holding {
+                            queued = Some(next);
+                        } else {
+                            cue = next;
+                            started = Instant::now();
+                            queued = None;
+                        }
+                    }
+                    _ = interval.tick() => {
+                        let payment_shown = started.elapsed() >= PAYMENT_CUE_TIME;
+                        if let Some(next) = queued.filter(|_| payment_shown) {
+                            queued = None;
+                            cue = next;
+                            started = Instant::now();
+                        }
+                        self.render(self.effect(cue), started.elapsed(), &mut rng);
+                        if let Err(e) = self.show() {
+                            warn!("Failed to update LED strip: {}", e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
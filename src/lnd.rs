@@ -0,0 +1,129 @@
+use crate::wallet::PaymentProvider;
+use async_trait::async_trait;
+use fedimint_core::anyhow::{self, Context, ensure};
+use lightning_invoice::Bolt11Invoice;
+use serde::Deserialize;
+use serde_json::json;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const INVOICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `[lnd]` table of the config file, takes payments with the operator's own LND node instead of
+/// Fedimint
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LndConfig {
+    /// REST endpoint of the node, e.g. `https://mynode.local:8080`
+    pub rest_url: String,
+    /// Macaroon file, `invoice.macaroon` only allows creating and looking up invoices
+    pub macaroon_path: PathBuf,
+    /// The node's `tls.cert`, needed unless the certificate is signed by a public CA
+    pub tls_cert_path: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct AddInvoiceResponse {
+    payment_request: String,
+}
+
+#[derive(Deserialize)]
+struct LookupInvoiceResponse {
+    #[serde(default)]
+    state: String,
+}
+
+/// Invoices of an LND node through its REST interface, the funds stay on the node
+pub struct LndNode {
+    client: reqwest::Client,
+    url: String,
+    macaroon: String,
+}
+
+impl LndNode {
+    /// Connects to the node and checks the macaroon is accepted
+    pub async fn connect(config: &LndConfig) -> anyhow::Result<Self> {
+        let macaroon = fs::read(&config.macaroon_path)
+            .with_context(|| format!("Failed to read {}", config.macaroon_path.display()))?;
+        let mut client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+        if let Some(path) = &config.tls_cert_path {
+            let pem =
+                fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+            client = client.add_root_certificate(
+                reqwest::Certificate::from_pem(&pem).context("Invalid LND TLS certificate")?,
+            );
+        }
+        let node = Self {
+            client: client.build()?,
+            url: config.rest_url.trim_end_matches('/').to_owned(),
+            macaroon: hex::encode(macaroon),
+        };
+
+        // Also allowed by the invoice macaroon, unlike `/v1/getinfo`
+        node.get("/v1/invoices?num_max_invoices=1")
+            .await
+            .context("Failed to reach LND node")?;
+        info!(node = %node.url, "Connected to LND node");
+        Ok(node)
+    }
+
+    async fn get(&self, path: &str) -> anyhow::Result<reqwest::Response> {
+        Ok(self
+            .client
+            .get(format!("{}{}", self.url, path))
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+
+    async fn invoice_state(&self, path: &str) -> anyhow::Result<String> {
+        let lookup: LookupInvoiceResponse = self.get(path).await?.json().await?;
+        Ok(lookup.state)
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for LndNode {
+    async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let response: AddInvoiceResponse = self
+            .client
+            .post(format!("{}/v1/invoices", self.url))
+            .header("Grpc-Metadata-macaroon", &self.macaroon)
+            .json(&json!({
+                "value_msat": amount_msats.to_string(),
+                "memo": description,
+            }))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(Bolt11Invoice::from_str(&response.payment_request)?)
+    }
+
+    /// Polls the node until the invoice was settled
+    async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        let path = format!("/v1/invoice/{}", invoice.payment_hash());
+        loop {
+            match self.invoice_state(&path).await {
+                Ok(state) if state == "SETTLED" => return Ok(()),
+                Ok(state) => ensure!(state != "CANCELED", "Invoice was canceled"),
+                // The node may be restarting, keep trying until the invoice expires
+                Err(e) => warn!("Failed to look up invoice: {:#}", e),
+            }
+
+            ensure!(!invoice.is_expired(), "Invoice expired before being paid");
+            tokio::time::sleep(INVOICE_POLL_INTERVAL).await;
+        }
+    }
+}
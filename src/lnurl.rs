@@ -0,0 +1,141 @@
+use crate::retry::{self, Retry};
+use fedimint_core::anyhow::{self, Context, ensure};
+use lightning_invoice::Bolt11Invoice;
+use serde::Deserialize;
+use std::str::FromStr;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PayResponse {
+    callback: String,
+    min_sendable: u64,
+    max_sendable: u64,
+}
+
+#[derive(Deserialize)]
+struct InvoiceResponse {
+    pr: String,
+}
+
+/// Requests an invoice over `amount_msats` from a Lightning address (`user@domain`) via LNURL-pay
+pub async fn invoice_from_lightning_address(
+    address: &str,
+    amount_msats: u64,
+) -> anyhow::Result<Bolt11Invoice> {
+    let (user, domain) = address
+        .split_once('@')
+        .context("Lightning address must look like user@domain")?;
+
+    let client = reqwest::Client::new();
+    let pay_url = format!("https://{domain}/.well-known/lnurlp/{user}");
+    let pay: PayResponse = Retry::NETWORK
+        .run(
+            "fetch LNURL-pay parameters",
+            retry::is_transient_http,
+            async || client.get(&pay_url).send().await?.error_for_status(),
+        )
+        .await?
+        .json()
+        .await
+        .context("Invalid LNURL-pay response")?;
+    ensure!(
+        (pay.min_sendable..=pay.max_sendable).contains(&amount_msats),
+        "{} only accepts between {} and {} msat",
+        address,
+        pay.min_sendable,
+        pay.max_sendable
+    );
+
+    let separator = if pay.callback.contains('?') { '&' } else { '?' };
+    let callback_url = format!("{}{}amount={}", pay.callback, separator, amount_msats);
+    let response: InvoiceResponse = Retry::NETWORK
+        .run(
+            "request LNURL-pay invoice",
+            retry::is_transient_http,
+            async || client.get(&callback_url).send().await?.error_for_status(),
+        )
+        .await?
+        .json()
+        .await
+        .context("Invalid LNURL-pay callback response")?;
+
+    let invoice = Bolt11Invoice::from_str(&response.pr)?;
+    ensure!(
+        invoice.amount_milli_satoshis() == Some(amount_msats),
+        "LNURL server returned an invoice for the wrong amount"
+    );
+
+    Ok(invoice)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WithdrawResponse {
+    tag: String,
+    callback: String,
+    k1: String,
+    min_withdrawable: u64,
+    max_withdrawable: u64,
+}
+
+/// Error or success reply of an LNURL callback
+#[derive(Deserialize)]
+struct StatusResponse {
+    status: String,
+    reason: Option<String>,
+}
+
+/// Asks the LNURL-withdraw service at `url` to pay `invoice`, e.g. from a tapped Bolt Card. The
+/// payment itself arrives like any other, this only returns once the service accepted it.
+pub async fn withdraw(url: &str, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+    let amount_msats = invoice
+        .amount_milli_satoshis()
+        .context("Invoice has no amount")?;
+    let client = reqwest::Client::new();
+    let withdraw: WithdrawResponse = Retry::NETWORK
+        .run(
+            "fetch LNURL-withdraw parameters",
+            retry::is_transient_http,
+            async || client.get(url).send().await?.error_for_status(),
+        )
+        .await?
+        .json()
+        .await
+        .context("Invalid LNURL-withdraw response")?;
+    ensure!(
+        withdraw.tag == "withdrawRequest",
+        "Not an LNURL-withdraw link"
+    );
+    ensure!(
+        (withdraw.min_withdrawable..=withdraw.max_withdrawable).contains(&amount_msats),
+        "LNURL-withdraw only pays between {} and {} msat",
+        withdraw.min_withdrawable,
+        withdraw.max_withdrawable
+    );
+
+    // The invoice only holds bech32 characters and the k1 is hex, neither needs escaping
+    let separator = if withdraw.callback.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    let callback_url = format!(
+        "{}{}k1={}&pr={}",
+        withdraw.callback, separator, withdraw.k1, invoice
+    );
+    // Not retried, the service may have paid even if the response got lost
+    let response: StatusResponse = client
+        .get(&callback_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("Invalid LNURL-withdraw callback response")?;
+    ensure!(
+        response.status.eq_ignore_ascii_case("OK"),
+        "LNURL-withdraw refused: {}",
+        response.reason.unwrap_or_default()
+    );
+    Ok(())
+}
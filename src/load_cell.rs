@@ -0,0 +1,158 @@
+use crate::notify::Notifier;
+use crate::retry::{self, Retry};
+use fedimint_core::anyhow::{self, Context};
+use rppal::gpio::{Gpio, InputPin, OutputPin};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Environment variable with the raw HX711 reading of the empty hopper
+pub const TARE_ENV: &str = "CANDYPI_LOAD_CELL_TARE";
+/// Environment variable with the raw HX711 counts per gram, enables the load cell if set
+pub const SCALE_ENV: &str = "CANDYPI_LOAD_CELL_SCALE";
+
+/// At 10 samples per second a conversion takes 100 ms, anything longer means no HX711 is attached
+const READY_TIMEOUT: Duration = Duration::from_millis(500);
+const SAMPLES_PER_READING: usize = 5;
+const READING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Warn the operator once stock drops below this, and again only after a refill
+const LOW_STOCK_GRAMS: u32 = 200;
+const LOW_STOCK_HYSTERESIS_GRAMS: u32 = 100;
+
+pub struct LoadCellCalibration {
+    tare: i32,
+    counts_per_gram: f32,
+}
+
+impl LoadCellCalibration {
+    /// Reads the calibration from [`TARE_ENV`] and [`SCALE_ENV`], returns `None` if the load cell
+    /// isn't configured
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Ok(scale) = std::env::var(SCALE_ENV) else {
+            return Ok(None);
+        };
+        let counts_per_gram = scale
+            .parse::<f32>()
+            .with_context(|| format!("Invalid {SCALE_ENV}"))?;
+        let tare = match std::env::var(TARE_ENV) {
+            Ok(tare) => tare
+                .parse()
+                .with_context(|| format!("Invalid {TARE_ENV}"))?,
+            Err(_) => 0,
+        };
+
+        Ok(Some(Self {
+            tare,
+            counts_per_gram,
+        }))
+    }
+}
+
+/// HX711 load cell amplifier under the hopper. The protocol is bit-banged, so it has to sit on
+/// native pins.
+pub struct Hx711 {
+    dout: InputPin,
+    sck: OutputPin,
+}
+
+impl Hx711 {
+    pub fn new(gpio: &Gpio, dout_pin: u8, sck_pin: u8) -> rppal::gpio::Result<Self> {
+        Ok(Self {
+            dout: gpio.get(dout_pin)?.into_input(),
+            sck: gpio.get(sck_pin)?.into_output_low(),
+        })
+    }
+
+    fn read_raw(&mut self) -> Option<i32> {
+        // DOUT goes low once a conversion is ready
+        let deadline = Instant::now() + READY_TIMEOUT;
+        while self.dout.is_high() {
+            if Instant::now() > deadline {
+                return None;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let mut value = 0u32;
+        for _ in 0..24 {
+            self.sck.set_high();
+            value = (value << 1) | u32::from(self.dout.is_high());
+            self.sck.set_low();
+        }
+
+        // 25th pulse selects channel A with gain 128 for the next conversion
+        self.sck.set_high();
+        self.sck.set_low();
+
+        // Sign extend the 24 bit two's complement value
+        Some(((value << 8) as i32) >> 8)
+    }
+
+    /// Median of several conversions, since a badly timed preemption while clocking out bits
+    /// occasionally produces garbage
+    pub fn read_median(&mut self) -> Option<i32> {
+        let mut samples: Vec<i32> = (0..SAMPLES_PER_READING)
+            .filter_map(|_| self.read_raw())
+            .collect();
+        if samples.is_empty() {
+            return None;
+        }
+
+        samples.sort_unstable();
+        Some(samples[samples.len() / 2])
+    }
+
+    /// Weighs the hopper on a dedicated thread, the timing is too tight for the async runtime.
+    /// The returned channel holds the latest weight in grams, `None` until the first reading.
+    pub fn spawn(
+        mut self,
+        calibration: LoadCellCalibration,
+        notifier: Notifier,
+    ) -> watch::Receiver<Option<u32>> {
+        let (tx, rx) = watch::channel(None);
+
+        thread::spawn(move || {
+            loop {
+                let raw = Retry::SENSOR.run_blocking("read load cell", retry::always, || {
+                    self.read_median().ok_or("HX711 not responding")
+                });
+                match raw {
+                    Ok(raw) => {
+                        let grams = ((raw - calibration.tare) as f32 / calibration.counts_per_gram)
+                            .max(0.0) as u32;
+                        metrics::gauge!("candypi_stock_grams").set(f64::from(grams));
+                        if tx.send(Some(grams)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Failed to read load cell: {}", e),
+                }
+                thread::sleep(READING_INTERVAL);
+            }
+        });
+
+        tokio::spawn(alert_low_stock(rx.clone(), notifier));
+        rx
+    }
+}
+
+async fn alert_low_stock(mut stock: watch::Receiver<Option<u32>>, notifier: Notifier) {
+    let mut alerted = false;
+
+    while stock.changed().await.is_ok() {
+        let Some(grams) = *stock.borrow_and_update() else {
+            continue;
+        };
+
+        if !alerted && grams < LOW_STOCK_GRAMS {
+            alerted = true;
+            notifier
+                .notify(&format!("Low stock: about {} g of candy left", grams))
+                .await;
+        } else if alerted && grams >= LOW_STOCK_GRAMS + LOW_STOCK_HYSTERESIS_GRAMS {
+            alerted = false;
+        }
+    }
+}
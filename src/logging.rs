@@ -0,0 +1,23 @@
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable selecting the log format, `json` for one JSON object per line, e.g. for
+/// journald's structured fields. Plain text otherwise.
+pub const LOG_FORMAT_ENV: &str = "CANDYPI_LOG_FORMAT";
+
+/// Log levels per module in `RUST_LOG` syntax, `info` unless set
+pub const LOG_FILTER_ENV: &str = "RUST_LOG";
+
+/// Installs the global subscriber, call it before anything logs. Logs go to stderr, stdout is
+/// kept for the answers of the stdin control protocol.
+pub fn init() {
+    let filter = EnvFilter::try_from_env(LOG_FILTER_ENV).unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+
+    if std::env::var(LOG_FORMAT_ENV).is_ok_and(|format| format == "json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
@@ -1,91 +1,253 @@
-use embedded_graphics::{
-    image::{Image, ImageRaw},
-    mono_font::{MonoTextStyle, ascii::FONT_6X10},
-    pixelcolor::Rgb565,
-    prelude::*,
-    primitives::{PrimitiveStyleBuilder, Rectangle},
-    text::Text,
+use candypi::api::ApiServer;
+use candypi::audit::{self, AuditLog};
+use candypi::cashu::CashuWallet;
+use candypi::config::{Config, DEFAULT_CONFIG_PATH, LedStripConfig, Product};
+use candypi::connectivity::ConnectionMonitor;
+use candypi::control::{
+    self, ControlCommand, ControlRequest, ControlResponse, ControlServer, CurrentInvoice,
+    MachineStatus,
 };
-use qrcode::QrCode;
-use rppal::gpio::{Gpio, OutputPin};
-use rppal::hal::Delay;
-use rppal::spi::{Bus, Mode, SimpleHalSpiDevice, SlaveSelect, Spi};
-use st7735_lcd::{Orientation, ST7735};
+use candypi::deposit::{DepositEvent, DepositWatcher};
+use candypi::dispenser::{DispenseAction, Dispenser, DispenserHandle};
+use candypi::door::{self, DoorEvent};
+use candypi::events::{Event, EventBus};
+use candypi::fedimint::{FedimintBuilder, RefundTo};
+use candypi::hardware::{self, Hardware, HardwareBuilder};
+use candypi::hopper::HopperSensorConfig;
+use candypi::input::Button;
+use candypi::inventory::{self, CandyCount, Inventory};
+use candypi::jam::JamSensorConfig;
+use candypi::light_sensor::LightSensor;
+use candypi::lnd::LndNode;
+use candypi::load_cell::Hx711;
+use candypi::mdns::MdnsAdvertiser;
+use candypi::mqtt::MqttTelemetry;
+use candypi::nfc::{NfcTap, Pn532};
+use candypi::notify::Notifier;
+use candypi::operator::{self, MenuOutcome, OperatorPin};
+use candypi::pins::{PinRef, Pins};
+use candypi::rates::RateProvider;
+use candypi::recovery::{self, Recovery, UnfinishedSale};
+use candypi::retry::{self, Retry};
+use candypi::sales::{self, PaymentMethod, Sale, SalesLedger};
+use candypi::screen::{
+    ConnectionStatus, Display, OnchainFallback, Screen, StatusBar, clear_display, draw_status_bar,
+};
+use candypi::signed_config::{self, ConfigVerifier};
+use candypi::status_display::StatusDisplay;
+use candypi::theme::Theme;
+use candypi::tpm::SeedKey;
+use candypi::ups::UpsEvent;
+use candypi::vending::{VendingEvent, VendingStateMachine};
+use candypi::wallet::Wallet;
+use candypi::watchdog::{self, HardwareWatchdog};
+use candypi::wifi_setup::{NetworkStatus, WifiSetup};
+use candypi::{gateway, i18n, lnurl, logging, memory, prometheus, rtc, watch_only, wipe};
+use clap::{Parser, Subcommand, ValueEnum};
+use fedimint_core::Amount;
+use fedimint_core::anyhow::{self, Context};
+use fedimint_core::bitcoin::hashes::sha256;
+use fedimint_core::invite_code::InviteCode;
+use fedimint_mint_client::OOBNotes;
+use lightning_invoice::Bolt11Invoice;
+use rppal::gpio::Gpio;
 use std::io::{self, BufRead};
 use std::net::UdpSocket;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use crate::fedimint::Fedimint;
+use std::time::Duration;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{Instrument, debug, error, info, info_span, warn};
 
-mod fedimint;
+const TEST_DISPLAY_DURATION: Duration = Duration::from_secs(5);
 
-const MOTOR_PIN: u8 = 4;
-const MOTOR_DISPENSE_DURATION_MS: u64 = 500;
+/// Cooldown after a sale before the next invoice is shown
+const SUCCESS_SCREEN_DURATION: Duration = Duration::from_secs(3);
 
-const LCD_LED_PIN: u8 = 22;
-const LCD_DC_PIN: u8 = 24;
-const LCD_RST_PIN: u8 = 25;
+const TAMPER_ALARM_SCREEN_DURATION: Duration = Duration::from_secs(10);
 
-const DISPLAY_WIDTH: u32 = 128;
-const DISPLAY_HEIGHT: u32 = 160;
+/// How long customers are told to come back later once their on-chain payment was seen
+const DEPOSIT_DETECTED_SCREEN_DURATION: Duration = Duration::from_secs(15);
 
-const STATUS_BAR_HEIGHT: u32 = 13;
+/// Long enough to find the next coin in a pocket
+const COIN_CREDIT_SCREEN_DURATION: Duration = Duration::from_secs(30);
 
-type Display = ST7735<SimpleHalSpiDevice<Spi>, OutputPin, OutputPin>;
+/// Status bar changes arriving within this window are drawn together
+const STATUS_BAR_DEBOUNCE: Duration = Duration::from_millis(250);
 
-#[derive(Clone)]
-enum ConnectionStatus {
-    Connected,
-    Disconnected,
-}
+/// How often the ecash balance on the status bar is refreshed, besides right after sales
+const BALANCE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
 
-struct StatusBar {
-    height: u32,
-    ip_address: String,
-    connection_status: ConnectionStatus,
-}
+/// How often the local IP is looked up again, e.g. after a DHCP renewal or late Wi-Fi association
+const IP_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
 
-impl StatusBar {
-    fn new(ip_address: String) -> Self {
-        Self {
-            height: STATUS_BAR_HEIGHT,
-            ip_address,
-            connection_status: ConnectionStatus::Disconnected,
-        }
-    }
+/// Invoices are replaced this long before they expire, so a wallet that is slow to pay doesn't
+/// end up with an expired invoice
+const INVOICE_EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+/// Replaced invoices are watched this long past their expiry, a payment started just before may
+/// still settle
+const SUPERSEDED_INVOICE_GRACE: Duration = Duration::from_secs(60);
+/// How often the bar below the QR code showing the invoice's remaining validity is updated
+const INVOICE_COUNTDOWN_INTERVAL: Duration = Duration::from_secs(1);
+/// Time each part of a QR code too long for a single one is shown
+const QR_FRAME_INTERVAL: Duration = Duration::from_millis(300);
 
-    fn update_ip(&mut self, ip: String) {
-        self.ip_address = ip;
-    }
+/// Wallet calls taking longer than this are considered stalled, e.g. by a gateway outage
+const WALLET_CALL_TIMEOUT: Duration = Duration::from_secs(60);
 
-    fn set_connection_status(&mut self, status: ConnectionStatus) {
-        self.connection_status = status;
-    }
-}
+/// How long the offline screen is shown after creating an invoice failed before trying again
+const INVOICE_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A jammed dispense is retried this often before the payment is refunded
+const JAM_RETRIES: u32 = 1;
+
+/// Long enough for the operator to read why a refund from the menu failed
+const REFUND_FAILED_SCREEN_DURATION: Duration = Duration::from_secs(10);
 
-struct DisplayLayout {
-    qr_size: u32,
-    qr_y_offset: u32,
-    amount_y: u32,
-    status_bar_height: u32,
+/// Refund notes nobody scanned return to the balance after this long
+const REFUND_CLAIM_TIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The refund QR code stays up this long unless a button is pressed
+const REFUND_SCREEN_DURATION: Duration = Duration::from_secs(120);
+
+/// Longer than a connectivity check may hold on to the wallet
+const WALLET_RELEASE_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Parser)]
+#[command(version, about = "Lightning-powered candy dispenser")]
+struct Cli {
+    /// Config file, defaults to /etc/candypi.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Price of one dispense in sats, overrides `price_sats` in the config file
+    #[arg(long, global = true)]
+    price: Option<u64>,
+    /// Invite code of the federation to join, overrides the config file
+    #[arg(long, global = true)]
+    invite: Option<String>,
+    /// Fedimint data directory, overrides the config file
+    #[arg(long, global = true)]
+    datadir: Option<PathBuf>,
+    /// How long the motor runs or the servo stays open per dispense, overrides the config file
+    #[arg(long, global = true)]
+    dispense_ms: Option<u64>,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-impl DisplayLayout {
-    fn new() -> Self {
-        let status_bar_height = STATUS_BAR_HEIGHT;
-        let qr_size = DISPLAY_WIDTH - 4; // Leave 2px margin on each side
-        let qr_y_offset = status_bar_height + 4; // Start after status bar + small margin
-        let amount_y = qr_y_offset + qr_size + 8; // 8px below QR
+impl Cli {
+    fn config_path(&self) -> PathBuf {
+        self.config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH))
+    }
 
-        Self {
-            qr_size,
-            qr_y_offset,
-            amount_y,
-            status_bar_height,
+    /// Loads the config file and applies the flags on top
+    fn config(&self) -> anyhow::Result<Config> {
+        let mut config = Config::load(self.config.as_deref())?;
+        if let Some(price) = self.price {
+            anyhow::ensure!(price != 0, "--price must be at least 1");
+            config.price_sats = Some(price);
+        }
+        if let Some(invite) = &self.invite {
+            config.fedimint.invite = Some(invite.clone());
         }
+        if let Some(datadir) = &self.datadir {
+            config.fedimint.datadir = Some(datadir.clone());
+        }
+        match (self.dispense_ms, &mut config.servo, &config.stepper) {
+            (Some(run_ms), None, None) if config.products.iter().any(|p| p.motor.is_some()) => {
+                for motor in config.products.iter_mut().flat_map(|p| &mut p.motor) {
+                    motor.run_ms = run_ms;
+                }
+            }
+            (Some(_), _, Some(_)) => anyhow::bail!("--dispense-ms can't be used with a stepper"),
+            (Some(dwell_ms), Some(servo), None) => servo.dwell_ms = dwell_ms,
+            (Some(run_ms), None, None) => {
+                config.motor.get_or_insert_with(Default::default).run_ms = run_ms
+            }
+            (None, _, _) => {}
+        }
+        Ok(config)
     }
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Runs the dispenser, the default
+    Run,
+    /// Prints the wallet balance
+    Balance,
+    /// Pays out funds to a BOLT11 invoice or Lightning address
+    Withdraw {
+        /// BOLT11 invoice or Lightning address
+        destination: String,
+        /// Amount to send, required for Lightning addresses
+        #[arg(long)]
+        amount_sats: Option<u64>,
+    },
+    /// Dispenses once without taking payment
+    TestMotor,
+    /// Shows a test message on the display
+    TestDisplay,
+    /// Pays back a customer who was charged but got no candy
+    Refund {
+        /// Payment hash of the customer's invoice, as listed by `candypi sales`
+        payment_hash: String,
+        /// BOLT11 invoice or Lightning address to pay, prints ecash notes if left out
+        destination: Option<String>,
+        /// Refunds a sale even though its candy was dispensed
+        #[arg(long)]
+        force: bool,
+    },
+    /// Sweeps all funds and deletes the wallet
+    Wipe,
+    /// Checks the audit log's hash chain
+    VerifyAudit,
+    /// Replaces the config file with one signed by the operator, e.g. downloaded for this machine
+    ApplyConfig {
+        /// The new config file
+        file: PathBuf,
+        /// Raw ed25519 signature, as written by `openssl pkeyutl -sign`, over
+        /// `candypi-config`, the serial, the device and the file, each followed by a newline
+        signature: PathBuf,
+        /// Has to be higher than the serial of the installed config
+        #[arg(long)]
+        serial: u64,
+        /// Device ID the config was signed for, `*` if it was signed for every machine
+        #[arg(long)]
+        device: String,
+    },
+    /// Prints raw load cell readings for calibration
+    LoadCellRaw,
+    /// Sets the number of candy pieces after refilling the hopper
+    Refill { count: u32 },
+    /// Exports the sales ledger
+    Sales {
+        #[arg(long, value_enum, default_value_t = SalesFormat::Csv)]
+        format: SalesFormat,
+    },
+    /// Renders every screen to a PNG, e.g. to preview a theme or compare against golden images
+    #[cfg(feature = "simulate")]
+    RenderScreens {
+        /// Directory the images are written to
+        dir: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SalesFormat {
+    Csv,
+    /// One JSON array
+    Json,
+}
+
 fn get_local_ip() -> String {
     match UdpSocket::bind("0.0.0.0:0") {
         Ok(socket) => {
@@ -100,277 +262,2107 @@ fn get_local_ip() -> String {
     "No IP".to_string()
 }
 
-fn clear_display(display: &mut Display) {
-    let bg = Rectangle::new(Point::new(0, 0), Size::new(DISPLAY_WIDTH, DISPLAY_HEIGHT))
-        .into_styled(
-            PrimitiveStyleBuilder::new()
-                .fill_color(Rgb565::BLACK)
-                .build(),
-        );
-    let _ = bg.draw(display);
-}
+type PaymentWatch = JoinHandle<anyhow::Result<()>>;
 
-fn draw_status_bar(display: &mut Display, status_bar: &StatusBar) {
-    // Black background for status bar
-    let status_bg = Rectangle::new(
-        Point::new(0, 0),
-        Size::new(DISPLAY_WIDTH, STATUS_BAR_HEIGHT),
+/// Waits for the invoice to be paid in a background task, so a payment is picked up even while
+/// the main loop is busy with another screen, e.g. the operator menu. Once the invoice is
+/// replaced the watch lives on as a [`SupersededInvoice`] until the invoice expires.
+fn watch_payment(ln: &Arc<Wallet>, invoice: Bolt11Invoice) -> PaymentWatch {
+    let ln = ln.clone();
+    let span = info_span!("payment", hash = %invoice.payment_hash());
+    tokio::spawn(
+        async move {
+            ln.await_payment(&invoice).await?;
+            info!("Payment received");
+            Ok(())
+        }
+        .instrument(span),
     )
-    .into_styled(
-        PrimitiveStyleBuilder::new()
-            .fill_color(Rgb565::BLACK)
-            .build(),
-    );
-    let _ = status_bg.draw(display);
-
-    let text_style = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
+}
 
-    // Connection status indicator (left side)
-    let status_text = match status_bar.connection_status {
-        ConnectionStatus::Connected => "*",
-        ConnectionStatus::Disconnected => "o",
-    };
-    let status_display = Text::new(
-        status_text,
-        Point::new(2, STATUS_BAR_HEIGHT as i32 - 3),
-        text_style,
-    );
-    let _ = status_display.draw(display);
-
-    // IP address (right side)
-    let ip_x = DISPLAY_WIDTH as i32 - (status_bar.ip_address.len() as i32 * 6) - 2;
-    let ip_display = Text::new(
-        &status_bar.ip_address,
-        Point::new(ip_x, STATUS_BAR_HEIGHT as i32 - 3),
-        text_style,
-    );
-    let _ = ip_display.draw(display);
+/// An invoice replaced on screen, a customer who scanned it before may still pay it
+struct SupersededInvoice {
+    invoice: Bolt11Invoice,
+    /// Product the invoice was for
+    tier: usize,
+    amount_msat: u64,
+    watch: PaymentWatch,
+    /// Unpaid watches are stopped after this
+    expires: Instant,
 }
 
-fn generate_qr_image(
-    data: &str,
-    target_size: u32,
-) -> Result<(Vec<u8>, u32), Box<dyn std::error::Error>> {
-    // Generate QR code with minimal border
-    let code = QrCode::with_error_correction_level(data, qrcode::EcLevel::L)?;
-    let qr_modules = code.width() as u32;
+/// Waits for one of the replaced invoices to be paid, dropping those that expired unpaid. Never
+/// completes without any.
+async fn late_payment(superseded: &mut Vec<SupersededInvoice>) -> SupersededInvoice {
+    loop {
+        let now = Instant::now();
+        superseded.retain(|invoice| {
+            // A payment that came in while the main loop was busy is kept
+            let expired = invoice.expires <= now && !invoice.watch.is_finished();
+            if expired {
+                invoice.watch.abort();
+            }
+            !expired
+        });
+        let Some(next_expiry) = superseded.iter().map(|invoice| invoice.expires).min() else {
+            return std::future::pending().await;
+        };
 
-    // Calculate scale to fit nicely within target size
-    let scale = (target_size / qr_modules).max(1);
-    let actual_size = qr_modules * scale;
+        let finished = std::future::poll_fn(|cx| {
+            superseded
+                .iter_mut()
+                .enumerate()
+                .find_map(
+                    |(idx, invoice)| match Pin::new(&mut invoice.watch).poll(cx) {
+                        Poll::Ready(result) => Some(Poll::Ready((idx, result))),
+                        Poll::Pending => None,
+                    },
+                )
+                .unwrap_or(Poll::Pending)
+        });
+        tokio::select! {
+            (idx, result) = finished => {
+                let invoice = superseded.swap_remove(idx);
+                match result {
+                    Ok(Ok(())) => return invoice,
+                    Ok(Err(e)) => debug!(
+                        hash = %invoice.invoice.payment_hash(),
+                        "Replaced invoice won't be paid: {:#}",
+                        e
+                    ),
+                    Err(e) => debug!("Payment watch ended: {}", e),
+                }
+            }
+            _ = tokio::time::sleep_until(next_expiry) => {}
+        }
+    }
+}
 
-    // Create RGB565 image buffer manually for clean, square modules
-    let mut qr_data = Vec::with_capacity((actual_size * actual_size * 2) as usize);
+/// Every running payment watch, to be stopped before the wallet is shut down
+fn take_watches(
+    current: &mut Option<PaymentWatch>,
+    superseded: &mut Vec<SupersededInvoice>,
+) -> Vec<PaymentWatch> {
+    current
+        .take()
+        .into_iter()
+        .chain(superseded.drain(..).map(|invoice| invoice.watch))
+        .collect()
+}
 
-    for y in 0..actual_size {
-        for x in 0..actual_size {
-            let module_x = x / scale;
-            let module_y = y / scale;
+/// Waits for the next progress of an on-chain payment, never completes without on-chain payments
+async fn deposit_event(
+    events: &mut Option<mpsc::UnboundedReceiver<DepositEvent>>,
+) -> Option<DepositEvent> {
+    match events {
+        Some(events) => events.recv().await,
+        None => std::future::pending().await,
+    }
+}
 
-            let is_dark = if module_x < qr_modules && module_y < qr_modules {
-                code[(module_x as usize, module_y as usize)] == qrcode::Color::Dark
-            } else {
-                false // White border if outside QR bounds
-            };
+/// Waits for the watched invoice to be paid, never completes without one, e.g. during maintenance
+async fn payment_received(watch: &mut Option<PaymentWatch>) -> anyhow::Result<()> {
+    match watch {
+        Some(watch) => watch.await?,
+        None => std::future::pending().await,
+    }
+}
 
-            let rgb565 = if is_dark { 0x0000u16 } else { 0xFFFFu16 };
-            qr_data.push((rgb565 & 0xFF) as u8); // Low byte
-            qr_data.push((rgb565 >> 8) as u8); // High byte
+/// Stops watching for payments and shuts the wallet down. Besides the watches only a running
+/// connectivity check may still use it, which is waited for. Returns whether the wallet was shut
+/// down cleanly.
+async fn shutdown_wallet(
+    mut ln: Arc<Wallet>,
+    watches: Vec<PaymentWatch>,
+    deposits: Option<DepositWatcher>,
+) -> bool {
+    for watch in watches {
+        watch.abort();
+        let _ = watch.await;
+    }
+    if let Some(deposits) = deposits {
+        deposits.stop().await;
+    }
+    let deadline = Instant::now() + WALLET_RELEASE_TIMEOUT;
+    loop {
+        match Arc::try_unwrap(ln) {
+            Ok(ln) => {
+                ln.shutdown().await;
+                return true;
+            }
+            Err(shared) if Instant::now() < deadline => {
+                ln = shared;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(_) => {
+                warn!("Wallet still in use, skipping clean shutdown");
+                return false;
+            }
         }
     }
+}
+
+/// When the invoice expires and can't be paid anymore
+fn invoice_expiry(invoice: &Bolt11Invoice) -> Instant {
+    let remaining = invoice
+        .expiry_time()
+        .saturating_sub(invoice.duration_since_timestamp());
+    Instant::now() + remaining
+}
 
-    Ok((qr_data, actual_size))
+/// When the invoice should be replaced by a fresh one
+fn invoice_refresh_deadline(invoice: &Bolt11Invoice) -> Instant {
+    let remaining = invoice
+        .expiry_time()
+        .saturating_sub(invoice.duration_since_timestamp());
+    Instant::now() + remaining.saturating_sub(INVOICE_EXPIRY_MARGIN)
 }
 
-fn display_invoice_screen(
+/// Takes ecash notes, or a token of the Cashu mint, as payment for a dispense at `price_msat`,
+/// returns what they were worth
+async fn redeem_notes(ln: &Wallet, notes: &str, price_msat: u64) -> anyhow::Result<Amount> {
+    let fedimint = match ln {
+        Wallet::Fedimint(fedimint) => fedimint,
+        Wallet::Cashu(cashu) => {
+            let sats = cashu.receive(notes, price_msat.div_ceil(1000)).await?;
+            return Ok(Amount::from_sats(sats));
+        }
+        Wallet::WatchOnly(_) => anyhow::bail!("Ecash isn't accepted in watch-only mode"),
+        Wallet::Lnd(_) => anyhow::bail!("The LND backend doesn't accept ecash"),
+    };
+    let notes: OOBNotes = notes.trim().parse().context("Invalid ecash notes")?;
+    anyhow::ensure!(
+        notes.total_amount().msats >= price_msat,
+        "Notes are worth {}, less than the price",
+        notes.total_amount()
+    );
+    fedimint.redeem_notes(notes).await
+}
+
+/// Pays a customer back as ecash after a jam, `None` if that isn't possible, e.g. in watch-only
+/// mode. A Lightning payment is refunded by what reached the wallet, without the gateway's fee,
+/// anything else by `amount_msat`.
+async fn refund_notes(
+    ln: &Wallet,
+    amount_msat: u64,
+    payment_hash: Option<sha256::Hash>,
+) -> Option<OOBNotes> {
+    let fedimint = ln.fedimint()?;
+    let refund = async {
+        match payment_hash {
+            Some(payment_hash) => fedimint
+                .refund(
+                    &payment_hash,
+                    RefundTo::Notes {
+                        claim_time: REFUND_CLAIM_TIME,
+                    },
+                )
+                .await?
+                .context("Ecash refund returned no notes"),
+            None => {
+                fedimint
+                    .spend_notes(Amount::from_msats(amount_msat), REFUND_CLAIM_TIME)
+                    .await
+            }
+        }
+    };
+    watchdog::within("refund", WALLET_CALL_TIMEOUT, refund)
+        .await
+        .inspect_err(|e| error!("Failed to refund {} msat: {:#}", amount_msat, e))
+        .ok()
+}
+
+/// Completes a sale interrupted by a restart: dispenses if the customer paid moments ago,
+/// otherwise records it as not dispensed and asks the operator to refund it
+#[allow(clippy::too_many_arguments)]
+async fn recover_sale(
+    sale: &UnfinishedSale,
+    products: &[Product],
+    dispenser: &mut DispenserHandle,
     display: &mut Display,
-    invoice_data: &str,
-    amount: &str,
     status_bar: &StatusBar,
+    theme: &Theme,
+    bus: &EventBus,
+    audit_log: &AuditLog,
+    sales_ledger: &SalesLedger,
+    inventory: &Inventory,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Generating invoice display for: {}", invoice_data);
+    let dispensed = match sale.recovery {
+        Recovery::Dispense => {
+            audit_log.record(&format!("recovered_dispense {}", sale.payment_hash));
+            let tier = products
+                .iter()
+                .position(|product| product.name == sale.product)
+                .unwrap_or(0);
+            let product = &products[tier];
+            let bought = (products.len() > 1).then_some(product.name.as_str());
+            Screen::PaymentSuccess { product: bought }.draw(display, status_bar, theme)?;
+            dispenser.select_channel(tier);
+            bus.publish(Event::DispenseStarted);
+            let completed = match product.run() {
+                Some(run) => dispenser.dispense_for(run).await,
+                None => dispenser.dispense().await,
+            };
+            bus.publish(Event::DispenseDone { completed });
+            if completed {
+                inventory.dispensed();
+            }
+            completed
+        }
+        Recovery::Refund => {
+            report_refund_due(&sale.payment_hash, sale.amount_msat, audit_log);
+            false
+        }
+    };
+    sales_ledger.record(&Sale::now(
+        &sale.product,
+        sale.amount_msat,
+        PaymentMethod::Lightning,
+        Some(sale.payment_hash.clone()),
+        dispensed,
+    ));
+    Ok(())
+}
+
+/// Records that a customer paid without getting any candy and asks the operator to refund them
+fn report_refund_due(payment_hash: &str, amount_msat: u64, audit_log: &AuditLog) {
+    audit_log.record_now(&format!(
+        "refund_due {} {}",
+        payment_hash,
+        amount_msat / 1000
+    ));
+    let message = format!(
+        "Payment of {} sats ({}) was never dispensed, please refund the customer",
+        amount_msat / 1000,
+        payment_hash
+    );
+    // Retries while offline, which shouldn't hold up the main loop
+    tokio::spawn(async move { Notifier::from_env().notify(&message).await });
+}
+
+/// Sleeps until the deadline, never completes without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// When to start the attract loop next, if the theme has anything to show in it
+fn next_attract(theme: &Theme) -> Option<Instant> {
+    let interval = theme
+        .attract
+        .interval()
+        .filter(|_| attract_screen(theme, 0, "").is_some())?;
+    Some(Instant::now() + interval)
+}
+
+/// Screen `slide` of the attract loop: the splash, if any, followed by the promo texts
+fn attract_screen<'a>(theme: &'a Theme, slide: usize, amount: &'a str) -> Option<Screen<'a>> {
+    let splash = theme.splash.as_ref().map(|_| Screen::Splash);
+    let promos = theme
+        .attract
+        .messages
+        .iter()
+        .map(|text| Screen::Promo { text, amount });
+    splash.into_iter().chain(promos).nth(slide)
+}
 
-    let layout = DisplayLayout::new();
+/// Short or long press of any of the two buttons
+async fn next_press(
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    long_presses: &mut mpsc::UnboundedReceiver<Button>,
+) -> Option<Button> {
+    tokio::select! {
+        Some(button) = buttons.recv() => Some(button),
+        Some(button) = long_presses.recv() => Some(button),
+        else => None,
+    }
+}
 
-    // Clear screen with white background
-    let bg = Rectangle::new(Point::new(0, 0), Size::new(DISPLAY_WIDTH, DISPLAY_HEIGHT))
-        .into_styled(
-            PrimitiveStyleBuilder::new()
-                .fill_color(Rgb565::WHITE)
-                .build(),
+fn fedimint_builder(config: &Config) -> anyhow::Result<FedimintBuilder> {
+    let mut fedimint_builder = config.fedimint_builder()?;
+    if let Some(seed_key) = SeedKey::from_env()? {
+        fedimint_builder = fedimint_builder.seed_key(seed_key);
+    }
+    Ok(fedimint_builder)
+}
+
+fn hardware_builder(config: &Config) -> anyhow::Result<HardwareBuilder> {
+    let mut builder = HardwareBuilder::from_env()?
+        .display_pins(config.display)
+        .backlight(config.backlight.settings());
+    if let Some(mechanism) = config.mechanism() {
+        builder = builder.dispense_mechanism(mechanism);
+    }
+    if let Some(channels) = config.channels() {
+        builder = builder.dispense_channels(channels);
+    }
+    if let Some(pin) = config.tier_button_pin {
+        builder = builder.tier_button(PinRef::Native(pin));
+    }
+    if let Some(buttons) = &config.buttons {
+        builder = builder
+            .button_pins(
+                PinRef::Native(buttons.next_pin),
+                PinRef::Native(buttons.select_pin),
+            )
+            .button_timing(buttons.timing());
+        if let Some(pin) = buttons.refresh_pin {
+            builder = builder.refresh_button(PinRef::Native(pin));
+        }
+    }
+    if let Some(sensor) = JamSensorConfig::from_env_or(config.jam_sensor)? {
+        builder = builder.jam_sensor(sensor);
+    }
+    if let Some(sensor) = HopperSensorConfig::from_env_or(config.hopper_sensor)? {
+        builder = builder.hopper_sensor(sensor);
+    }
+    if let Some(led_strip) = LedStripConfig::from_env_or(config.led_strip)? {
+        builder = builder.led_strip(led_strip.length, led_strip.choreography());
+    }
+    if let Some(sensor) = LightSensor::from_config(config.light_sensor)? {
+        builder = builder.light_sensor(sensor);
+    }
+    if let Some(display) = StatusDisplay::from_config(config.status_display)? {
+        builder = builder.status_display(display);
+    }
+    if let Some(reader) = Pn532::from_config(config.nfc_reader)? {
+        builder = builder.nfc_reader(reader);
+    }
+    if let Some(coins) = &config.coins {
+        builder = builder.coin_acceptor(PinRef::Native(coins.pin), coins.timing());
+    }
+    if let Some(encoder) = config.encoder {
+        builder = builder.rotary_encoder(
+            PinRef::Native(encoder.a_pin),
+            PinRef::Native(encoder.b_pin),
+            PinRef::Native(encoder.push_pin),
         );
-    let _ = bg.draw(display);
+    }
+    Ok(builder)
+}
+
+/// Opens the configured wallet, reporting startup steps to `progress` for the connecting screen
+async fn connect_wallet(
+    config: Config,
+    progress: watch::Sender<&'static str>,
+    mut network: watch::Receiver<NetworkStatus>,
+) -> anyhow::Result<Wallet> {
+    if *network.borrow() != NetworkStatus::Online {
+        progress.send_replace("Waiting for network");
+        // Only fails if the WiFi setup is gone, connecting will tell what's unreachable then
+        let _ = network
+            .wait_for(|status| *status == NetworkStatus::Online)
+            .await;
+    }
 
-    // Draw status bar
-    draw_status_bar(display, status_bar);
+    match (
+        std::env::var(watch_only::NWC_URI_ENV),
+        &config.cashu,
+        &config.lnd,
+    ) {
+        (Ok(uri), _, _) => {
+            info!("Running in watch-only mode");
+            progress.send_replace("Reaching NWC wallet");
+            let nwc = watch_only::NwcReceiver::connect(&uri)
+                .await
+                .context("Could not connect to NWC wallet")?;
+            Ok(Wallet::WatchOnly(nwc))
+        }
+        (Err(_), Some(cashu), _) => {
+            progress.send_replace("Reaching Cashu mint");
+            let cashu = CashuWallet::open(cashu, SeedKey::from_env()?.as_ref())
+                .await
+                .context("Could not connect to Cashu mint")?;
+            Ok(Wallet::Cashu(cashu))
+        }
+        (Err(_), None, Some(lnd)) => {
+            progress.send_replace("Reaching LND node");
+            let lnd = LndNode::connect(lnd)
+                .await
+                .context("Could not connect to LND node")?;
+            Ok(Wallet::Lnd(lnd))
+        }
+        (Err(_), None, None) => {
+            let mut fedimint = fedimint_builder(&config)?
+                .progress(progress.clone())
+                .build()
+                .await
+                .context("Could not connect to Fedimint")?;
 
-    // Generate QR code image
-    let (qr_data, actual_qr_size) = generate_qr_image(&invoice_data.to_uppercase(), layout.qr_size)?;
+            progress.send_replace("Selecting gateway");
+            if let Err(e) = fedimint.warm_up_gateway().await {
+                warn!("Failed to select LN gateway: {:#}", e);
+            }
+            fedimint.spawn_gateway_refresh();
+            Ok(Wallet::Fedimint(fedimint))
+        }
+    }
+}
 
-    let qr_x_offset = dbg!((DISPLAY_WIDTH - dbg!(actual_qr_size)) / 2);
-    let qr_raw_image = ImageRaw::<Rgb565>::new(&qr_data, actual_qr_size);
-    let qr_image_display = Image::new(
-        &qr_raw_image,
-        Point::new(qr_x_offset as i32, layout.qr_y_offset as i32),
-    );
-    let _ = qr_image_display.draw(display);
+/// Opens the wallet the dispenser runs on, for one-off commands
+async fn open_wallet(config: &Config) -> anyhow::Result<Wallet> {
+    let (progress, _) = watch::channel("");
+    let (_, network) = watch::channel(NetworkStatus::Online);
+    connect_wallet(config.clone(), progress, network).await
+}
 
-    // Text styles
-    let text_style = MonoTextStyle::new(&FONT_6X10, Rgb565::BLACK);
+/// `candypi balance`: prints the balance of the wallet
+async fn balance_command(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let ln = open_wallet(config).await?;
+    let balance_msat = ln.balance_msat().await;
+    ln.shutdown().await;
+    println!("{} sats", balance_msat? / 1000);
+    Ok(())
+}
 
-    // Display amount below QR code
-    let amount_text = Text::new(
-        amount,
-        Point::new(
-            ((DISPLAY_WIDTH - (amount.len() as u32 * 6)) / 2) as i32, // Center text
-            layout.amount_y as i32,
-        ),
-        text_style,
+/// `candypi withdraw`: pays an invoice, or a Lightning address the given amount, from the wallet
+async fn withdraw_command(
+    config: &Config,
+    destination: &str,
+    amount_sats: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let invoice = if destination.contains('@') {
+        let amount_sats = amount_sats.ok_or("--amount-sats is required for Lightning addresses")?;
+        lnurl::invoice_from_lightning_address(destination, amount_sats * 1000).await?
+    } else {
+        if amount_sats.is_some() {
+            return Err("--amount-sats only applies to Lightning addresses".into());
+        }
+        destination.parse::<Bolt11Invoice>()?
+    };
+
+    let audit_log = AuditLog::open(&AuditLog::default_path())?;
+    println!(
+        "Withdrawing {} sats...",
+        invoice.amount_milli_satoshis().unwrap_or_default() / 1000
     );
-    let _ = amount_text.draw(display);
+    let ln = open_wallet(config).await?;
+    let result = ln.pay_invoice(&invoice).await;
+    if result.is_ok() {
+        audit_log.record(&format!(
+            "withdraw {}",
+            invoice.amount_milli_satoshis().unwrap_or_default() / 1000
+        ));
+        audit_log.flush();
+    }
+    let balance_msat = ln.balance_msat().await;
+    ln.shutdown().await;
+    result?;
 
-    println!("Invoice screen displayed!");
+    println!(
+        "Withdrawal sent, remaining balance: {} sats",
+        balance_msat? / 1000
+    );
     Ok(())
 }
 
-fn display_payment_success_screen(
+/// `candypi refund`: pays back what was received for an invoice to the customer's invoice or
+/// Lightning address, or as ecash notes printed for the operator to pass on
+async fn refund_command(
+    config: &Config,
+    payment_hash: &str,
+    destination: Option<&str>,
+    force: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payment_hash: sha256::Hash = payment_hash.parse()?;
+    let sales = sales::read(&SalesLedger::default_path())?;
+    let sale = sales::refundable(&sales, &payment_hash.to_string(), force)?;
+
+    let wallet = open_wallet(config).await?;
+    let refund = async {
+        let ln = wallet
+            .fedimint()
+            .context("Refunds need a Fedimint wallet")?;
+        let to = match destination {
+            Some(address) if address.contains('@') => {
+                let received_msat = ln.received_msat(&payment_hash).await?;
+                RefundTo::Invoice(
+                    lnurl::invoice_from_lightning_address(address, received_msat).await?,
+                )
+            }
+            Some(invoice) => RefundTo::Invoice(invoice.parse()?),
+            None => RefundTo::Notes {
+                claim_time: REFUND_CLAIM_TIME,
+            },
+        };
+        let invoice_msat = match &to {
+            RefundTo::Invoice(invoice) => invoice.amount_milli_satoshis().unwrap_or_default(),
+            RefundTo::Notes { .. } => 0,
+        };
+        let notes = ln.refund(&payment_hash, to).await?;
+        let refunded_msat = notes
+            .as_ref()
+            .map_or(invoice_msat, |notes| notes.total_amount().msats);
+        anyhow::Ok((refunded_msat, notes))
+    }
+    .await;
+    wallet.shutdown().await;
+    let (refunded_msat, notes) = refund?;
+
+    let audit_log = AuditLog::open(&AuditLog::default_path())?;
+    let sales_ledger = SalesLedger::open(&SalesLedger::default_path())?;
+    record_refund(sale, refunded_msat, &sales_ledger, &audit_log);
+
+    match notes {
+        Some(notes) => println!("{}", notes),
+        None => println!("Refunded {} sats", refunded_msat / 1000),
+    }
+    Ok(())
+}
+
+/// Marks `sale` as refunded in the sales ledger and the audit log
+fn record_refund(
+    sale: &Sale,
+    refunded_msat: u64,
+    sales_ledger: &SalesLedger,
+    audit_log: &AuditLog,
+) {
+    audit_log.record_now(&format!("refunded {}", refunded_msat / 1000));
+    sales_ledger.record(&Sale {
+        refunded_msat: Some(refunded_msat),
+        ..sale.clone()
+    });
+}
+
+/// Refunds the last sale that charged a customer without dispensing as ecash notes, chosen from
+/// the operator menu
+async fn refund_last_sale(
+    ln: &Wallet,
+    sales_ledger: &SalesLedger,
+    audit_log: &AuditLog,
+) -> anyhow::Result<OOBNotes> {
+    let fedimint = ln.fedimint().context("Refunds need a Fedimint wallet")?;
+    let sales = sales::read(&SalesLedger::default_path())?;
+    let sale = sales::last_refundable(&sales).context("Nothing to refund")?;
+    let payment_hash: sha256::Hash = sale
+        .payment_hash
+        .as_deref()
+        .context("Sale has no payment hash")?
+        .parse()?;
+    let notes = watchdog::within(
+        "refund",
+        WALLET_CALL_TIMEOUT,
+        fedimint.refund(
+            &payment_hash,
+            RefundTo::Notes {
+                claim_time: REFUND_CLAIM_TIME,
+            },
+        ),
+    )
+    .await?
+    .context("Ecash refund returned no notes")?;
+    record_refund(sale, notes.total_amount().msats, sales_ledger, audit_log);
+    Ok(notes)
+}
+
+/// Shows refund notes as a QR code until the customer had time to scan them or a button is
+/// pressed. Without notes the current screen, e.g. the jam apology, stays up as long.
+async fn show_refund(
     display: &mut Display,
     status_bar: &StatusBar,
+    theme: &watch::Receiver<Arc<Theme>>,
+    notes: Option<&str>,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    long_presses: &mut mpsc::UnboundedReceiver<Button>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    println!("Displaying payment success/dispensing screen");
-
-    // Clear screen with green background to indicate success
-    let bg = Rectangle::new(Point::new(0, 0), Size::new(DISPLAY_WIDTH, DISPLAY_HEIGHT))
-        .into_styled(
-            PrimitiveStyleBuilder::new()
-                .fill_color(Rgb565::new(0, 31, 0)) // Green background
-                .build(),
-        );
-    let _ = bg.draw(display);
-
-    // Draw status bar
-    draw_status_bar(display, status_bar);
-
-    let text_style = MonoTextStyle::new(&FONT_6X10, Rgb565::WHITE);
-
-    // "Payment Received" message
-    let payment_text = "Payment Received!";
-    let payment_x = ((DISPLAY_WIDTH - (payment_text.len() as u32 * 6)) / 2) as i32;
-    let payment_y = STATUS_BAR_HEIGHT as i32 + 30;
-    let payment_display = Text::new(payment_text, Point::new(payment_x, payment_y), text_style);
-    let _ = payment_display.draw(display);
-
-    // "Dispensing..." message
-    let dispensing_text = "Dispensing...";
-    let dispensing_x = ((DISPLAY_WIDTH - (dispensing_text.len() as u32 * 6)) / 2) as i32;
-    let dispensing_y = payment_y + 20;
-    let dispensing_display = Text::new(
-        dispensing_text,
-        Point::new(dispensing_x, dispensing_y),
-        text_style,
+    let refund_screen = notes.map(|notes| Screen::Refund { notes });
+    let mut qr_animation = None;
+    if let Some(screen) = &refund_screen {
+        screen.draw(display, status_bar, &theme.borrow())?;
+        qr_animation = screen.qr(display)?.and_then(|qr| qr.animation);
+    }
+    let refund_until = Instant::now() + REFUND_SCREEN_DURATION;
+    let mut qr_frames = tokio::time::interval(QR_FRAME_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = sleep_until(Some(refund_until)) => break,
+            Some(_) = next_press(buttons, long_presses) => break,
+            _ = qr_frames.tick(), if qr_animation.is_some() => {
+                let (Some(animation), Some(screen)) = (&mut qr_animation, &refund_screen) else {
+                    continue;
+                };
+                if let Err(e) = animation.draw_next(display) {
+                    warn!("{}, re-initializing display", e);
+                    screen.recover(display, status_bar, &theme.borrow())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `candypi test-motor`: runs the configured dispense mechanism once, or each product's motor
+/// in turn
+async fn test_motor_command(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let pins = Pins::new(Gpio::new()?);
+    let mechanisms = config
+        .channels()
+        .unwrap_or_else(|| vec![config.mechanism().unwrap_or(hardware::DISPENSE_MECHANISM)]);
+    let (_estop, estop_rx) = watch::channel(false);
+    for mechanism in mechanisms {
+        let mut dispenser = Dispenser::new(&pins, mechanism, estop_rx.clone())?;
+        if !dispenser.dispense().await {
+            return Err("Dispense did not complete".into());
+        }
+    }
+    Ok(())
+}
+
+/// `candypi test-display`: shows a message for a few seconds to check the display wiring
+async fn test_display_command(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let gpio = Gpio::new()?;
+    let (mut display, _backlight) = hardware_builder(config)?.build_display(&gpio)?;
+    let status_bar = StatusBar::new(get_local_ip());
+    Screen::Message("Display OK").draw(&mut display, &status_bar, &Theme::default())?;
+    tokio::time::sleep(TEST_DISPLAY_DURATION).await;
+    clear_display(&mut display)?;
+    Ok(())
+}
+
+/// `candypi wipe`: sweeps all funds and deletes the wallet once the operator confirmed it
+async fn wipe_command(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    println!(
+        "This sweeps all funds to {} and irrevocably deletes the wallet.",
+        std::env::var(wipe::SWEEP_ADDRESS_ENV).unwrap_or_else(|_| "<unset>".to_string())
     );
-    let _ = dispensing_display.draw(display);
-
-    // Simple progress indicator using dots
-    let progress_text = ". . . . .";
-    let progress_x = ((DISPLAY_WIDTH - (progress_text.len() as u32 * 6)) / 2) as i32;
-    let progress_y = dispensing_y + 25;
-    let progress_display = Text::new(
-        progress_text,
-        Point::new(progress_x, progress_y),
-        text_style,
+    println!("Type WIPE to confirm:");
+
+    let mut confirmation = String::new();
+    io::stdin().lock().read_line(&mut confirmation)?;
+    if confirmation.trim() != "WIPE" {
+        println!("Aborted");
+        return Ok(());
+    }
+
+    let ln = open_wallet(config).await?;
+    let datadir = wallet_datadir(config, &ln);
+    let audit_log = AuditLog::open(&AuditLog::default_path())?;
+    let swept = wipe::begin_factory_reset(&ln, &audit_log).await;
+    // The database can only be deleted once the client let go of it
+    ln.shutdown().await;
+    swept?;
+    wipe::finish_factory_reset(&datadir, None, &audit_log)?;
+
+    Ok(())
+}
+
+/// Where the wallet keeps its database and seed
+fn wallet_datadir(config: &Config, ln: &Wallet) -> PathBuf {
+    match (ln, &config.cashu) {
+        (Wallet::Cashu(_), Some(cashu)) => cashu.datadir(),
+        _ => config.datadir(),
+    }
+}
+
+/// `candypi verify-audit`: checks the audit log's hash chain and prints the head hash
+fn verify_audit_command() -> Result<(), Box<dyn std::error::Error>> {
+    let head = audit::verify(&AuditLog::default_path())?;
+    println!("Audit log intact, head hash: {}", head);
+    Ok(())
+}
+
+/// `candypi apply-config`: installs a signed config file, used from the next start on
+fn apply_config_command(
+    config_path: &Path,
+    file: &Path,
+    signature: &Path,
+    serial: u64,
+    device: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let operator_key = signed_config::operator_key_from_env()?.ok_or_else(|| {
+        format!(
+            "{} must be set to verify the config",
+            signed_config::OPERATOR_PUBKEY_ENV
+        )
+    })?;
+    let mut signed = format!("{}\n{}\n", serial, device).into_bytes();
+    signed.extend(std::fs::read(file)?);
+    signed_config::install(
+        &ConfigVerifier::new(operator_key),
+        &signed,
+        &std::fs::read(signature)?,
+        config_path,
+        signed_config::device_id_from_env().as_deref(),
+    )?;
+    println!(
+        "Installed {}, restart candypi to apply it",
+        config_path.display()
     );
-    let _ = progress_display.draw(display);
+    Ok(())
+}
 
-    println!("Payment success screen displayed!");
+/// `candypi refill`: sets the candy count, a running dispenser picks it up within seconds
+fn refill_command(count: u32) -> Result<(), Box<dyn std::error::Error>> {
+    inventory::save(
+        &inventory::default_path(),
+        CandyCount {
+            left: count,
+            capacity: count,
+        },
+    )?;
+    println!("Candy count set to {}", count);
     Ok(())
 }
 
-fn generate_invoice_string() -> String {
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+/// `candypi sales`: exports every recorded sale to stdout
+fn sales_command(format: SalesFormat) -> Result<(), Box<dyn std::error::Error>> {
+    let sales = sales::read(&SalesLedger::default_path())?;
+    match format {
+        SalesFormat::Csv => sales::write_csv(&sales, io::stdout().lock())?,
+        SalesFormat::Json => println!("{}", serde_json::to_string_pretty(&sales)?),
+    }
+    Ok(())
+}
 
-    // Generate a unique invoice string for each dispense
-    // In production, this would be a real Lightning invoice
-    format!(
-        "lightning:lnbc100u1pvjluezpp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdpl2p{}",
-        timestamp
-    )
+/// `candypi load-cell-raw`: prints raw load cell readings for calibrating tare and scale
+fn load_cell_raw_command() -> Result<(), Box<dyn std::error::Error>> {
+    let mut hx711 = Hx711::new(
+        &Gpio::new()?,
+        hardware::LOAD_CELL_DOUT_PIN,
+        hardware::LOAD_CELL_SCK_PIN,
+    )?;
+    loop {
+        match hx711.read_median() {
+            Some(raw) => println!("{}", raw),
+            None => println!("No reading, is the HX711 connected?"),
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
 }
 
-async fn dispense_candy(motor_pin: &mut OutputPin) {
-    println!("Dispensing candy for {} ms...", MOTOR_DISPENSE_DURATION_MS);
-    motor_pin.set_high();
-    tokio::time::sleep(Duration::from_millis(MOTOR_DISPENSE_DURATION_MS)).await;
-    motor_pin.set_low();
-    println!("Candy dispensed!");
+/// `candypi render-screens`: draws each screen with sample data into a simulated display
+#[cfg(feature = "simulate")]
+fn render_screens_command(dir: &std::path::Path) -> Result<(), Box<dyn std::error::Error>> {
+    use candypi::screen::{DisplayOrientation, Panel};
+    use candypi::simulator::{self, SimulatedDisplay};
+
+    let theme = Theme::load(&Theme::dir_from_env(), &i18n::locale_dir()).unwrap_or_else(|e| {
+        warn!("Failed to load theme, using defaults: {:#}", e);
+        Theme::default()
+    });
+    let status_bar = simulator::sample_status_bar();
+
+    let simulated = SimulatedDisplay::new();
+    let mut display = Display::new(
+        Panel::Simulated(simulated.clone()),
+        DisplayOrientation::default(),
+    );
+    std::fs::create_dir_all(dir)?;
+    for screen in simulator::sample_screens() {
+        screen.draw(&mut display, &status_bar, &theme)?;
+        let path = dir.join(format!("{}.png", screen.name()));
+        simulated.save(&path)?;
+        println!("{}", path.display());
+    }
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Initializing Candy Dispenser...");
+    logging::init();
+    // SAFETY: nothing has been spawned yet that could access the environment concurrently
+    unsafe { memory::apply_low_memory_env() };
 
-    let ln = Fedimint::new().await.expect("Could not connect to Fedimint");
+    let cli = Cli::parse();
+    let config = cli.config()?;
+    let config_path = cli.config_path();
 
-    let gpio = Gpio::new()?;
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => {}
+        Command::Balance => return balance_command(&config).await,
+        Command::Withdraw {
+            destination,
+            amount_sats,
+        } => return withdraw_command(&config, &destination, amount_sats).await,
+        Command::TestMotor => return test_motor_command(&config).await,
+        Command::TestDisplay => return test_display_command(&config).await,
+        Command::Refund {
+            payment_hash,
+            destination,
+            force,
+        } => {
+            return refund_command(&config, &payment_hash, destination.as_deref(), force).await;
+        }
+        Command::Wipe => return wipe_command(&config).await,
+        Command::VerifyAudit => return verify_audit_command(),
+        Command::ApplyConfig {
+            file,
+            signature,
+            serial,
+            device,
+        } => {
+            return apply_config_command(&config_path, &file, &signature, serial, &device);
+        }
+        Command::LoadCellRaw => return load_cell_raw_command(),
+        Command::Refill { count } => return refill_command(count),
+        Command::Sales { format } => return sales_command(format),
+        #[cfg(feature = "simulate")]
+        Command::RenderScreens { dir } => return render_screens_command(&dir),
+    }
 
-    // Initialize SPI and display
-    let spi = Spi::new(Bus::Spi0, SlaveSelect::Ss0, 16_000_000, Mode::Mode0)?;
-    let spi_device = SimpleHalSpiDevice::new(spi);
-
-    let dc_pin = gpio.get(LCD_DC_PIN)?.into_output();
-    let rst_pin = gpio.get(LCD_RST_PIN)?.into_output();
-    let mut led_pin = gpio.get(LCD_LED_PIN)?.into_output();
-    led_pin.set_high();
-
-    let mut display = ST7735::new(
-        spi_device,
-        dc_pin,
-        rst_pin,
-        false,
-        false,
-        DISPLAY_WIDTH,
-        DISPLAY_HEIGHT,
-    );
+    info!("Initializing Candy Dispenser...");
+    let mut stop_signal = spawn_signal_handler()?;
+    let mut hardware_watchdog = HardwareWatchdog::from_env().unwrap_or_else(|e| {
+        warn!("Failed to open hardware watchdog: {}", e);
+        None
+    });
+
+    prometheus::init_from_env().await?;
+    memory::spawn_reporting();
 
-    let mut delay = Delay::new();
-    display
-        .init(&mut delay)
-        .map_err(|_| "Failed to initialize display")?;
-    display
-        .set_orientation(&Orientation::PortraitSwapped)
-        .map_err(|_| "Failed to set orientation")?;
+    // Restore the clock first, TLS and invoice expiry checks depend on it
+    if std::env::var(rtc::RTC_ENV).is_ok_and(|value| value == "1") {
+        match rtc::Ds3231::new() {
+            Ok(mut rtc) => {
+                if let Err(e) = rtc.restore_system_time() {
+                    warn!("Failed to restore system time from RTC: {}", e);
+                }
+                rtc.spawn_sync();
+            }
+            Err(e) => warn!("Failed to open DS3231 RTC: {}", e),
+        }
+    }
 
-    // Initialize motor
-    let mut motor_pin = gpio.get(MOTOR_PIN)?.into_output();
-    motor_pin.set_low();
+    // Opening the wallet mostly waits for the SD card and the network, so it runs alongside the
+    // display and GPIO setup below and is joined once the connecting screen is up
+    let (progress_tx, mut progress) = watch::channel("Starting");
+    let (wifi_setup, mut network) = WifiSetup::spawn(config.wifi_setup.clone());
+    let mut connecting = tokio::spawn(connect_wallet(config.clone(), progress_tx, network.clone()));
+
+    // Initialize operator access, the menu is only reachable if a PIN was configured
+    let operator_pin = OperatorPin::from_env()?;
+    if operator_pin.is_none() {
+        info!(
+            "{} not set, operator menu disabled",
+            operator::OPERATOR_PIN_ENV
+        );
+    }
+
+    let audit_log = AuditLog::open(&AuditLog::default_path())?;
+    info!("Audit log head hash: {}", audit_log.head_hash());
+    audit_log.spawn_flusher();
+    let sales_ledger = SalesLedger::open(&SalesLedger::default_path())?;
+    let door_pin_ack = door::pin_ack_required();
+    if door_pin_ack && operator_pin.is_none() {
+        info!(
+            "{} set without {}, door openings will only be logged",
+            door::DOOR_PIN_ACK_ENV,
+            operator::OPERATOR_PIN_ENV
+        );
+    }
+
+    let bus = EventBus::default();
+    prometheus::spawn_event_metrics(bus.subscribe());
+    Notifier::from_env().spawn_event_alerts(bus.subscribe());
+
+    #[cfg(not(feature = "simulate"))]
+    let hardware =
+        hardware_builder(&config)?.build(Notifier::from_env(), audit_log.clone(), &bus)?;
+    #[cfg(feature = "simulate")]
+    let hardware = hardware_builder(&config)?.build_simulated();
+    let Hardware {
+        mut display,
+        backlight,
+        status_display,
+        dispenser,
+        mut jams,
+        estop,
+        mut buttons,
+        mut long_presses,
+        mut tier_button,
+        mut refresh_button,
+        mut tamper_alarms,
+        mut door_events,
+        mut ups_events,
+        mut nfc_taps,
+        mut coins,
+        stock,
+        mut hopper_low,
+    } = hardware;
+    // The motor, the payment watch and the sensors run in their own tasks, this loop only
+    // coordinates them and owns the display
+    let mut dispenser = DispenserHandle::spawn(dispenser);
+    let mut estop_events = estop.subscribe();
+    let mut theme = Theme::spawn_watcher(Theme::dir_from_env(), i18n::locale_dir());
 
     // Initialize status bar
     let ip = get_local_ip();
+    bus.publish(Event::NetworkChanged { ip: ip.clone() });
     let mut status_bar = StatusBar::new(ip);
     status_bar.set_connection_status(ConnectionStatus::Disconnected);
+    status_bar.set_low_stock(*hopper_low.borrow());
 
-    loop {
-        let invoice = ln
-            .lightning_invoice(42_000, "M&Ms")
-            .await
-            .expect("Failed to create invoice");
-        display_invoice_screen(&mut display, &invoice.to_string(), "42 sats", &status_bar)?;
+    // Joining a federation can take minutes, show what's going on instead of a blank panel
+    let ln = loop {
+        let step = *progress.borrow_and_update();
+        // Without a network at boot the operator picks one from their phone
+        let network_status = network.borrow_and_update().clone();
+        let screen = match &network_status {
+            NetworkStatus::Hotspot { qr_data, url } => Screen::WifiSetup { qr_data, url },
+            _ => Screen::Connecting { step },
+        };
+        screen.draw(&mut display, &status_bar, &theme.borrow_and_update())?;
+        tokio::select! {
+            result = &mut connecting => break Arc::new(result??),
+            _ = &mut stop_signal => {
+                // Joining starts over on the next boot, nothing was sold yet
+                connecting.abort();
+                wifi_setup.shutdown();
+                dispenser.set_idle();
+                audit_log.flush();
+                if let Some(backlight) = &backlight {
+                    backlight.off();
+                }
+                clear_display_on_exit(&mut display).await;
+                if let Some(hardware_watchdog) = hardware_watchdog {
+                    hardware_watchdog.disarm().await;
+                }
+                return Ok(());
+            }
+            Ok(()) = progress.changed() => {}
+            Ok(()) = theme.changed() => {}
+            Ok(()) = network.changed() => {}
+        }
+    };
+
+    // Holding on to the sender keeps the channel open once stdin is closed
+    let (control_tx, mut control_requests) = mpsc::channel(8);
+    control::spawn_stdin(control_tx.clone());
+    if let Some(server) = ControlServer::from_env()? {
+        server.spawn(control_tx.clone());
+    }
+    let mut mdns = None;
+    if let Some(api) = &config.api {
+        ApiServer::bind(api).await?.spawn(control_tx.clone());
+        // Nobody else on the LAN could reach it anyway
+        if !api.listen.ip().is_loopback() {
+            match MdnsAdvertiser::advertise(api.mdns_hostname(), api.listen.port()) {
+                Ok(advertiser) => mdns = Some(advertiser),
+                Err(e) => warn!("Failed to advertise API via mDNS: {:#}", e),
+            }
+        }
+    }
+    if let Some(telemetry) = MqttTelemetry::from_config(config.mqtt.as_ref())? {
+        telemetry.spawn(bus.subscribe(), control_tx.clone(), config_path.clone());
+    }
+
+    let mut connection = ConnectionMonitor::new(&ln).spawn();
+    status_bar.set_connection_status(*connection.borrow());
 
-        ln.await_payment(&invoice).await.expect("Failed to await payment");
+    let inventory = Inventory::open(inventory::default_path());
+    let mut candy = inventory.subscribe();
 
-        display_payment_success_screen(&mut display, &status_bar)?;
-        dispense_candy(&mut motor_pin).await;
-        tokio::time::sleep(Duration::from_secs(3)).await;
+    // Follows the status bar whenever it is drawn
+    let (status_updates, status) = watch::channel(status_bar.clone());
+    if let Some(status_display) = status_display {
+        status_display.spawn(status, inventory.subscribe());
+    }
+
+    let mut products = config.products();
+    let mut tier = 0;
+    let mut vending = VendingStateMachine::default();
+    let mut rates = match &config.fiat {
+        Some(fiat) => RateProvider::new(fiat).spawn(),
+        None => watch::channel(None).1,
+    };
+    // Payments the previous run took but never dispensed, e.g. because the Pi lost power
+    if let Some(fedimint) = ln.fedimint() {
+        let ledger_path = SalesLedger::default_path();
+        match recovery::find_unfinished_sales(fedimint, &ledger_path).await {
+            Ok(unfinished) => {
+                for sale in unfinished {
+                    recover_sale(
+                        &sale,
+                        &products,
+                        &mut dispenser,
+                        &mut display,
+                        &status_bar,
+                        &theme.borrow(),
+                        &bus,
+                        &audit_log,
+                        &sales_ledger,
+                        &inventory,
+                    )
+                    .await?;
+                }
+            }
+            Err(e) => warn!("Failed to look for unfinished sales: {:#}", e),
+        }
+    }
+
+    // Set while status bar changes wait to be drawn
+    let mut status_bar_redraw = None;
+    let mut payment_watch = None;
+    // Replaced invoices that are still payable
+    let mut superseded = Vec::new();
+    // Value of the coins inserted towards the next sale, in the fiat currency
+    let mut coin_credit = 0.0;
+    let (mut deposits, mut deposit_events) = match &config.onchain {
+        Some(onchain) => match DepositWatcher::new(&ln, onchain.dispense_after) {
+            Ok((watcher, events)) => (Some(watcher), Some(events)),
+            Err(e) => {
+                warn!("On-chain payments disabled: {:#}", e);
+                (None, None)
+            }
+        },
+        None => (None, None),
+    };
+    // Offered with every invoice until something is paid to it
+    let mut onchain_address: Option<String> = None;
+    // Set while a tamper alarm or message is shown on top of the idle screen
+    let mut screen_timeout = None;
+    // Set while the attract loop is shown, the timeout moves it on to the next screen
+    let mut attract_slide = None;
+    let mut tamper_alarm_shown = false;
+    let mut balance_refresh = tokio::time::interval(BALANCE_REFRESH_INTERVAL);
+    let mut ip_refresh = tokio::time::interval(IP_REFRESH_INTERVAL);
+
+    'vend: loop {
+        let product = products[tier].clone();
+        let rate = config.fiat.as_ref().and_then(|fiat| {
+            let rate = (*rates.borrow_and_update())?;
+            rate.is_fresh(fiat.max_rate_age()).then_some(rate)
+        });
+        let price_sats = product.current_price_sats(rate);
+        if product.price.is_some() && rate.is_none() {
+            warn!(
+                "No recent exchange rate, charging the fallback price of {} sats",
+                price_sats
+            );
+        }
+        let price_msat = price_sats * 1000;
+        // Charged by Lightning, the gateway's receive fee on top if it is passed on to customers
+        let invoice_sats = match ln.fedimint().filter(|_| config.fedimint.gateway.add_fee) {
+            Some(fedimint) => match fedimint.gateway_fees() {
+                Some(fees) => gateway::gross_amount_msat(&fees, price_msat).div_ceil(1000),
+                None => price_sats,
+            },
+            None => price_sats,
+        };
+        let invoice_msat = invoice_sats * 1000;
+        let online = *connection.borrow_and_update() == ConnectionStatus::Connected;
+        let sold_out = candy
+            .borrow_and_update()
+            .is_some_and(|count| count.is_sold_out());
+        let mut invoice_refresh = None;
+        let invoice = if vending.in_maintenance() || sold_out || !online {
+            None
+        } else {
+            let started = Instant::now();
+            let created = watchdog::within(
+                "create invoice",
+                WALLET_CALL_TIMEOUT,
+                ln.lightning_invoice(invoice_msat, &product.name),
+            )
+            .instrument(info_span!("create_invoice", invoice_sats))
+            .await;
+            match created {
+                Ok(invoice) => {
+                    info!(hash = %invoice.payment_hash(), "Invoice created");
+                    prometheus::record_duration(
+                        "candypi_invoice_creation_seconds",
+                        started.elapsed(),
+                    );
+                    bus.publish(Event::InvoiceCreated {
+                        amount_msat: invoice_msat,
+                    });
+                    vending.step(VendingEvent::InvoiceShown);
+                    invoice_refresh = Some(invoice_refresh_deadline(&invoice));
+                    Some(invoice)
+                }
+                Err(e) => {
+                    // Shows the offline screen until the next attempt
+                    warn!("Failed to create invoice: {:#}", e);
+                    invoice_refresh = Some(Instant::now() + INVOICE_RETRY_DELAY);
+                    None
+                }
+            }
+        };
+        let invoice_text = invoice.as_ref().map(|invoice| invoice.to_string());
+        if let Some(watcher) = deposits
+            .as_mut()
+            .filter(|_| invoice.is_some() && onchain_address.is_none())
+        {
+            let allocated = watchdog::within(
+                "allocate deposit address",
+                WALLET_CALL_TIMEOUT,
+                watcher.allocate(),
+            );
+            match allocated.await {
+                Ok(address) => onchain_address = Some(address),
+                // The invoice alone still works
+                Err(e) => warn!("Failed to allocate on-chain address: {:#}", e),
+            }
+        }
+        payment_watch = invoice.clone().map(|invoice| watch_payment(&ln, invoice));
+        // Set if the dispense is paid some other way than the invoice
+        let mut paid_by = None;
+        // Set if the dispense is paid by an invoice replaced earlier
+        let mut late_paid = None;
+        // Fiat price taken from the coin credit, given back if the dispense jams
+        let mut paid_coins = None;
+        let mut amount = if products.len() > 1 {
+            format!("{} {} sats", product.name, invoice_sats)
+        } else {
+            format!("{} sats", invoice_sats)
+        };
+        if let (Some(fiat), Some(rate)) = (&config.fiat, rate) {
+            match &product.price {
+                // A passed on fee makes the fiat price approximate
+                Some(price) if invoice_sats == price_sats => {
+                    amount.push_str(&format!(" ({:.2} {})", price.amount, fiat.currency))
+                }
+                _ => amount.push_str(&format!(
+                    " (~{:.2} {})",
+                    rate.sats_to_fiat(invoice_sats),
+                    fiat.currency
+                )),
+            }
+        }
+        // Owned, the address may be retired while the screen is still shown
+        let offered_address = onchain_address.clone();
+        // Set once a payment to the shown address was seen, a fresh one is shown afterwards
+        let mut address_used = false;
+        let idle_screen = match &invoice_text {
+            Some(invoice) => Screen::Invoice {
+                invoice,
+                amount: &amount,
+                onchain: offered_address.as_deref().map(|address| OnchainFallback {
+                    address,
+                    amount_sats: price_sats,
+                }),
+            },
+            None if vending.in_maintenance() => Screen::Maintenance,
+            None if sold_out => Screen::SoldOut,
+            None => Screen::Offline,
+        };
+        idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+        status_updates.send_replace(status_bar.clone());
+        status_bar_redraw = None;
+        // The new invoice ends the attract loop
+        if attract_slide.take().is_some() {
+            screen_timeout = None;
+        }
+        let invoice_shown = Instant::now();
+        let mut countdown = tokio::time::interval(INVOICE_COUNTDOWN_INTERVAL);
+        countdown.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        let mut attract = next_attract(&theme.borrow());
+        // Worked out once here, the countdown reuses the size on every tick
+        let (qr_size, mut qr_animation) = match idle_screen.qr(&display)? {
+            Some(qr) => (Some(qr.size), qr.animation),
+            None => (None, None),
+        };
+        let mut qr_frames = tokio::time::interval(QR_FRAME_INTERVAL);
+
+        let paid = loop {
+            tokio::select! {
+                result = payment_received(&mut payment_watch) => {
+                    // Finished, nothing left to keep watching
+                    payment_watch = None;
+                    if let Err(e) = result {
+                        warn!("Failed to await payment, replacing the invoice: {:#}", e);
+                        break false;
+                    }
+                    bus.publish(Event::PaymentReceived {
+                        amount_msat: invoice_msat,
+                    });
+                    break true;
+                }
+                late = late_payment(&mut superseded) => {
+                    let hash = late.invoice.payment_hash().to_string();
+                    info!(%hash, "Replaced invoice paid");
+                    bus.publish(Event::PaymentReceived {
+                        amount_msat: late.amount_msat,
+                    });
+                    if vending.in_maintenance() || sold_out {
+                        report_refund_due(&hash, late.amount_msat, &audit_log);
+                        sales_ledger.record(&Sale::now(
+                            &products[late.tier].name,
+                            late.amount_msat,
+                            PaymentMethod::Lightning,
+                            Some(hash),
+                            false,
+                        ));
+                        audit_log.flush();
+                        continue;
+                    }
+                    late_paid = Some(late);
+                    break true;
+                }
+                Some(event) = deposit_event(&mut deposit_events) => match event {
+                    DepositEvent::Detected { address, sats } => {
+                        audit_log.record(&format!("deposit_detected {}", sats));
+                        if onchain_address.as_deref() == Some(address.as_str()) {
+                            // The next customer gets a fresh address
+                            onchain_address = None;
+                            address_used = true;
+                        }
+                        let amount = format!("{} sats", sats);
+                        Screen::DepositDetected { amount: &amount }.draw(
+                            &mut display,
+                            &status_bar,
+                            &theme.borrow(),
+                        )?;
+                        screen_timeout = Some(Instant::now() + DEPOSIT_DETECTED_SCREEN_DURATION);
+                        attract_slide = None;
+                    }
+                    DepositEvent::Received { address, sats } => {
+                        if let Some(watcher) = &mut deposits {
+                            watcher.finished(&address);
+                        }
+                        let shown = onchain_address.as_deref() == Some(address.as_str());
+                        if shown {
+                            onchain_address = None;
+                        }
+                        if sats >= price_sats {
+                            audit_log.record(&format!("deposit_received {}", sats));
+                            bus.publish(Event::PaymentReceived {
+                                amount_msat: sats * 1000,
+                            });
+                            paid_by = Some((PaymentMethod::Onchain, sats * 1000));
+                            break true;
+                        }
+                        warn!(
+                            "On-chain payment of {} sats is below the price of {} sats",
+                            sats, price_sats
+                        );
+                        audit_log.record(&format!("deposit_underpaid {}", sats));
+                        if shown {
+                            break false;
+                        }
+                    }
+                    DepositEvent::Failed { address, error } => {
+                        warn!("Failed to await on-chain payment to {}: {:#}", address, error);
+                        if let Some(watcher) = &mut deposits {
+                            watcher.finished(&address);
+                        }
+                        if onchain_address.as_deref() == Some(address.as_str()) {
+                            onchain_address = None;
+                            break false;
+                        }
+                    }
+                },
+                _ = balance_refresh.tick(), if ln.holds_funds() => {
+                    match ln.balance_msat().await {
+                        Ok(balance_msat) => {
+                            status_bar.set_balance(balance_msat / 1000);
+                            status_bar_redraw
+                                .get_or_insert_with(|| Instant::now() + STATUS_BAR_DEBOUNCE);
+                        }
+                        Err(e) => warn!("Failed to read balance: {:#}", e),
+                    }
+                }
+                _ = ip_refresh.tick() => {
+                    let ip = get_local_ip();
+                    if ip != status_bar.ip() {
+                        info!("Local IP changed to {}", ip);
+                        bus.publish(Event::NetworkChanged { ip: ip.clone() });
+                        status_bar.update_ip(ip);
+                        status_bar_redraw
+                            .get_or_insert_with(|| Instant::now() + STATUS_BAR_DEBOUNCE);
+                    }
+                }
+                Ok(()) = candy.changed() => {
+                    let now_sold_out = candy
+                        .borrow_and_update()
+                        .is_some_and(|count| count.is_sold_out());
+                    if now_sold_out != sold_out {
+                        break false;
+                    }
+                }
+                Ok(()) = hopper_low.changed() => {
+                    status_bar.set_low_stock(*hopper_low.borrow_and_update());
+                    status_bar_redraw.get_or_insert_with(|| Instant::now() + STATUS_BAR_DEBOUNCE);
+                }
+                Ok(()) = connection.changed() => {
+                    let status = *connection.borrow_and_update();
+                    status_bar.set_connection_status(status);
+                    // A shown invoice is kept while offline, it can still be paid once the
+                    // connection is back
+                    if status == ConnectionStatus::Connected
+                        && invoice_text.is_none()
+                        && !vending.in_maintenance()
+                    {
+                        break false;
+                    }
+                    status_bar_redraw.get_or_insert_with(|| Instant::now() + STATUS_BAR_DEBOUNCE);
+                }
+                Ok(()) = rates.changed(), if product.price.is_some() && rate.is_none() => {
+                    // The fallback price was charged while no rate was available
+                    break false;
+                }
+                _ = sleep_until(invoice_refresh) => {
+                    if invoice_text.is_some() {
+                        info!("Invoice about to expire, creating a new one");
+                    }
+                    break false;
+                }
+                Some(()) = tier_button.recv() => {
+                    if products.len() > 1 {
+                        tier = (tier + 1) % products.len();
+                        // Replace the invoice with one for the selected tier, the old one stays
+                        // payable until it expires
+                        break false;
+                    }
+                }
+                Some(()) = refresh_button.recv() => {
+                    info!("Invoice refresh requested");
+                    break false;
+                }
+                // Short presses only mean something inside the menus, or bring back the invoice
+                Some(_) = buttons.recv() => {
+                    if attract_slide.take().is_some() {
+                        screen_timeout = None;
+                        attract = next_attract(&theme.borrow());
+                        idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                    }
+                }
+                Some(_) = long_presses.recv() => {
+                    let Some(pin) = &operator_pin else {
+                        continue;
+                    };
+                    // Presses from before the long one aren't PIN digits
+                    while buttons.try_recv().is_ok() {}
+                    attract_slide = None;
+
+                    // Keep the invoice alive while the menu is open, it may already have been
+                    // scanned
+                    let outcome = operator::run_operator_menu(
+                        &mut display,
+                        &status_bar,
+                        &mut buttons,
+                        &mut nfc_taps,
+                        pin,
+                        price_sats,
+                        &ln,
+                        &mut dispenser,
+                        &stock,
+                        &inventory,
+                    )
+                    .await?;
+                    // Holding a button in the menu shouldn't reopen it
+                    while long_presses.try_recv().is_ok() {}
+
+                    if let MenuOutcome::PriceChanged(sats) = outcome {
+                        audit_log.record_now(&format!("price_set {}", sats));
+                        products[tier].price_sats = sats;
+                        products[tier].price = None;
+                        break false;
+                    }
+                    if outcome == MenuOutcome::Reboot {
+                        Screen::Message("Rebooting").draw(
+                            &mut display,
+                            &status_bar,
+                            &theme.borrow(),
+                        )?;
+                        bus.publish(Event::ShuttingDown);
+                        dispenser.set_idle();
+                        audit_log.record("operator_reboot");
+                        audit_log.flush();
+
+                        let watches = take_watches(&mut payment_watch, &mut superseded);
+                        shutdown_wallet(ln, watches, deposits.take()).await;
+                        if let Some(backlight) = &backlight {
+                            backlight.off();
+                        }
+                        if let Some(hardware_watchdog) = hardware_watchdog.take() {
+                            hardware_watchdog.disarm().await;
+                        }
+                        std::process::Command::new("systemctl")
+                            .arg("reboot")
+                            .status()?;
+                        return Ok(());
+                    }
+                    if outcome == MenuOutcome::RefundLastSale {
+                        match refund_last_sale(&ln, &sales_ledger, &audit_log).await {
+                            Ok(notes) => {
+                                show_refund(
+                                    &mut display,
+                                    &status_bar,
+                                    &theme,
+                                    Some(&notes.to_string()),
+                                    &mut buttons,
+                                    &mut long_presses,
+                                )
+                                .await?;
+                            }
+                            Err(e) => {
+                                warn!("Refund failed: {:#}", e);
+                                Screen::Message(&format!("Refund failed: {:#}", e)).draw(
+                                    &mut display,
+                                    &status_bar,
+                                    &theme.borrow(),
+                                )?;
+                                screen_timeout =
+                                    Some(Instant::now() + REFUND_FAILED_SCREEN_DURATION);
+                                continue;
+                            }
+                        }
+                    }
+                    if let MenuOutcome::JoinFederation(invite) = &outcome {
+                        // Handled like a remote request, which checks the federation first
+                        let command = ControlCommand::JoinFederation {
+                            invite: invite.clone(),
+                        };
+                        let requests = control_tx.clone();
+                        tokio::spawn(async move {
+                            let response = ControlRequest::send(&requests, command).await;
+                            if let Some(ControlResponse::Error(e)) = response {
+                                warn!("Switching federation failed: {}", e);
+                            }
+                        });
+                    }
+                    if outcome == MenuOutcome::FactoryReset {
+                        let datadir = wallet_datadir(&config, &ln);
+                        match wipe::begin_factory_reset(&ln, &audit_log).await {
+                            Ok(()) => {
+                                // Exit so the service manager restarts us into the first-run state
+                                bus.publish(Event::ShuttingDown);
+                                dispenser.set_idle();
+                                // The database can only be deleted once the client let go of it
+                                let watches = take_watches(&mut payment_watch, &mut superseded);
+                                let released = shutdown_wallet(ln, watches, deposits.take()).await;
+                                if !released {
+                                    return Err(
+                                        "Wallet still in use, run the factory reset again".into()
+                                    );
+                                }
+                                wipe::finish_factory_reset(&datadir, None, &audit_log)?;
+                                if let Some(backlight) = &backlight {
+                                    backlight.off();
+                                }
+                                clear_display_on_exit(&mut display).await;
+                                if let Some(hardware_watchdog) = hardware_watchdog.take() {
+                                    hardware_watchdog.disarm().await;
+                                }
+                                return Ok(());
+                            }
+                            Err(e) => error!("Factory reset failed: {:#}", e),
+                        }
+                    }
+                    idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                }
+                Some(event) = door_events.recv() => {
+                    let Some(pin) = operator_pin.as_ref().filter(|_| door_pin_ack) else {
+                        continue;
+                    };
+                    if event != DoorEvent::Opened {
+                        continue;
+                    }
+
+                    operator::acknowledge_door_open(
+                        &mut display,
+                        &status_bar,
+                        &mut buttons,
+                        pin,
+                        &audit_log,
+                    )
+                    .await?;
+                    idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                }
+                Some(event) = ups_events.recv() => match event {
+                    UpsEvent::Status(status) => {
+                        status_bar.set_battery(status);
+                        status_bar_redraw
+                            .get_or_insert_with(|| Instant::now() + STATUS_BAR_DEBOUNCE);
+                    }
+                    UpsEvent::ShutdownRequired => {
+                        Screen::Shutdown.draw(&mut display, &status_bar, &theme.borrow())?;
+                        bus.publish(Event::ShuttingDown);
+                        dispenser.set_idle();
+                        audit_log.record("ups_shutdown");
+                        audit_log.flush();
+
+                        // Let the client flush its database before the power goes away
+                        let watches = take_watches(&mut payment_watch, &mut superseded);
+                        shutdown_wallet(ln, watches, deposits.take()).await;
+                        if let Some(backlight) = &backlight {
+                            backlight.off();
+                        }
+                        if let Some(hardware_watchdog) = hardware_watchdog.take() {
+                            hardware_watchdog.disarm().await;
+                        }
+                        std::process::Command::new("systemctl")
+                            .arg("poweroff")
+                            .status()?;
+                        return Ok(());
+                    }
+                },
+                Some(pulses) = coins.recv() => {
+                    let Some(coin_config) = &config.coins else {
+                        continue;
+                    };
+                    let currency = config.fiat.as_ref().map_or("", |fiat| fiat.currency.as_str());
+                    let value = f64::from(pulses) * coin_config.pulse_value;
+                    coin_credit += value;
+                    audit_log.record(&format!("coin_inserted {:.2} {}", value, currency));
+                    // The price the product is pegged to, or its sats price at the current rate
+                    let fiat_price = match (&product.price, rate) {
+                        (Some(price), _) => price.amount,
+                        (None, Some(rate)) => rate.sats_to_fiat(price_sats),
+                        (None, None) => {
+                            warn!("No recent exchange rate, keeping the coins as credit");
+                            continue;
+                        }
+                    };
+                    // Pulse values like 0.1 don't add up exactly
+                    let covered = coin_credit + 0.005 >= fiat_price;
+                    if covered && !vending.in_maintenance() && !sold_out {
+                        coin_credit = (coin_credit - fiat_price).max(0.0);
+                        bus.publish(Event::PaymentReceived {
+                            amount_msat: price_msat,
+                        });
+                        paid_by = Some((PaymentMethod::Coins, price_msat));
+                        paid_coins = Some(fiat_price);
+                        break true;
+                    }
+                    let credit = format!("{:.2} / {:.2} {}", coin_credit, fiat_price, currency);
+                    Screen::CoinCredit { credit: &credit }.draw(
+                        &mut display,
+                        &status_bar,
+                        &theme.borrow(),
+                    )?;
+                    screen_timeout = Some(Instant::now() + COIN_CREDIT_SCREEN_DURATION);
+                    attract_slide = None;
+                }
+                Some(tap) = nfc_taps.recv() => {
+                    let Some(invoice) = &invoice else {
+                        info!("Ignoring NFC tap, no invoice shown");
+                        continue;
+                    };
+                    match tap {
+                        NfcTap::Ecash(notes) => {
+                            let redeemed = watchdog::within(
+                                "redeem tapped ecash",
+                                WALLET_CALL_TIMEOUT,
+                                redeem_notes(&ln, &notes, price_msat),
+                            );
+                            match redeemed.await {
+                                Ok(amount) => {
+                                    let sats = amount.msats / 1000;
+                                    audit_log.record(&format!("notes_redeemed {}", sats));
+                                    bus.publish(Event::PaymentReceived {
+                                        amount_msat: amount.msats,
+                                    });
+                                    paid_by = Some((PaymentMethod::Ecash, amount.msats));
+                                    break true;
+                                }
+                                Err(e) => warn!("Failed to redeem tapped ecash: {:#}", e),
+                            }
+                        }
+                        // Pays the shown invoice, which is then received like any other payment
+                        NfcTap::LnurlWithdraw(url) => {
+                            let withdrawn = tokio::time::timeout(
+                                WALLET_CALL_TIMEOUT,
+                                lnurl::withdraw(&url, invoice),
+                            );
+                            match withdrawn.await {
+                                Ok(Ok(())) => audit_log.record("lnurl_withdraw_requested"),
+                                Ok(Err(e)) => {
+                                    warn!("Failed to withdraw from tapped LNURL: {:#}", e)
+                                }
+                                Err(_) => warn!("LNURL-withdraw service not responding"),
+                            }
+                        }
+                        NfcTap::Invite(_) => {
+                            info!("Ignoring invite code outside the operator menu")
+                        }
+                    }
+                }
+                _ = countdown.tick(), if invoice_text.is_some() && screen_timeout.is_none() => {
+                    let (Some(deadline), Some(qr_size)) = (invoice_refresh, qr_size) else {
+                        continue;
+                    };
+                    let validity = deadline.saturating_duration_since(invoice_shown);
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    let share = remaining.as_secs_f32() / validity.as_secs_f32().max(1.0);
+                    let theme = theme.borrow();
+                    let drawn = idle_screen.draw_countdown(&mut display, qr_size, share, &theme);
+                    if let Err(e) = drawn {
+                        warn!("{}, re-initializing display", e);
+                        idle_screen.recover(&mut display, &status_bar, &theme)?;
+                    }
+                }
+                _ = qr_frames.tick(), if qr_animation.is_some() && screen_timeout.is_none() => {
+                    let Some(animation) = &mut qr_animation else {
+                        continue;
+                    };
+                    if let Err(e) = animation.draw_next(&mut display) {
+                        warn!("{}, re-initializing display", e);
+                        idle_screen.recover(&mut display, &status_bar, &theme.borrow())?;
+                    }
+                }
+                _ = sleep_until(attract), if invoice_text.is_some() && screen_timeout.is_none() => {
+                    attract = None;
+                    let theme = theme.borrow();
+                    if let Some(screen) = attract_screen(&theme, 0, &amount) {
+                        screen.draw(&mut display, &status_bar, &theme)?;
+                        screen_timeout = Some(Instant::now() + theme.attract.duration());
+                        attract_slide = Some(0);
+                    }
+                }
+                _ = sleep_until(status_bar_redraw) => {
+                    status_bar_redraw = None;
+                    status_updates.send_replace(status_bar.clone());
+                    if let Err(e) = draw_status_bar(&mut display, &status_bar) {
+                        warn!("{}, re-initializing display", e);
+                        idle_screen.recover(&mut display, &status_bar, &theme.borrow())?;
+                    }
+                }
+                Ok(()) = estop_events.changed() => {
+                    if !*estop_events.borrow_and_update() {
+                        continue;
+                    }
+
+                    dispenser.set_idle();
+                    audit_log.record_now("emergency_stop");
+                    bus.publish(Event::EmergencyStop);
+                    operator::reset_emergency_stop(
+                        &mut display,
+                        &status_bar,
+                        &mut buttons,
+                        operator_pin.as_ref(),
+                        &estop,
+                        &audit_log,
+                    )
+                    .await?;
+                    idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                    bus.publish(Event::AlarmCleared);
+                }
+                Ok(()) = theme.changed() => {
+                    theme.mark_unchanged();
+                    // A temporary screen picks up the new theme when it is replaced
+                    if screen_timeout.is_none() {
+                        idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                    }
+                }
+                _ = sleep_until(screen_timeout) => {
+                    screen_timeout = None;
+                    let theme = theme.borrow();
+                    let next_slide = attract_slide.take().map(|slide| slide + 1).and_then(|slide| {
+                        Some((slide, attract_screen(&theme, slide, &amount)?))
+                    });
+                    if let Some((slide, screen)) = next_slide {
+                        screen.draw(&mut display, &status_bar, &theme)?;
+                        screen_timeout = Some(Instant::now() + theme.attract.duration());
+                        attract_slide = Some(slide);
+                        continue;
+                    }
+                    if address_used {
+                        break false;
+                    }
+                    attract = next_attract(&theme);
+                    idle_screen.draw(&mut display, &status_bar, &theme)?;
+                    if std::mem::take(&mut tamper_alarm_shown) {
+                        bus.publish(Event::AlarmCleared);
+                    }
+                }
+                Some(_) = tamper_alarms.recv() => {
+                    Screen::TamperAlarm.draw(&mut display, &status_bar, &theme.borrow())?;
+                    bus.publish(Event::TamperAlarm);
+                    screen_timeout = Some(Instant::now() + TAMPER_ALARM_SCREEN_DURATION);
+                    attract_slide = None;
+                    tamper_alarm_shown = true;
+                }
+                _ = &mut stop_signal => break 'vend,
+                Some(request) = control_requests.recv() => match request.command {
+                    ControlCommand::Status => {
+                        // A head that isn't on disk yet wouldn't verify after a power cut
+                        audit_log.flush();
+                        request.reply(ControlResponse::Status(MachineStatus {
+                            price_sats: price_msat / 1000,
+                            maintenance: vending.in_maintenance(),
+                            state: vending.state(),
+                            emergency_stop: estop.is_tripped(),
+                            stock_grams: *stock.borrow(),
+                            candy_left: inventory.count().map(|count| count.left),
+                            ip: status_bar.ip().to_string(),
+                            audit_head: audit_log.head_hash(),
+                        }));
+                    }
+                    ControlCommand::Invoice => {
+                        request.reply(ControlResponse::Invoice(CurrentInvoice {
+                            invoice: invoice_text.clone(),
+                            amount_sats: invoice_sats,
+                        }));
+                    }
+                    ControlCommand::Dispense => {
+                        audit_log.record("control_dispense");
+                        bus.publish(Event::DispenseStarted);
+                        dispenser.select_channel(tier);
+                        let completed = dispenser.dispense().await;
+                        let jammed = jams.try_recv().is_ok();
+                        if jammed {
+                            audit_log.record("dispense_jammed");
+                            bus.publish(Event::DispenseJammed);
+                        }
+                        bus.publish(Event::DispenseDone { completed });
+                        if completed {
+                            inventory.dispensed();
+                        }
+                        audit_log.flush();
+                        request.reply(match (completed, jammed) {
+                            (true, _) => ControlResponse::Ok,
+                            (false, true) => {
+                                ControlResponse::Error("Dispenser jammed".to_string())
+                            }
+                            (false, false) => ControlResponse::Error(
+                                "Dispense aborted by the emergency stop".to_string(),
+                            ),
+                        });
+                    }
+                    ControlCommand::SetPrice { sats } => {
+                        if sats == 0 {
+                            request.reply(ControlResponse::Error(
+                                "Price must be at least 1 sat".to_string(),
+                            ));
+                            continue;
+                        }
+                        audit_log.record_now(&format!("price_set {}", sats));
+                        // A fixed price replaces the fiat one
+                        products[tier].price_sats = sats;
+                        products[tier].price = None;
+                        request.reply(ControlResponse::Ok);
+                        // Replace the invoice showing the old price
+                        break false;
+                    }
+                    ControlCommand::RedeemNotes { ref notes } => {
+                        if vending.in_maintenance() {
+                            request.reply(ControlResponse::Error("Out of service".to_string()));
+                            continue;
+                        }
+                        let redeemed = watchdog::within(
+                            "redeem ecash notes",
+                            WALLET_CALL_TIMEOUT,
+                            redeem_notes(&ln, notes, price_msat),
+                        );
+                        match redeemed.await {
+                            Ok(amount) => {
+                                let sats = amount.msats / 1000;
+                                audit_log.record(&format!("notes_redeemed {}", sats));
+                                bus.publish(Event::PaymentReceived {
+                                    amount_msat: amount.msats,
+                                });
+                                request.reply(ControlResponse::Ok);
+                                paid_by = Some((PaymentMethod::Ecash, amount.msats));
+                                break true;
+                            }
+                            Err(e) => request.reply(ControlResponse::Error(format!("{:#}", e))),
+                        }
+                    }
+                    ControlCommand::Refill { count } => {
+                        match inventory.refill(count) {
+                            Some(count) => {
+                                audit_log.record(&format!("refilled {}", count.left));
+                                request.reply(ControlResponse::Ok);
+                            }
+                            None => request.reply(ControlResponse::Error(
+                                "No previous refill, pass a count".to_string(),
+                            )),
+                        }
+                    }
+                    ControlCommand::Maintenance { enabled } => {
+                        audit_log.record(if enabled {
+                            "maintenance_started"
+                        } else {
+                            "maintenance_ended"
+                        });
+                        vending.step(if enabled {
+                            VendingEvent::MaintenanceStarted
+                        } else {
+                            VendingEvent::MaintenanceEnded
+                        });
+                        request.reply(ControlResponse::Ok);
+                        break false;
+                    }
+                    ControlCommand::ShowMessage { ref text, seconds } => {
+                        let until = Instant::now().checked_add(Duration::from_secs(seconds));
+                        let Some(until) = until else {
+                            let error = format!("Can't show a message for {} seconds", seconds);
+                            request.reply(ControlResponse::Error(error));
+                            continue;
+                        };
+                        Screen::Message(text).draw(&mut display, &status_bar, &theme.borrow())?;
+                        request.reply(ControlResponse::Ok);
+                        screen_timeout = Some(until);
+                        attract_slide = None;
+                    }
+                    ControlCommand::JoinFederation { ref invite } => {
+                        let Some(fedimint) = ln.fedimint() else {
+                            request.reply(ControlResponse::Error(
+                                "Only the Fedimint backend joins federations".to_string(),
+                            ));
+                            continue;
+                        };
+                        let invite = match invite.trim().parse::<InviteCode>() {
+                            Ok(invite) => invite,
+                            Err(e) => {
+                                let error = format!("Invalid invite code: {}", e);
+                                request.reply(ControlResponse::Error(error));
+                                continue;
+                            }
+                        };
+                        if invite.federation_id() == fedimint.client().federation_id() {
+                            request.reply(ControlResponse::Error(
+                                "Already in this federation".to_string(),
+                            ));
+                            continue;
+                        }
+
+                        // Only give up the current federation for one that can be joined
+                        Screen::Message("Checking federation").draw(
+                            &mut display,
+                            &status_bar,
+                            &theme.borrow(),
+                        )?;
+                        let preview = async {
+                            config
+                                .fedimint_builder()?
+                                .federation_invite(invite.clone())
+                                .preview()
+                                .await
+                        };
+                        match watchdog::within("preview federation", WALLET_CALL_TIMEOUT, preview)
+                            .await
+                        {
+                            Ok(name) => info!(
+                                "Switching to federation {}",
+                                name.as_deref().unwrap_or("without a name")
+                            ),
+                            Err(e) => {
+                                let error = format!("Can't reach the federation: {:#}", e);
+                                request.reply(ControlResponse::Error(error));
+                                idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                                continue;
+                            }
+                        }
+
+                        Screen::Message("Switching federation").draw(
+                            &mut display,
+                            &status_bar,
+                            &theme.borrow(),
+                        )?;
+                        if let Err(e) = wipe::begin_factory_reset(&ln, &audit_log).await {
+                            error!("Switching federation failed: {:#}", e);
+                            request.reply(ControlResponse::Error(format!("{:#}", e)));
+                            idle_screen.draw(&mut display, &status_bar, &theme.borrow())?;
+                            continue;
+                        }
+                        // The database can only be deleted once the client let go of it
+                        let watches = take_watches(&mut payment_watch, &mut superseded);
+                        let released = shutdown_wallet(ln, watches, deposits.take()).await;
+                        let switched = if released {
+                            let datadir = config.datadir();
+                            wipe::finish_factory_reset(&datadir, Some(&invite), &audit_log)
+                        } else {
+                            Err(anyhow::anyhow!("Wallet still in use, switch the federation again"))
+                        };
+                        if let Err(e) = switched {
+                            error!("Switching federation failed: {:#}", e);
+                            request.reply(ControlResponse::Error(format!("{:#}", e)));
+                            return Err(e.into());
+                        }
+                        let event = format!("federation_switched {}", invite.federation_id());
+                        audit_log.record(&event);
+                        request.reply(ControlResponse::Ok);
+
+                        // Exit so the service manager restarts us into joining the new federation
+                        bus.publish(Event::ShuttingDown);
+                        dispenser.set_idle();
+                        audit_log.flush();
+                        if let Some(backlight) = &backlight {
+                            backlight.off();
+                        }
+                        clear_display_on_exit(&mut display).await;
+                        if let Some(hardware_watchdog) = hardware_watchdog.take() {
+                            hardware_watchdog.disarm().await;
+                        }
+                        return Ok(());
+                    }
+                    ControlCommand::Quit => {
+                        request.reply(ControlResponse::Ok);
+                        break 'vend;
+                    }
+                },
+            }
+        };
+        // Either the invoice is replaced or the sale was paid some other way. Whoever scanned
+        // the invoice can still pay it until it expires.
+        if let (Some(watch), Some(invoice)) = (payment_watch.take(), &invoice) {
+            superseded.push(SupersededInvoice {
+                invoice: invoice.clone(),
+                tier,
+                amount_msat: invoice_msat,
+                watch,
+                expires: invoice_expiry(invoice) + SUPERSEDED_INVOICE_GRACE,
+            });
+        }
+        if !paid {
+            vending.step(VendingEvent::InvoiceReplaced);
+            continue;
+        }
+        vending.step(VendingEvent::PaymentReceived);
+        // The sale is for whatever the paid invoice was for
+        let (sold_tier, product, invoice_msat, invoice) = match late_paid {
+            Some(late) => (
+                late.tier,
+                products[late.tier].clone(),
+                late.amount_msat,
+                Some(late.invoice),
+            ),
+            None => (tier, product, invoice_msat, invoice),
+        };
+        let payment_hash = invoice
+            .as_ref()
+            .map(|invoice| invoice.payment_hash().to_string());
+
+        let bought = (products.len() > 1).then_some(product.name.as_str());
+        Screen::PaymentSuccess { product: bought }.draw(
+            &mut display,
+            &status_bar,
+            &theme.borrow(),
+        )?;
+        vending.step(VendingEvent::DispenseStarted);
+        dispenser.select_channel(sold_tier);
+        bus.publish(Event::DispenseStarted);
+        // Jams of test dispenses were already dealt with by whoever ran them
+        while jams.try_recv().is_ok() {}
+        let mut retries = 0;
+        let (completed, jammed) = loop {
+            let completed = match product.run() {
+                Some(run) => dispenser.dispense_for(run).await,
+                None => dispenser.dispense().await,
+            };
+            let jammed = jams.try_recv().is_ok();
+            if jammed {
+                audit_log.record("dispense_jammed");
+                bus.publish(Event::DispenseJammed);
+                Screen::Jammed.draw(&mut display, &status_bar, &theme.borrow())?;
+            }
+            if !jammed || retries == JAM_RETRIES {
+                break (completed, jammed);
+            }
+            retries += 1;
+            info!("Retrying jammed dispense");
+        };
+        bus.publish(Event::DispenseDone { completed });
+        vending.step(VendingEvent::DispenseDone);
+        if completed {
+            inventory.dispensed();
+        }
+
+        let (method, paid_msat) = paid_by.unwrap_or((PaymentMethod::Lightning, invoice_msat));
+        let refund = if jammed && method != PaymentMethod::Coins {
+            let lightning_hash = invoice
+                .as_ref()
+                .filter(|_| method == PaymentMethod::Lightning)
+                .map(|invoice| *invoice.payment_hash());
+            refund_notes(&ln, paid_msat, lightning_hash).await
+        } else {
+            None
+        };
+        if let Some(price) = paid_coins.filter(|_| jammed) {
+            coin_credit += price;
+            audit_log.record("coin_credit_restored");
+        }
+        if let Some(notes) = &refund {
+            audit_log.record_now(&format!("refunded {}", notes.total_amount().msats / 1000));
+        }
+        sales_ledger.record(&Sale {
+            jammed,
+            refunded_msat: refund.as_ref().map(|notes| notes.total_amount().msats),
+            ..Sale::now(
+                &product.name,
+                paid_msat,
+                method,
+                payment_hash.filter(|_| method == PaymentMethod::Lightning),
+                completed,
+            )
+        });
+        // Whatever led up to a sale should survive a power cut
+        audit_log.flush();
+        balance_refresh.reset_immediately();
+
+        if jammed {
+            // Stays on the apology screen if nothing could be refunded, the operator was notified
+            let notes = refund.as_ref().map(|notes| notes.to_string());
+            show_refund(
+                &mut display,
+                &status_bar,
+                &theme,
+                notes.as_deref(),
+                &mut buttons,
+                &mut long_presses,
+            )
+            .await?;
+            // Every further customer would end up on this screen as well
+            vending.step(VendingEvent::CooldownElapsed);
+            vending.step(VendingEvent::MaintenanceStarted);
+            audit_log.record("maintenance_started");
+            continue;
+        }
+
+        tokio::time::sleep(SUCCESS_SCREEN_DURATION).await;
+        vending.step(VendingEvent::CooldownElapsed);
     }
 
     // Cleanup
-    println!("Shutting down...");
-    motor_pin.set_low();
-    led_pin.set_low();
-    clear_display(&mut display);
+    info!("Shutting down...");
+    bus.publish(Event::ShuttingDown);
+    dispenser.set_idle();
+    audit_log.flush();
+    shutdown_wallet(
+        ln,
+        take_watches(&mut payment_watch, &mut superseded),
+        deposits,
+    )
+    .await;
+    if let Some(mdns) = mdns {
+        mdns.shutdown();
+    }
+    if let Some(backlight) = &backlight {
+        backlight.off();
+    }
+    clear_display_on_exit(&mut display).await;
+    if let Some(hardware_watchdog) = hardware_watchdog {
+        hardware_watchdog.disarm().await;
+    }
 
     Ok(())
 }
+
+/// Completes on the first SIGTERM (e.g. `systemctl stop`) or SIGINT (Ctrl+C). A second signal
+/// exits right away in case the clean shutdown hangs.
+fn spawn_signal_handler() -> io::Result<oneshot::Receiver<()>> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let (stop_tx, stop_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => info!("Received SIGTERM"),
+            _ = sigint.recv() => info!("Received SIGINT"),
+        }
+        let _ = stop_tx.send(());
+        tokio::select! {
+            _ = sigterm.recv() => {}
+            _ = sigint.recv() => {}
+        }
+        warn!("Received another signal, exiting without cleanup");
+        std::process::exit(1);
+    });
+    Ok(stop_rx)
+}
+
+/// Leaves a blank panel rather than a stale invoice nobody will pay
+async fn clear_display_on_exit(display: &mut Display) {
+    let cleared = Retry::SPI
+        .run("clear display", retry::always, async || {
+            clear_display(display)
+        })
+        .await;
+    if let Err(e) = cleared {
+        warn!("Failed to clear display: {}", e);
+    }
+}
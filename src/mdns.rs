@@ -0,0 +1,50 @@
+use fedimint_core::anyhow::{self, Context};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{info, warn};
+
+/// Plain HTTP, so the API shows up in generic service browsers
+const SERVICE_TYPE: &str = "_http._tcp.local.";
+
+pub const DEFAULT_HOSTNAME: &str = "candypi";
+
+/// Announces the HTTP API as `<hostname>.local` on the LAN
+pub struct MdnsAdvertiser {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsAdvertiser {
+    /// Starts answering mDNS queries in a background thread. The announced addresses follow
+    /// the network interfaces, e.g. WiFi connecting after boot.
+    pub fn advertise(hostname: &str, port: u16) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new().context("Failed to start mDNS responder")?;
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            hostname,
+            &format!("{}.local.", hostname),
+            "",
+            port,
+            &[("path", "/status")][..],
+        )
+        .context("Invalid mDNS hostname")?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_owned();
+        daemon
+            .register(service)
+            .context("Failed to register mDNS service")?;
+        info!("Advertising API as http://{}.local:{}", hostname, port);
+
+        Ok(Self { daemon, fullname })
+    }
+
+    /// Tells the LAN the service is gone, so browsers drop it right away instead of when the
+    /// record expires
+    pub fn shutdown(self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!("Failed to unregister mDNS service: {}", e);
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("Failed to stop mDNS responder: {}", e);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Environment variable enabling the low-memory profile for 512 MB boards like the Pi Zero 2 W
+/// if set to `1`
+pub const LOW_MEMORY_ENV: &str = "CANDYPI_LOW_MEMORY";
+
+/// Read by fedimint-rocksdb when opening the database
+const ROCKSDB_WRITE_BUFFER_SIZE_ENV: &str = "FM_ROCKSDB_WRITE_BUFFER_SIZE";
+/// RocksDB defaults to 64 MiB per memtable, the wallet writes far too little to need that
+const LOW_MEMORY_WRITE_BUFFER_SIZE: usize = 4 * 1024 * 1024;
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+pub fn low_memory() -> bool {
+    std::env::var(LOW_MEMORY_ENV).is_ok_and(|value| value == "1")
+}
+
+/// Configures the libraries that are only tunable through the environment for the low-memory
+/// profile. Settings made explicitly by the operator are left alone.
+///
+/// # Safety
+/// Modifies the environment, so no other thread may read or write it at the same time. Call it
+/// first thing in `main`.
+pub unsafe fn apply_low_memory_env() {
+    if !low_memory() {
+        return;
+    }
+    info!("Low-memory profile enabled");
+
+    if std::env::var_os(ROCKSDB_WRITE_BUFFER_SIZE_ENV).is_none() {
+        unsafe {
+            std::env::set_var(
+                ROCKSDB_WRITE_BUFFER_SIZE_ENV,
+                LOW_MEMORY_WRITE_BUFFER_SIZE.to_string(),
+            )
+        };
+    }
+}
+
+/// Resident set size of this process, `None` if `/proc` isn't available
+pub fn rss_bytes() -> Option<u64> {
+    // Second field of statm is the resident size in pages
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+/// Exports the memory usage as the `candypi_memory_rss_bytes` gauge, in the low-memory profile
+/// it is logged as well
+pub fn spawn_reporting() {
+    let log = low_memory();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(REPORT_INTERVAL);
+        loop {
+            interval.tick().await;
+            let Some(rss) = rss_bytes() else {
+                warn!("Failed to read memory usage");
+                return;
+            };
+            metrics::gauge!("candypi_memory_rss_bytes").set(rss as f64);
+            if log {
+                info!("Memory usage: {} MiB", rss / (1024 * 1024));
+            }
+        }
+    });
+}
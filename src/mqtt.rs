@@ -0,0 +1,385 @@
+use crate::control::{ControlCommand, ControlRequest, ControlResponse};
+use crate::events::{Event, EventSubscriber};
+use crate::remote_dispense::ReplayGuard;
+use crate::signed_config::{self, ConfigVerifier, Domain};
+use fedimint_core::anyhow::{self, Context, ensure};
+use reqwest::Url;
+use rumqttc::{AsyncClient, LastWill, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Environment variable overriding the broker URL of the `[mqtt]` table
+pub const MQTT_URL_ENV: &str = "CANDYPI_MQTT_URL";
+
+const MQTT_DEFAULT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// `[mqtt]` table of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    /// `mqtt://[user:password@]host[:port]/prefix` URL of the broker fleet telemetry is sent to.
+    /// Topics are created below the prefix, e.g. `candypi/machine-1`.
+    pub url: String,
+}
+
+/// Fields every signed command carries besides the command itself
+#[derive(Deserialize)]
+struct CommandEnvelope {
+    /// Device ID of the machine the command is meant for
+    device: String,
+    /// Unique per command
+    nonce: String,
+    /// Unix time in seconds when the command was signed
+    timestamp: u64,
+}
+
+/// Checks signed commands and configs, both need the operator key and a device ID
+struct RemoteControl {
+    verifier: ConfigVerifier,
+    replays: ReplayGuard,
+}
+
+/// Publishes events to `<prefix>/events` and takes price and maintenance commands from
+/// `<prefix>/command`. Whether the machine is connected is kept retained in `<prefix>/online`.
+pub struct MqttTelemetry {
+    options: MqttOptions,
+    prefix: String,
+    /// Commands are only accepted with an operator key to check their signature
+    remote: Option<RemoteControl>,
+}
+
+impl MqttTelemetry {
+    /// Uses the broker from [`MQTT_URL_ENV`] if set, the configured one otherwise. Returns `None`
+    /// without either.
+    pub fn from_config(config: Option<&MqttConfig>) -> anyhow::Result<Option<Self>> {
+        let Some(url) = std::env::var(MQTT_URL_ENV)
+            .ok()
+            .or_else(|| config.map(|config| config.url.clone()))
+        else {
+            return Ok(None);
+        };
+        let url = Url::parse(&url).context("Invalid MQTT URL")?;
+        ensure!(
+            url.scheme() == "mqtt",
+            "Only plain mqtt:// URLs are supported"
+        );
+        let prefix = url.path().trim_matches('/').to_string();
+        ensure!(!prefix.is_empty(), "MQTT URL has no topic prefix");
+
+        let host = url.host_str().context("MQTT URL has no host")?;
+        let mut options = MqttOptions::new(
+            format!("candypi-{}", prefix.replace('/', "-")),
+            host,
+            url.port().unwrap_or(MQTT_DEFAULT_PORT),
+        );
+        options.set_keep_alive(KEEP_ALIVE);
+        options.set_last_will(LastWill::new(
+            format!("{prefix}/online"),
+            "false",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if !url.username().is_empty() {
+            options.set_credentials(url.username(), url.password().unwrap_or(""));
+        }
+
+        let remote = signed_config::operator_key_from_env()?
+            .zip(signed_config::device_id_from_env())
+            .map(|(operator_key, device_id)| RemoteControl {
+                verifier: ConfigVerifier::new(operator_key),
+                replays: ReplayGuard::new(device_id),
+            });
+        if remote.is_none() {
+            info!(
+                "{} or {} not set, MQTT commands are ignored",
+                signed_config::OPERATOR_PUBKEY_ENV,
+                signed_config::DEVICE_ID_ENV
+            );
+        }
+
+        Ok(Some(Self {
+            options,
+            prefix,
+            remote,
+        }))
+    }
+
+    /// Connects in the background and keeps reconnecting while the broker is unreachable. Signed
+    /// configs pushed to `<prefix>/config` replace `config_path`.
+    pub fn spawn(
+        self,
+        mut events: EventSubscriber,
+        requests: mpsc::Sender<ControlRequest>,
+        config_path: PathBuf,
+    ) {
+        info!("Sending telemetry to MQTT under {}", self.prefix);
+        let (client, mut eventloop) = AsyncClient::new(self.options, 16);
+
+        let events_topic = format!("{}/events", self.prefix);
+        let publisher = client.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let Some(message) = event_message(&event) else {
+                    continue;
+                };
+                if let Err(e) = publisher
+                    .publish(&events_topic, QoS::AtLeastOnce, false, message.to_string())
+                    .await
+                {
+                    warn!("Failed to queue MQTT event: {}", e);
+                }
+            }
+        });
+
+        let prefix = self.prefix;
+        let config_topic = format!("{prefix}/config");
+        let config_path = Arc::new(config_path);
+        let remote = self.remote.map(Arc::new);
+        tokio::spawn(async move {
+            loop {
+                let packet = match eventloop.poll().await {
+                    Ok(rumqttc::Event::Incoming(packet)) => packet,
+                    Ok(rumqttc::Event::Outgoing(_)) => continue,
+                    Err(e) => {
+                        warn!("MQTT connection failed: {}", e);
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+
+                match packet {
+                    Packet::ConnAck(_) => {
+                        info!("Connected to MQTT broker");
+                        // The event loop has to keep running for these to go out
+                        let online = client.try_publish(
+                            format!("{prefix}/online"),
+                            QoS::AtLeastOnce,
+                            true,
+                            "true",
+                        );
+                        let subscribed = if remote.is_some() {
+                            client
+                                .try_subscribe(format!("{prefix}/command"), QoS::AtLeastOnce)
+                                .and(client.try_subscribe(&config_topic, QoS::AtLeastOnce))
+                        } else {
+                            Ok(())
+                        };
+                        if let Err(e) = online.and(subscribed) {
+                            warn!("Failed to set up MQTT topics: {}", e);
+                        }
+                    }
+                    Packet::Publish(publish) => {
+                        let Some(remote) = remote.clone() else {
+                            continue;
+                        };
+                        let client = client.clone();
+                        let responses_topic = format!("{prefix}/responses");
+                        let requests = requests.clone();
+                        if publish.topic == config_topic {
+                            // Would be applied again on every restart, restarting forever
+                            if publish.retain {
+                                warn!("Ignoring retained config, publish it without retain");
+                                continue;
+                            }
+                            let config_path = config_path.clone();
+                            tokio::spawn(async move {
+                                let response = apply_config(
+                                    &remote,
+                                    &publish.payload,
+                                    &config_path,
+                                    &requests,
+                                )
+                                .await;
+                                let _ = client
+                                    .publish(
+                                        responses_topic,
+                                        QoS::AtLeastOnce,
+                                        false,
+                                        response.to_json().to_string(),
+                                    )
+                                    .await;
+                            });
+                            continue;
+                        }
+                        // The main loop may take a moment to answer, the connection has to be
+                        // kept alive meanwhile
+                        tokio::spawn(async move {
+                            let response = match verify_command(&remote, &publish.payload) {
+                                Ok(command) => ControlRequest::send(&requests, command)
+                                    .await
+                                    .unwrap_or_else(|| {
+                                        ControlResponse::Error("Shutting down".to_string())
+                                    }),
+                                Err(e) => {
+                                    warn!("Rejected MQTT command: {:#}", e);
+                                    ControlResponse::Error(format!("{:#}", e))
+                                }
+                            };
+                            let _ = client
+                                .publish(
+                                    responses_topic,
+                                    QoS::AtLeastOnce,
+                                    false,
+                                    response.to_json().to_string(),
+                                )
+                                .await;
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+}
+
+/// Telemetry message for events the fleet cares about
+fn event_message(event: &Event) -> Option<serde_json::Value> {
+    let message = match event {
+        Event::InvoiceCreated { amount_msat } => {
+            serde_json::json!({ "event": "invoice_created", "amount_msat": amount_msat })
+        }
+        Event::PaymentReceived { amount_msat } => {
+            serde_json::json!({ "event": "payment_received", "amount_msat": amount_msat })
+        }
+        Event::DispenseDone { completed } => {
+            serde_json::json!({ "event": "dispensed", "completed": completed })
+        }
+        Event::DispenseJammed => serde_json::json!({ "event": "error", "error": "jammed" }),
+        Event::TamperAlarm => serde_json::json!({ "event": "error", "error": "tamper_alarm" }),
+        Event::EmergencyStop => {
+            serde_json::json!({ "event": "error", "error": "emergency_stop" })
+        }
+        Event::HopperLow { low } => serde_json::json!({ "event": "low_stock", "low": low }),
+        _ => return None,
+    };
+    Some(message)
+}
+
+/// Installs a pushed config and restarts into it. The hex signature goes on the first line, then
+/// the serial and the config as signed, see [`signed_config::install`].
+async fn apply_config(
+    remote: &RemoteControl,
+    payload: &[u8],
+    config_path: &Path,
+    requests: &mpsc::Sender<ControlRequest>,
+) -> ControlResponse {
+    let installed = split_signature(payload).and_then(|(signature, signed)| {
+        let device_id = Some(remote.replays.device_id());
+        signed_config::install(&remote.verifier, signed, &signature, config_path, device_id)
+    });
+    if let Err(e) = installed {
+        warn!("Rejected pushed config: {:#}", e);
+        return ControlResponse::Error(format!("{:#}", e));
+    }
+    info!("Installed pushed config, restarting");
+    // The service manager starts us again with the new config
+    ControlRequest::send(requests, ControlCommand::Quit)
+        .await
+        .unwrap_or(ControlResponse::Ok)
+}
+
+/// Splits off the hex signature on the first line of a signed message
+fn split_signature(payload: &[u8]) -> anyhow::Result<(Vec<u8>, &[u8])> {
+    let newline = payload
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .context("Message must start with a signature line")?;
+    let signature =
+        hex::decode(payload[..newline].trim_ascii()).context("Signature is not valid hex")?;
+    Ok((signature, &payload[newline + 1..]))
+}
+
+/// Commands are the hex signature on the first line, followed by the JSON command with the
+/// fields of [`CommandEnvelope`], signed in [`Domain::Command`]. Only price changes and
+/// maintenance mode may be changed remotely.
+fn verify_command(remote: &RemoteControl, payload: &[u8]) -> anyhow::Result<ControlCommand> {
+    let (signature, signed) = split_signature(payload)?;
+    let CommandEnvelope {
+        device,
+        nonce,
+        timestamp,
+    } = serde_json::from_slice(signed).context("Command needs a device, nonce and timestamp")?;
+    remote.replays.check(&device, &nonce, timestamp)?;
+    let json = remote
+        .verifier
+        .verify(Domain::Command, signed, &signature)?;
+    remote.replays.remember(&nonce, timestamp)?;
+
+    let command = serde_json::from_slice(json).context("Invalid command")?;
+    ensure!(
+        matches!(
+            command,
+            ControlCommand::SetPrice { .. } | ControlCommand::Maintenance { .. }
+        ),
+        "Only set-price and maintenance are accepted over MQTT"
+    );
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::operator;
+    use ed25519_dalek::Signer;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn remote() -> RemoteControl {
+        RemoteControl {
+            verifier: ConfigVerifier::new(operator().verifying_key()),
+            replays: ReplayGuard::new("lobby".to_string()),
+        }
+    }
+
+    fn set_price(device: &str, nonce: &str) -> serde_json::Value {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        serde_json::json!({
+            "command": "set-price",
+            "sats": 21,
+            "device": device,
+            "nonce": nonce,
+            "timestamp": timestamp,
+        })
+    }
+
+    fn sign(command: &serde_json::Value) -> Vec<u8> {
+        let json = command.to_string();
+        let signed = [Domain::Command.tag(), json.as_bytes()].concat();
+        let signature = hex::encode(operator().sign(&signed).to_bytes());
+        format!("{signature}\n{json}").into_bytes()
+    }
+
+    #[test]
+    fn accepts_command_once() {
+        let remote = remote();
+        let command = sign(&set_price("lobby", "01"));
+        assert!(matches!(
+            verify_command(&remote, &command).unwrap(),
+            ControlCommand::SetPrice { sats: 21 }
+        ));
+        let error = verify_command(&remote, &command).unwrap_err().to_string();
+        assert!(error.contains("already used"), "{error}");
+    }
+
+    #[test]
+    fn rejects_command_for_another_machine() {
+        let command = sign(&set_price("foyer", "01"));
+        let error = verify_command(&remote(), &command).unwrap_err().to_string();
+        assert!(error.contains("another machine"), "{error}");
+    }
+
+    #[test]
+    fn rejects_command_without_nonce() {
+        let mut command = set_price("lobby", "01");
+        command.as_object_mut().unwrap().remove("nonce");
+        assert!(verify_command(&remote(), &sign(&command)).is_err());
+    }
+}
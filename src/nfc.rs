@@ -0,0 +1,424 @@
+use crate::retry::{self, Retry};
+use fedimint_core::anyhow::{self, Context, bail, ensure};
+use rppal::i2c::I2c;
+use rppal::spi::{Bus, Mode, SlaveSelect, Spi};
+use serde::Deserialize;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Environment variable overriding `nfc_reader` of the config file, `i2c` or `spi`
+pub const NFC_READER_ENV: &str = "CANDYPI_NFC_READER";
+
+const PN532_I2C_ADDRESS: u16 = 0x24;
+/// The PN532 tops out at 5 MHz, long jumper wires don't
+const SPI_CLOCK_HZ: u32 = 1_000_000;
+/// SPI prefixes of a status read, a data write and a data read
+const SPI_STATUS_READ: u8 = 0x02;
+const SPI_DATA_WRITE: u8 = 0x01;
+const SPI_DATA_READ: u8 = 0x03;
+
+const HOST_TO_PN532: u8 = 0xD4;
+const PN532_TO_HOST: u8 = 0xD5;
+const ACK: [u8; 6] = [0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+
+const CMD_SAM_CONFIGURATION: u8 = 0x14;
+const CMD_RF_CONFIGURATION: u8 = 0x32;
+const CMD_IN_DATA_EXCHANGE: u8 = 0x40;
+const CMD_IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+const CMD_IN_RELEASE: u8 = 0x52;
+
+/// Longest response frame: preamble, length, checksum, TFI and 255 bytes of data
+const MAX_FRAME_LEN: usize = 262;
+/// Bytes read from the NDEF file per APDU, keeps responses within one frame
+const READ_CHUNK: usize = 200;
+/// Ecash notes are long, but not this long
+const MAX_NDEF_LEN: usize = 8 * 1024;
+
+const ACK_TIMEOUT: Duration = Duration::from_millis(100);
+/// Phones take a while to wake up their wallet app after the tap
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// A card left on the reader is only read once
+const SAME_TAP_COOLDOWN: Duration = Duration::from_secs(3);
+
+/// NFC Forum Type 4 tag application, emulated by phones and used by Bolt Cards
+const SELECT_NDEF_APPLICATION: [u8; 13] = [
+    0x00, 0xA4, 0x04, 0x00, 0x07, 0xD2, 0x76, 0x00, 0x00, 0x85, 0x01, 0x01, 0x00,
+];
+const CAPABILITY_CONTAINER_FILE: [u8; 2] = [0xE1, 0x03];
+
+/// Abbreviations of URI records, only the common ones; the rest don't occur in payments
+const URI_PREFIXES: [&str; 5] = ["", "http://www.", "https://www.", "http://", "https://"];
+
+/// What a customer tapped on the reader
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NfcTap {
+    /// Fedimint notes or a Cashu token, validated when redeeming
+    Ecash(String),
+    /// LNURL-withdraw link, decoded to its HTTPS URL, that can pay the shown invoice
+    LnurlWithdraw(String),
+    /// Federation invite code, only used in the operator menu to switch federations
+    Invite(String),
+}
+
+enum Transport {
+    I2c(I2c),
+    /// The PN532 sends and expects the least significant bit first, the Pi's SPI can't
+    Spi(Spi),
+}
+
+impl Transport {
+    fn write(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Transport::I2c(i2c) => {
+                i2c.write(frame)?;
+            }
+            Transport::Spi(spi) => {
+                let data: Vec<u8> = [SPI_DATA_WRITE]
+                    .iter()
+                    .chain(frame)
+                    .map(|byte| byte.reverse_bits())
+                    .collect();
+                spi.write(&data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_ready(&mut self) -> anyhow::Result<bool> {
+        let status = match self {
+            Transport::I2c(i2c) => {
+                let mut status = [0u8];
+                i2c.read(&mut status)?;
+                status[0]
+            }
+            Transport::Spi(spi) => {
+                let mut status = [0u8; 2];
+                spi.transfer(&mut status, &[SPI_STATUS_READ.reverse_bits(), 0])?;
+                status[1].reverse_bits()
+            }
+        };
+        Ok(status & 0x01 != 0)
+    }
+
+    fn read(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Transport::I2c(i2c) => {
+                // Every I2C read starts with the status byte
+                let mut data = vec![0u8; len + 1];
+                i2c.read(&mut data)?;
+                Ok(data.split_off(1))
+            }
+            Transport::Spi(spi) => {
+                let mut write = vec![0u8; len + 1];
+                write[0] = SPI_DATA_READ.reverse_bits();
+                let mut data = vec![0u8; len + 1];
+                spi.transfer(&mut data, &write)?;
+                Ok(data[1..].iter().map(|byte| byte.reverse_bits()).collect())
+            }
+        }
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = Instant::now() + timeout;
+        while !self.is_ready()? {
+            ensure!(Instant::now() < deadline, "PN532 not responding");
+            thread::sleep(Duration::from_millis(1));
+        }
+        Ok(())
+    }
+}
+
+/// How the PN532 is connected, `nfc_reader` of the config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NfcBus {
+    I2c,
+    Spi,
+}
+
+/// PN532 NFC reader reading NDEF messages from phones and cards
+pub struct Pn532 {
+    transport: Transport,
+}
+
+impl Pn532 {
+    /// Opens the reader on the bus from [`NFC_READER_ENV`] if set, `bus` otherwise. Returns `None`
+    /// without either.
+    pub fn from_config(bus: Option<NfcBus>) -> anyhow::Result<Option<Self>> {
+        let bus = match std::env::var(NFC_READER_ENV).as_deref() {
+            Ok("i2c") => NfcBus::I2c,
+            Ok("spi") => NfcBus::Spi,
+            Ok(other) => bail!("Unknown {NFC_READER_ENV} '{other}', expected i2c or spi"),
+            Err(_) => match bus {
+                Some(bus) => bus,
+                None => return Ok(None),
+            },
+        };
+        let transport = match bus {
+            NfcBus::I2c => {
+                let mut i2c = I2c::new().context("Failed to open I2C bus")?;
+                i2c.set_slave_address(PN532_I2C_ADDRESS)?;
+                Transport::I2c(i2c)
+            }
+            NfcBus::Spi => Transport::Spi(
+                Spi::new(Bus::Spi0, SlaveSelect::Ss1, SPI_CLOCK_HZ, Mode::Mode0)
+                    .context("Failed to open SPI bus")?,
+            ),
+        };
+
+        let mut reader = Self { transport };
+        // Normal mode, no secure access module
+        reader
+            .command(&[CMD_SAM_CONFIGURATION, 0x01, 0x14, 0x01], ACK_TIMEOUT)
+            .context("Failed to configure PN532")?;
+        // Give up looking for a target after two attempts instead of waiting forever
+        reader.command(&[CMD_RF_CONFIGURATION, 0x05, 0xFF, 0x01, 0x02], ACK_TIMEOUT)?;
+        info!("NFC reader ready");
+        Ok(Some(reader))
+    }
+
+    /// Sends a command and returns the response data after the response code
+    fn command(&mut self, command: &[u8], timeout: Duration) -> anyhow::Result<Vec<u8>> {
+        self.transport.write(&frame(command))?;
+        self.transport.wait_ready(ACK_TIMEOUT)?;
+        ensure!(self.transport.read(ACK.len())? == ACK, "PN532 didn't ACK");
+
+        self.transport.wait_ready(timeout)?;
+        let response = self.transport.read(MAX_FRAME_LEN)?;
+        parse_response(&response, command[0])
+    }
+
+    /// Looks for a card or phone in the field, returns its UID
+    fn find_target(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        // One ISO 14443 type A target at 106 kbps
+        let response = self.command(&[CMD_IN_LIST_PASSIVE_TARGET, 0x01, 0x00], EXCHANGE_TIMEOUT)?;
+        if response.first() != Some(&1) {
+            return Ok(None);
+        }
+        // Number of targets, target number, SENS_RES (two bytes), SEL_RES, UID length, UID
+        let uid_len = usize::from(*response.get(5).context("Short target response")?);
+        let uid = response.get(6..6 + uid_len).context("Short target UID")?;
+        Ok(Some(uid.to_vec()))
+    }
+
+    /// Exchanges an APDU with the target, returns the response without the status word
+    fn apdu(&mut self, apdu: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut command = vec![CMD_IN_DATA_EXCHANGE, 0x01];
+        command.extend_from_slice(apdu);
+        let response = self.command(&command, EXCHANGE_TIMEOUT)?;
+        ensure!(
+            response.first() == Some(&0),
+            "Target exchange failed with status {:#04x}",
+            response.first().copied().unwrap_or_default()
+        );
+        let body = &response[1..];
+        ensure!(
+            body.len() >= 2 && body[body.len() - 2..] == [0x90, 0x00],
+            "Target refused APDU"
+        );
+        Ok(body[..body.len() - 2].to_vec())
+    }
+
+    fn select_file(&mut self, file: [u8; 2]) -> anyhow::Result<()> {
+        self.apdu(&[0x00, 0xA4, 0x00, 0x0C, 0x02, file[0], file[1]])?;
+        Ok(())
+    }
+
+    fn read_binary(&mut self, offset: usize, len: usize) -> anyhow::Result<Vec<u8>> {
+        let [offset_high, offset_low] = (offset as u16).to_be_bytes();
+        self.apdu(&[0x00, 0xB0, offset_high, offset_low, len as u8])
+    }
+
+    /// Reads the NDEF message of a Type 4 tag
+    fn read_ndef(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.apdu(&SELECT_NDEF_APPLICATION)
+            .context("No NDEF application")?;
+        self.select_file(CAPABILITY_CONTAINER_FILE)?;
+        let capabilities = self.read_binary(0, 15)?;
+        // The NDEF file control TLV names the file the message is in
+        let ndef_file = capabilities
+            .get(9..11)
+            .context("Short capability container")?;
+        self.select_file([ndef_file[0], ndef_file[1]])?;
+
+        let length = self.read_binary(0, 2)?;
+        ensure!(length.len() == 2, "Short NDEF length");
+        let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+        ensure!(length <= MAX_NDEF_LEN, "NDEF message too long");
+        let mut message = Vec::with_capacity(length);
+        while message.len() < length {
+            let chunk = READ_CHUNK.min(length - message.len());
+            let data = self.read_binary(2 + message.len(), chunk)?;
+            ensure!(!data.is_empty(), "NDEF message ended early");
+            message.extend_from_slice(&data);
+        }
+        Ok(message)
+    }
+
+    fn read_tap(&mut self) -> anyhow::Result<Option<NfcTap>> {
+        let message = self.read_ndef();
+        // Lets the next target be found, even if this one couldn't be read
+        let _ = self.command(&[CMD_IN_RELEASE, 0x00], ACK_TIMEOUT);
+        let text = ndef_text(&message?).context("No readable NDEF record")?;
+        Ok(tap_from_text(&text))
+    }
+
+    /// Polls for taps on a dedicated thread, the reader blocks while talking to a target
+    pub fn spawn(mut self) -> mpsc::UnboundedReceiver<NfcTap> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        thread::spawn(move || {
+            let mut last_tap: Option<(Vec<u8>, Instant)> = None;
+            loop {
+                let target = Retry::SENSOR
+                    .run_blocking("poll NFC reader", retry::always, || self.find_target());
+                match target {
+                    Ok(Some(uid)) => {
+                        let repeated = last_tap.as_ref().is_some_and(|(last, at)| {
+                            *last == uid && at.elapsed() < SAME_TAP_COOLDOWN
+                        });
+                        last_tap = Some((uid, Instant::now()));
+                        if !repeated {
+                            match self.read_tap() {
+                                Ok(Some(tap)) => {
+                                    info!("NFC tap");
+                                    if tx.send(tap).is_err() {
+                                        return;
+                                    }
+                                }
+                                Ok(None) => debug!("Tapped NFC record isn't a payment"),
+                                Err(e) => warn!("Failed to read NFC tap: {:#}", e),
+                            }
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Failed to poll NFC reader: {:#}", e),
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        rx
+    }
+}
+
+/// Wraps a command into a normal information frame
+fn frame(command: &[u8]) -> Vec<u8> {
+    let len = (command.len() + 1) as u8;
+    let sum = command
+        .iter()
+        .fold(HOST_TO_PN532, |sum, byte| sum.wrapping_add(*byte));
+    let mut frame = vec![0x00, 0x00, 0xFF, len, len.wrapping_neg(), HOST_TO_PN532];
+    frame.extend_from_slice(command);
+    frame.push(sum.wrapping_neg());
+    frame.push(0x00);
+    frame
+}
+
+/// Extracts the data of the response to `command` from a frame read off the bus
+fn parse_response(frame: &[u8], command: u8) -> anyhow::Result<Vec<u8>> {
+    let start = frame
+        .windows(3)
+        .position(|preamble| preamble == [0x00, 0x00, 0xFF])
+        .context("No PN532 frame")?
+        + 3;
+    let header = frame.get(start..start + 2).context("Short PN532 frame")?;
+    ensure!(
+        header[0].wrapping_add(header[1]) == 0,
+        "Invalid PN532 frame length"
+    );
+    let len = usize::from(header[0]);
+    let body = frame
+        .get(start + 2..start + 2 + len)
+        .context("Truncated PN532 frame")?;
+    let checksum = frame
+        .get(start + 2 + len)
+        .context("Truncated PN532 frame")?;
+    ensure!(
+        body.iter()
+            .fold(*checksum, |sum, byte| sum.wrapping_add(*byte))
+            == 0,
+        "Invalid PN532 frame checksum"
+    );
+    ensure!(
+        body.len() >= 2 && body[0] == PN532_TO_HOST && body[1] == command + 1,
+        "Unexpected PN532 response"
+    );
+    Ok(body[2..].to_vec())
+}
+
+/// Text of the first record of an NDEF message, for URI, text and MIME records
+fn ndef_text(message: &[u8]) -> Option<String> {
+    let header = *message.first()?;
+    let type_format = header & 0x07;
+    let short_record = header & 0x10 != 0;
+    let has_id = header & 0x08 != 0;
+    let type_len = usize::from(*message.get(1)?);
+    let (payload_len, mut offset) = if short_record {
+        (usize::from(*message.get(2)?), 3)
+    } else {
+        let len = message.get(2..6)?;
+        (
+            u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize,
+            6,
+        )
+    };
+    let id_len = if has_id {
+        offset += 1;
+        usize::from(*message.get(offset - 1)?)
+    } else {
+        0
+    };
+    let record_type = message.get(offset..offset + type_len)?;
+    offset += type_len + id_len;
+    let payload = message.get(offset..offset + payload_len)?;
+
+    match (type_format, record_type) {
+        // Well-known URI record, the first byte abbreviates the scheme
+        (0x01, b"U") => {
+            let prefix = URI_PREFIXES
+                .get(usize::from(*payload.first()?))
+                .unwrap_or(&"");
+            let rest = std::str::from_utf8(&payload[1..]).ok()?;
+            Some(format!("{prefix}{rest}"))
+        }
+        // Well-known text record, the first byte holds the language code length
+        (0x01, b"T") => {
+            let lang_len = usize::from(*payload.first()? & 0x3F);
+            let text = payload.get(1 + lang_len..)?;
+            String::from_utf8(text.to_vec()).ok()
+        }
+        _ => String::from_utf8(payload.to_vec()).ok(),
+    }
+}
+
+/// Tells LNURL-withdraw links and invite codes from ecash, `None` for anything else
+fn tap_from_text(text: &str) -> Option<NfcTap> {
+    let text = text.trim();
+    let text = text
+        .get(..10)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("lightning:"))
+        .map_or(text, |_| &text[10..]);
+    let lowercase = text.to_ascii_lowercase();
+
+    if lowercase.starts_with("lnurl1") {
+        let (_, url) = bech32::decode(text).ok()?;
+        return String::from_utf8(url).ok().map(NfcTap::LnurlWithdraw);
+    }
+    // LUD-17 scheme, the rest of the URL is kept as is
+    if lowercase.starts_with("lnurlw://") {
+        let rest = &text["lnurlw://".len()..];
+        return Some(NfcTap::LnurlWithdraw(format!("https://{rest}")));
+    }
+    if lowercase.starts_with("fed1") {
+        return Some(NfcTap::Invite(text.to_owned()));
+    }
+    // Web links on payment cards usually lead to a shop or a wallet download page
+    if lowercase.starts_with("http://") || lowercase.starts_with("https://") {
+        return None;
+    }
+    Some(NfcTap::Ecash(text.to_owned()))
+}
@@ -0,0 +1,67 @@
+use crate::events::{Event, EventSubscriber};
+use crate::retry::{self, Retry};
+use tracing::{info, warn};
+
+/// Environment variable with a URL that notifications get POSTed to as plain text, e.g. an
+/// ntfy.sh topic. Notifications are only logged if it is unset.
+pub const NOTIFY_URL_ENV: &str = "CANDYPI_NOTIFY_URL";
+
+/// Sends alerts that need the operator's attention
+#[derive(Clone)]
+pub struct Notifier {
+    url: Option<String>,
+    client: reqwest::Client,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        Self {
+            url: std::env::var(NOTIFY_URL_ENV).ok(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Delivers the message to the configured URL, retrying while the network is down. Failures are
+    /// only logged since there is nobody to report them to.
+    pub async fn notify(&self, message: &str) {
+        info!("Notification: {}", message);
+
+        let Some(url) = &self.url else {
+            return;
+        };
+
+        let result = Retry::NETWORK
+            .run("send notification", retry::is_transient_http, async || {
+                self.client
+                    .post(url)
+                    .body(message.to_owned())
+                    .send()
+                    .await?
+                    .error_for_status()
+            })
+            .await;
+        if let Err(e) = result {
+            warn!("Failed to send notification: {}", e);
+        }
+    }
+
+    /// Notifies the operator about events on the bus that need attention and aren't reported by
+    /// their source already
+    pub fn spawn_event_alerts(self, mut events: EventSubscriber) {
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    Event::EmergencyStop => self.notify("Emergency stop pressed").await,
+                    Event::DispenseJammed => self.notify("Dispenser jammed").await,
+                    Event::DispenseDone { completed: false } => {
+                        self.notify(
+                            "Dispense was aborted, a customer may not have received their candy",
+                        )
+                        .await
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+}
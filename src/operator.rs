@@ -0,0 +1,573 @@
+use crate::audit::AuditLog;
+use crate::dispenser::DispenseAction;
+use crate::estop::EStopLatch;
+use crate::input::Button;
+use crate::inventory::Inventory;
+use crate::nfc::NfcTap;
+use crate::screen::{Display, Layout, StatusBar, TextSize, draw_status_bar, draw_text};
+use crate::wallet::Wallet;
+use embedded_graphics::{
+    pixelcolor::Rgb565, prelude::*, primitives::PrimitiveStyleBuilder, text::Text,
+};
+use fedimint_core::invite_code::InviteCode;
+use std::str::FromStr;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+/// Environment variable holding the operator PIN. The menu stays disabled if it is unset.
+pub const OPERATOR_PIN_ENV: &str = "CANDYPI_OPERATOR_PIN";
+
+const MIN_PIN_LENGTH: usize = 4;
+
+/// Prices are entered with this many digits, up to 99999 sats
+const PRICE_DIGITS: usize = 5;
+
+/// Leave the menu if nobody touches a button for this long
+const MENU_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Pause after a wrong PIN to slow down guessing
+const WRONG_PIN_DELAY: Duration = Duration::from_secs(5);
+
+/// Long enough for the emergency stop monitor to notice a button that is still pressed
+const ESTOP_SETTLE_DELAY: Duration = Duration::from_millis(50);
+
+pub struct OperatorPin(String);
+
+impl OperatorPin {
+    /// Reads the PIN from [`OPERATOR_PIN_ENV`], returns `None` if no PIN was configured
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var(OPERATOR_PIN_ENV) {
+            Ok(pin) => Self::new(pin).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn new(pin: String) -> Result<Self, String> {
+        if pin.len() < MIN_PIN_LENGTH || !pin.chars().all(|c| c.is_ascii_digit()) {
+            return Err(format!(
+                "Operator PIN must consist of at least {MIN_PIN_LENGTH} digits"
+            ));
+        }
+        Ok(Self(pin))
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Compares without short-circuiting so the timing doesn't leak how many digits were right
+    fn matches(&self, entered: &[u8]) -> bool {
+        bool::from(self.0.as_bytes().ct_eq(entered))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MenuOutcome {
+    Resume,
+    /// The operator entered a new price in sats for the selected product
+    PriceChanged(u64),
+    /// The operator confirmed a factory reset, the caller has to wipe the wallet and exit
+    FactoryReset,
+    /// The operator confirmed a reboot, the caller has to shut down cleanly first
+    Reboot,
+    /// The operator confirmed refunding the last failed sale, the caller has to show the notes
+    RefundLastSale,
+    /// The operator tapped an invite code and confirmed switching to its federation
+    JoinFederation(String),
+}
+
+#[derive(Clone, Copy)]
+enum MenuItem {
+    TestDispense,
+    Price,
+    Balance,
+    Stock,
+    ResetStock,
+    Refund,
+    ShowSeed,
+    JoinFederation,
+    FactoryReset,
+    Reboot,
+    Exit,
+}
+
+impl MenuItem {
+    const ALL: [MenuItem; 11] = [
+        MenuItem::TestDispense,
+        MenuItem::Price,
+        MenuItem::Balance,
+        MenuItem::Stock,
+        MenuItem::ResetStock,
+        MenuItem::Refund,
+        MenuItem::ShowSeed,
+        MenuItem::JoinFederation,
+        MenuItem::FactoryReset,
+        MenuItem::Reboot,
+        MenuItem::Exit,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            MenuItem::TestDispense => "Test dispense",
+            MenuItem::Price => "Price",
+            MenuItem::Balance => "Balance",
+            MenuItem::Stock => "Stock",
+            MenuItem::ResetStock => "Reset stock",
+            MenuItem::Refund => "Refund sale",
+            MenuItem::ShowSeed => "Show seed",
+            MenuItem::JoinFederation => "Join federation",
+            MenuItem::FactoryReset => "Factory reset",
+            MenuItem::Reboot => "Reboot",
+            MenuItem::Exit => "Exit",
+        }
+    }
+}
+
+/// Asks for the operator PIN and, if it was correct, runs the service menu until the operator
+/// exits or stops pressing buttons. `price_sats` is the current price of the selected product.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_operator_menu(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    nfc_taps: &mut mpsc::UnboundedReceiver<NfcTap>,
+    pin: &OperatorPin,
+    price_sats: u64,
+    ln: &Wallet,
+    dispenser: &mut dyn DispenseAction,
+    stock: &watch::Receiver<Option<u32>>,
+    inventory: &Inventory,
+) -> Result<MenuOutcome, Box<dyn std::error::Error>> {
+    info!("Operator menu requested");
+
+    let mut outcome = MenuOutcome::Resume;
+    if enter_pin(display, status_bar, buttons, pin).await? {
+        let mut selected = 0;
+        loop {
+            display_menu_screen(display, status_bar, selected)?;
+
+            let Some(button) = next_button(buttons).await else {
+                break;
+            };
+            match button {
+                Button::Next => selected = (selected + 1) % MenuItem::ALL.len(),
+                Button::Previous => {
+                    selected = (selected + MenuItem::ALL.len() - 1) % MenuItem::ALL.len()
+                }
+                Button::Select => match MenuItem::ALL[selected] {
+                    MenuItem::TestDispense => {
+                        if dispenser.dispense().await {
+                            inventory.dispensed();
+                        }
+                    }
+                    MenuItem::Price => {
+                        match enter_price(display, status_bar, buttons, price_sats).await? {
+                            Some(sats) => {
+                                outcome = MenuOutcome::PriceChanged(sats);
+                                break;
+                            }
+                            None => continue,
+                        }
+                    }
+                    MenuItem::Stock => {
+                        let message = match *stock.borrow() {
+                            Some(grams) => format!("Stock: {} g", grams),
+                            None => "Stock unknown".to_string(),
+                        };
+                        display_message_screen(display, status_bar, &message)?;
+                        if next_button(buttons).await.is_none() {
+                            break;
+                        }
+                    }
+                    MenuItem::Balance if ln.holds_funds() => {
+                        let message = match ln.balance_msat().await {
+                            Ok(balance_msat) => format!("{} sats", balance_msat / 1000),
+                            Err(e) => {
+                                warn!("Failed to get balance: {:#}", e);
+                                "Balance unavailable".to_string()
+                            }
+                        };
+                        display_message_screen(display, status_bar, &message)?;
+                        if next_button(buttons).await.is_none() {
+                            break;
+                        }
+                    }
+                    MenuItem::ResetStock => {
+                        let message = match inventory.refill(None) {
+                            Some(count) => format!("Refilled: {}", count.left),
+                            None => "Run candypi refill".to_string(),
+                        };
+                        display_message_screen(display, status_bar, &message)?;
+                        if next_button(buttons).await.is_none() {
+                            break;
+                        }
+                    }
+                    MenuItem::ShowSeed if ln.holds_funds() => {
+                        let mnemonic = ln.mnemonic().await?;
+                        display_seed_screen(display, status_bar, &mnemonic.to_string())?;
+
+                        // Keep the seed on screen until any button is pressed
+                        if next_button(buttons).await.is_none() {
+                            break;
+                        }
+                    }
+                    MenuItem::Refund if ln.fedimint().is_some() => {
+                        display_message_screen(display, status_bar, "Select to refund")?;
+                        if next_button(buttons).await == Some(Button::Select) {
+                            outcome = MenuOutcome::RefundLastSale;
+                            break;
+                        }
+                    }
+                    MenuItem::JoinFederation if ln.fedimint().is_some() => {
+                        let Some(invite) =
+                            scan_invite(display, status_bar, buttons, nfc_taps).await?
+                        else {
+                            continue;
+                        };
+                        display_message_screen(display, status_bar, "Select to switch")?;
+                        if next_button(buttons).await == Some(Button::Select) {
+                            outcome = MenuOutcome::JoinFederation(invite);
+                            break;
+                        }
+                    }
+                    MenuItem::FactoryReset if ln.holds_funds() => {
+                        display_message_screen(display, status_bar, "Select to wipe")?;
+                        if next_button(buttons).await == Some(Button::Select) {
+                            outcome = MenuOutcome::FactoryReset;
+                            break;
+                        }
+                    }
+                    MenuItem::Reboot => {
+                        display_message_screen(display, status_bar, "Select to reboot")?;
+                        if next_button(buttons).await == Some(Button::Select) {
+                            outcome = MenuOutcome::Reboot;
+                            break;
+                        }
+                    }
+                    MenuItem::Balance
+                    | MenuItem::Refund
+                    | MenuItem::ShowSeed
+                    | MenuItem::JoinFederation
+                    | MenuItem::FactoryReset => {
+                        display_message_screen(display, status_bar, unavailable_reason(ln))?;
+                        if next_button(buttons).await.is_none() {
+                            break;
+                        }
+                    }
+                    MenuItem::Exit => break,
+                },
+            }
+        }
+    }
+
+    // Don't let presses queued up while we were busy re-open the menu right away
+    while buttons.try_recv().is_ok() {}
+
+    info!("Leaving operator menu");
+    Ok(outcome)
+}
+
+/// Why a wallet item of the menu can't be used with the machine's backend
+fn unavailable_reason(ln: &Wallet) -> &'static str {
+    match ln {
+        Wallet::WatchOnly(_) => "Watch-only mode",
+        Wallet::Lnd(_) => "Not with LND",
+        // Only refunds are left, they pay out Fedimint ecash
+        Wallet::Fedimint(_) | Wallet::Cashu(_) => "Needs Fedimint",
+    }
+}
+
+/// Locks the screen after the cabinet door was opened until the operator PIN is entered
+pub async fn acknowledge_door_open(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    pin: &OperatorPin,
+    audit_log: &AuditLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    display_message_screen(display, status_bar, "Door open!")?;
+
+    // Wait for the operator to start entering the PIN, the first press doesn't count as a digit
+    buttons.recv().await;
+    while !enter_pin(display, status_bar, buttons, pin).await? {
+        audit_log.record_now("door_ack_failed");
+    }
+    audit_log.record_now("door_acknowledged");
+
+    while buttons.try_recv().is_ok() {}
+    Ok(())
+}
+
+/// Keeps the machine locked after an emergency stop until the operator released the button and
+/// entered the PIN. Without a configured PIN any button press resets it.
+pub async fn reset_emergency_stop(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    pin: Option<&OperatorPin>,
+    estop: &EStopLatch,
+    audit_log: &AuditLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    display_message_screen(display, status_bar, "Emergency stop")?;
+
+    while estop.is_tripped() {
+        buttons.recv().await;
+        let confirmed = match pin {
+            Some(pin) => enter_pin(display, status_bar, buttons, pin).await?,
+            None => true,
+        };
+        if !confirmed {
+            audit_log.record_now("estop_reset_failed");
+            display_message_screen(display, status_bar, "Emergency stop")?;
+            continue;
+        }
+
+        estop.reset();
+        tokio::time::sleep(ESTOP_SETTLE_DELAY).await;
+        if estop.is_tripped() {
+            display_message_screen(display, status_bar, "Release e-stop")?;
+        }
+    }
+    audit_log.record_now("estop_reset");
+
+    while buttons.try_recv().is_ok() {}
+    Ok(())
+}
+
+/// Lets the operator enter the PIN digit by digit: "next" increments the current digit (turning
+/// the encoder back decrements it), "select" confirms it. Returns whether the entered PIN was
+/// correct.
+async fn enter_pin(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    pin: &OperatorPin,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut entered = Vec::with_capacity(pin.len());
+    let mut digit = 0u8;
+
+    while entered.len() < pin.len() {
+        display_pin_screen(display, status_bar, entered.len(), pin.len(), digit)?;
+
+        match next_button(buttons).await {
+            Some(Button::Next) => digit = (digit + 1) % 10,
+            Some(Button::Previous) => digit = (digit + 9) % 10,
+            Some(Button::Select) => {
+                entered.push(b'0' + digit);
+                digit = 0;
+            }
+            None => return Ok(false),
+        }
+    }
+
+    if pin.matches(&entered) {
+        return Ok(true);
+    }
+
+    info!("Wrong operator PIN entered");
+    display_message_screen(display, status_bar, "Wrong PIN")?;
+    tokio::time::sleep(WRONG_PIN_DELAY).await;
+    Ok(false)
+}
+
+/// Lets the operator change the price digit by digit, starting from `current`. Returns `None` if
+/// the operator walked away or entered zero.
+async fn enter_price(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    current: u64,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let mut digits: Vec<u8> = format!("{:0width$}", current.min(99_999), width = PRICE_DIGITS)
+        .bytes()
+        .map(|digit| digit - b'0')
+        .collect();
+
+    for idx in 0..PRICE_DIGITS {
+        loop {
+            display_price_screen(display, status_bar, &digits, idx)?;
+            match next_button(buttons).await {
+                Some(Button::Next) => digits[idx] = (digits[idx] + 1) % 10,
+                Some(Button::Previous) => digits[idx] = (digits[idx] + 9) % 10,
+                Some(Button::Select) => break,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    let sats = digits
+        .iter()
+        .fold(0, |sats, digit| sats * 10 + u64::from(*digit));
+    if sats == 0 {
+        display_message_screen(display, status_bar, "Price must be > 0")?;
+        next_button(buttons).await;
+        return Ok(None);
+    }
+    info!("Operator changed the price to {} sats", sats);
+    Ok(Some(sats))
+}
+
+/// Waits for an invite code to be tapped on the NFC reader. Returns `None` if the operator
+/// pressed a button instead or walked away.
+async fn scan_invite(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    buttons: &mut mpsc::UnboundedReceiver<Button>,
+    nfc_taps: &mut mpsc::UnboundedReceiver<NfcTap>,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    display_message_screen(display, status_bar, "Tap invite code")?;
+    loop {
+        let tap = tokio::select! {
+            _ = next_button(buttons) => return Ok(None),
+            Some(tap) = nfc_taps.recv() => tap,
+        };
+        let NfcTap::Invite(invite) = tap else {
+            continue;
+        };
+        if InviteCode::from_str(&invite).is_ok() {
+            return Ok(Some(invite));
+        }
+        info!("Operator tapped an invalid invite code");
+        display_message_screen(display, status_bar, "Invalid invite code")?;
+    }
+}
+
+/// Waits for the next button press, returns `None` if the operator walked away
+async fn next_button(buttons: &mut mpsc::UnboundedReceiver<Button>) -> Option<Button> {
+    tokio::time::timeout(MENU_IDLE_TIMEOUT, buttons.recv())
+        .await
+        .ok()
+        .flatten()
+}
+
+fn clear_menu_screen(display: &mut Display, status_bar: &StatusBar) {
+    let bg = Layout::new(display).screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(Rgb565::BLACK)
+            .build(),
+    );
+    let _ = bg.draw(display);
+
+    let _ = draw_status_bar(display, status_bar);
+}
+
+fn draw_centered_text(display: &mut Display, text: &str, y: i32) {
+    let _ = draw_text(display, text, TextSize::Body, Rgb565::WHITE, y);
+}
+
+fn display_pin_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    entered_digits: usize,
+    pin_length: usize,
+    current_digit: u8,
+) -> Result<(), Box<dyn std::error::Error>> {
+    clear_menu_screen(display, status_bar);
+
+    let layout = Layout::new(display);
+    let title_y = layout.below_status_bar(30);
+    draw_centered_text(display, "Operator PIN", title_y);
+
+    // Already entered digits are masked, the one being edited is shown in clear
+    let pin_text: String = (0..pin_length)
+        .map(|idx| match idx.cmp(&entered_digits) {
+            std::cmp::Ordering::Less => '*',
+            std::cmp::Ordering::Equal => char::from(b'0' + current_digit),
+            std::cmp::Ordering::Greater => '_',
+        })
+        .collect();
+    draw_centered_text(display, &pin_text, title_y + layout.scaled(25));
+
+    Ok(())
+}
+
+fn display_price_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    digits: &[u8],
+    editing: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    clear_menu_screen(display, status_bar);
+
+    let layout = Layout::new(display);
+    let title_y = layout.below_status_bar(30);
+    draw_centered_text(display, "Price (sats)", title_y);
+
+    let price_text: String = digits
+        .iter()
+        .map(|digit| char::from(b'0' + digit))
+        .collect();
+    draw_centered_text(display, &price_text, title_y + layout.scaled(25));
+    // Marks the digit being edited
+    let cursor_text: String = (0..digits.len())
+        .map(|idx| if idx == editing { '^' } else { ' ' })
+        .collect();
+    draw_centered_text(display, &cursor_text, title_y + layout.scaled(37));
+
+    Ok(())
+}
+
+fn display_menu_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    selected: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    clear_menu_screen(display, status_bar);
+
+    let layout = Layout::new(display);
+    let text_style = layout.text_style(TextSize::Body, Rgb565::WHITE);
+    let line_height = layout.scaled(14);
+    let mut y = layout.below_status_bar(20);
+    // Scrolls once the selection moves past the bottom, e.g. in landscape
+    let rows = ((layout.height as i32 - y) / line_height + 1).max(1) as usize;
+    let first = selected.saturating_sub(rows - 1);
+    for (idx, item) in MenuItem::ALL.iter().enumerate().skip(first).take(rows) {
+        let marker = if idx == selected { ">" } else { " " };
+        let line = format!("{} {}", marker, item.label());
+        let _ = Text::new(&line, Point::new(4, y), text_style).draw(display);
+        y += line_height;
+    }
+
+    Ok(())
+}
+
+fn display_seed_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    mnemonic: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    clear_menu_screen(display, status_bar);
+
+    let layout = Layout::new(display);
+    let text_style = layout.text_style(TextSize::Body, Rgb565::WHITE);
+
+    // Two columns of six words each, the longest BIP39 words are 8 characters
+    let column_width = 10 * layout.char_width(TextSize::Body) as i32;
+    let y_start = layout.below_status_bar(14);
+    for (idx, word) in mnemonic.split_whitespace().enumerate() {
+        let column = (idx / 6) as i32;
+        let row = (idx % 6) as i32;
+        let line = format!("{} {}", idx + 1, word);
+        let _ = Text::new(
+            &line,
+            Point::new(2 + column * column_width, y_start + row * layout.scaled(14)),
+            text_style,
+        )
+        .draw(display);
+    }
+
+    Ok(())
+}
+
+fn display_message_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    message: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    clear_menu_screen(display, status_bar);
+    let y = Layout::new(display).below_status_bar(50);
+    draw_centered_text(display, message, y);
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use fedimint_core::anyhow::{self, Context};
+use rppal::gpio::{Gpio, InputPin, OutputPin};
+use rppal::i2c::I2c;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// Default address with A0-A2 tied to ground
+const MCP23017_ADDRESS: u16 = 0x20;
+
+// Register addresses with IOCON.BANK = 0 (power-on default), port B follows port A
+const REG_IODIR: u8 = 0x00;
+const REG_GPPU: u8 = 0x0C;
+const REG_GPIO: u8 = 0x12;
+const REG_OLAT: u8 = 0x14;
+
+/// Software PWM frequency for partially switched outputs, high enough for inductive loads to
+/// smooth it out
+pub const SOFT_PWM_FREQUENCY: f64 = 500.0;
+
+/// A pin either on the Pi's header or on the MCP23017 expander
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinRef {
+    /// BCM GPIO number
+    Native(u8),
+    /// Expander pin, 0-7 are GPA0-GPA7 and 8-15 are GPB0-GPB7
+    Expander(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    /// Common on relay boards, which switch on when the input is pulled low
+    ActiveLow,
+}
+
+/// How an output is wired: which pin, which level switches it on and what state it rests in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputSpec {
+    pub pin: PinRef,
+    pub polarity: Polarity,
+    /// Whether the output is switched on while idle, e.g. for a fail-secure lock
+    pub idle_active: bool,
+}
+
+impl OutputSpec {
+    pub const fn active_high(pin: PinRef) -> Self {
+        Self {
+            pin,
+            polarity: Polarity::ActiveHigh,
+            idle_active: false,
+        }
+    }
+
+    pub const fn active_low(pin: PinRef) -> Self {
+        Self {
+            pin,
+            polarity: Polarity::ActiveLow,
+            idle_active: false,
+        }
+    }
+
+    pub const fn idle_active(mut self) -> Self {
+        self.idle_active = true;
+        self
+    }
+
+    /// Pin level corresponding to the given logical state
+    fn level(&self, active: bool) -> bool {
+        active == (self.polarity == Polarity::ActiveHigh)
+    }
+}
+
+/// MCP23017 16-bit I2C GPIO expander, for machines that run out of native pins
+pub(crate) struct Mcp23017 {
+    i2c: Mutex<I2c>,
+}
+
+impl Mcp23017 {
+    fn new(address: u16) -> rppal::i2c::Result<Self> {
+        let mut i2c = I2c::new()?;
+        i2c.set_slave_address(address)?;
+        Ok(Self {
+            i2c: Mutex::new(i2c),
+        })
+    }
+
+    fn update_bit(&self, base_register: u8, pin: u8, set: bool) -> rppal::i2c::Result<()> {
+        let register = base_register + pin / 8;
+        let mask = 1 << (pin % 8);
+
+        let i2c = self.i2c.lock().expect("I2C lock poisoned");
+        let value = i2c.smbus_read_byte(register)?;
+        let value = if set { value | mask } else { value & !mask };
+        i2c.smbus_write_byte(register, value)
+    }
+
+    fn read_bit(&self, base_register: u8, pin: u8) -> rppal::i2c::Result<bool> {
+        let register = base_register + pin / 8;
+        let i2c = self.i2c.lock().expect("I2C lock poisoned");
+        Ok(i2c.smbus_read_byte(register)? & (1 << (pin % 8)) != 0)
+    }
+}
+
+/// Hands out input and output pins, opening the expander on first use
+pub struct Pins {
+    gpio: Gpio,
+    expander: Mutex<Option<Arc<Mcp23017>>>,
+    soft_pwm_frequency: f64,
+}
+
+impl Pins {
+    pub fn new(gpio: Gpio) -> Self {
+        Self {
+            gpio,
+            expander: Mutex::new(None),
+            soft_pwm_frequency: SOFT_PWM_FREQUENCY,
+        }
+    }
+
+    /// Sets the software PWM frequency of outputs handed out from now on. Defaults to
+    /// [`SOFT_PWM_FREQUENCY`].
+    pub fn soft_pwm_frequency(mut self, frequency: f64) -> Self {
+        self.soft_pwm_frequency = frequency;
+        self
+    }
+
+    fn expander(&self) -> anyhow::Result<Arc<Mcp23017>> {
+        let mut expander = self.expander.lock().expect("Expander lock poisoned");
+        if let Some(expander) = expander.as_ref() {
+            return Ok(expander.clone());
+        }
+
+        let opened = Arc::new(Mcp23017::new(MCP23017_ADDRESS).context("Failed to open MCP23017")?);
+        *expander = Some(opened.clone());
+        Ok(opened)
+    }
+
+    /// Returns an output in its idle state. The idle level is applied before the pin is switched
+    /// to output mode, so active-low relays don't click on at boot.
+    pub fn output(&self, spec: OutputSpec) -> anyhow::Result<Output> {
+        let idle_high = spec.level(spec.idle_active);
+        let pin = match spec.pin {
+            PinRef::Native(pin) => {
+                let pin = self.gpio.get(pin)?;
+                OutputPinKind::Native(if idle_high {
+                    pin.into_output_high()
+                } else {
+                    pin.into_output_low()
+                })
+            }
+            PinRef::Expander(pin) => {
+                let chip = self.expander()?;
+                chip.update_bit(REG_OLAT, pin, idle_high)?;
+                chip.update_bit(REG_IODIR, pin, false)?;
+                OutputPinKind::Expander { chip, pin }
+            }
+        };
+
+        Ok(Output {
+            pin,
+            spec,
+            pwm: false,
+            soft_pwm_frequency: self.soft_pwm_frequency,
+        })
+    }
+
+    /// Returns an input pin with the pull-up enabled
+    pub fn input_pullup(&self, pin: PinRef) -> anyhow::Result<Input> {
+        Ok(match pin {
+            PinRef::Native(pin) => Input(InputPinKind::Native(
+                self.gpio.get(pin)?.into_input_pullup(),
+            )),
+            PinRef::Expander(pin) => {
+                let chip = self.expander()?;
+                chip.update_bit(REG_IODIR, pin, true)?;
+                chip.update_bit(REG_GPPU, pin, true)?;
+                Input(InputPinKind::Expander { chip, pin })
+            }
+        })
+    }
+}
+
+enum OutputPinKind {
+    Native(OutputPin),
+    Expander { chip: Arc<Mcp23017>, pin: u8 },
+}
+
+/// Output that is switched on and off in logical terms, the polarity is applied underneath
+pub struct Output {
+    pin: OutputPinKind,
+    spec: OutputSpec,
+    /// Whether software PWM is running and has to be stopped before the next write
+    pwm: bool,
+    soft_pwm_frequency: f64,
+}
+
+impl Output {
+    pub fn activate(&mut self) {
+        self.write(self.spec.level(true));
+    }
+
+    pub fn deactivate(&mut self) {
+        self.write(self.spec.level(false));
+    }
+
+    /// Switches the output on at a reduced duty cycle, e.g. to hold a solenoid with less current.
+    /// Expander pins can't be pulsed fast enough and are switched fully on instead.
+    pub fn activate_partial(&mut self, duty_cycle: f64) {
+        let duty_cycle = match self.spec.polarity {
+            Polarity::ActiveHigh => duty_cycle,
+            Polarity::ActiveLow => 1.0 - duty_cycle,
+        };
+
+        if let OutputPinKind::Native(pin) = &mut self.pin {
+            match pin.set_pwm_frequency(self.soft_pwm_frequency, duty_cycle) {
+                Ok(()) => {
+                    self.pwm = true;
+                    return;
+                }
+                Err(e) => warn!("Failed to start PWM, switching fully on: {}", e),
+            }
+        }
+        self.activate();
+    }
+
+    /// Emits pulses of `pulse_width` every `period`, e.g. to position a hobby servo. Only works on
+    /// native pins, the polarity is ignored.
+    pub fn pulse(&mut self, period: Duration, pulse_width: Duration) {
+        let OutputPinKind::Native(pin) = &mut self.pin else {
+            warn!("Expander pins can't generate pulses");
+            return;
+        };
+        match pin.set_pwm(period, pulse_width) {
+            Ok(()) => self.pwm = true,
+            Err(e) => warn!("Failed to start pulses: {}", e),
+        }
+    }
+
+    /// Returns the output to its configured safe state
+    pub fn set_idle(&mut self) {
+        self.write(self.spec.level(self.spec.idle_active));
+    }
+
+    fn write(&mut self, high: bool) {
+        if let (OutputPinKind::Native(pin), true) = (&mut self.pin, self.pwm) {
+            if let Err(e) = pin.clear_pwm() {
+                warn!("Failed to stop PWM: {}", e);
+            }
+            self.pwm = false;
+        }
+
+        match &mut self.pin {
+            OutputPinKind::Native(pin) if high => pin.set_high(),
+            OutputPinKind::Native(pin) => pin.set_low(),
+            OutputPinKind::Expander { chip, pin } => {
+                if let Err(e) = chip.update_bit(REG_OLAT, *pin, high) {
+                    warn!("Failed to set expander pin {} to {}: {}", pin, high, e);
+                }
+            }
+        }
+    }
+}
+
+enum InputPinKind {
+    Native(InputPin),
+    Expander { chip: Arc<Mcp23017>, pin: u8 },
+}
+
+/// Input pin on either the Pi's header or the expander
+pub struct Input(InputPinKind);
+
+impl Input {
+    pub fn is_high(&self) -> bool {
+        match &self.0 {
+            InputPinKind::Native(pin) => pin.is_high(),
+            // Treat a failed read like the idle state of a pulled-up input
+            InputPinKind::Expander { chip, pin } => {
+                chip.read_bit(REG_GPIO, *pin).unwrap_or_else(|e| {
+                    warn!("Failed to read expander pin {}: {}", pin, e);
+                    true
+                })
+            }
+        }
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.is_high()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn active_high_drives_high_when_on() {
+        let spec = OutputSpec::active_high(PinRef::Native(4));
+        assert!(spec.level(true));
+        assert!(!spec.level(false));
+    }
+
+    #[test]
+    fn active_low_drives_low_when_on() {
+        let spec = OutputSpec::active_low(PinRef::Native(4));
+        assert!(!spec.level(true));
+        assert!(spec.level(false));
+    }
+
+    #[test]
+    fn idle_level_follows_polarity() {
+        // A fail-secure lock on an active-low relay rests pulled low
+        let lock = OutputSpec::active_low(PinRef::Expander(3)).idle_active();
+        assert!(!lock.level(lock.idle_active));
+
+        let motor = OutputSpec::active_low(PinRef::Native(4));
+        assert!(motor.level(motor.idle_active));
+    }
+}
@@ -0,0 +1,141 @@
+use crate::api_auth::{API_TOKEN_ENV, ApiAuth, AuthError};
+use crate::events::{Event, EventSubscriber};
+use axum::Router;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use fedimint_core::anyhow::{self, Context};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+/// Environment variable with the address to serve Prometheus metrics on, e.g. `0.0.0.0:9100`.
+/// Metrics are still recorded but not exported if it is unset.
+pub const METRICS_ADDR_ENV: &str = "CANDYPI_METRICS_ADDR";
+
+/// Buckets of all `*_seconds` histograms, from a fast screen redraw to a slow federation
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Histograms are drained this often while nobody scrapes them
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Exporter {
+    handle: PrometheusHandle,
+    /// Unset without an API token, scrapes are answered to anyone then
+    auth: Option<ApiAuth>,
+}
+
+/// Starts the Prometheus exporter if an address was configured. With [`API_TOKEN_ENV`] set,
+/// scrapes need the token like the API, since sales figures shouldn't be readable by everyone
+/// on the venue's network.
+pub async fn init_from_env() -> anyhow::Result<()> {
+    let Ok(addr) = std::env::var(METRICS_ADDR_ENV) else {
+        return Ok(());
+    };
+    let addr: SocketAddr = addr
+        .parse()
+        .with_context(|| format!("Invalid {METRICS_ADDR_ENV}"))?;
+    let auth = ApiAuth::from_env().map_err(anyhow::Error::msg)?;
+    if auth.is_none() {
+        warn!(
+            "{} not set, metrics can be read without a token",
+            API_TOKEN_ENV
+        );
+    }
+
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), &LATENCY_BUCKETS)?
+        .install_recorder()
+        .context("Failed to install metrics recorder")?;
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    let router = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(Arc::new(Exporter { handle, auth }));
+    tokio::spawn(async move {
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        if let Err(e) = axum::serve(listener, service).await {
+            warn!("Metrics exporter stopped: {}", e);
+        }
+    });
+    info!("Serving metrics on http://{}/metrics", addr);
+    Ok(())
+}
+
+async fn scrape(
+    State(exporter): State<Arc<Exporter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(auth) = &exporter.auth {
+        let authorization = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        match auth.check(peer.ip(), authorization) {
+            Ok(()) => {}
+            Err(AuthError::RateLimited) => return StatusCode::TOO_MANY_REQUESTS.into_response(),
+            Err(AuthError::Unauthorized) => return StatusCode::UNAUTHORIZED.into_response(),
+        }
+    }
+    exporter.handle.render().into_response()
+}
+
+/// Adds a measurement in seconds to the histogram `name`
+pub fn record_duration(name: &'static str, duration: Duration) {
+    metrics::histogram!(name).record(duration.as_secs_f64());
+}
+
+/// Counts events from the bus in a background task
+pub fn spawn_event_metrics(mut events: EventSubscriber) {
+    tokio::spawn(async move {
+        let mut dispense_started = None;
+        while let Some(event) = events.recv().await {
+            match event {
+                Event::InvoiceCreated { .. } => {
+                    metrics::counter!("candypi_invoices_total").increment(1)
+                }
+                Event::PaymentReceived { amount_msat } => {
+                    metrics::counter!("candypi_payments_total").increment(1);
+                    metrics::counter!("candypi_received_msat_total").increment(amount_msat);
+                }
+                Event::DispenseStarted => dispense_started = Some(Instant::now()),
+                Event::DispenseDone { completed } => {
+                    let result = if completed { "completed" } else { "aborted" };
+                    metrics::counter!("candypi_dispenses_total", "result" => result).increment(1);
+                    if let Some(started) = dispense_started.take() {
+                        record_duration("candypi_dispense_seconds", started.elapsed());
+                    }
+                }
+                Event::DispenseJammed => metrics::counter!("candypi_jams_total").increment(1),
+                Event::ButtonPressed(_) | Event::ButtonHeld(_) => {
+                    metrics::counter!("candypi_button_presses_total").increment(1)
+                }
+                Event::TamperAlarm => metrics::counter!("candypi_tamper_alarms_total").increment(1),
+                Event::EmergencyStop => {
+                    metrics::counter!("candypi_emergency_stops_total").increment(1)
+                }
+                Event::HopperLow { low } => {
+                    metrics::gauge!("candypi_hopper_low").set(if low { 1.0 } else { 0.0 })
+                }
+                Event::NetworkChanged { .. } | Event::AlarmCleared | Event::ShuttingDown => {}
+            }
+        }
+    });
+}
@@ -0,0 +1,161 @@
+use crate::retry::{self, Retry};
+use fedimint_core::anyhow::{self, Context, ensure};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::warn;
+
+/// Answers with the BTC price in all major currencies, e.g. `{"USD": 67000, "EUR": 62000, ..}`
+pub const DEFAULT_RATE_URL: &str = "https://mempool.space/api/v1/prices";
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const SATS_PER_BTC: f64 = 100_000_000.0;
+
+/// Older rates are considered stale unless configured otherwise
+const DEFAULT_MAX_RATE_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Fiat currency and where its exchange rate comes from
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FiatConfig {
+    /// Currency code as used by the rate API, e.g. `EUR`
+    pub currency: String,
+    /// Any URL answering with a JSON object mapping currency codes to the BTC price, mempool.space
+    /// unless set
+    pub rate_url: Option<String>,
+    /// Fiat prices fall back to the fixed sats price once the last rate is older than this
+    pub max_rate_age_secs: Option<u64>,
+}
+
+impl FiatConfig {
+    pub fn max_rate_age(&self) -> Duration {
+        self.max_rate_age_secs
+            .map_or(DEFAULT_MAX_RATE_AGE, Duration::from_secs)
+    }
+}
+
+/// Price given in fiat, e.g. `0.50 EUR` in the config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FiatPrice {
+    pub amount: f64,
+    pub currency: String,
+}
+
+impl FromStr for FiatPrice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (amount, currency) = s
+            .trim()
+            .split_once(' ')
+            .context("Fiat price must look like \"0.50 EUR\"")?;
+        let amount: f64 = amount.parse().context("Invalid fiat amount")?;
+        ensure!(
+            amount.is_finite() && amount > 0.0,
+            "Fiat price must be positive"
+        );
+        Ok(Self {
+            amount,
+            currency: currency.trim().to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for FiatPrice {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// BTC price in the configured currency at one point in time
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub fiat_per_btc: f64,
+    pub fetched_at: Instant,
+}
+
+impl Rate {
+    pub fn sats_to_fiat(&self, sats: u64) -> f64 {
+        sats as f64 / SATS_PER_BTC * self.fiat_per_btc
+    }
+
+    /// Rounded up, so the machine never sells below the fiat price
+    pub fn fiat_to_sats(&self, amount: f64) -> u64 {
+        (amount / self.fiat_per_btc * SATS_PER_BTC).ceil() as u64
+    }
+
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        self.fetched_at.elapsed() <= max_age
+    }
+}
+
+/// Fetches the exchange rate periodically, so the current one is at hand when an invoice is
+/// created without waiting for the network
+pub struct RateProvider {
+    client: reqwest::Client,
+    url: String,
+    currency: String,
+}
+
+impl RateProvider {
+    pub fn new(config: &FiatConfig) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: config
+                .rate_url
+                .clone()
+                .unwrap_or_else(|| DEFAULT_RATE_URL.to_string()),
+            currency: config.currency.clone(),
+        }
+    }
+
+    pub async fn fetch(&self) -> anyhow::Result<f64> {
+        let prices: HashMap<String, serde_json::Value> = Retry::NETWORK
+            .run(
+                "fetch exchange rate",
+                retry::is_transient_http,
+                async || self.client.get(&self.url).send().await?.error_for_status(),
+            )
+            .await?
+            .json()
+            .await
+            .context("Invalid exchange rate response")?;
+        prices
+            .get(&self.currency)
+            .and_then(serde_json::Value::as_f64)
+            .filter(|price| *price > 0.0)
+            .with_context(|| format!("No {} price in exchange rate response", self.currency))
+    }
+
+    /// Refreshes the rate in a background task, the receiver holds the latest one. A failed
+    /// refresh keeps the previous rate, its age tells how stale it is.
+    pub fn spawn(self) -> watch::Receiver<Option<Rate>> {
+        let (tx, rx) = watch::channel(None);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+            loop {
+                interval.tick().await;
+                match self.fetch().await {
+                    Ok(fiat_per_btc) => {
+                        let rate = Rate {
+                            fiat_per_btc,
+                            fetched_at: Instant::now(),
+                        };
+                        if tx.send(Some(rate)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Failed to fetch exchange rate: {:#}", e),
+                }
+            }
+        });
+        rx
+    }
+}
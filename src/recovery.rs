@@ -0,0 +1,136 @@
+use crate::fedimint::Fedimint;
+use crate::sales;
+use fedimint_core::anyhow;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescriptionRef};
+use std::collections::HashSet;
+use std::path::Path;
+use std::task::Poll;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Invoices older than this are expired, no payment to them can have been missed
+const LOOKBACK: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// A payment that was funded before the restart gets claimed by the client in the meantime, an
+/// unpaid invoice never completes. Bounds the wait for all unsettled invoices together.
+const CLAIM_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Customers who paid this recently may still be waiting at the machine
+const DISPENSE_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// What to do about a payment that was received but never recorded as a sale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Paid moments before the restart, the customer is likely still there
+    Dispense,
+    /// Too long ago to hand out candy to whoever stands in front of the machine now
+    Refund,
+}
+
+/// Lightning payment received before the machine went down, but not dispensed
+#[derive(Debug, Clone)]
+pub struct UnfinishedSale {
+    /// Invoice description, which is the product name
+    pub product: String,
+    pub amount_msat: u64,
+    pub payment_hash: String,
+    pub recovery: Recovery,
+}
+
+/// Finds invoices of the last two hours that were paid but have no sale in the ledger at
+/// `ledger_path`, e.g. because the Pi rebooted between payment and dispense
+pub async fn find_unfinished_sales(
+    fedimint: &Fedimint,
+    ledger_path: &Path,
+) -> anyhow::Result<Vec<UnfinishedSale>> {
+    let recorded: HashSet<String> = sales::read(ledger_path)?
+        .into_iter()
+        .filter_map(|sale| sale.payment_hash)
+        .collect();
+
+    let since = SystemTime::now() - LOOKBACK;
+    let mut paid = Vec::new();
+    let mut unsettled = Vec::new();
+    for (created, invoice) in fedimint.recent_receives(since).await {
+        if recorded.contains(&invoice.payment_hash().to_string()) {
+            continue;
+        }
+        match fedimint.receive_outcome(invoice.payment_hash()).await {
+            Ok(Some(true)) => paid.push((created, invoice)),
+            // Canceled, e.g. expired unpaid
+            Ok(Some(false)) | Err(_) => {}
+            Ok(None) => unsettled.push((created, invoice)),
+        }
+    }
+    let claimed = await_claims(fedimint, &unsettled).await;
+    paid.extend(
+        unsettled
+            .into_iter()
+            .zip(claimed)
+            .filter_map(|(receive, claimed)| claimed.then_some(receive)),
+    );
+
+    let mut unfinished = Vec::new();
+    for (created, invoice) in paid {
+        let payment_hash = invoice.payment_hash().to_string();
+        let recent = created
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed < DISPENSE_WINDOW);
+        let sale = UnfinishedSale {
+            product: product_name(&invoice),
+            amount_msat: invoice.amount_milli_satoshis().unwrap_or_default(),
+            payment_hash,
+            recovery: if recent {
+                Recovery::Dispense
+            } else {
+                Recovery::Refund
+            },
+        };
+        warn!(
+            hash = %sale.payment_hash,
+            recovery = ?sale.recovery,
+            "Found paid invoice without a sale"
+        );
+        unfinished.push(sale);
+    }
+    if unfinished.is_empty() {
+        info!("No unfinished sales");
+    }
+    Ok(unfinished)
+}
+
+/// Waits for the unsettled invoices together, up to [`CLAIM_TIMEOUT`] in total. Returns whether
+/// each was paid, those still unsettled by then weren't.
+async fn await_claims(fedimint: &Fedimint, receives: &[(SystemTime, Bolt11Invoice)]) -> Vec<bool> {
+    let mut checks: Vec<_> = receives
+        .iter()
+        .map(|(_, invoice)| {
+            Some(Box::pin(
+                fedimint.await_payment_by_hash(invoice.payment_hash()),
+            ))
+        })
+        .collect();
+    let mut claimed = vec![false; receives.len()];
+    let all_settled = std::future::poll_fn(|cx| {
+        for (check, claimed) in checks.iter_mut().zip(claimed.iter_mut()) {
+            if let Some(Poll::Ready(result)) = check.as_mut().map(|check| check.as_mut().poll(cx)) {
+                *claimed = result.is_ok();
+                *check = None;
+            }
+        }
+        if checks.iter().all(Option::is_none) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    });
+    let _ = tokio::time::timeout(CLAIM_TIMEOUT, all_settled).await;
+    claimed
+}
+
+fn product_name(invoice: &Bolt11Invoice) -> String {
+    match invoice.description() {
+        Bolt11InvoiceDescriptionRef::Direct(description) => description.to_string(),
+        Bolt11InvoiceDescriptionRef::Hash(_) => String::new(),
+    }
+}
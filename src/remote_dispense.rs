@@ -0,0 +1,296 @@
+use ed25519_dalek::{Signature, VerifyingKey};
+use fedimint_core::anyhow::{self, Context, ensure};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Requests whose timestamp is further off than this are rejected. This also bounds how long
+/// nonces have to be remembered, older ones fail the timestamp check anyway.
+const MAX_REQUEST_AGE_SECS: u64 = 300;
+
+const MAX_NONCE_LENGTH: usize = 64;
+
+/// Command received over the network, signed by the operator
+#[derive(Debug, Deserialize)]
+pub struct SignedRequest {
+    /// `device_id` of the machine the request is meant for, so it can't be replayed against
+    /// other machines trusting the same operator key
+    pub device: String,
+    /// Unique per request, e.g. 16 random bytes in hex
+    pub nonce: String,
+    /// Unix time in seconds when the request was signed
+    pub timestamp: u64,
+    /// Hex encoded ed25519 signature over the [`SignedAction`]'s message
+    pub signature: String,
+}
+
+/// What a [`SignedRequest`] asks for. Every action has its own message prefix, so a signature
+/// for one can't be passed off as another.
+#[derive(Debug, Clone, Copy)]
+pub enum SignedAction<'a> {
+    /// Signed as `candypi-dispense:<device>:<timestamp>:<nonce>`
+    Dispense,
+    /// Signed as `candypi-join-federation:<device>:<timestamp>:<nonce>:<invite>`
+    JoinFederation { invite: &'a str },
+}
+
+impl SignedAction<'_> {
+    fn signed_message(&self, request: &SignedRequest) -> String {
+        let SignedRequest {
+            device,
+            nonce,
+            timestamp,
+            ..
+        } = request;
+        match self {
+            SignedAction::Dispense => format!("candypi-dispense:{device}:{timestamp}:{nonce}"),
+            SignedAction::JoinFederation { invite } => {
+                format!("candypi-join-federation:{device}:{timestamp}:{nonce}:{invite}")
+            }
+        }
+    }
+}
+
+/// Verifies remote requests and remembers their nonces, so a request captured on the venue
+/// network can't be replayed to empty the hopper or move the funds.
+pub struct RemoteAuthorizer {
+    operator_key: VerifyingKey,
+    replays: ReplayGuard,
+}
+
+impl RemoteAuthorizer {
+    pub fn new(operator_key: VerifyingKey, device_id: String) -> Self {
+        Self {
+            operator_key,
+            replays: ReplayGuard::new(device_id),
+        }
+    }
+
+    pub fn authorize(&self, request: &SignedRequest, action: SignedAction) -> anyhow::Result<()> {
+        self.replays
+            .check(&request.device, &request.nonce, request.timestamp)?;
+
+        let signature_bytes =
+            hex::decode(&request.signature).context("Request signature is not valid hex")?;
+        let signature =
+            Signature::from_slice(&signature_bytes).context("Malformed request signature")?;
+        self.operator_key
+            .verify_strict(action.signed_message(request).as_bytes(), &signature)
+            .context("Request signature does not match")?;
+
+        self.replays.remember(&request.nonce, request.timestamp)
+    }
+}
+
+/// Checks that signed messages name this machine, are recent and weren't seen before, so one
+/// captured on the network can't be replayed later or against other machines of the fleet
+pub struct ReplayGuard {
+    device_id: String,
+    /// Nonces are only kept in memory, messages signed before the start could be replayed
+    /// after a restart and are rejected
+    started: u64,
+    seen_nonces: Mutex<HashMap<String, u64>>,
+}
+
+impl ReplayGuard {
+    pub fn new(device_id: String) -> Self {
+        Self {
+            device_id,
+            started: unix_now(),
+            seen_nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Name of this machine that messages have to carry
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Checks everything but the signature, before it is verified
+    pub fn check(&self, device: &str, nonce: &str, timestamp: u64) -> anyhow::Result<()> {
+        ensure!(
+            device == self.device_id,
+            "Request is meant for another machine"
+        );
+        ensure!(
+            timestamp.abs_diff(unix_now()) <= MAX_REQUEST_AGE_SECS,
+            "Request timestamp is too far off, check the clocks"
+        );
+        ensure!(
+            timestamp >= self.started,
+            "Request was signed before the dispenser started, sign a new one"
+        );
+        ensure!(
+            !nonce.is_empty() && nonce.len() <= MAX_NONCE_LENGTH,
+            "Request nonce must be 1 to {} characters",
+            MAX_NONCE_LENGTH
+        );
+        Ok(())
+    }
+
+    /// Remembers the nonce of a message whose signature was valid, fails if it was used before.
+    /// Nonces of invalid messages aren't kept, otherwise anyone could fill up the map.
+    pub fn remember(&self, nonce: &str, timestamp: u64) -> anyhow::Result<()> {
+        let now = unix_now();
+        let mut seen_nonces = self.seen_nonces.lock().expect("Nonce lock poisoned");
+        seen_nonces.retain(|_, timestamp| timestamp.abs_diff(now) <= MAX_REQUEST_AGE_SECS);
+        ensure!(
+            seen_nonces.insert(nonce.to_string(), timestamp).is_none(),
+            "Request was already used"
+        );
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{operator, stranger};
+    use SignedAction::{Dispense, JoinFederation};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn authorizer() -> RemoteAuthorizer {
+        RemoteAuthorizer::new(operator().verifying_key(), "lobby".to_string())
+    }
+
+    fn request(key: &SigningKey, nonce: &str, timestamp: u64) -> SignedRequest {
+        sign(
+            key,
+            Dispense,
+            SignedRequest {
+                device: "lobby".to_string(),
+                nonce: nonce.to_string(),
+                timestamp,
+                signature: String::new(),
+            },
+        )
+    }
+
+    fn sign(key: &SigningKey, action: SignedAction, mut request: SignedRequest) -> SignedRequest {
+        let message = action.signed_message(&request);
+        request.signature = hex::encode(key.sign(message.as_bytes()).to_bytes());
+        request
+    }
+
+    #[test]
+    fn accepts_signed_request() {
+        let authorizer = authorizer();
+        authorizer
+            .authorize(&request(&operator(), "01", unix_now()), Dispense)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_replay() {
+        let authorizer = authorizer();
+        let request = request(&operator(), "01", unix_now());
+        authorizer.authorize(&request, Dispense).unwrap();
+        let error = authorizer
+            .authorize(&request, Dispense)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("already used"), "{error}");
+    }
+
+    #[test]
+    fn rejects_stale_timestamp() {
+        let authorizer = authorizer();
+        let stale = unix_now() - MAX_REQUEST_AGE_SECS - 60;
+        let error = authorizer
+            .authorize(&request(&operator(), "01", stale), Dispense)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("too far off"), "{error}");
+    }
+
+    #[test]
+    fn rejects_request_signed_before_start() {
+        let authorizer = authorizer();
+        // Still recent enough, but its nonce may have been used before a restart
+        let before = authorizer.started - 10;
+        let error = authorizer
+            .authorize(&request(&operator(), "01", before), Dispense)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("before the dispenser started"), "{error}");
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let authorizer = authorizer();
+        let error = authorizer
+            .authorize(&request(&stranger(), "01", unix_now()), Dispense)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("does not match"), "{error}");
+
+        // A signature over another nonce doesn't carry over either
+        let mut tampered = request(&operator(), "01", unix_now());
+        tampered.nonce = "02".to_string();
+        assert!(authorizer.authorize(&tampered, Dispense).is_err());
+    }
+
+    #[test]
+    fn rejected_requests_keep_their_nonce_usable() {
+        let authorizer = authorizer();
+        assert!(
+            authorizer
+                .authorize(&request(&stranger(), "01", unix_now()), Dispense)
+                .is_err()
+        );
+        authorizer
+            .authorize(&request(&operator(), "01", unix_now()), Dispense)
+            .unwrap();
+    }
+
+    #[test]
+    fn rejects_request_for_another_machine() {
+        let authorizer = authorizer();
+        let mut foyer = request(&operator(), "01", unix_now());
+        foyer.device = "foyer".to_string();
+        let mut foyer = sign(&operator(), Dispense, foyer);
+        let error = authorizer
+            .authorize(&foyer, Dispense)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("another machine"), "{error}");
+
+        // The signature doesn't carry over when the request is relabeled for this machine
+        foyer.device = "lobby".to_string();
+        assert!(authorizer.authorize(&foyer, Dispense).is_err());
+    }
+
+    #[test]
+    fn join_federation_signature_covers_invite() {
+        let authorizer = authorizer();
+        let join = JoinFederation { invite: "fed11a" };
+        let signed = sign(&operator(), join, request(&operator(), "01", unix_now()));
+        authorizer.authorize(&signed, join).unwrap();
+
+        let signed = sign(&operator(), join, request(&operator(), "02", unix_now()));
+        let error = authorizer
+            .authorize(&signed, JoinFederation { invite: "fed11b" })
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("does not match"), "{error}");
+    }
+
+    #[test]
+    fn signatures_do_not_carry_over_between_actions() {
+        let authorizer = authorizer();
+        let dispense = request(&operator(), "01", unix_now());
+        let join = JoinFederation { invite: "fed11a" };
+        assert!(authorizer.authorize(&dispense, join).is_err());
+
+        let join_request = sign(&operator(), join, request(&operator(), "02", unix_now()));
+        assert!(authorizer.authorize(&join_request, Dispense).is_err());
+    }
+}
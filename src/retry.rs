@@ -0,0 +1,128 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::Display;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+use tracing::warn;
+
+/// How often and how patiently a fallible hardware or network operation is retried. The delay
+/// doubles after every failed attempt up to `max_delay`.
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of each delay that is randomized, so concurrent retries don't run in lockstep
+    pub jitter: f32,
+}
+
+impl Retry {
+    /// Glitches on the SPI bus are either gone after a few milliseconds or permanent
+    pub const SPI: Retry = Retry {
+        attempts: 3,
+        initial_delay: Duration::from_millis(5),
+        max_delay: Duration::from_millis(20),
+        jitter: 0.0,
+    };
+
+    /// I2C and single wire sensors regularly fail single reads
+    pub const SENSOR: Retry = Retry {
+        attempts: 4,
+        initial_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(500),
+        jitter: 0.2,
+    };
+
+    /// Venue Wi-Fi drops out for seconds at a time
+    pub const NETWORK: Retry = Retry {
+        attempts: 5,
+        initial_delay: Duration::from_millis(500),
+        max_delay: Duration::from_secs(8),
+        jitter: 0.5,
+    };
+
+    /// Runs `operation` until it succeeds, fails with an error `is_transient` rejects or runs out
+    /// of attempts. Failed attempts are logged with `what` describing the operation.
+    pub async fn run<T, E: Display>(
+        &self,
+        what: &str,
+        is_transient: impl Fn(&E) -> bool,
+        mut operation: impl AsyncFnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut attempt = 1;
+        loop {
+            match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    warn!(
+                        "Failed to {} (attempt {}/{}): {}",
+                        what, attempt, self.attempts, e
+                    );
+                    tokio::time::sleep(self.delay(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Like [`Retry::run`], for dedicated threads outside the async runtime
+    pub fn run_blocking<T, E: Display>(
+        &self,
+        what: &str,
+        is_transient: impl Fn(&E) -> bool,
+        mut operation: impl FnMut() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut attempt = 1;
+        loop {
+            match operation() {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.attempts && is_transient(&e) => {
+                    warn!(
+                        "Failed to {} (attempt {}/{}): {}",
+                        what, attempt, self.attempts, e
+                    );
+                    std::thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(self.max_delay);
+
+        // A freshly keyed hasher is random enough for jitter without pulling in a RNG
+        let random = RandomState::new().build_hasher().finish() as f32 / u64::MAX as f32;
+        backoff.mul_f32(1.0 - self.jitter.clamp(0.0, 1.0) * random)
+    }
+}
+
+/// Retries every error, for operations without distinguishable permanent failures
+pub fn always<E>(_: &E) -> bool {
+    true
+}
+
+/// I/O errors that may go away on their own, e.g. a missed edge on a sensor line
+pub fn is_transient_io(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+
+    matches!(
+        e.kind(),
+        ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock
+    ) || e.raw_os_error() == Some(libc::EIO)
+}
+
+/// Connection problems, timeouts and server side errors, but not e.g. a wrong URL
+pub fn is_transient_http(e: &reqwest::Error) -> bool {
+    if e.is_connect() || e.is_timeout() {
+        return true;
+    }
+    e.status().is_some_and(|status| {
+        status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+    })
+}
@@ -0,0 +1,157 @@
+use chrono::{DateTime, Datelike, NaiveDate, TimeDelta, Timelike, Utc};
+use fedimint_core::anyhow::{self, Context, ensure};
+use rppal::i2c::I2c;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Environment variable that, if set to `1`, enables the DS3231 real-time clock on the I2C bus
+pub const RTC_ENV: &str = "CANDYPI_RTC_DS3231";
+
+const DS3231_ADDRESS: u16 = 0x68;
+const REG_TIME: u8 = 0x00;
+const REG_STATUS: u8 = 0x0F;
+const STATUS_OSCILLATOR_STOPPED: u8 = 0x80;
+
+/// Only correct the system clock if it is off by more than this
+const MAX_CLOCK_DRIFT: TimeDelta = TimeDelta::seconds(120);
+
+/// How often the RTC is updated from the system clock while NTP keeps the latter accurate
+const RTC_SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Battery backed clock keeping time through power cycles at venues without reliable NTP
+pub struct Ds3231 {
+    i2c: I2c,
+}
+
+impl Ds3231 {
+    pub fn new() -> rppal::i2c::Result<Self> {
+        let mut i2c = I2c::new()?;
+        i2c.set_slave_address(DS3231_ADDRESS)?;
+        Ok(Self { i2c })
+    }
+
+    /// Reads the time, which is always kept in UTC
+    pub fn read_time(&mut self) -> anyhow::Result<DateTime<Utc>> {
+        let status = self.i2c.smbus_read_byte(REG_STATUS)?;
+        ensure!(
+            status & STATUS_OSCILLATOR_STOPPED == 0,
+            "RTC oscillator stopped, time is invalid (battery empty?)"
+        );
+
+        let mut regs = [0u8; 7];
+        self.i2c.block_read(REG_TIME, &mut regs)?;
+
+        let second = from_bcd(regs[0] & 0x7F);
+        let minute = from_bcd(regs[1] & 0x7F);
+        let hour = if regs[2] & 0x40 != 0 {
+            // 12 hour mode, bit 5 is PM
+            from_bcd(regs[2] & 0x1F) % 12 + if regs[2] & 0x20 != 0 { 12 } else { 0 }
+        } else {
+            from_bcd(regs[2] & 0x3F)
+        };
+        let day = from_bcd(regs[4] & 0x3F);
+        let month = from_bcd(regs[5] & 0x1F);
+        let century = if regs[5] & 0x80 != 0 { 100 } else { 0 };
+        let year = 2000 + century + i32::from(from_bcd(regs[6]));
+
+        let time = NaiveDate::from_ymd_opt(year, month.into(), day.into())
+            .and_then(|date| date.and_hms_opt(hour.into(), minute.into(), second.into()))
+            .context("RTC returned an invalid date")?;
+        Ok(time.and_utc())
+    }
+
+    /// Sets the time and clears the oscillator stopped flag
+    pub fn set_time(&mut self, time: DateTime<Utc>) -> anyhow::Result<()> {
+        let year = time.year() - 2000;
+        ensure!(
+            (0..200).contains(&year),
+            "Year not representable by the RTC"
+        );
+
+        let regs = [
+            to_bcd(time.second() as u8),
+            to_bcd(time.minute() as u8),
+            to_bcd(time.hour() as u8),
+            to_bcd(time.weekday().number_from_monday() as u8),
+            to_bcd(time.day() as u8),
+            to_bcd(time.month() as u8) | if year >= 100 { 0x80 } else { 0 },
+            to_bcd((year % 100) as u8),
+        ];
+        self.i2c.block_write(REG_TIME, &regs)?;
+
+        let status = self.i2c.smbus_read_byte(REG_STATUS)?;
+        self.i2c
+            .smbus_write_byte(REG_STATUS, status & !STATUS_OSCILLATOR_STOPPED)?;
+        Ok(())
+    }
+
+    /// Sets the system clock from the RTC unless NTP already took care of it. Has to run before
+    /// anything that depends on the time, like TLS certificate checks or invoice expiry.
+    pub fn restore_system_time(&mut self) -> anyhow::Result<()> {
+        if ntp_synchronized() {
+            return Ok(());
+        }
+
+        let rtc_time = self.read_time()?;
+        let drift = rtc_time - Utc::now();
+        if drift.abs() <= MAX_CLOCK_DRIFT {
+            return Ok(());
+        }
+
+        info!(
+            "System clock is off by {}s and not synchronized, setting it from RTC to {}",
+            drift.num_seconds(),
+            rtc_time
+        );
+        set_system_time(rtc_time)
+    }
+
+    /// Periodically copies the system time to the RTC while NTP is synchronized, so it stays
+    /// accurate for the next boot without network
+    pub fn spawn_sync(mut self) {
+        tokio::spawn(async move {
+            loop {
+                if ntp_synchronized() {
+                    if let Err(e) = self.set_time(Utc::now()) {
+                        warn!("Failed to update RTC: {}", e);
+                    }
+                }
+                tokio::time::sleep(RTC_SYNC_INTERVAL).await;
+            }
+        });
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Asks the kernel whether some time daemon disciplines the clock
+fn ntp_synchronized() -> bool {
+    // SAFETY: adjtimex with `modes == 0` only reads the kernel clock state into `timex`
+    let state = unsafe {
+        let mut timex: libc::timex = std::mem::zeroed();
+        libc::adjtimex(&mut timex)
+    };
+    state >= 0 && state != libc::TIME_ERROR
+}
+
+fn set_system_time(time: DateTime<Utc>) -> anyhow::Result<()> {
+    let timespec = libc::timespec {
+        tv_sec: time.timestamp() as libc::time_t,
+        tv_nsec: time.timestamp_subsec_nanos() as libc::c_long,
+    };
+
+    // SAFETY: `timespec` is a valid, initialized value that outlives the call
+    let result = unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &timespec) };
+    ensure!(
+        result == 0,
+        "Failed to set system time: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
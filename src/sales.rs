@@ -0,0 +1,258 @@
+use chrono::Local;
+use fedimint_core::anyhow::{self, Context, bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PaymentMethod {
+    Lightning,
+    Ecash,
+    /// Pegged in through the federation's on-chain wallet
+    Onchain,
+    /// Fiat coins, recorded at their value in sats at the time of the sale
+    Coins,
+}
+
+/// One paid dispense
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sale {
+    /// RFC 3339 in local time
+    pub timestamp: String,
+    pub product: String,
+    pub amount_msat: u64,
+    pub method: PaymentMethod,
+    /// Only Lightning payments have one
+    pub payment_hash: Option<String>,
+    /// False if the dispense was cut short, e.g. by the emergency stop
+    pub dispensed: bool,
+    /// The mechanism jammed and nothing came out
+    #[serde(default)]
+    pub jammed: bool,
+    /// Paid back as ecash after a jam
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refunded_msat: Option<u64>,
+}
+
+impl Sale {
+    /// A sale happening now
+    pub fn now(
+        product: &str,
+        amount_msat: u64,
+        method: PaymentMethod,
+        payment_hash: Option<String>,
+        dispensed: bool,
+    ) -> Self {
+        Self {
+            timestamp: Local::now().to_rfc3339(),
+            product: product.to_string(),
+            amount_msat,
+            method,
+            payment_hash,
+            dispensed,
+            jammed: false,
+            refunded_msat: None,
+        }
+    }
+}
+
+/// Every sale as one JSON line, for reconciling earnings with what left the hopper
+#[derive(Clone)]
+pub struct SalesLedger {
+    file: Arc<Mutex<File>>,
+}
+
+impl SalesLedger {
+    /// Defaults to `$XDG_DATA_HOME/candypi/sales.jsonl`, next to the audit log
+    pub fn default_path() -> PathBuf {
+        xdg::BaseDirectories::new()
+            .data_home
+            .expect("Could not determine XDG data home")
+            .join("candypi/sales.jsonl")
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Appends a sale and syncs it right away, sales are rare enough to spare the SD card
+    /// batching. Failures are only logged, the audit log still has the payment.
+    pub fn record(&self, sale: &Sale) {
+        let mut line = serde_json::to_string(sale).expect("Sales serialize to JSON");
+        line.push('\n');
+
+        let mut file = self.file.lock().expect("Sales ledger lock poisoned");
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|()| file.sync_data())
+        {
+            error!("Failed to record sale: {}", e);
+        }
+    }
+}
+
+/// Reads all recorded sales, an incomplete last line from a power cut is skipped. A sale recorded
+/// again with the same payment hash, e.g. once it was refunded, replaces the earlier line.
+pub fn read(path: &Path) -> anyhow::Result<Vec<Sale>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    let mut sales = Vec::new();
+    let mut lines = content.lines().enumerate().peekable();
+    while let Some((idx, line)) = lines.next() {
+        match serde_json::from_str::<Sale>(line) {
+            Ok(sale) => {
+                let earlier = sale.payment_hash.as_ref().and_then(|hash| {
+                    sales
+                        .iter()
+                        .position(|earlier: &Sale| earlier.payment_hash.as_ref() == Some(hash))
+                });
+                match earlier {
+                    Some(idx) => sales[idx] = sale,
+                    None => sales.push(sale),
+                }
+            }
+            Err(_) if lines.peek().is_none() && !content.ends_with('\n') => break,
+            Err(e) => return Err(e).with_context(|| format!("Invalid sale on line {}", idx + 1)),
+        }
+    }
+    Ok(sales)
+}
+
+/// The latest Lightning sale that took the customer's money without handing out candy and
+/// wasn't refunded yet
+pub fn last_refundable(sales: &[Sale]) -> Option<&Sale> {
+    sales.iter().rev().find(|sale| {
+        sale.method == PaymentMethod::Lightning
+            && sale.payment_hash.is_some()
+            && (!sale.dispensed || sale.jammed)
+            && sale.refunded_msat.is_none()
+    })
+}
+
+/// The sale paid by the invoice with `payment_hash`, if it may be refunded. Fails for payments
+/// without a recorded sale and for sales refunded already, so a customer can't be paid back
+/// twice. Sales that handed out candy are only refunded with `force`.
+pub fn refundable<'a>(
+    sales: &'a [Sale],
+    payment_hash: &str,
+    force: bool,
+) -> anyhow::Result<&'a Sale> {
+    let sale = sales
+        .iter()
+        .find(|sale| sale.payment_hash.as_deref() == Some(payment_hash))
+        .context("No sale recorded for this payment hash")?;
+    if let Some(refunded_msat) = sale.refunded_msat {
+        bail!("Sale was already refunded with {} msat", refunded_msat);
+    }
+    ensure!(
+        force || !sale.dispensed || sale.jammed,
+        "Sale was dispensed, pass --force to refund it anyway"
+    );
+    Ok(sale)
+}
+
+/// Writes sales as CSV with a header line
+pub fn write_csv(sales: &[Sale], mut out: impl Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "timestamp,product,amount_msat,method,payment_hash,dispensed,jammed,refunded_msat"
+    )?;
+    for sale in sales {
+        let method = match sale.method {
+            PaymentMethod::Lightning => "lightning",
+            PaymentMethod::Ecash => "ecash",
+            PaymentMethod::Onchain => "onchain",
+            PaymentMethod::Coins => "coins",
+        };
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            sale.timestamp,
+            csv_field(&sale.product),
+            sale.amount_msat,
+            method,
+            sale.payment_hash.as_deref().unwrap_or(""),
+            sale.dispensed,
+            sale.jammed,
+            sale.refunded_msat
+                .map_or(String::new(), |msat| msat.to_string())
+        )?;
+    }
+    Ok(())
+}
+
+/// Quotes fields that would otherwise break the CSV structure
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sale(payment_hash: &str, refunded_msat: Option<u64>) -> Sale {
+        Sale {
+            jammed: true,
+            refunded_msat,
+            ..Sale::now(
+                "M&Ms",
+                42_000,
+                PaymentMethod::Lightning,
+                Some(payment_hash.to_string()),
+                false,
+            )
+        }
+    }
+
+    #[test]
+    fn finds_unrefunded_sale() {
+        let sales = [sale("aa", Some(42_000)), sale("bb", None)];
+        let found = refundable(&sales, "bb", false).unwrap();
+        assert_eq!(found.payment_hash.as_deref(), Some("bb"));
+    }
+
+    #[test]
+    fn rejects_refunded_sale() {
+        let sales = [sale("aa", Some(42_000))];
+        let error = refundable(&sales, "aa", true).unwrap_err().to_string();
+        assert!(error.contains("already refunded"), "{error}");
+    }
+
+    #[test]
+    fn rejects_unknown_payment() {
+        let sales = [sale("aa", None)];
+        let error = refundable(&sales, "bb", true).unwrap_err().to_string();
+        assert!(error.contains("No sale recorded"), "{error}");
+    }
+
+    #[test]
+    fn dispensed_sale_needs_force() {
+        let dispensed = Sale {
+            dispensed: true,
+            jammed: false,
+            ..sale("aa", None)
+        };
+        let sales = [dispensed];
+        let error = refundable(&sales, "aa", false).unwrap_err().to_string();
+        assert!(error.contains("--force"), "{error}");
+        assert!(refundable(&sales, "aa", true).is_ok());
+    }
+}
@@ -0,0 +1,1651 @@
+use crate::bbqr;
+use crate::ili9341::Ili9341;
+use crate::soft_spi::DisplaySpi;
+use crate::theme::{Splash, Theme};
+use crate::ups::UpsStatus;
+use embedded_graphics::{
+    image::{Image, ImageRaw},
+    mono_font::{
+        MonoFont, MonoTextStyle,
+        iso_8859_1::{FONT_6X10, FONT_10X20},
+    },
+    pixelcolor::Rgb565,
+    prelude::*,
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, Triangle},
+    text::Text,
+};
+use qrcode::types::QrError;
+use qrcode::{EcLevel, QrCode, QrResult, Version};
+use rppal::gpio::OutputPin;
+use rppal::hal::Delay;
+use serde::Deserialize;
+use st7735_lcd::{Orientation, ST7735};
+use std::fmt;
+use std::time::Instant;
+use tracing::{debug, debug_span, warn};
+
+pub const DISPLAY_WIDTH: u32 = 128;
+pub const DISPLAY_HEIGHT: u32 = 160;
+
+pub const STATUS_BAR_HEIGHT: u32 = 13;
+
+pub type St7735 = ST7735<DisplaySpi, OutputPin, OutputPin>;
+
+/// How the panel is mounted. Portrait has the connector at the bottom, swapped modes are turned
+/// by 180°.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisplayOrientation {
+    Portrait,
+    Landscape,
+    /// Matches the original enclosure
+    #[default]
+    PortraitSwapped,
+    LandscapeSwapped,
+}
+
+impl DisplayOrientation {
+    pub fn is_landscape(self) -> bool {
+        matches!(
+            self,
+            DisplayOrientation::Landscape | DisplayOrientation::LandscapeSwapped
+        )
+    }
+
+    /// Width and height of a panel with the given portrait size
+    pub fn size(self, width: u32, height: u32) -> (u32, u32) {
+        if self.is_landscape() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+}
+
+/// Error correction of the QR codes. Higher levels still scan through glare or a scratched
+/// window, but need more and thus smaller modules for the same data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum QrErrorCorrection {
+    #[default]
+    L,
+    M,
+    Q,
+    H,
+}
+
+impl From<QrErrorCorrection> for EcLevel {
+    fn from(level: QrErrorCorrection) -> Self {
+        match level {
+            QrErrorCorrection::L => EcLevel::L,
+            QrErrorCorrection::M => EcLevel::M,
+            QrErrorCorrection::Q => EcLevel::Q,
+            QrErrorCorrection::H => EcLevel::H,
+        }
+    }
+}
+
+/// How the QR codes of invoices and refunds are encoded
+#[derive(Debug, Clone, Copy)]
+pub struct QrStyle {
+    pub error_correction: QrErrorCorrection,
+    /// Light modules around the code, which helps scanners find it on a dark or busy background
+    pub quiet_zone: u32,
+    /// Prefixes invoices with `LIGHTNING:`, so phone camera apps hand them to a wallet
+    pub lightning_uri: bool,
+    /// Encodes invoices in uppercase, which allows the smaller alphanumeric mode
+    pub uppercase: bool,
+}
+
+impl Default for QrStyle {
+    fn default() -> Self {
+        Self {
+            error_correction: QrErrorCorrection::default(),
+            quiet_zone: 0,
+            lightning_uri: false,
+            uppercase: true,
+        }
+    }
+}
+
+/// The LCD controller, or a framebuffer saved as an image when running in the simulator
+pub enum Panel {
+    St7735(St7735),
+    Ili9341(Ili9341),
+    #[cfg(feature = "simulate")]
+    Simulated(crate::simulator::SimulatedDisplay),
+}
+
+impl OriginDimensions for Panel {
+    fn size(&self) -> Size {
+        match self {
+            Panel::St7735(panel) => panel.size(),
+            Panel::Ili9341(panel) => panel.size(),
+            #[cfg(feature = "simulate")]
+            Panel::Simulated(display) => display.size(),
+        }
+    }
+}
+
+// Everything is forwarded, the panel driver implements the fills with far fewer SPI transfers
+impl DrawTarget for Panel {
+    type Color = Rgb565;
+    type Error = DisplayError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        match self {
+            Panel::St7735(panel) => panel.draw_iter(pixels).map_err(|_| DisplayError),
+            Panel::Ili9341(panel) => panel.draw_iter(pixels).map_err(|_| DisplayError),
+            #[cfg(feature = "simulate")]
+            Panel::Simulated(display) => display.draw_iter(pixels).map_err(|_| DisplayError),
+        }
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        match self {
+            Panel::St7735(panel) => panel
+                .fill_contiguous(area, colors)
+                .map_err(|_| DisplayError),
+            Panel::Ili9341(panel) => panel
+                .fill_contiguous(area, colors)
+                .map_err(|_| DisplayError),
+            #[cfg(feature = "simulate")]
+            Panel::Simulated(display) => display
+                .fill_contiguous(area, colors)
+                .map_err(|_| DisplayError),
+        }
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        match self {
+            Panel::St7735(panel) => panel.fill_solid(area, color).map_err(|_| DisplayError),
+            Panel::Ili9341(panel) => panel.fill_solid(area, color).map_err(|_| DisplayError),
+            #[cfg(feature = "simulate")]
+            Panel::Simulated(display) => display.fill_solid(area, color).map_err(|_| DisplayError),
+        }
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        match self {
+            Panel::St7735(panel) => panel.clear(color).map_err(|_| DisplayError),
+            Panel::Ili9341(panel) => panel.clear(color).map_err(|_| DisplayError),
+            #[cfg(feature = "simulate")]
+            Panel::Simulated(display) => display.clear(color).map_err(|_| DisplayError),
+        }
+    }
+}
+
+/// The panel as mounted in the enclosure. Screens draw through [`DrawTarget`] and lay
+/// themselves out for its size.
+pub struct Display {
+    panel: Panel,
+    orientation: DisplayOrientation,
+    /// Flipped left to right, e.g. when viewed through a mirror
+    mirrored: bool,
+    qr_style: QrStyle,
+}
+
+impl Display {
+    /// The panel has to be created with the size for `orientation`
+    pub fn new(panel: Panel, orientation: DisplayOrientation) -> Self {
+        Self {
+            panel,
+            orientation,
+            mirrored: false,
+            qr_style: QrStyle::default(),
+        }
+    }
+
+    pub fn mirrored(mut self, mirrored: bool) -> Self {
+        self.mirrored = mirrored;
+        self
+    }
+
+    pub fn qr_style(mut self, qr_style: QrStyle) -> Self {
+        self.qr_style = qr_style;
+        self
+    }
+
+    /// Where `area` ends up on the panel
+    fn flip(&self, area: &Rectangle) -> Rectangle {
+        if !self.mirrored {
+            return *area;
+        }
+        let x = self.size().width as i32 - area.top_left.x - area.size.width as i32;
+        Rectangle::new(Point::new(x, area.top_left.y), area.size)
+    }
+}
+
+impl OriginDimensions for Display {
+    fn size(&self) -> Size {
+        self.panel.size()
+    }
+}
+
+impl DrawTarget for Display {
+    type Color = Rgb565;
+    type Error = DisplayError;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        if !self.mirrored {
+            return self.panel.draw_iter(pixels);
+        }
+        let right = self.size().width as i32 - 1;
+        self.panel.draw_iter(
+            pixels
+                .into_iter()
+                .map(|Pixel(point, color)| Pixel(Point::new(right - point.x, point.y), color)),
+        )
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        if !self.mirrored || area.size.width == 0 {
+            return self.panel.fill_contiguous(area, colors);
+        }
+        let pixels = area.size.width as usize * area.size.height as usize;
+        let mut colors: Vec<_> = colors.into_iter().take(pixels).collect();
+        colors
+            .chunks_mut(area.size.width as usize)
+            .for_each(|row| row.reverse());
+        let flipped = self.flip(area);
+        self.panel.fill_contiguous(&flipped, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let flipped = self.flip(area);
+        self.panel.fill_solid(&flipped, color)
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        self.panel.clear(color)
+    }
+}
+
+/// A command or pixel write to the panel failed, its contents are undefined afterwards
+#[derive(Debug)]
+pub struct DisplayError;
+
+impl fmt::Display for DisplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Display write failed")
+    }
+}
+
+impl std::error::Error for DisplayError {}
+
+/// Runs the panel's reset and init sequence, also used to recover it after a failed write
+pub fn init_panel(display: &mut Display) -> Result<(), DisplayError> {
+    match &mut display.panel {
+        Panel::Ili9341(panel) => panel.init().map_err(|_| DisplayError),
+        Panel::St7735(panel) => {
+            let orientation = match display.orientation {
+                DisplayOrientation::Portrait => Orientation::Portrait,
+                DisplayOrientation::Landscape => Orientation::Landscape,
+                DisplayOrientation::PortraitSwapped => Orientation::PortraitSwapped,
+                DisplayOrientation::LandscapeSwapped => Orientation::LandscapeSwapped,
+            };
+            panel.init(&mut Delay::new()).map_err(|_| DisplayError)?;
+            panel
+                .set_orientation(&orientation)
+                .map_err(|_| DisplayError)
+        }
+        #[cfg(feature = "simulate")]
+        Panel::Simulated(_) => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+}
+
+#[derive(Clone)]
+pub struct StatusBar {
+    height: u32,
+    ip_address: String,
+    connection_status: ConnectionStatus,
+    battery: Option<UpsStatus>,
+    /// Ecash balance in sats, unknown in watch-only mode
+    balance_sats: Option<u64>,
+    /// Set while the hopper level sensor reports low stock
+    low_stock: bool,
+}
+
+impl StatusBar {
+    pub fn new(ip_address: String) -> Self {
+        Self {
+            height: STATUS_BAR_HEIGHT,
+            ip_address,
+            connection_status: ConnectionStatus::Disconnected,
+            battery: None,
+            balance_sats: None,
+            low_stock: false,
+        }
+    }
+
+    pub fn ip(&self) -> &str {
+        &self.ip_address
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.connection_status
+    }
+
+    pub fn battery(&self) -> Option<UpsStatus> {
+        self.battery
+    }
+
+    pub fn balance_sats(&self) -> Option<u64> {
+        self.balance_sats
+    }
+
+    pub fn low_stock(&self) -> bool {
+        self.low_stock
+    }
+
+    pub fn update_ip(&mut self, ip: String) {
+        self.ip_address = ip;
+    }
+
+    pub fn set_connection_status(&mut self, status: ConnectionStatus) {
+        self.connection_status = status;
+    }
+
+    pub fn set_battery(&mut self, status: UpsStatus) {
+        self.battery = Some(status);
+    }
+
+    pub fn set_balance(&mut self, sats: u64) {
+        self.balance_sats = Some(sats);
+    }
+
+    pub fn set_low_stock(&mut self, low: bool) {
+        self.low_stock = low;
+    }
+}
+
+/// Text sizes screens choose from, the fonts depend on the panel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextSize {
+    Body,
+    /// Headlines and the amount, where they fit
+    Large,
+}
+
+/// Where things go on the panel. Screens are designed for the 128x160 ST7735 in portrait, on
+/// larger panels the font and vertical distances are scaled up and the QR code grows with the
+/// panel.
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    /// Multiplies the vertical distances of the 128x160 design
+    scale: u32,
+    body_font: &'static MonoFont<'static>,
+    large_font: &'static MonoFont<'static>,
+    pub status_bar_height: u32,
+    qr_size: u32,
+    qr_y_offset: u32,
+    qr_style: QrStyle,
+}
+
+impl Layout {
+    pub fn new(display: &Display) -> Self {
+        let Size { width, height } = display.size();
+        let scale = (width.max(height) / DISPLAY_HEIGHT).max(1);
+        // There is no larger Latin-1 font, large panels use it for everything
+        let body_font = if scale > 1 { &FONT_10X20 } else { &FONT_6X10 };
+        let status_bar_height = STATUS_BAR_HEIGHT * scale;
+        let qr_y_offset = status_bar_height + 4; // Start after status bar + small margin
+        // Leave 2px margin on each side, in landscape the height limits it with room for the amount
+        let qr_size = (width - 4).min(height.saturating_sub(qr_y_offset + 12 * scale));
+
+        Self {
+            width,
+            height,
+            scale,
+            body_font,
+            large_font: &FONT_10X20,
+            status_bar_height,
+            qr_size,
+            qr_y_offset,
+            qr_style: display.qr_style,
+        }
+    }
+
+    /// A distance of the 128x160 design on this panel
+    pub fn scaled(&self, distance: i32) -> i32 {
+        distance * self.scale as i32
+    }
+
+    /// Baseline `offset` pixels of the 128x160 design below the status bar
+    pub fn below_status_bar(&self, offset: i32) -> i32 {
+        self.status_bar_height as i32 + self.scaled(offset)
+    }
+
+    fn font(&self, size: TextSize) -> &'static MonoFont<'static> {
+        match size {
+            TextSize::Body => self.body_font,
+            TextSize::Large => self.large_font,
+        }
+    }
+
+    pub fn text_style(&self, size: TextSize, color: Rgb565) -> MonoTextStyle<'static, Rgb565> {
+        MonoTextStyle::new(self.font(size), color)
+    }
+
+    pub fn char_width(&self, size: TextSize) -> u32 {
+        let font = self.font(size);
+        font.character_size.width + font.character_spacing
+    }
+
+    /// Distance from the top of a line to its baseline
+    pub fn ascent(&self, size: TextSize) -> i32 {
+        self.font(size).baseline as i32
+    }
+
+    /// Distance between the baselines of wrapped lines
+    pub fn line_height(&self, size: TextSize) -> i32 {
+        self.font(size).character_size.height as i32 + self.scaled(2)
+    }
+
+    /// Characters fitting on a line
+    pub fn columns(&self, size: TextSize) -> usize {
+        (self.width / self.char_width(size)) as usize
+    }
+
+    /// Large if `text` fits on a single line that way
+    pub fn headline_size(&self, text: &str) -> TextSize {
+        if text.chars().count() <= self.columns(TextSize::Large) {
+            TextSize::Large
+        } else {
+            TextSize::Body
+        }
+    }
+
+    /// Left edge of a line of text centered on the display, texts from the theme may not fit
+    pub fn centered_x(&self, text: &str, size: TextSize) -> i32 {
+        let text_width = text.chars().count() as u32 * self.char_width(size);
+        (self.width.saturating_sub(text_width) / 2) as i32
+    }
+
+    pub fn screen(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(self.width, self.height))
+    }
+}
+
+/// Draws `text` wrapped to the display width with every line centered and the first baseline at
+/// `y`, returns the baseline of the line that would follow
+pub fn draw_text(
+    display: &mut Display,
+    text: &str,
+    size: TextSize,
+    color: Rgb565,
+    y: i32,
+) -> Result<i32, DisplayError> {
+    let layout = Layout::new(display);
+    let style = layout.text_style(size, color);
+    let mut y = y;
+    for line in wrap_text(text, layout.columns(size)) {
+        let x = layout.centered_x(&line, size);
+        Text::new(&line, Point::new(x, y), style).draw(display)?;
+        y += layout.line_height(size);
+    }
+    Ok(y)
+}
+
+pub fn clear_display(display: &mut Display) -> Result<(), DisplayError> {
+    let layout = Layout::new(display);
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(Rgb565::BLACK)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)
+}
+
+pub fn draw_status_bar(display: &mut Display, status_bar: &StatusBar) -> Result<(), DisplayError> {
+    let layout = Layout::new(display);
+
+    // Black background for status bar
+    let status_bg = Rectangle::new(
+        Point::new(0, 0),
+        Size::new(layout.width, layout.status_bar_height),
+    )
+    .into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(Rgb565::BLACK)
+            .build(),
+    );
+    status_bg.draw(display).map_err(|_| DisplayError)?;
+
+    let text_style = layout.text_style(TextSize::Body, Rgb565::WHITE);
+    let baseline = layout.status_bar_height as i32 - layout.scaled(3);
+    let char_width = layout.char_width(TextSize::Body) as i32;
+
+    // Connection status indicator (left side)
+    let status_text = match status_bar.connection_status {
+        ConnectionStatus::Connected => "*",
+        ConnectionStatus::Disconnected => "o",
+    };
+    let status_display = Text::new(status_text, Point::new(2, baseline), text_style);
+    status_display.draw(display).map_err(|_| DisplayError)?;
+
+    let mut left_x = 4 + char_width;
+
+    // Orange warning triangle while the hopper needs a refill
+    if status_bar.low_stock {
+        let size = layout.scaled(6);
+        Triangle::new(
+            Point::new(left_x, baseline),
+            Point::new(left_x + size, baseline),
+            Point::new(left_x + size / 2, baseline - layout.scaled(7)),
+        )
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::CSS_ORANGE))
+        .draw(display)
+        .map_err(|_| DisplayError)?;
+        left_x += size + layout.scaled(4);
+    }
+
+    // Battery charge next, marked while running without mains power
+    if let Some(battery) = status_bar.battery {
+        let marker = if battery.on_battery { "!" } else { "" };
+        let battery_text = format!("{}%{}", battery.battery_percent, marker);
+        let battery_display = Text::new(&battery_text, Point::new(left_x, baseline), text_style);
+        battery_display.draw(display).map_err(|_| DisplayError)?;
+        left_x += (battery_text.len() as i32 + 1) * char_width;
+    }
+
+    // IP address (right side)
+    let ip_x = layout.width as i32 - (status_bar.ip_address.len() as i32 * char_width) - 2;
+
+    // Balance in between, left out if a long IP address leaves no room for it
+    if let Some(sats) = status_bar.balance_sats {
+        let balance_text = format_sats(sats);
+        if left_x + (balance_text.len() as i32 + 1) * char_width <= ip_x {
+            let balance_display = Text::new(
+                &balance_text,
+                Point::new(left_x, baseline),
+                layout.text_style(TextSize::Body, Rgb565::CSS_GOLD),
+            );
+            balance_display.draw(display).map_err(|_| DisplayError)?;
+        }
+    }
+
+    let ip_display = Text::new(
+        &status_bar.ip_address,
+        Point::new(ip_x, baseline),
+        text_style,
+    );
+    ip_display.draw(display).map_err(|_| DisplayError)?;
+
+    Ok(())
+}
+
+/// Short form of a sats amount for the status bar, e.g. `950`, `12k` or `1.2M`
+fn format_sats(sats: u64) -> String {
+    match sats {
+        0..1_000 => sats.to_string(),
+        1_000..1_000_000 => format!("{}k", sats / 1_000),
+        _ => format!("{:.1}M", sats as f64 / 1_000_000.0),
+    }
+}
+
+/// Largest multiple of the module count fitting within `target_size`, at least one pixel per
+/// module
+fn qr_image_size(qr_modules: u32, target_size: u32) -> u32 {
+    qr_modules * (target_size / qr_modules).max(1)
+}
+
+/// Smallest modules phones reliably scan, longer data is split into a sequence of codes
+const MIN_MODULE_PIXELS: u32 = 2;
+
+/// Width of `code` in modules including the quiet zone
+fn qr_modules(code: &QrCode, style: QrStyle) -> u32 {
+    code.width() as u32 + 2 * style.quiet_zone
+}
+
+/// What the QR code area shows for `data`: a single code, or the first one of its animation
+fn qr_code(data: &str, layout: &Layout) -> QrResult<(QrCode, Option<QrAnimation>)> {
+    let ec_level = layout.qr_style.error_correction.into();
+    // Data too long for any version is split as well
+    let single = QrCode::with_error_correction_level(data, ec_level)
+        .ok()
+        .filter(|code| layout.qr_size / qr_modules(code, layout.qr_style) >= MIN_MODULE_PIXELS);
+    if let Some(code) = single {
+        return Ok((code, None));
+    }
+    let animation = QrAnimation::split(data, layout)?;
+    Ok((animation.code(layout)?, Some(animation)))
+}
+
+/// A screen's QR code as drawn, see [`Screen::qr`]
+pub struct ScreenQr {
+    /// Width and height in pixels
+    pub size: u32,
+    /// Set if the data is too long for a single code and cycles through parts
+    pub animation: Option<QrAnimation>,
+}
+
+/// BBQr parts of data too long for a single QR code, cycled on a timer. All parts share a
+/// version, so the code keeps its size while it changes.
+pub struct QrAnimation {
+    parts: Vec<String>,
+    version: Version,
+    frame: usize,
+}
+
+impl QrAnimation {
+    fn split(data: &str, layout: &Layout) -> QrResult<Self> {
+        let ec_level = layout.qr_style.error_correction.into();
+        // A version has 17 + 4 * version modules
+        let max_modules =
+            (layout.qr_size / MIN_MODULE_PIXELS).saturating_sub(2 * layout.qr_style.quiet_zone);
+        let version = Version::Normal((max_modules.saturating_sub(17) / 4).clamp(1, 40) as i16);
+        for count in 2..=bbqr::MAX_PARTS {
+            let parts = bbqr::split(data, count);
+            // The first part is the longest
+            if QrCode::with_version(&parts[0], version, ec_level).is_ok() {
+                return Ok(Self {
+                    parts,
+                    version,
+                    frame: 0,
+                });
+            }
+        }
+        Err(QrError::DataTooLong)
+    }
+
+    fn code(&self, layout: &Layout) -> QrResult<QrCode> {
+        QrCode::with_version(
+            &self.parts[self.frame],
+            self.version,
+            layout.qr_style.error_correction.into(),
+        )
+    }
+
+    /// Replaces the code on screen with the next part
+    pub fn draw_next(&mut self, display: &mut Display) -> Result<(), Box<dyn std::error::Error>> {
+        self.frame = (self.frame + 1) % self.parts.len();
+        let layout = Layout::new(display);
+        draw_qr_code(display, &self.code(&layout)?, &layout)?;
+        Ok(())
+    }
+}
+
+/// Text of an invoice's QR code. In uppercase the QR code can use the alphanumeric mode, which
+/// needs fewer and thus larger modules than lowercase bytes.
+fn invoice_qr_data(invoice: &str, onchain: Option<OnchainFallback>, style: QrStyle) -> String {
+    let data = match onchain {
+        // BIP21 with the invoice as a parameter, wallets take either payment
+        Some(onchain) => format!(
+            "bitcoin:{}?amount={}&lightning={}",
+            onchain.address,
+            format_btc(onchain.amount_sats),
+            invoice
+        ),
+        None if style.lightning_uri => format!("lightning:{}", invoice),
+        None => invoice.to_string(),
+    };
+    if style.uppercase {
+        data.to_uppercase()
+    } else {
+        data
+    }
+}
+
+/// Decimal BTC amount of BIP21 URIs, without trailing zeros
+fn format_btc(sats: u64) -> String {
+    let btc = format!("{}.{:08}", sats / 100_000_000, sats % 100_000_000);
+    btc.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+fn generate_qr_image(code: &QrCode, layout: &Layout) -> (Vec<u8>, u32) {
+    let qr_modules = qr_modules(code, layout.qr_style);
+    let quiet_zone = layout.qr_style.quiet_zone as usize;
+    let is_dark = |x: usize, y: usize| {
+        let (Some(x), Some(y)) = (x.checked_sub(quiet_zone), y.checked_sub(quiet_zone)) else {
+            return false;
+        };
+        x < code.width() && y < code.width() && code[(x, y)] == qrcode::Color::Dark
+    };
+
+    let actual_size = qr_image_size(qr_modules, layout.qr_size);
+    let scale = actual_size / qr_modules;
+
+    // Create RGB565 image buffer manually for clean, square modules
+    let dark = 0x0000u16.to_be_bytes();
+    let light = 0xFFFFu16.to_be_bytes();
+    let row_len = (actual_size * 2) as usize;
+    let mut qr_data = Vec::with_capacity(row_len * actual_size as usize);
+
+    for module_y in 0..qr_modules as usize {
+        // Render the first pixel row of each module row, the remaining ones are copies of it
+        let row_start = qr_data.len();
+        for module_x in 0..qr_modules as usize {
+            let pixel = if is_dark(module_x, module_y) {
+                dark
+            } else {
+                light
+            };
+            for _ in 0..scale {
+                qr_data.extend_from_slice(&pixel);
+            }
+        }
+        for _ in 1..scale {
+            qr_data.extend_from_within(row_start..row_start + row_len);
+        }
+    }
+
+    (qr_data, actual_size)
+}
+
+/// Draws `code` centered below the status bar, returns its size in pixels
+fn draw_qr_code(
+    display: &mut Display,
+    code: &QrCode,
+    layout: &Layout,
+) -> Result<u32, DisplayError> {
+    let (qr_data, qr_size) = generate_qr_image(code, layout);
+    let qr_x_offset = (layout.width.saturating_sub(qr_size) / 2) as i32;
+    let qr_raw_image = ImageRaw::<Rgb565>::new(&qr_data, qr_size);
+    Image::new(
+        &qr_raw_image,
+        Point::new(qr_x_offset, layout.qr_y_offset as i32),
+    )
+    .draw(display)?;
+    Ok(qr_size)
+}
+
+fn display_invoice_screen(
+    display: &mut Display,
+    qr_data: &str,
+    amount: &str,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Generating invoice display for: {}", qr_data);
+
+    let layout = Layout::new(display);
+
+    // Clear screen, white by default
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(theme.colors.invoice_background.0)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)?;
+
+    // Draw status bar
+    draw_status_bar(display, status_bar)?;
+
+    let (code, _) = qr_code(qr_data, &layout)?;
+    let actual_qr_size = draw_qr_code(display, &code, &layout)?;
+    draw_countdown_bar(display, &layout, actual_qr_size, 1.0, theme)?;
+
+    // Amount below the QR code, in large print if the QR code leaves room for it
+    let amount_top = (layout.qr_y_offset + actual_qr_size) as i32 + layout.scaled(4);
+    let size = match layout.headline_size(amount) {
+        TextSize::Large
+            if amount_top + layout.line_height(TextSize::Large) <= layout.height as i32 =>
+        {
+            TextSize::Large
+        }
+        _ => TextSize::Body,
+    };
+    draw_text(
+        display,
+        amount,
+        size,
+        theme.colors.invoice_text.0,
+        amount_top + layout.ascent(size),
+    )?;
+
+    debug!("Invoice screen displayed!");
+    Ok(())
+}
+
+/// Fills the gap between the QR code and the amount
+fn draw_countdown_bar(
+    display: &mut Display,
+    layout: &Layout,
+    qr_size: u32,
+    remaining: f32,
+    theme: &Theme,
+) -> Result<(), DisplayError> {
+    let top_left = Point::new(
+        ((layout.width - qr_size) / 2) as i32,
+        (layout.qr_y_offset + qr_size) as i32 + 1,
+    );
+    let height = layout.scaled(2) as u32;
+    let filled = (qr_size as f32 * remaining.clamp(0.0, 1.0)).round() as u32;
+    Rectangle::new(top_left, Size::new(qr_size, height))
+        .into_styled(PrimitiveStyle::with_fill(theme.colors.invoice_background.0))
+        .draw(display)?;
+    Rectangle::new(top_left, Size::new(filled, height))
+        .into_styled(PrimitiveStyle::with_fill(theme.colors.invoice_text.0))
+        .draw(display)
+}
+
+fn display_payment_success_screen(
+    display: &mut Display,
+    product: Option<&str>,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying payment success/dispensing screen");
+
+    let layout = Layout::new(display);
+
+    // Clear screen with a green background by default to indicate success
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(theme.colors.success_background.0)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)?;
+
+    // Draw status bar
+    draw_status_bar(display, status_bar)?;
+
+    let color = theme.colors.success_text.0;
+
+    // "Payment Received" message
+    let payment_text = &theme.strings.payment_received;
+    let size = layout.headline_size(payment_text);
+    let y = draw_text(
+        display,
+        payment_text,
+        size,
+        color,
+        layout.below_status_bar(30),
+    )?;
+
+    // "Dispensing..." message
+    let dispensing_text = &theme.strings.dispensing;
+    let y = draw_text(
+        display,
+        dispensing_text,
+        TextSize::Body,
+        color,
+        y + layout.scaled(8),
+    )?;
+
+    // The product bought if there is a choice, otherwise a simple progress indicator
+    let progress_text = product.unwrap_or(". . . . .");
+    let y = draw_text(
+        display,
+        progress_text,
+        TextSize::Body,
+        color,
+        y + layout.scaled(13),
+    )?;
+
+    // Operator's logo in the remaining space
+    if let Some(logo) = &theme.logo {
+        let logo_x = layout.width.saturating_sub(logo.width) / 2;
+        let logo_y = y - layout.scaled(2);
+        let logo_image = ImageRaw::<Rgb565>::new(&logo.data, logo.width);
+        Image::new(&logo_image, Point::new(logo_x as i32, logo_y))
+            .draw(display)
+            .map_err(|_| DisplayError)?;
+    }
+
+    debug!("Payment success screen displayed!");
+    Ok(())
+}
+
+fn display_tamper_alarm_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying tamper alarm screen");
+
+    let layout = Layout::new(display);
+
+    // Red background so the alarm is visible from across the room
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(theme.colors.alarm_background.0)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)?;
+
+    draw_status_bar(display, status_bar)?;
+
+    let color = theme.colors.alarm_text.0;
+    let alarm_text = &theme.strings.alarm;
+    let size = layout.headline_size(alarm_text);
+    let y = draw_text(
+        display,
+        alarm_text,
+        size,
+        color,
+        layout.below_status_bar(40),
+    )?;
+
+    let notice_text = &theme.strings.operator_notified;
+    draw_text(
+        display,
+        notice_text,
+        TextSize::Body,
+        color,
+        y + layout.scaled(8),
+    )?;
+
+    Ok(())
+}
+
+fn display_shutdown_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying shutdown screen");
+
+    let layout = Layout::new(display);
+
+    clear_display(display)?;
+    draw_status_bar(display, status_bar)?;
+
+    let battery_text = &theme.strings.battery_empty;
+    let size = layout.headline_size(battery_text);
+    let y = draw_text(
+        display,
+        battery_text,
+        size,
+        Rgb565::WHITE,
+        layout.below_status_bar(40),
+    )?;
+
+    let shutdown_text = &theme.strings.shutting_down;
+    draw_text(
+        display,
+        shutdown_text,
+        TextSize::Body,
+        Rgb565::WHITE,
+        y + layout.scaled(8),
+    )?;
+
+    Ok(())
+}
+
+/// A single message in large print if it fits, on a black background
+fn display_notice_screen(
+    display: &mut Display,
+    text: &str,
+    status_bar: &StatusBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let layout = Layout::new(display);
+
+    clear_display(display)?;
+    draw_status_bar(display, status_bar)?;
+
+    let size = layout.headline_size(text);
+    draw_text(
+        display,
+        text,
+        size,
+        Rgb565::WHITE,
+        layout.below_status_bar(60),
+    )?;
+
+    Ok(())
+}
+
+fn display_deposit_detected_screen(
+    display: &mut Display,
+    amount: &str,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying deposit detected screen");
+
+    let layout = Layout::new(display);
+
+    clear_display(display)?;
+    draw_status_bar(display, status_bar)?;
+
+    let detected_text = &theme.strings.payment_detected;
+    let size = layout.headline_size(detected_text);
+    let y = draw_text(
+        display,
+        detected_text,
+        size,
+        Rgb565::WHITE,
+        layout.below_status_bar(30),
+    )?;
+
+    let y = draw_text(
+        display,
+        amount,
+        layout.headline_size(amount),
+        Rgb565::WHITE,
+        y + layout.scaled(8),
+    )?;
+
+    let waiting_text = &theme.strings.awaiting_confirmation;
+    draw_text(
+        display,
+        waiting_text,
+        TextSize::Body,
+        Rgb565::WHITE,
+        y + layout.scaled(8),
+    )?;
+
+    Ok(())
+}
+
+fn display_coin_credit_screen(
+    display: &mut Display,
+    credit: &str,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying coin credit screen");
+
+    let layout = Layout::new(display);
+
+    clear_display(display)?;
+    draw_status_bar(display, status_bar)?;
+
+    let inserted_text = &theme.strings.coins_inserted;
+    let y = draw_text(
+        display,
+        inserted_text,
+        layout.headline_size(inserted_text),
+        Rgb565::WHITE,
+        layout.below_status_bar(30),
+    )?;
+
+    draw_text(
+        display,
+        credit,
+        layout.headline_size(credit),
+        Rgb565::WHITE,
+        y + layout.scaled(8),
+    )?;
+
+    Ok(())
+}
+
+fn display_jammed_screen(
+    display: &mut Display,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying jammed screen");
+
+    let layout = Layout::new(display);
+
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(theme.colors.alarm_background.0)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)?;
+
+    draw_status_bar(display, status_bar)?;
+
+    let color = theme.colors.alarm_text.0;
+    let jammed_text = &theme.strings.jammed;
+    let size = layout.headline_size(jammed_text);
+    let y = draw_text(
+        display,
+        jammed_text,
+        size,
+        color,
+        layout.below_status_bar(40),
+    )?;
+
+    let notice_text = &theme.strings.operator_notified;
+    draw_text(
+        display,
+        notice_text,
+        TextSize::Body,
+        color,
+        y + layout.scaled(8),
+    )?;
+
+    Ok(())
+}
+
+fn display_refund_screen(
+    display: &mut Display,
+    notes: &str,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying refund screen");
+
+    let layout = Layout::new(display);
+
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(theme.colors.invoice_background.0)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)?;
+
+    draw_status_bar(display, status_bar)?;
+
+    // Unlike invoices, notes are case sensitive and can't use the denser alphanumeric QR mode
+    let (code, _) = qr_code(notes, &layout)?;
+    let actual_qr_size = draw_qr_code(display, &code, &layout)?;
+
+    let refund_top = (layout.qr_y_offset + actual_qr_size) as i32 + layout.scaled(4);
+    draw_text(
+        display,
+        &theme.strings.scan_for_refund,
+        TextSize::Body,
+        theme.colors.invoice_text.0,
+        refund_top + layout.ascent(TextSize::Body),
+    )?;
+
+    Ok(())
+}
+
+fn display_wifi_setup_screen(
+    display: &mut Display,
+    qr_data: &str,
+    url: &str,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying WiFi setup screen");
+
+    let layout = Layout::new(display);
+
+    let bg = layout.screen().into_styled(
+        PrimitiveStyleBuilder::new()
+            .fill_color(theme.colors.invoice_background.0)
+            .build(),
+    );
+    bg.draw(display).map_err(|_| DisplayError)?;
+
+    draw_status_bar(display, status_bar)?;
+
+    let (code, _) = qr_code(qr_data, &layout)?;
+    let actual_qr_size = draw_qr_code(display, &code, &layout)?;
+
+    let text_top = (layout.qr_y_offset + actual_qr_size) as i32 + layout.scaled(4);
+    let y = draw_text(
+        display,
+        &theme.strings.wifi_setup,
+        TextSize::Body,
+        theme.colors.invoice_text.0,
+        text_top + layout.ascent(TextSize::Body),
+    )?;
+    draw_text(display, url, TextSize::Body, theme.colors.invoice_text.0, y)?;
+
+    Ok(())
+}
+
+fn display_connecting_screen(
+    display: &mut Display,
+    step: &str,
+    status_bar: &StatusBar,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying connecting screen: {}", step);
+
+    let layout = Layout::new(display);
+
+    // The operator's splash stays up during boot, only the step is shown in a band at the bottom
+    if let Some(splash) = &theme.splash {
+        draw_splash(display, splash)?;
+        draw_status_bar(display, status_bar)?;
+        let band_height = layout.line_height(TextSize::Body) as u32 + layout.scaled(4) as u32;
+        let band_top = (layout.height - band_height) as i32;
+        Rectangle::new(
+            Point::new(0, band_top),
+            Size::new(layout.width, band_height),
+        )
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(display)?;
+        draw_text(
+            display,
+            step,
+            TextSize::Body,
+            Rgb565::WHITE,
+            band_top + layout.scaled(2) + layout.ascent(TextSize::Body),
+        )?;
+        return Ok(());
+    }
+
+    clear_display(display)?;
+    draw_status_bar(display, status_bar)?;
+
+    let connecting_text = &theme.strings.connecting;
+    let size = layout.headline_size(connecting_text);
+    let y = draw_text(
+        display,
+        connecting_text,
+        size,
+        Rgb565::WHITE,
+        layout.below_status_bar(60),
+    )?;
+
+    draw_text(
+        display,
+        step,
+        TextSize::Body,
+        Rgb565::CSS_GRAY,
+        y + layout.scaled(8),
+    )?;
+
+    Ok(())
+}
+
+/// The operator's splash image on the whole panel, or a blank one without it
+fn display_splash_screen(
+    display: &mut Display,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying splash screen");
+
+    match &theme.splash {
+        Some(splash) => draw_splash(display, splash)?,
+        None => clear_display(display)?,
+    }
+    Ok(())
+}
+
+fn draw_splash(display: &mut Display, splash: &Splash) -> Result<(), DisplayError> {
+    let layout = Layout::new(display);
+    let bitmap = splash.fit(layout.width, layout.height);
+    let image = ImageRaw::<Rgb565>::new(&bitmap.data, bitmap.width);
+    Image::new(&image, Point::zero()).draw(display)
+}
+
+fn display_message_screen(
+    display: &mut Display,
+    text: &str,
+    status_bar: &StatusBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("Displaying message: {}", text);
+
+    let layout = Layout::new(display);
+
+    clear_display(display)?;
+    draw_status_bar(display, status_bar)?;
+
+    // Centered vertically in the space below the status bar
+    let lines = wrap_text(text, layout.columns(TextSize::Body)).len() as i32;
+    let line_height = layout.line_height(TextSize::Body);
+    let y =
+        (layout.height as i32 + layout.status_bar_height as i32) / 2 - (lines * line_height) / 2;
+    draw_text(display, text, TextSize::Body, Rgb565::WHITE, y)?;
+
+    Ok(())
+}
+
+/// Promo text in the invoice colors, large and centered on the whole panel if every word fits
+fn display_promo_screen(
+    display: &mut Display,
+    text: &str,
+    amount: &str,
+    theme: &Theme,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let text = text.replace("{price}", amount);
+    debug!("Displaying promo: {}", text);
+
+    let layout = Layout::new(display);
+
+    layout
+        .screen()
+        .into_styled(PrimitiveStyle::with_fill(theme.colors.invoice_background.0))
+        .draw(display)?;
+
+    let longest_word = text
+        .split_whitespace()
+        .map(|word| word.chars().count())
+        .max();
+    let size = if longest_word.unwrap_or(0) <= layout.columns(TextSize::Large) {
+        TextSize::Large
+    } else {
+        TextSize::Body
+    };
+    let lines = wrap_text(&text, layout.columns(size)).len() as i32;
+    let line_height = layout.line_height(size);
+    let y = (layout.height as i32 - lines * line_height) / 2 + layout.ascent(size);
+    draw_text(display, &text, size, theme.colors.invoice_text.0, y)?;
+
+    Ok(())
+}
+
+/// Breaks text into lines of at most `width` characters at spaces where possible
+fn wrap_text(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word;
+        // Words longer than a line get split
+        while word.chars().count() > width {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            let split = word
+                .char_indices()
+                .nth(width)
+                .map_or(word.len(), |(i, _)| i);
+            lines.push(word[..split].to_string());
+            word = &word[split..];
+        }
+        if word.is_empty() {
+            continue;
+        }
+
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// On-chain address offered next to an invoice
+#[derive(Debug, Clone, Copy)]
+pub struct OnchainFallback<'a> {
+    pub address: &'a str,
+    pub amount_sats: u64,
+}
+
+/// Full-screen views shown to customers
+pub enum Screen<'a> {
+    /// QR code of a Lightning invoice with the amount below it
+    Invoice {
+        invoice: &'a str,
+        amount: &'a str,
+        /// Makes the QR code a unified BIP21 URI for wallets without Lightning
+        onchain: Option<OnchainFallback<'a>>,
+    },
+    /// Names the product being dispensed if there is more than one
+    PaymentSuccess {
+        product: Option<&'a str>,
+    },
+    TamperAlarm,
+    /// The UPS battery is about to run out
+    Shutdown,
+    /// Taken out of service by the operator
+    Maintenance,
+    /// No invoices can be created until the connection is back
+    Offline,
+    /// The candy count reached zero, no invoices until the machine is refilled
+    SoldOut,
+    /// The dispenser jammed, apologizes while the dispense is retried or refunded
+    Jammed,
+    /// An on-chain payment was seen but waits for confirmations before the candy is dispensed
+    DepositDetected {
+        amount: &'a str,
+    },
+    /// Coins inserted so far against the price, e.g. `0.30 / 0.50 EUR`
+    CoinCredit {
+        credit: &'a str,
+    },
+    /// QR code of ecash notes refunding a jammed dispense
+    Refund {
+        notes: &'a str,
+    },
+    /// QR code joining the setup hotspot, with the address of the setup page below it
+    WifiSetup {
+        qr_data: &'a str,
+        url: &'a str,
+    },
+    /// Free text, wrapped to the display width
+    Message(&'a str),
+    /// Shown at boot until the wallet is ready, with the current startup step below
+    Connecting {
+        step: &'a str,
+    },
+    /// The operator's splash image, shown at boot and between the promos of the attract loop
+    Splash,
+    /// Operator's promo text of the attract loop, `{price}` is replaced by the invoice amount
+    Promo {
+        text: &'a str,
+        amount: &'a str,
+    },
+}
+
+impl Screen<'_> {
+    /// Draws the screen, re-initializing the panel and trying once more if a write fails
+    pub fn draw(
+        &self,
+        display: &mut Display,
+        status_bar: &StatusBar,
+        theme: &Theme,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _span = debug_span!("render", screen = self.name()).entered();
+        let started = Instant::now();
+        let result = match self.draw_once(display, status_bar, theme) {
+            Err(e) if e.is::<DisplayError>() => {
+                warn!("{}, re-initializing display", e);
+                self.recover(display, status_bar, theme)
+            }
+            result => result,
+        };
+        metrics::histogram!("candypi_render_seconds", "screen" => self.name())
+            .record(started.elapsed().as_secs_f64());
+        result
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Screen::Invoice { .. } => "invoice",
+            Screen::PaymentSuccess { .. } => "payment_success",
+            Screen::TamperAlarm => "tamper_alarm",
+            Screen::Shutdown => "shutdown",
+            Screen::Maintenance => "maintenance",
+            Screen::Offline => "offline",
+            Screen::SoldOut => "sold_out",
+            Screen::Jammed => "jammed",
+            Screen::DepositDetected { .. } => "deposit_detected",
+            Screen::CoinCredit { .. } => "coin_credit",
+            Screen::Refund { .. } => "refund",
+            Screen::WifiSetup { .. } => "wifi_setup",
+            Screen::Message(_) => "message",
+            Screen::Connecting { .. } => "connecting",
+            Screen::Splash => "splash",
+            Screen::Promo { .. } => "promo",
+        }
+    }
+
+    /// Works out the screen's QR code once after drawing it, `None` for screens without one
+    pub fn qr(&self, display: &Display) -> Result<Option<ScreenQr>, Box<dyn std::error::Error>> {
+        let layout = Layout::new(display);
+        let Some(data) = self.qr_data(layout.qr_style) else {
+            return Ok(None);
+        };
+        let (code, animation) = qr_code(&data, &layout)?;
+        Ok(Some(ScreenQr {
+            size: qr_image_size(qr_modules(&code, layout.qr_style), layout.qr_size),
+            animation,
+        }))
+    }
+
+    /// Redraws only the bar below an invoice's QR code of `qr_size` pixels, which shrinks with the
+    /// `remaining` share of the invoice's validity from 1 to 0 so customers know when to rescan
+    pub fn draw_countdown(
+        &self,
+        display: &mut Display,
+        qr_size: u32,
+        remaining: f32,
+        theme: &Theme,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Screen::Invoice { .. } = self else {
+            return Ok(());
+        };
+        draw_countdown_bar(display, &Layout::new(display), qr_size, remaining, theme)?;
+        Ok(())
+    }
+
+    fn qr_data(&self, style: QrStyle) -> Option<String> {
+        match self {
+            Screen::Invoice {
+                invoice, onchain, ..
+            } => Some(invoice_qr_data(invoice, *onchain, style)),
+            Screen::Refund { notes } => Some(notes.to_string()),
+            Screen::WifiSetup { qr_data, .. } => Some(qr_data.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Resets the panel and draws the screen from scratch, for when anything drawn on top of it
+    /// (e.g. the status bar) failed
+    pub fn recover(
+        &self,
+        display: &mut Display,
+        status_bar: &StatusBar,
+        theme: &Theme,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        init_panel(display)?;
+        self.draw_once(display, status_bar, theme)
+    }
+
+    fn draw_once(
+        &self,
+        display: &mut Display,
+        status_bar: &StatusBar,
+        theme: &Theme,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Screen::Invoice {
+                invoice,
+                amount,
+                onchain,
+            } => {
+                let qr_data = invoice_qr_data(invoice, *onchain, display.qr_style);
+                display_invoice_screen(display, &qr_data, amount, status_bar, theme)
+            }
+            Screen::PaymentSuccess { product } => {
+                display_payment_success_screen(display, *product, status_bar, theme)
+            }
+            Screen::TamperAlarm => display_tamper_alarm_screen(display, status_bar, theme),
+            Screen::Shutdown => display_shutdown_screen(display, status_bar, theme),
+            Screen::Maintenance => {
+                debug!("Displaying maintenance screen");
+                display_notice_screen(display, &theme.strings.out_of_service, status_bar)
+            }
+            Screen::Offline => {
+                debug!("Displaying offline screen");
+                display_notice_screen(display, &theme.strings.offline, status_bar)
+            }
+            Screen::SoldOut => {
+                debug!("Displaying sold out screen");
+                display_notice_screen(display, &theme.strings.sold_out, status_bar)
+            }
+            Screen::Jammed => display_jammed_screen(display, status_bar, theme),
+            Screen::DepositDetected { amount } => {
+                display_deposit_detected_screen(display, amount, status_bar, theme)
+            }
+            Screen::CoinCredit { credit } => {
+                display_coin_credit_screen(display, credit, status_bar, theme)
+            }
+            Screen::Refund { notes } => display_refund_screen(display, notes, status_bar, theme),
+            Screen::WifiSetup { qr_data, url } => {
+                display_wifi_setup_screen(display, qr_data, url, status_bar, theme)
+            }
+            Screen::Message(text) => display_message_screen(display, text, status_bar),
+            Screen::Connecting { step } => {
+                display_connecting_screen(display, step, status_bar, theme)
+            }
+            Screen::Splash => display_splash_screen(display, theme),
+            Screen::Promo { text, amount } => display_promo_screen(display, text, amount, theme),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Example invoice of BOLT 11
+    const INVOICE: &str = "lnbc2500u1pvjluezsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygspp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdq5xysxxatsyp3k7enxv4jsxqzpu9qrsgquk0rl77nj30yxdy8j9vdx85fkpmdla2087ne0xh8nhedh8w27kyke0lp53ut353s06fv3qfegext0eh0ymjpf39tuven09sam30g4vgpfna3rh";
+    const ADDRESS: &str = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+
+    fn modules(data: &str) -> usize {
+        QrCode::with_error_correction_level(data, EcLevel::L)
+            .unwrap()
+            .width()
+    }
+
+    #[test]
+    fn invoice_is_uppercased() {
+        let data = invoice_qr_data(INVOICE, None, QrStyle::default());
+        assert_eq!(data, INVOICE.to_uppercase());
+        assert!(modules(&data) < modules(INVOICE));
+    }
+
+    #[test]
+    fn invoice_case_can_be_kept() {
+        let style = QrStyle {
+            lightning_uri: true,
+            uppercase: false,
+            ..QrStyle::default()
+        };
+        let data = invoice_qr_data(INVOICE, None, style);
+        assert_eq!(data, format!("lightning:{INVOICE}"));
+    }
+
+    #[test]
+    fn lightning_uri_stays_alphanumeric() {
+        let style = QrStyle {
+            lightning_uri: true,
+            ..QrStyle::default()
+        };
+        let data = invoice_qr_data(INVOICE, None, style);
+        assert_eq!(data, format!("LIGHTNING:{}", INVOICE.to_uppercase()));
+        assert!(modules(&data) < modules(INVOICE));
+        assert!(modules(&data) < modules(&format!("lightning:{INVOICE}")));
+    }
+
+    #[test]
+    fn bip21_carries_the_invoice() {
+        let onchain = OnchainFallback {
+            address: ADDRESS,
+            amount_sats: 2500,
+        };
+        // BIP21 URIs already have a scheme, `lightning_uri` doesn't add another one
+        let style = QrStyle {
+            lightning_uri: true,
+            ..QrStyle::default()
+        };
+        let data = invoice_qr_data(INVOICE, Some(onchain), style);
+        assert_eq!(
+            data,
+            format!(
+                "BITCOIN:{}?amount=0.000025&lightning={}",
+                ADDRESS.to_uppercase(),
+                INVOICE.to_uppercase()
+            )
+        );
+        let lowercase = format!("bitcoin:{ADDRESS}?amount=0.000025&lightning={INVOICE}");
+        assert!(modules(&data) < modules(&lowercase));
+    }
+
+    #[test]
+    fn btc_amounts_drop_trailing_zeros() {
+        assert_eq!(format_btc(2500), "0.000025");
+        assert_eq!(format_btc(150_000_000), "1.5");
+        assert_eq!(format_btc(100_000_000), "1");
+        assert_eq!(format_btc(1), "0.00000001");
+    }
+}
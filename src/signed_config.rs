@@ -0,0 +1,272 @@
+use crate::config::Config;
+use ed25519_dalek::{Signature, VerifyingKey};
+use fedimint_core::anyhow::{self, Context, anyhow, ensure};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Environment variable with the hex encoded ed25519 public key of the operator. Remote config
+/// and remote commands are rejected altogether if it is unset.
+pub const OPERATOR_PUBKEY_ENV: &str = "CANDYPI_OPERATOR_PUBKEY";
+
+/// Reads the operator key from [`OPERATOR_PUBKEY_ENV`], returns `None` if none was configured
+pub fn operator_key_from_env() -> anyhow::Result<Option<VerifyingKey>> {
+    match std::env::var(OPERATOR_PUBKEY_ENV) {
+        Ok(pubkey) => parse_operator_key(&pubkey).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Environment variable naming this machine in signed requests. Must be unique among the
+/// machines sharing an operator key, so what is signed for one can't be replayed against another.
+pub const DEVICE_ID_ENV: &str = "CANDYPI_DEVICE_ID";
+
+/// Reads this machine's name from [`DEVICE_ID_ENV`], `None` if unset or empty
+pub fn device_id_from_env() -> Option<String> {
+    std::env::var(DEVICE_ID_ENV)
+        .ok()
+        .map(|device_id| device_id.trim().to_string())
+        .filter(|device_id| !device_id.is_empty())
+}
+
+pub fn parse_operator_key(pubkey: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(pubkey.trim())
+        .context("Operator public key is not valid hex")?
+        .try_into()
+        .map_err(|_| anyhow!("Operator public key must be 32 bytes"))?;
+
+    VerifyingKey::from_bytes(&bytes).context("Invalid operator public key")
+}
+
+/// What was signed, prepended to the signed bytes so a signed command can't be passed off as a
+/// config or the other way round
+#[derive(Debug, Clone, Copy)]
+pub enum Domain {
+    Config,
+    Command,
+}
+
+impl Domain {
+    pub(crate) fn tag(self) -> &'static [u8] {
+        match self {
+            Domain::Config => b"candypi-config\n",
+            Domain::Command => b"candypi-command\n",
+        }
+    }
+}
+
+/// Checks operator signatures on config and commands received over the network before they are
+/// applied.
+///
+/// Signatures are plain ed25519 over the domain tag followed by the message, so they can be
+/// created with stock OpenSSL: `openssl pkeyutl -sign -rawin -inkey operator.pem -in signed -out signed.sig`
+pub struct ConfigVerifier {
+    operator_key: VerifyingKey,
+}
+
+impl ConfigVerifier {
+    pub fn new(operator_key: VerifyingKey) -> Self {
+        Self { operator_key }
+    }
+
+    /// Returns `message` only if `signature` is a valid operator signature over it in `domain`
+    pub fn verify<'a>(
+        &self,
+        domain: Domain,
+        message: &'a [u8],
+        signature: &[u8],
+    ) -> anyhow::Result<&'a [u8]> {
+        let signature = Signature::from_slice(signature).context("Malformed signature")?;
+        let signed = [domain.tag(), message].concat();
+        self.operator_key
+            .verify_strict(&signed, &signature)
+            .context("Signature does not match, refusing to apply it")?;
+
+        Ok(message)
+    }
+}
+
+/// Device line of signed configs meant for every machine of the fleet
+pub const ANY_DEVICE: &str = "*";
+
+/// Replaces the config file at `path` with a signed one, which takes effect on the next start.
+///
+/// `signed` is the config's serial on the first line, the device ID of the machine it is meant
+/// for (or [`ANY_DEVICE`]) on the second, followed by the config, signed in [`Domain::Config`].
+/// Configs for other machines than `device_id` are refused. The serial has to be higher than
+/// that of the installed config, so an old config can't be pushed again to roll back prices or
+/// the federation. Configs that wouldn't load are refused as well, a machine that can't start
+/// can't be fixed remotely.
+pub fn install(
+    verifier: &ConfigVerifier,
+    signed: &[u8],
+    signature: &[u8],
+    path: &Path,
+    device_id: Option<&str>,
+) -> anyhow::Result<()> {
+    let signed = verifier.verify(Domain::Config, signed, signature)?;
+    let (serial, signed) = split_line(signed).context("Signed config must start with a serial")?;
+    let serial: u64 = serial.parse().context("Config serial is not a number")?;
+    let (device, blob) = split_line(signed).context("Signed config has no device line")?;
+    ensure!(
+        device == ANY_DEVICE || Some(device) == device_id,
+        "Config is meant for device {}, not this machine",
+        device
+    );
+    let installed = installed_serial(path)?;
+    ensure!(
+        serial > installed,
+        "Config serial {} is not newer than the installed {}, refusing to roll back",
+        serial,
+        installed
+    );
+    let content = std::str::from_utf8(blob).context("Config is not UTF-8")?;
+    Config::parse(content).context("Signed config is invalid")?;
+
+    // Serial first, a config that fails to be replaced can't be installed later either
+    let serial_path = serial_path(path);
+    write_atomically(&serial_path, serial.to_string().as_bytes())?;
+    write_atomically(path, blob)
+}
+
+/// Serial of the last signed config installed at `path`, 0 if none was
+fn installed_serial(path: &Path) -> anyhow::Result<u64> {
+    let serial_path = serial_path(path);
+    match fs::read_to_string(&serial_path) {
+        Ok(serial) => serial
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid serial in {}", serial_path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", serial_path.display())),
+    }
+}
+
+/// Kept next to the config, e.g. `/etc/candypi.toml.serial`
+fn serial_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".serial")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Splits off the first line, trimmed, `None` if there is no complete UTF-8 line
+fn split_line(signed: &[u8]) -> Option<(&str, &[u8])> {
+    let newline = signed.iter().position(|byte| *byte == b'\n')?;
+    let line = std::str::from_utf8(&signed[..newline]).ok()?;
+    Some((line.trim(), &signed[newline + 1..]))
+}
+
+/// Replaces `path` with `content` without a power cut ever leaving a partial file behind
+fn write_atomically(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let tmp = with_suffix(path, ".tmp");
+    File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+
+    // The rename itself is only durable once the directory is synced
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("Failed to sync {}", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::operator;
+    use ed25519_dalek::Signer;
+    use tempfile::tempdir;
+
+    fn verifier() -> ConfigVerifier {
+        ConfigVerifier::new(operator().verifying_key())
+    }
+
+    fn sign(domain: Domain, message: &[u8]) -> Vec<u8> {
+        let signed = [domain.tag(), message].concat();
+        operator().sign(&signed).to_bytes().to_vec()
+    }
+
+    fn signed_config(serial: u64, device: &str, price_sats: u64) -> Vec<u8> {
+        format!("{serial}\n{device}\nprice_sats = {price_sats}\n").into_bytes()
+    }
+
+    /// Installs `config` with a valid signature on the machine called `lobby`
+    fn install_signed(config: &[u8], path: &Path) -> anyhow::Result<()> {
+        let signature = sign(Domain::Config, config);
+        install(&verifier(), config, &signature, path, Some("lobby"))
+    }
+
+    #[test]
+    fn installs_newer_serials_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("candypi.toml");
+        install_signed(&signed_config(2, "lobby", 21), &path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "price_sats = 21\n");
+        assert_eq!(installed_serial(&path).unwrap(), 2);
+
+        // Validly signed, but older than what is installed
+        let error = install_signed(&signed_config(1, "lobby", 1000), &path)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("roll back"), "{error}");
+        assert!(install_signed(&signed_config(2, "lobby", 1000), &path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "price_sats = 21\n");
+
+        install_signed(&signed_config(3, "lobby", 42), &path).unwrap();
+        assert_eq!(installed_serial(&path).unwrap(), 3);
+    }
+
+    #[test]
+    fn serial_is_covered_by_signature() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("candypi.toml");
+        let signature = sign(Domain::Config, &signed_config(1, "lobby", 21));
+        let bumped = signed_config(5, "lobby", 21);
+        let installed = install(&verifier(), &bumped, &signature, &path, Some("lobby"));
+        assert!(installed.is_err());
+    }
+
+    #[test]
+    fn installs_configs_for_this_machine_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("candypi.toml");
+        let error = install_signed(&signed_config(1, "foyer", 21), &path)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("meant for device foyer"), "{error}");
+        assert!(!path.exists());
+
+        install_signed(&signed_config(2, ANY_DEVICE, 21), &path).unwrap();
+        assert_eq!(installed_serial(&path).unwrap(), 2);
+
+        // A machine without a device ID only takes configs for the whole fleet
+        let config = signed_config(3, "lobby", 42);
+        let signature = sign(Domain::Config, &config);
+        assert!(install(&verifier(), &config, &signature, &path, None).is_err());
+    }
+
+    #[test]
+    fn signatures_do_not_carry_over_between_domains() {
+        let command = br#"{"command": "set-price", "sats": 1}"#;
+        let signature = sign(Domain::Command, command);
+        verifier()
+            .verify(Domain::Command, command, &signature)
+            .unwrap();
+        assert!(
+            verifier()
+                .verify(Domain::Config, command, &signature)
+                .is_err()
+        );
+    }
+}
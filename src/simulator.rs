@@ -0,0 +1,271 @@
+use crate::dispenser::{DispenseAction, Mechanism};
+use crate::screen::{ConnectionStatus, DISPLAY_HEIGHT, DISPLAY_WIDTH, Screen, StatusBar};
+use async_trait::async_trait;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Environment variable with the path the simulated screen is saved to, `candypi-screen.png` in
+/// the working directory unless set
+pub const SIMULATOR_SCREEN_ENV: &str = "CANDYPI_SIMULATOR_SCREEN";
+
+const DEFAULT_SCREEN_PATH: &str = "candypi-screen.png";
+
+/// Environment variable showing the screen in an SDL window instead of saving it if set to `1`,
+/// needs the `simulate-window` feature
+pub const SIMULATOR_WINDOW_ENV: &str = "CANDYPI_SIMULATOR_WINDOW";
+
+/// Screens are drawn in many small steps, only the result is shown
+const SAVE_INTERVAL: Duration = Duration::from_millis(200);
+
+// Any text makes a QR code, these are as long as a typical invoice and a single note refund
+const SAMPLE_INVOICE: &str = "lnbc420n1pjsampleinvoicepp5qqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqqqsyqcyq5rqwzqfqypqdqqcqzzsxqyz5vqsp5zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zyg3zygs9qyyssq";
+const SAMPLE_NOTES: &str = "AwEEsamplenotesAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEB";
+
+/// Every screen with sample data, drawn by `candypi render-screens` and compared against the
+/// reference images in `tests/screens`
+pub fn sample_screens() -> Vec<Screen<'static>> {
+    vec![
+        Screen::Invoice {
+            invoice: SAMPLE_INVOICE,
+            amount: "42 sats",
+            onchain: None,
+        },
+        Screen::PaymentSuccess { product: None },
+        Screen::TamperAlarm,
+        Screen::Shutdown,
+        Screen::Maintenance,
+        Screen::Offline,
+        Screen::SoldOut,
+        Screen::Jammed,
+        Screen::DepositDetected { amount: "42 sats" },
+        Screen::CoinCredit {
+            credit: "0.30 / 0.50 EUR",
+        },
+        Screen::Refund {
+            notes: SAMPLE_NOTES,
+        },
+        Screen::WifiSetup {
+            qr_data: "WIFI:T:WPA;S:candypi-setup;P:candysetup;;",
+            url: "http://10.42.0.1",
+        },
+        Screen::Message("Back in 5 minutes"),
+        Screen::Connecting {
+            step: "Joining federation",
+        },
+        Screen::Splash,
+        Screen::Promo {
+            text: "Candy {price} - pay with Lightning!",
+            amount: "42 sats",
+        },
+    ]
+}
+
+/// Status bar the sample screens are drawn with
+pub fn sample_status_bar() -> StatusBar {
+    let mut status_bar = StatusBar::new("192.168.1.42".to_string());
+    status_bar.set_connection_status(ConnectionStatus::Connected);
+    status_bar.set_balance(21_000);
+    status_bar
+}
+
+struct Framebuffer {
+    pixels: Vec<Rgb565>,
+    dirty: bool,
+}
+
+/// Framebuffer standing in for the LCD. It is saved as a PNG whenever it changed, so the UI can
+/// be followed in any image viewer that reloads on change. Clones share the framebuffer.
+#[derive(Clone)]
+pub struct SimulatedDisplay(Arc<Mutex<Framebuffer>>);
+
+impl Framebuffer {
+    fn to_image(&self) -> image::RgbImage {
+        image::RgbImage::from_fn(DISPLAY_WIDTH, DISPLAY_HEIGHT, |x, y| {
+            let color = self.pixels[(y * DISPLAY_WIDTH + x) as usize];
+            // Scale the 5 and 6 bit channels up to 8 bits
+            image::Rgb([color.r() << 3, color.g() << 2, color.b() << 3])
+        })
+    }
+}
+
+impl SimulatedDisplay {
+    /// A black screen that is only kept in memory, see [`save`](Self::save)
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(Framebuffer {
+            pixels: vec![Rgb565::BLACK; (DISPLAY_WIDTH * DISPLAY_HEIGHT) as usize],
+            dirty: true,
+        })))
+    }
+
+    /// The current contents with 8 bits per channel
+    pub fn image(&self) -> image::RgbImage {
+        self.0.lock().expect("Framebuffer lock poisoned").to_image()
+    }
+
+    /// Writes the current contents as an image, the format follows the file extension
+    pub fn save(&self, path: &Path) -> image::ImageResult<()> {
+        self.image().save(path)
+    }
+
+    /// Starts showing the screen in the background, in a window if [`SIMULATOR_WINDOW_ENV`] is
+    /// set and saved to [`SIMULATOR_SCREEN_ENV`] otherwise
+    pub fn spawn() -> Self {
+        let display = Self::new();
+        if std::env::var(SIMULATOR_WINDOW_ENV).is_ok_and(|value| value == "1") {
+            #[cfg(feature = "simulate-window")]
+            {
+                window::spawn(display.0.clone());
+                return display;
+            }
+            #[cfg(not(feature = "simulate-window"))]
+            warn!(
+                "{} needs the simulate-window feature, saving images instead",
+                SIMULATOR_WINDOW_ENV
+            );
+        }
+
+        let path = PathBuf::from(
+            std::env::var(SIMULATOR_SCREEN_ENV).unwrap_or_else(|_| DEFAULT_SCREEN_PATH.to_string()),
+        );
+        info!("Simulated screen is saved to {}", path.display());
+
+        let framebuffer = display.0.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let image = {
+                    let mut framebuffer = framebuffer.lock().expect("Framebuffer lock poisoned");
+                    if !std::mem::take(&mut framebuffer.dirty) {
+                        continue;
+                    }
+                    framebuffer.to_image()
+                };
+                if let Err(e) = image.save(&path) {
+                    warn!("Failed to save simulated screen: {}", e);
+                }
+            }
+        });
+
+        display
+    }
+}
+
+impl Default for SimulatedDisplay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "simulate-window")]
+mod window {
+    use super::{Framebuffer, SAVE_INTERVAL};
+    use crate::screen::{DISPLAY_HEIGHT, DISPLAY_WIDTH};
+    use embedded_graphics::pixelcolor::Rgb565;
+    use embedded_graphics::prelude::*;
+    use embedded_graphics_simulator::{
+        OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    /// Enlarged, the real panel is tiny
+    const WINDOW_SCALE: u32 = 3;
+
+    /// Mirrors the framebuffer into an SDL window from its own thread, SDL wants all calls on the
+    /// thread that created the window. Closing the window exits the process.
+    pub(super) fn spawn(framebuffer: Arc<Mutex<Framebuffer>>) {
+        thread::spawn(move || {
+            let mut window = Window::new(
+                "candypi",
+                &OutputSettingsBuilder::new().scale(WINDOW_SCALE).build(),
+            );
+            let mut mirror =
+                SimulatorDisplay::<Rgb565>::new(Size::new(DISPLAY_WIDTH, DISPLAY_HEIGHT));
+            loop {
+                {
+                    let framebuffer = framebuffer.lock().expect("Framebuffer lock poisoned");
+                    let pixels = framebuffer.pixels.iter().enumerate().map(|(idx, color)| {
+                        let idx = idx as u32;
+                        let point =
+                            Point::new((idx % DISPLAY_WIDTH) as i32, (idx / DISPLAY_WIDTH) as i32);
+                        Pixel(point, *color)
+                    });
+                    let _ = mirror.draw_iter(pixels);
+                }
+                window.update(&mirror);
+                if window.events().any(|event| event == SimulatorEvent::Quit) {
+                    std::process::exit(0);
+                }
+                thread::sleep(SAVE_INTERVAL);
+            }
+        });
+    }
+}
+
+impl OriginDimensions for SimulatedDisplay {
+    fn size(&self) -> Size {
+        Size::new(DISPLAY_WIDTH, DISPLAY_HEIGHT)
+    }
+}
+
+impl DrawTarget for SimulatedDisplay {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let mut framebuffer = self.0.lock().expect("Framebuffer lock poisoned");
+        for Pixel(point, color) in pixels {
+            let (Ok(x), Ok(y)) = (u32::try_from(point.x), u32::try_from(point.y)) else {
+                continue;
+            };
+            if x < DISPLAY_WIDTH && y < DISPLAY_HEIGHT {
+                framebuffer.pixels[(y * DISPLAY_WIDTH + x) as usize] = color;
+            }
+        }
+        framebuffer.dirty = true;
+        Ok(())
+    }
+}
+
+/// Logs dispenses instead of moving anything, taking about as long as the mechanism would
+pub struct SimulatedDispenser {
+    run: Duration,
+}
+
+impl SimulatedDispenser {
+    pub fn new(mechanism: Mechanism) -> Self {
+        let run = match mechanism {
+            Mechanism::Motor { run, .. } | Mechanism::DualMotor { run, .. } => run,
+            Mechanism::Solenoid { energize, hold, .. } => energize + hold,
+            Mechanism::Servo { hold, .. } => hold,
+            Mechanism::Stepper {
+                steps,
+                step_interval,
+                ..
+            } => step_interval * steps,
+        };
+        Self { run }
+    }
+}
+
+#[async_trait]
+impl DispenseAction for SimulatedDispenser {
+    async fn dispense(&mut self) -> bool {
+        self.dispense_for(self.run).await
+    }
+
+    async fn dispense_for(&mut self, run: Duration) -> bool {
+        info!("Simulating a dispense for {} ms", run.as_millis());
+        tokio::time::sleep(run).await;
+        true
+    }
+}
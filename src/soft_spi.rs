@@ -0,0 +1,84 @@
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use rppal::gpio::OutputPin;
+use rppal::spi::{SimpleHalSpiDevice, Spi};
+use std::thread;
+use std::time::Duration;
+
+/// Bit-banged SPI mode 0 without MISO, for boards where the SPI driver isn't usable. Slow, but
+/// the display only ever gets written to.
+pub struct SoftSpi {
+    sclk: OutputPin,
+    mosi: OutputPin,
+    cs: OutputPin,
+}
+
+impl SoftSpi {
+    /// Takes the pins in output mode, chip select is active low
+    pub fn new(mut sclk: OutputPin, mosi: OutputPin, mut cs: OutputPin) -> Self {
+        sclk.set_low();
+        cs.set_high();
+        Self { sclk, mosi, cs }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        for bit in (0..8).rev() {
+            if byte & (1 << bit) != 0 {
+                self.mosi.set_high();
+            } else {
+                self.mosi.set_low();
+            }
+            // The panel samples on the rising edge, GPIO writes are slow enough to not need delays
+            self.sclk.set_high();
+            self.sclk.set_low();
+        }
+    }
+}
+
+impl ErrorType for SoftSpi {
+    type Error = rppal::spi::Error;
+}
+
+impl SpiDevice for SoftSpi {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        self.cs.set_low();
+        for operation in operations {
+            match operation {
+                Operation::Write(words) => words.iter().for_each(|word| self.write_byte(*word)),
+                // Nothing is wired to MISO, reads come back as zeroes
+                Operation::Read(words) => words.fill(0),
+                Operation::Transfer(read, write) => {
+                    write.iter().for_each(|word| self.write_byte(*word));
+                    read.fill(0);
+                }
+                Operation::TransferInPlace(words) => {
+                    for word in words.iter_mut() {
+                        self.write_byte(*word);
+                        *word = 0;
+                    }
+                }
+                Operation::DelayNs(ns) => thread::sleep(Duration::from_nanos(u64::from(*ns))),
+            }
+        }
+        self.cs.set_high();
+        Ok(())
+    }
+}
+
+/// SPI connection of the display, either through the kernel driver or bit-banged
+pub enum DisplaySpi {
+    Hardware(SimpleHalSpiDevice<Spi>),
+    Software(SoftSpi),
+}
+
+impl ErrorType for DisplaySpi {
+    type Error = rppal::spi::Error;
+}
+
+impl SpiDevice for DisplaySpi {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        match self {
+            DisplaySpi::Hardware(spi) => spi.transaction(operations),
+            DisplaySpi::Software(spi) => spi.transaction(operations),
+        }
+    }
+}
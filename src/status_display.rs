@@ -0,0 +1,207 @@
+use crate::inventory::CandyCount;
+use crate::screen::{ConnectionStatus, StatusBar};
+use embedded_graphics::{
+    mono_font::{MonoTextStyle, iso_8859_1::FONT_6X10},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::Text,
+};
+use fedimint_core::anyhow::{self, Context};
+use rppal::i2c::I2c;
+use tokio::sync::watch;
+use tracing::warn;
+
+/// Environment variable overriding `status_display` of the config file, `1` enables the display
+/// and anything else disables it
+pub const STATUS_DISPLAY_ENV: &str = "CANDYPI_STATUS_DISPLAY";
+
+const SSD1306_ADDRESS: u16 = 0x3C;
+const WIDTH: u32 = 128;
+const HEIGHT: u32 = 64;
+/// Eight rows of pixels per byte
+const PAGES: u8 = (HEIGHT / 8) as u8;
+
+/// Control bytes starting every I2C write
+const COMMAND: u8 = 0x00;
+const DATA: u8 = 0x40;
+
+const DISPLAY_OFF: u8 = 0xAE;
+const DISPLAY_ON: u8 = 0xAF;
+/// Init sequence for the common 128x64 modules with the internal charge pump
+const INIT: &[&[u8]] = &[
+    &[DISPLAY_OFF],
+    // Clock divider
+    &[0xD5, 0x80],
+    // 64 rows
+    &[0xA8, 0x3F],
+    &[0xD3, 0x00],
+    &[0x40],
+    // Charge pump on
+    &[0x8D, 0x14],
+    // Horizontal addressing
+    &[0x20, 0x00],
+    // Columns and rows mirrored, so the header pins are on top
+    &[0xA1],
+    &[0xC8],
+    // Alternative COM pin layout
+    &[0xDA, 0x12],
+    // Contrast, pre-charge period and VCOMH level
+    &[0x81, 0xCF],
+    &[0xD9, 0xF1],
+    &[0xDB, 0x40],
+    // Show the RAM contents, not inverted
+    &[0xA4],
+    &[0xA6],
+    &[DISPLAY_ON],
+];
+const COLUMN_ADDRESS: u8 = 0x21;
+const PAGE_ADDRESS: u8 = 0x22;
+
+/// Frame drawn in memory and sent in one go, a byte covers eight rows of a column
+struct Frame([u8; (WIDTH * HEIGHT / 8) as usize]);
+
+impl OriginDimensions for Frame {
+    fn size(&self) -> Size {
+        Size::new(WIDTH, HEIGHT)
+    }
+}
+
+impl DrawTarget for Frame {
+    type Color = BinaryColor;
+    type Error = std::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if !self.bounding_box().contains(point) {
+                continue;
+            }
+            let (x, y) = (point.x as usize, point.y as usize);
+            let byte = &mut self.0[x + (y / 8) * WIDTH as usize];
+            let bit = 1 << (y % 8);
+            match color {
+                BinaryColor::On => *byte |= bit,
+                BinaryColor::Off => *byte &= !bit,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Small I2C OLED next to the main display, showing the operator the balance, stock and
+/// connection at a glance while the main display stays with the customer
+pub struct StatusDisplay {
+    i2c: I2c,
+}
+
+impl StatusDisplay {
+    /// Opens the display if enabled by [`STATUS_DISPLAY_ENV`], or by `enabled` without it
+    pub fn from_config(enabled: bool) -> anyhow::Result<Option<Self>> {
+        let enabled = std::env::var(STATUS_DISPLAY_ENV).map_or(enabled, |value| value == "1");
+        if enabled {
+            Ok(Some(Self::new()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn new() -> anyhow::Result<Self> {
+        let mut i2c = I2c::new().context("Failed to open I2C bus")?;
+        i2c.set_slave_address(SSD1306_ADDRESS)?;
+        let display = Self { i2c };
+        display
+            .init()
+            .context("Failed to initialize status display")?;
+        Ok(display)
+    }
+
+    fn init(&self) -> anyhow::Result<()> {
+        INIT.iter().try_for_each(|command| self.command(command))
+    }
+
+    fn command(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut message = vec![COMMAND];
+        message.extend_from_slice(bytes);
+        self.i2c.write(&message)?;
+        Ok(())
+    }
+
+    fn show(&self, frame: &Frame) -> anyhow::Result<()> {
+        self.command(&[COLUMN_ADDRESS, 0, (WIDTH - 1) as u8])?;
+        self.command(&[PAGE_ADDRESS, 0, PAGES - 1])?;
+        let mut message = vec![DATA];
+        message.extend_from_slice(&frame.0);
+        self.i2c.write(&message)?;
+        Ok(())
+    }
+
+    /// Redraws whenever the status bar or the candy count changes. The display is switched off
+    /// once the status bar's sender is dropped, e.g. on shutdown.
+    pub fn spawn(
+        self,
+        mut status: watch::Receiver<StatusBar>,
+        mut candy: watch::Receiver<Option<CandyCount>>,
+    ) {
+        tokio::spawn(async move {
+            // Re-initialized after a failed write, e.g. the display was unplugged
+            let mut failed = false;
+            loop {
+                let frame = render(&status.borrow_and_update(), *candy.borrow_and_update());
+                let shown = if failed {
+                    self.init().and_then(|()| self.show(&frame))
+                } else {
+                    self.show(&frame)
+                };
+                if let Err(e) = &shown {
+                    warn!("Failed to update status display: {:#}", e);
+                }
+                failed = shown.is_err();
+
+                tokio::select! {
+                    changed = status.changed() => {
+                        if changed.is_err() {
+                            let _ = self.command(&[DISPLAY_OFF]);
+                            return;
+                        }
+                    }
+                    Ok(()) = candy.changed() => {}
+                }
+            }
+        });
+    }
+}
+
+fn render(status: &StatusBar, candy: Option<CandyCount>) -> Frame {
+    let mut frame = Frame([0; (WIDTH * HEIGHT / 8) as usize]);
+    let connection = match status.connection_status() {
+        ConnectionStatus::Connected => "Online",
+        ConnectionStatus::Disconnected => "OFFLINE",
+    };
+    let balance = match status.balance_sats() {
+        Some(sats) => format!("Balance {} sats", sats),
+        None => "Balance unknown".to_string(),
+    };
+    let mut stock = match candy {
+        Some(count) => format!("Stock {}/{}", count.left, count.capacity),
+        None => "Stock not counted".to_string(),
+    };
+    if status.low_stock() {
+        stock.push_str(" LOW");
+    }
+    let battery = match status.battery() {
+        Some(battery) if battery.on_battery => format!("Battery {}%", battery.battery_percent),
+        Some(battery) => format!("Mains, {}%", battery.battery_percent),
+        None => String::new(),
+    };
+
+    let style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
+    let lines = [connection, &balance, &stock, status.ip(), &battery];
+    for (row, line) in lines.into_iter().enumerate() {
+        let y = 9 + row as i32 * 12;
+        // Drawing into memory can't fail
+        let _ = Text::new(line, Point::new(0, y), style).draw(&mut frame);
+    }
+    frame
+}
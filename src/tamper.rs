@@ -0,0 +1,137 @@
+use crate::notify::Notifier;
+use crate::pins::{Input, Output, OutputSpec, PinRef, Pins};
+use chrono::{Local, Timelike};
+use fedimint_core::anyhow;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Environment variable restricting the alarm to outside business hours, e.g. `8-20` for 08:00
+/// to 20:00 local time. The alarm is armed around the clock if it is unset.
+pub const BUSINESS_HOURS_ENV: &str = "CANDYPI_BUSINESS_HOURS";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Don't raise the alarm again while the machine is still being moved
+const ALARM_COOLDOWN: Duration = Duration::from_secs(60);
+
+const BUZZER_BEEPS: u32 = 10;
+const BUZZER_BEEP_DURATION: Duration = Duration::from_millis(300);
+
+/// Raised when the machine was moved while the alarm was armed
+pub struct TamperAlarm;
+
+pub struct BusinessHours {
+    start_hour: u32,
+    end_hour: u32,
+}
+
+impl BusinessHours {
+    /// Reads business hours from [`BUSINESS_HOURS_ENV`], returns `None` if none were configured
+    pub fn from_env() -> Result<Option<Self>, String> {
+        match std::env::var(BUSINESS_HOURS_ENV) {
+            Ok(hours) => Self::parse(&hours).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn parse(hours: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid business hours '{hours}', expected e.g. '8-20'");
+
+        let (start, end) = hours.split_once('-').ok_or_else(invalid)?;
+        let start_hour = start.trim().parse::<u32>().map_err(|_| invalid())?;
+        let end_hour = end.trim().parse::<u32>().map_err(|_| invalid())?;
+        if start_hour > 24 || end_hour > 24 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            start_hour,
+            end_hour,
+        })
+    }
+
+    fn contains(&self, hour: u32) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            // Venue open past midnight
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// Watches a tilt or vibration switch and sounds the buzzer if it changes state
+pub struct TamperMonitor {
+    sensor: Input,
+    buzzer: Output,
+    business_hours: Option<BusinessHours>,
+    notifier: Notifier,
+}
+
+impl TamperMonitor {
+    pub fn new(
+        pins: &Pins,
+        sensor_pin: PinRef,
+        buzzer: OutputSpec,
+        business_hours: Option<BusinessHours>,
+        notifier: Notifier,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            sensor: pins.input_pullup(sensor_pin)?,
+            buzzer: pins.output(buzzer)?,
+            business_hours,
+            notifier,
+        })
+    }
+
+    fn is_armed(&self) -> bool {
+        self.business_hours
+            .as_ref()
+            .is_none_or(|hours| !hours.contains(Local::now().hour()))
+    }
+
+    /// Polls the sensor in a background task. Any deviation from the level read at startup counts
+    /// as movement, so both normally-open and normally-closed sensors work.
+    pub fn spawn(mut self) -> mpsc::UnboundedReceiver<TamperAlarm> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let resting_level = self.sensor.is_high();
+
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+
+                if self.sensor.is_high() == resting_level || !self.is_armed() {
+                    continue;
+                }
+
+                warn!("Tamper sensor triggered!");
+                if tx.send(TamperAlarm).is_err() {
+                    return;
+                }
+
+                let message = match self.business_hours {
+                    Some(_) => "Tamper alarm: the candy machine was moved outside business hours",
+                    None => "Tamper alarm: the candy machine was moved",
+                };
+                tokio::join!(
+                    self.notifier.notify(message),
+                    sound_buzzer(&mut self.buzzer),
+                );
+                tokio::time::sleep(ALARM_COOLDOWN).await;
+            }
+        });
+
+        rx
+    }
+}
+
+async fn sound_buzzer(buzzer: &mut Output) {
+    for _ in 0..BUZZER_BEEPS {
+        buzzer.activate();
+        tokio::time::sleep(BUZZER_BEEP_DURATION).await;
+        buzzer.deactivate();
+        tokio::time::sleep(BUZZER_BEEP_DURATION).await;
+    }
+}
@@ -0,0 +1,13 @@
+//! Fixtures shared by the unit tests
+
+use ed25519_dalek::SigningKey;
+
+/// The operator's key, whose public half the machine under test trusts
+pub fn operator() -> SigningKey {
+    SigningKey::from_bytes(&[7; 32])
+}
+
+/// Somebody else's key
+pub fn stranger() -> SigningKey {
+    SigningKey::from_bytes(&[8; 32])
+}
@@ -0,0 +1,413 @@
+use crate::i18n::{self, Catalog, DEFAULT_LANGUAGE};
+use crate::memory;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::*;
+use fedimint_core::anyhow::{self, Context, anyhow};
+use image::imageops::FilterType;
+use image::{DynamicImage, RgbImage};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Environment variable with the theme directory, defaults to `$XDG_DATA_HOME/candypi/theme`
+pub const THEME_DIR_ENV: &str = "CANDYPI_THEME_DIR";
+
+const THEME_FILE: &str = "theme.toml";
+const LOGO_FILE: &str = "logo.png";
+/// Full-screen image, the first one found is used
+const SPLASH_FILES: [&str; 2] = ["splash.png", "splash.bmp"];
+
+/// Area below the dispensing message the logo is scaled into
+pub const LOGO_MAX_WIDTH: u32 = 120;
+pub const LOGO_MAX_HEIGHT: u32 = 56;
+
+/// Larger splash images are scaled down on load, this still covers the biggest panel
+const SPLASH_MAX_SIZE: u32 = 320;
+
+/// Fast enough to see tweaks right after saving, cheap enough to not matter on a Pi Zero
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Colors, texts and the logo of the customer facing screens
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub language: String,
+    pub colors: Colors,
+    pub strings: Strings,
+    pub logo: Option<Logo>,
+    pub splash: Option<Splash>,
+    pub attract: Attract,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            language: DEFAULT_LANGUAGE.to_string(),
+            colors: Colors::default(),
+            strings: Strings::default(),
+            logo: None,
+            splash: None,
+            attract: Attract::default(),
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ThemeFile {
+    language: Option<String>,
+    colors: Colors,
+    /// Overrides translations, keyed by the message ids of the Fluent files
+    strings: HashMap<String, String>,
+    attract: Attract,
+}
+
+/// Cycles through the splash image and promo texts once the invoice went unpaid for a while
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Attract {
+    /// Seconds without a payment before the loop starts, `0` only shows the splash at boot
+    pub interval_secs: u64,
+    /// Seconds each screen of the loop is shown
+    pub duration_secs: u64,
+    /// Shown after the splash, `{price}` is replaced by the invoice amount
+    pub messages: Vec<String>,
+}
+
+impl Default for Attract {
+    fn default() -> Self {
+        Self {
+            interval_secs: 0,
+            duration_secs: 5,
+            messages: Vec::new(),
+        }
+    }
+}
+
+impl Attract {
+    pub fn interval(&self) -> Option<Duration> {
+        (self.interval_secs > 0).then(|| Duration::from_secs(self.interval_secs))
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration_secs)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Colors {
+    pub invoice_background: ThemeColor,
+    pub invoice_text: ThemeColor,
+    pub success_background: ThemeColor,
+    pub success_text: ThemeColor,
+    pub alarm_background: ThemeColor,
+    pub alarm_text: ThemeColor,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            invoice_background: ThemeColor(Rgb565::WHITE),
+            invoice_text: ThemeColor(Rgb565::BLACK),
+            success_background: ThemeColor(Rgb565::new(0, 31, 0)),
+            success_text: ThemeColor(Rgb565::WHITE),
+            alarm_background: ThemeColor(Rgb565::new(31, 0, 0)),
+            alarm_text: ThemeColor(Rgb565::WHITE),
+        }
+    }
+}
+
+/// Texts of the customer screens, also the message ids in Fluent files and the theme
+#[derive(Debug, Clone)]
+pub struct Strings {
+    pub payment_received: String,
+    pub dispensing: String,
+    pub alarm: String,
+    pub operator_notified: String,
+    pub battery_empty: String,
+    pub shutting_down: String,
+    pub out_of_service: String,
+    pub connecting: String,
+    pub offline: String,
+    pub sold_out: String,
+    pub jammed: String,
+    pub scan_for_refund: String,
+    pub payment_detected: String,
+    pub awaiting_confirmation: String,
+    pub coins_inserted: String,
+    pub wifi_setup: String,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Self {
+            payment_received: "Payment Received!".to_string(),
+            dispensing: "Dispensing...".to_string(),
+            alarm: "ALARM!".to_string(),
+            operator_notified: "Operator notified".to_string(),
+            battery_empty: "Battery empty".to_string(),
+            shutting_down: "Shutting down...".to_string(),
+            out_of_service: "Out of service".to_string(),
+            connecting: "Connecting...".to_string(),
+            offline: "Offline".to_string(),
+            sold_out: "Sold out".to_string(),
+            jammed: "Sorry, it jammed!".to_string(),
+            scan_for_refund: "Scan for refund".to_string(),
+            payment_detected: "Payment detected".to_string(),
+            awaiting_confirmation: "Waiting for confirmation, come back later".to_string(),
+            coins_inserted: "Coins inserted".to_string(),
+            wifi_setup: "No network. Scan to join, then open".to_string(),
+        }
+    }
+}
+
+impl Strings {
+    const IDS: [&str; 16] = [
+        "payment_received",
+        "dispensing",
+        "alarm",
+        "operator_notified",
+        "battery_empty",
+        "shutting_down",
+        "out_of_service",
+        "connecting",
+        "offline",
+        "sold_out",
+        "jammed",
+        "scan_for_refund",
+        "payment_detected",
+        "awaiting_confirmation",
+        "coins_inserted",
+        "wifi_setup",
+    ];
+
+    fn get_mut(&mut self, id: &str) -> Option<&mut String> {
+        match id {
+            "payment_received" => Some(&mut self.payment_received),
+            "dispensing" => Some(&mut self.dispensing),
+            "alarm" => Some(&mut self.alarm),
+            "operator_notified" => Some(&mut self.operator_notified),
+            "battery_empty" => Some(&mut self.battery_empty),
+            "shutting_down" => Some(&mut self.shutting_down),
+            "out_of_service" => Some(&mut self.out_of_service),
+            "connecting" => Some(&mut self.connecting),
+            "offline" => Some(&mut self.offline),
+            "sold_out" => Some(&mut self.sold_out),
+            "jammed" => Some(&mut self.jammed),
+            "scan_for_refund" => Some(&mut self.scan_for_refund),
+            "payment_detected" => Some(&mut self.payment_detected),
+            "awaiting_confirmation" => Some(&mut self.awaiting_confirmation),
+            "coins_inserted" => Some(&mut self.coins_inserted),
+            "wifi_setup" => Some(&mut self.wifi_setup),
+            _ => None,
+        }
+    }
+
+    /// English defaults, replaced by whatever the catalog translates
+    fn localized(catalog: &Catalog) -> Self {
+        let mut strings = Self::default();
+        for id in Self::IDS {
+            if let Some(text) = catalog.get(id) {
+                *strings.get_mut(id).expect("Listed in IDS") = text;
+            }
+        }
+        strings
+    }
+}
+
+/// Color written as `#rrggbb` in the theme file
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ThemeColor(pub Rgb565);
+
+impl TryFrom<String> for ThemeColor {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let rgb = value
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| format!("Invalid color '{}', expected #rrggbb", value))?;
+        let [_, r, g, b] = rgb.to_be_bytes();
+        Ok(ThemeColor(Rgb565::new(r >> 3, g >> 2, b >> 3)))
+    }
+}
+
+/// Logo or fitted splash bitmap converted to big endian RGB565, as expected by `ImageRaw`
+#[derive(Debug, Clone)]
+pub struct Logo {
+    pub data: Vec<u8>,
+    pub width: u32,
+}
+
+/// Operator's splash image, kept at its own size and fitted to the panel when drawn
+#[derive(Debug, Clone)]
+pub struct Splash(DynamicImage);
+
+impl Splash {
+    /// Scales the image to cover `width` x `height`, cropping what sticks out evenly on both
+    /// sides
+    pub fn fit(&self, width: u32, height: u32) -> Logo {
+        to_rgb565(
+            &self
+                .0
+                .resize_to_fill(width, height, FilterType::Triangle)
+                .to_rgb8(),
+        )
+    }
+}
+
+impl Theme {
+    pub fn dir_from_env() -> PathBuf {
+        match std::env::var(THEME_DIR_ENV) {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => xdg::BaseDirectories::new()
+                .data_home
+                .expect("Could not determine XDG data home")
+                .join("candypi/theme"),
+        }
+    }
+
+    /// Loads `theme.toml`, `logo.png` and the splash image from `dir` and the translation for the
+    /// theme's language from `locale_dir`, anything missing keeps its default
+    pub fn load(dir: &Path, locale_dir: &Path) -> anyhow::Result<Self> {
+        let file: ThemeFile = match fs::read_to_string(dir.join(THEME_FILE)) {
+            Ok(content) => {
+                toml::from_str(&content).with_context(|| format!("Invalid {}", THEME_FILE))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => ThemeFile::default(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", THEME_FILE)),
+        };
+
+        let language = file.language.unwrap_or_else(i18n::language_from_env);
+        // English is built in, but may still be reworded through an `en.ftl`
+        let catalog_exists = locale_dir.join(format!("{}.ftl", language)).exists();
+        let mut strings = if language != DEFAULT_LANGUAGE || catalog_exists {
+            Strings::localized(&Catalog::load(locale_dir, &language)?)
+        } else {
+            Strings::default()
+        };
+        for (id, text) in file.strings {
+            *strings
+                .get_mut(&id)
+                .ok_or_else(|| anyhow!("Unknown string '{}' in {}", id, THEME_FILE))? = text;
+        }
+
+        let logo_path = dir.join(LOGO_FILE);
+        // The decoded bitmap stays in memory for the whole runtime
+        let logo = if logo_path.exists() && !memory::low_memory() {
+            Some(load_logo(&logo_path)?)
+        } else {
+            None
+        };
+        // Kept in memory as well, skipped along with the logo when memory is tight
+        let splash = SPLASH_FILES
+            .map(|file| dir.join(file))
+            .into_iter()
+            .find(|path| path.exists())
+            .filter(|_| !memory::low_memory())
+            .map(|path| load_splash(&path))
+            .transpose()?;
+
+        Ok(Theme {
+            language,
+            colors: file.colors,
+            strings,
+            logo,
+            splash,
+            attract: file.attract,
+        })
+    }
+
+    /// Loads the theme and reloads it in a background task whenever a file in `dir` or
+    /// `locale_dir` changes. A broken edit is logged and the previous theme kept.
+    pub fn spawn_watcher(dir: PathBuf, locale_dir: PathBuf) -> watch::Receiver<Arc<Theme>> {
+        let initial = Theme::load(&dir, &locale_dir).unwrap_or_else(|e| {
+            warn!("Failed to load theme, using defaults: {:#}", e);
+            Theme::default()
+        });
+        let (tx, rx) = watch::channel(Arc::new(initial));
+
+        tokio::spawn(async move {
+            let mut last_modified = latest_modification(&dir, &locale_dir);
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+
+                let modified = latest_modification(&dir, &locale_dir);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                match Theme::load(&dir, &locale_dir) {
+                    Ok(theme) => {
+                        info!(
+                            "Reloaded theme from {} in language {}",
+                            dir.display(),
+                            theme.language
+                        );
+                        if tx.send(Arc::new(theme)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => warn!("Failed to reload theme, keeping the old one: {:#}", e),
+                }
+            }
+        });
+
+        rx
+    }
+}
+
+fn latest_modification(dir: &Path, locale_dir: &Path) -> Option<SystemTime> {
+    let translations = fs::read_dir(locale_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()));
+    [THEME_FILE, LOGO_FILE]
+        .into_iter()
+        .chain(SPLASH_FILES)
+        .map(|file| dir.join(file))
+        .chain(translations)
+        .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+fn load_logo(path: &Path) -> anyhow::Result<Logo> {
+    let image = image::open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?
+        .thumbnail(LOGO_MAX_WIDTH, LOGO_MAX_HEIGHT)
+        .to_rgb8();
+    if image.width() == 0 {
+        return Err(anyhow!("{} is empty", path.display()));
+    }
+    Ok(to_rgb565(&image))
+}
+
+fn load_splash(path: &Path) -> anyhow::Result<Splash> {
+    let image = image::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    if image.width() == 0 || image.height() == 0 {
+        return Err(anyhow!("{} is empty", path.display()));
+    }
+    Ok(Splash(image.thumbnail(SPLASH_MAX_SIZE, SPLASH_MAX_SIZE)))
+}
+
+fn to_rgb565(image: &RgbImage) -> Logo {
+    let mut data = Vec::with_capacity((image.width() * image.height() * 2) as usize);
+    for pixel in image.pixels() {
+        let [r, g, b] = pixel.0;
+        let rgb565 = Rgb565::new(r >> 3, g >> 2, b >> 3).into_storage();
+        data.extend_from_slice(&rgb565.to_be_bytes());
+    }
+
+    Logo {
+        data,
+        width: image.width(),
+    }
+}
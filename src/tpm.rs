@@ -0,0 +1,77 @@
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use fedimint_core::anyhow::{self, Context, anyhow, ensure};
+use std::process::Command;
+
+/// Environment variable naming the TPM NV index (e.g. `0x1500016`) holding the seed key. The
+/// wallet secret is stored unencrypted if it is unset.
+pub const TPM_NV_INDEX_ENV: &str = "CANDYPI_TPM_NV_INDEX";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Key encrypting the wallet secret at rest. It only ever lives in the TPM and in memory, so
+/// copying the SD card alone isn't enough to steal the funds.
+pub struct SeedKey {
+    cipher: ChaCha20Poly1305,
+}
+
+impl SeedKey {
+    /// Reads the key from the NV index in [`TPM_NV_INDEX_ENV`], returns `None` if none was
+    /// configured
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        match std::env::var(TPM_NV_INDEX_ENV) {
+            Ok(nv_index) => Self::read_from_tpm(&nv_index).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Reads the key using `tpm2_nvread` from tpm2-tools, the index has to be provisioned by the
+    /// operator beforehand (see README)
+    pub fn read_from_tpm(nv_index: &str) -> anyhow::Result<Self> {
+        let output = Command::new("tpm2_nvread")
+            .args([nv_index, "--size", &KEY_LEN.to_string()])
+            .output()
+            .context("Failed to run tpm2_nvread, is tpm2-tools installed?")?;
+        ensure!(
+            output.status.success(),
+            "tpm2_nvread failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        ensure!(
+            output.stdout.len() == KEY_LEN,
+            "TPM NV index {} must hold a {} byte key",
+            nv_index,
+            KEY_LEN
+        );
+
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&output.stdout)),
+        })
+    }
+
+    /// Encrypts the secret, the random nonce is prepended to the ciphertext
+    pub fn seal(&self, secret: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, secret)
+            .map_err(|_| anyhow!("Failed to encrypt wallet secret"))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    pub fn unseal(&self, sealed: &[u8]) -> anyhow::Result<Vec<u8>> {
+        ensure!(
+            sealed.len() > NONCE_LEN,
+            "Sealed wallet secret is truncated"
+        );
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt wallet secret, wrong TPM key?"))
+    }
+}
@@ -0,0 +1,116 @@
+use crate::notify::Notifier;
+use crate::retry::{self, Retry};
+use rppal::i2c::I2c;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Environment variable that, if set to `1`, enables monitoring of a Waveshare UPS HAT (C)
+pub const UPS_ENV: &str = "CANDYPI_UPS";
+
+/// INA219 power monitor on the Waveshare UPS HAT (C) for the Pi Zero
+const INA219_ADDRESS: u16 = 0x43;
+const REG_SHUNT_VOLTAGE: u8 = 0x01;
+const REG_BUS_VOLTAGE: u8 = 0x02;
+
+/// Single Li-Po cell, linear approximation of the discharge curve
+const CELL_EMPTY_VOLTS: f32 = 3.0;
+const CELL_FULL_VOLTS: f32 = 4.2;
+
+/// Leave enough charge to shut down cleanly
+const SHUTDOWN_PERCENT: u8 = 10;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpsStatus {
+    pub battery_percent: u8,
+    pub on_battery: bool,
+}
+
+pub enum UpsEvent {
+    Status(UpsStatus),
+    /// Battery is about to run out, the machine has to shut down now
+    ShutdownRequired,
+}
+
+pub struct Ups {
+    i2c: I2c,
+}
+
+impl Ups {
+    pub fn new() -> rppal::i2c::Result<Self> {
+        let mut i2c = I2c::new()?;
+        i2c.set_slave_address(INA219_ADDRESS)?;
+        Ok(Self { i2c })
+    }
+
+    fn read_register(&mut self, register: u8) -> rppal::i2c::Result<u16> {
+        let mut value = [0u8; 2];
+        self.i2c.write_read(&[register], &mut value)?;
+        Ok(u16::from_be_bytes(value))
+    }
+
+    pub fn read_status(&mut self) -> rppal::i2c::Result<UpsStatus> {
+        // Bus voltage sits in bits 15..3 with a 4 mV LSB
+        let bus_volts = f32::from(self.read_register(REG_BUS_VOLTAGE)? >> 3) * 0.004;
+        // Current flows out of the battery through the shunt while discharging
+        let shunt = self.read_register(REG_SHUNT_VOLTAGE)? as i16;
+
+        let charge = (bus_volts - CELL_EMPTY_VOLTS) / (CELL_FULL_VOLTS - CELL_EMPTY_VOLTS);
+        Ok(UpsStatus {
+            battery_percent: (charge.clamp(0.0, 1.0) * 100.0) as u8,
+            on_battery: shunt < 0,
+        })
+    }
+
+    /// Polls the UPS in a background task, reporting status changes and notifying the operator
+    /// about power loss
+    pub fn spawn(mut self, notifier: Notifier) -> mpsc::UnboundedReceiver<UpsEvent> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut last_status: Option<UpsStatus> = None;
+
+            loop {
+                let status = Retry::SENSOR
+                    .run("read UPS status", retry::always, async || {
+                        self.read_status()
+                    })
+                    .await;
+                match status {
+                    Ok(status) if Some(status) != last_status => {
+                        let was_on_battery = last_status.is_some_and(|last| last.on_battery);
+                        if status.on_battery && !was_on_battery {
+                            notifier
+                                .notify(&format!(
+                                    "Mains power lost, running on battery ({}%)",
+                                    status.battery_percent
+                                ))
+                                .await;
+                        } else if !status.on_battery && was_on_battery {
+                            notifier.notify("Mains power restored").await;
+                        }
+
+                        last_status = Some(status);
+                        if tx.send(UpsEvent::Status(status)).is_err() {
+                            return;
+                        }
+
+                        if status.on_battery && status.battery_percent <= SHUTDOWN_PERCENT {
+                            notifier.notify("Battery almost empty, shutting down").await;
+                            let _ = tx.send(UpsEvent::ShutdownRequired);
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to read UPS status: {}", e),
+                }
+
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        });
+
+        rx
+    }
+}
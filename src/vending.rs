@@ -0,0 +1,166 @@
+use serde::Serialize;
+use tracing::{debug, warn};
+
+/// Where the machine is in selling one portion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VendingState {
+    /// About to create an invoice
+    Idle,
+    /// Invoice on screen, waiting for payment
+    InvoiceShown,
+    /// Paid, the dispense is about to start
+    Paid,
+    Dispensing,
+    /// The success screen stays up for a moment before the next invoice
+    Cooldown,
+    /// Taken out of service by the operator, no invoice is shown
+    Maintenance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VendingEvent {
+    InvoiceShown,
+    /// The invoice is withdrawn, e.g. after a price change or shortly before it expires
+    InvoiceReplaced,
+    PaymentReceived,
+    DispenseStarted,
+    /// The dispense finished, whether it completed or not
+    DispenseDone,
+    CooldownElapsed,
+    MaintenanceStarted,
+    MaintenanceEnded,
+}
+
+/// Explicit transitions of the vending flow. The main loop performs the side effects and steps
+/// the machine, new states only need a variant and their transitions here.
+#[derive(Debug)]
+pub struct VendingStateMachine {
+    state: VendingState,
+}
+
+impl Default for VendingStateMachine {
+    fn default() -> Self {
+        Self {
+            state: VendingState::Idle,
+        }
+    }
+}
+
+impl VendingStateMachine {
+    pub fn state(&self) -> VendingState {
+        self.state
+    }
+
+    pub fn in_maintenance(&self) -> bool {
+        self.state == VendingState::Maintenance
+    }
+
+    /// Applies `event` and returns the new state. Events that don't apply to the current state
+    /// are logged and leave it unchanged.
+    pub fn step(&mut self, event: VendingEvent) -> VendingState {
+        use VendingEvent as E;
+        use VendingState as S;
+
+        let next = match (self.state, event) {
+            (S::Idle | S::InvoiceShown | S::Maintenance, E::MaintenanceStarted) => S::Maintenance,
+            (S::Maintenance, E::MaintenanceEnded) => S::Idle,
+            // Nothing on screen to replace
+            (S::Idle | S::Maintenance, E::InvoiceReplaced) => self.state,
+            (S::Idle, E::InvoiceShown) => S::InvoiceShown,
+            (S::InvoiceShown, E::InvoiceReplaced) => S::Idle,
+            (S::InvoiceShown, E::PaymentReceived) => S::Paid,
+            // Coins don't need an invoice, e.g. while offline
+            (S::Idle, E::PaymentReceived) => S::Paid,
+            (S::Paid, E::DispenseStarted) => S::Dispensing,
+            (S::Dispensing, E::DispenseDone) => S::Cooldown,
+            (S::Cooldown, E::CooldownElapsed) => S::Idle,
+            (state, event) => {
+                warn!("Ignoring {:?} in vending state {:?}", event, state);
+                return state;
+            }
+        };
+
+        if next != self.state {
+            debug!(from = ?self.state, to = ?next, ?event, "Vending state changed");
+        }
+        self.state = next;
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use VendingEvent as E;
+    use VendingState as S;
+
+    fn machine_in(events: &[VendingEvent]) -> VendingStateMachine {
+        let mut machine = VendingStateMachine::default();
+        for &event in events {
+            machine.step(event);
+        }
+        machine
+    }
+
+    #[test]
+    fn sells_one_portion() {
+        let mut machine = VendingStateMachine::default();
+        assert_eq!(machine.state(), S::Idle);
+        assert_eq!(machine.step(E::InvoiceShown), S::InvoiceShown);
+        assert_eq!(machine.step(E::PaymentReceived), S::Paid);
+        assert_eq!(machine.step(E::DispenseStarted), S::Dispensing);
+        assert_eq!(machine.step(E::DispenseDone), S::Cooldown);
+        assert_eq!(machine.step(E::CooldownElapsed), S::Idle);
+    }
+
+    #[test]
+    fn replaced_invoice_returns_to_idle() {
+        let mut machine = machine_in(&[E::InvoiceShown]);
+        assert_eq!(machine.step(E::InvoiceReplaced), S::Idle);
+        assert_eq!(machine.step(E::InvoiceReplaced), S::Idle);
+    }
+
+    #[test]
+    fn payment_without_invoice_is_sold() {
+        let mut machine = VendingStateMachine::default();
+        assert_eq!(machine.step(E::PaymentReceived), S::Paid);
+    }
+
+    #[test]
+    fn maintenance_stops_sales() {
+        let mut machine = machine_in(&[E::InvoiceShown, E::MaintenanceStarted]);
+        assert!(machine.in_maintenance());
+        assert_eq!(machine.step(E::InvoiceShown), S::Maintenance);
+        assert_eq!(machine.step(E::PaymentReceived), S::Maintenance);
+        assert_eq!(machine.step(E::MaintenanceEnded), S::Idle);
+        assert!(!machine.in_maintenance());
+    }
+
+    #[test]
+    fn ignores_events_out_of_order() {
+        let cases = [
+            (&[][..], E::DispenseStarted, S::Idle),
+            (&[][..], E::DispenseDone, S::Idle),
+            (&[][..], E::MaintenanceEnded, S::Idle),
+            (&[E::InvoiceShown][..], E::InvoiceShown, S::InvoiceShown),
+            (&[E::PaymentReceived][..], E::PaymentReceived, S::Paid),
+            (&[E::PaymentReceived][..], E::InvoiceReplaced, S::Paid),
+            (
+                &[E::PaymentReceived, E::DispenseStarted][..],
+                E::MaintenanceStarted,
+                S::Dispensing,
+            ),
+            (
+                &[E::PaymentReceived, E::DispenseStarted, E::DispenseDone][..],
+                E::PaymentReceived,
+                S::Cooldown,
+            ),
+        ];
+        for (history, event, expected) in cases {
+            let mut machine = machine_in(history);
+            assert_eq!(machine.step(event), expected, "{event:?} after {history:?}");
+            assert_eq!(machine.state(), expected);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use crate::cashu::CashuWallet;
+use crate::fedimint::Fedimint;
+use crate::lnd::LndNode;
+use crate::watch_only::NwcReceiver;
+use async_trait::async_trait;
+use fedimint_bip39::Mnemonic;
+use fedimint_core::anyhow::{self, bail};
+use lightning_invoice::Bolt11Invoice;
+
+/// Creates invoices and waits for them to be paid, everything the vending loop needs from a
+/// Lightning backend
+#[async_trait]
+pub trait PaymentProvider: Send + Sync {
+    async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice>;
+
+    /// Returns once the invoice was paid, fails if it expired or was canceled
+    async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()>;
+}
+
+/// Where invoices come from and payments end up
+pub enum Wallet {
+    /// Local Fedimint client holding the earned ecash
+    Fedimint(Fedimint),
+    /// External wallet only granting invoice creation, nothing on the machine can spend funds
+    WatchOnly(NwcReceiver),
+    /// Ecash of a Cashu mint, for places where a federation isn't appropriate
+    Cashu(CashuWallet),
+    /// The operator's own Lightning node, the funds stay on the node
+    Lnd(LndNode),
+}
+
+impl Wallet {
+    fn provider(&self) -> &dyn PaymentProvider {
+        match self {
+            Wallet::Fedimint(fedimint) => fedimint,
+            Wallet::WatchOnly(nwc) => nwc,
+            Wallet::Cashu(cashu) => cashu,
+            Wallet::Lnd(lnd) => lnd,
+        }
+    }
+
+    pub async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        self.provider()
+            .lightning_invoice(amount_msats, description)
+            .await
+    }
+
+    pub async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        self.provider().await_payment(invoice).await
+    }
+
+    /// Funds held on the machine, fails for backends whose funds live elsewhere
+    pub async fn balance_msat(&self) -> anyhow::Result<u64> {
+        match self {
+            Wallet::Fedimint(fedimint) => Ok(fedimint.balance().await?.msats),
+            Wallet::Cashu(cashu) => Ok(cashu.balance().await? * 1000),
+            Wallet::WatchOnly(_) => bail!("No balance on the machine in watch-only mode"),
+            Wallet::Lnd(_) => bail!("The funds stay on the LND node, check its balance there"),
+        }
+    }
+
+    /// Pays out of the funds held on the machine, fails for backends that can't spend
+    pub async fn pay_invoice(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        match self {
+            Wallet::Fedimint(fedimint) => fedimint.pay_invoice(invoice).await,
+            Wallet::Cashu(cashu) => cashu.pay_invoice(invoice).await,
+            Wallet::WatchOnly(_) => bail!("Nothing to spend in watch-only mode"),
+            Wallet::Lnd(_) => bail!("The invoice macaroon can't spend, pay from the LND node"),
+        }
+    }
+
+    /// Seed words of the funds held on the machine, fails for backends without a seed
+    pub async fn mnemonic(&self) -> anyhow::Result<Mnemonic> {
+        match self {
+            Wallet::Fedimint(fedimint) => fedimint.mnemonic().await,
+            Wallet::Cashu(cashu) => Ok(cashu.mnemonic().clone()),
+            Wallet::WatchOnly(_) => bail!("No seed on the machine in watch-only mode"),
+            Wallet::Lnd(_) => bail!("The seed is kept by the LND node"),
+        }
+    }
+
+    /// Stops background tasks and flushes the database, e.g. before powering off
+    pub async fn shutdown(self) {
+        match self {
+            Wallet::Fedimint(fedimint) => fedimint.shutdown().await,
+            Wallet::WatchOnly(nwc) => nwc.shutdown(),
+            Wallet::Cashu(_) | Wallet::Lnd(_) => {}
+        }
+    }
+
+    /// Whether funds and a seed are kept on the machine, which a factory reset would wipe
+    pub fn holds_funds(&self) -> bool {
+        matches!(self, Wallet::Fedimint(_) | Wallet::Cashu(_))
+    }
+
+    /// Returns the local Fedimint wallet, `None` in watch-only mode where there is nothing to
+    /// spend and with other backends
+    pub fn fedimint(&self) -> Option<&Fedimint> {
+        match self {
+            Wallet::Fedimint(fedimint) => Some(fedimint),
+            Wallet::WatchOnly(_) | Wallet::Cashu(_) | Wallet::Lnd(_) => None,
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::wallet::PaymentProvider;
+use async_trait::async_trait;
+use fedimint_core::anyhow::{self, Context, ensure};
+use lightning_invoice::Bolt11Invoice;
+use nwc::prelude::*;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Environment variable with a Nostr Wallet Connect URI. If set the machine runs watch-only:
+/// invoices are created by the external wallet and no Fedimint client is started.
+pub const NWC_URI_ENV: &str = "CANDYPI_NWC_URI";
+
+const PAYMENT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Still polled with notifications, in case one got lost while a relay was down
+const NOTIFIED_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// NWC methods that would let a stolen machine move funds
+const SPENDING_METHODS: [&str; 4] = [
+    "pay_invoice",
+    "multi_pay_invoice",
+    "pay_keysend",
+    "multi_pay_keysend",
+];
+
+/// Receive-only connection to an external wallet, the machine holds no spendable keys
+pub struct NwcReceiver {
+    nwc: Arc<NWC>,
+    /// Payment hashes from `payment_received` notifications, `None` if the wallet doesn't send
+    /// any
+    received: Option<broadcast::Sender<String>>,
+    notifications: Option<JoinHandle<()>>,
+}
+
+impl NwcReceiver {
+    /// Connects to the wallet and refuses connections that are allowed to spend
+    pub async fn connect(uri: &str) -> anyhow::Result<Self> {
+        let uri = NostrWalletConnectURI::from_str(uri).context("Invalid NWC connection URI")?;
+        let nwc = NWC::new(uri);
+
+        let info = nwc.get_info().await.context("Failed to reach NWC wallet")?;
+        ensure!(
+            !info
+                .methods
+                .iter()
+                .any(|method| SPENDING_METHODS.contains(&method.to_string().as_str())),
+            "NWC connection is allowed to spend, create a receive-only connection for watch-only mode"
+        );
+
+        let nwc = Arc::new(nwc);
+        if !info
+            .notifications
+            .iter()
+            .any(|notification| notification.to_string() == "payment_received")
+        {
+            info!("NWC wallet doesn't send payment notifications, polling");
+            return Ok(Self {
+                nwc,
+                received: None,
+                notifications: None,
+            });
+        }
+
+        nwc.subscribe_to_notifications()
+            .await
+            .context("Failed to subscribe to NWC notifications")?;
+        let (received, _) = broadcast::channel(16);
+        let notifications = spawn_notification_listener(nwc.clone(), received.clone());
+
+        Ok(Self {
+            nwc,
+            received: Some(received),
+            notifications: Some(notifications),
+        })
+    }
+
+    /// Stops listening for notifications
+    pub fn shutdown(&self) {
+        if let Some(notifications) = &self.notifications {
+            notifications.abort();
+        }
+    }
+}
+
+/// Forwards the payment hash of every `payment_received` notification to `received`
+fn spawn_notification_listener(
+    nwc: Arc<NWC>,
+    received: broadcast::Sender<String>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let handled = nwc
+            .handle_notifications(|notification| {
+                let received = received.clone();
+                async move {
+                    if notification.notification_type == NotificationType::PaymentReceived {
+                        // Nobody waiting for it is fine, e.g. an invoice paid after expiry
+                        let _ = received.send(notification.notification.payment_hash);
+                    }
+                    // Keep listening
+                    Ok(false)
+                }
+            })
+            .await;
+        if let Err(e) = handled {
+            warn!("Stopped receiving NWC notifications, polling: {}", e);
+        }
+    })
+}
+
+/// Returns once a notification for `payment_hash` arrived or notifications were missed, never if
+/// they stopped
+async fn notified(received: &mut broadcast::Receiver<String>, payment_hash: &str) {
+    loop {
+        match received.recv().await {
+            Ok(hash) if hash == payment_hash => return,
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(_)) => return,
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+#[async_trait]
+impl PaymentProvider for NwcReceiver {
+    async fn lightning_invoice(
+        &self,
+        amount_msats: u64,
+        description: &str,
+    ) -> anyhow::Result<Bolt11Invoice> {
+        let response = self
+            .nwc
+            .make_invoice(MakeInvoiceRequest {
+                amount: amount_msats,
+                description: Some(description.to_owned()),
+                description_hash: None,
+                expiry: None,
+            })
+            .await?;
+
+        Ok(Bolt11Invoice::from_str(&response.invoice)?)
+    }
+
+    /// Looks the invoice up whenever the wallet notifies about a payment to it, or polls if the
+    /// wallet doesn't send notifications
+    async fn await_payment(&self, invoice: &Bolt11Invoice) -> anyhow::Result<()> {
+        let payment_hash = invoice.payment_hash().to_string();
+        let mut received = self.received.as_ref().map(broadcast::Sender::subscribe);
+
+        loop {
+            let lookup = self
+                .nwc
+                .lookup_invoice(LookupInvoiceRequest {
+                    payment_hash: Some(payment_hash.clone()),
+                    invoice: None,
+                })
+                .await;
+            match lookup {
+                Ok(LookupInvoiceResponse {
+                    settled_at: Some(settled_at),
+                    ..
+                }) => {
+                    // Includes up to one poll interval of waiting for the next lookup without
+                    // notifications
+                    let latency = Timestamp::now()
+                        .as_u64()
+                        .saturating_sub(settled_at.as_u64());
+                    metrics::histogram!("candypi_payment_detection_seconds").record(latency as f64);
+                    return Ok(());
+                }
+                Ok(_) => {}
+                // Relays drop out every now and then, keep trying until the invoice expires
+                Err(e) => warn!("Failed to look up invoice status: {}", e),
+            }
+
+            ensure!(!invoice.is_expired(), "Invoice expired before being paid");
+            match &mut received {
+                Some(received) => {
+                    tokio::select! {
+                        _ = notified(received, &payment_hash) => {}
+                        _ = tokio::time::sleep(NOTIFIED_POLL_INTERVAL) => {}
+                    }
+                }
+                None => tokio::time::sleep(PAYMENT_POLL_INTERVAL).await,
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use fedimint_core::anyhow::{self, anyhow};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Environment variable enabling the hardware watchdog if set to `1`
+pub const HARDWARE_WATCHDOG_ENV: &str = "CANDYPI_HARDWARE_WATCHDOG";
+
+const WATCHDOG_DEVICE: &str = "/dev/watchdog";
+
+/// Well within the BCM2835 watchdog's 15 s default timeout
+const FEED_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Gives up on `operation` after `limit`, for network calls that may hang forever, e.g. during
+/// a gateway outage. Timeouts are logged and counted in `candypi_watchdog_timeouts_total`.
+pub async fn within<T>(
+    what: &'static str,
+    limit: Duration,
+    operation: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    match tokio::time::timeout(limit, operation).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "Watchdog: {} stalled for {} s, giving up",
+                what,
+                limit.as_secs()
+            );
+            metrics::counter!("candypi_watchdog_timeouts_total", "operation" => what).increment(1);
+            Err(anyhow!("Timed out trying to {}", what))
+        }
+    }
+}
+
+/// Keeps the Pi's hardware watchdog from rebooting the machine as long as the async runtime is
+/// alive, so a deadlock or a hard crash ends in a reboot instead of a dead machine
+pub struct HardwareWatchdog {
+    stop: oneshot::Sender<()>,
+    feeder: JoinHandle<()>,
+}
+
+impl HardwareWatchdog {
+    /// Opens the watchdog if [`HARDWARE_WATCHDOG_ENV`] is set, which arms it
+    pub fn from_env() -> io::Result<Option<Self>> {
+        if !std::env::var(HARDWARE_WATCHDOG_ENV).is_ok_and(|value| value == "1") {
+            return Ok(None);
+        }
+        // Fails if systemd's RuntimeWatchdogSec already owns it
+        let device = OpenOptions::new().write(true).open(WATCHDOG_DEVICE)?;
+        info!("Hardware watchdog armed");
+        Ok(Some(Self::spawn(device)))
+    }
+
+    fn spawn(mut device: File) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+        let feeder = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FEED_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {
+                        if let Err(e) = device.write_all(b"\0") {
+                            warn!("Failed to feed hardware watchdog: {}", e);
+                        }
+                    }
+                    result = &mut stopped => {
+                        match result {
+                            // The magic close character, closing alone keeps the timer running
+                            Ok(()) => {
+                                if let Err(e) = device.write_all(b"V") {
+                                    warn!("Failed to disarm hardware watchdog: {}", e);
+                                }
+                            }
+                            // Dropped without disarming, the main loop died and the timer is
+                            // left to run out
+                            Err(_) => {}
+                        }
+                        break;
+                    }
+                }
+            }
+        });
+        Self { stop, feeder }
+    }
+
+    /// Stops the watchdog before a deliberate exit, otherwise it reboots the machine shortly
+    /// after
+    pub async fn disarm(self) {
+        let _ = self.stop.send(());
+        let _ = self.feeder.await;
+    }
+}
@@ -0,0 +1,321 @@
+use axum::extract::State;
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Form, Router};
+use fedimint_core::anyhow::{self, Context, ensure};
+use serde::Deserialize;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::process::Command;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+const DEFAULT_SSID: &str = "candypi-setup";
+const DEFAULT_INTERFACE: &str = "wlan0";
+const DEFAULT_PORT: u16 = 80;
+/// NetworkManager brings up known networks within seconds, but a router booting after the same
+/// power cut takes longer
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// NetworkManager connection profile of the hotspot
+const HOTSPOT_CONNECTION: &str = "candypi-setup";
+/// NetworkManager's shared mode hands out 10.42.0.0/24 and keeps the first address
+const HOTSPOT_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 42, 0, 1);
+
+const SETUP_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width, initial-scale=1"><title>candypi WiFi setup</title></head>
+<body>
+<h1>WiFi setup</h1>
+<form method="post">
+<p><label>Network name<br><input name="ssid" required></label></p>
+<p><label>Password<br><input name="password" type="password"></label></p>
+<p><button type="submit">Connect</button></p>
+</form>
+</body>
+</html>
+"#;
+
+const JOINING_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width, initial-scale=1"><title>candypi WiFi setup</title></head>
+<body>
+<h1>Connecting...</h1>
+<p>The setup WiFi goes away now. If it comes back, the network couldn't be joined, check the password and try again.</p>
+</body>
+</html>
+"#;
+
+/// `[wifi_setup]` table of the config file, opens a hotspot to enter WiFi credentials from a
+/// phone if no network comes up at boot
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WifiSetupConfig {
+    /// Name of the hotspot, `candypi-setup` unless set
+    pub ssid: Option<String>,
+    /// WPA2 passphrase of the hotspot, 8 to 63 characters
+    pub password: String,
+    /// `wlan0` unless set
+    pub interface: Option<String>,
+    /// Port of the setup page, 80 unless set
+    pub port: Option<u16>,
+    /// How long to wait for a known network at boot, 60 s unless set
+    pub timeout_secs: Option<u64>,
+}
+
+impl WifiSetupConfig {
+    pub fn ssid(&self) -> &str {
+        self.ssid.as_deref().unwrap_or(DEFAULT_SSID)
+    }
+
+    pub fn interface(&self) -> &str {
+        self.interface.as_deref().unwrap_or(DEFAULT_INTERFACE)
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout_secs
+            .map_or(DEFAULT_TIMEOUT, Duration::from_secs)
+    }
+}
+
+/// Where the machine is in getting online at boot
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkStatus {
+    /// Waiting for NetworkManager to bring up a known network
+    Waiting,
+    /// The hotspot is up, waiting for the operator to enter credentials
+    Hotspot {
+        /// `WIFI:` QR code payload joining the hotspot
+        qr_data: String,
+        /// Setup page, reachable once joined
+        url: String,
+    },
+    Online,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    ssid: String,
+    #[serde(default)]
+    password: String,
+}
+
+/// Falls back to a setup hotspot when no network comes up at boot, until the operator entered
+/// working WiFi credentials
+pub struct WifiSetup {
+    /// Unset without a config
+    task: Option<(JoinHandle<()>, WifiSetupConfig)>,
+}
+
+impl WifiSetup {
+    /// Checks the network in a background task, the returned status ends up
+    /// [`NetworkStatus::Online`]. Without a config the network is assumed to be up.
+    pub fn spawn(config: Option<WifiSetupConfig>) -> (Self, watch::Receiver<NetworkStatus>) {
+        let Some(config) = config else {
+            let (_, status) = watch::channel(NetworkStatus::Online);
+            return (Self { task: None }, status);
+        };
+
+        let (status_tx, status) = watch::channel(NetworkStatus::Waiting);
+        let setup = config.clone();
+        let task = tokio::spawn(async move {
+            if wait_online(setup.timeout()).await {
+                status_tx.send_replace(NetworkStatus::Online);
+                return;
+            }
+            info!(
+                "No network after {:?}, starting setup hotspot",
+                setup.timeout()
+            );
+            if let Err(e) = run_hotspot(&setup, &status_tx).await {
+                warn!("WiFi setup failed: {:#}", e);
+                // Boot carries on, connecting the wallet reports what can't be reached
+                status_tx.send_replace(NetworkStatus::Online);
+            }
+        });
+
+        (
+            Self {
+                task: Some((task, config)),
+            },
+            status,
+        )
+    }
+
+    /// Takes the hotspot down if it's still up
+    pub fn shutdown(self) {
+        let Some((task, config)) = self.task else {
+            return;
+        };
+        if !task.is_finished() {
+            task.abort();
+            if let Err(e) = stop_hotspot(&config) {
+                warn!("Failed to stop setup hotspot: {:#}", e);
+            }
+        }
+    }
+}
+
+/// Serves the setup page on the hotspot and joins every network entered until one works
+async fn run_hotspot(
+    config: &WifiSetupConfig,
+    status: &watch::Sender<NetworkStatus>,
+) -> anyhow::Result<()> {
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port()));
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    let (credentials_tx, mut credentials) = mpsc::channel(1);
+    let router = Router::new()
+        .route("/", get(setup_page).post(join))
+        .with_state(credentials_tx);
+    let server = tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            warn!("WiFi setup page stopped: {}", e);
+        }
+    });
+
+    let url = match config.port() {
+        DEFAULT_PORT => format!("http://{}", HOTSPOT_ADDRESS),
+        port => format!("http://{}:{}", HOTSPOT_ADDRESS, port),
+    };
+    let result = loop {
+        if let Err(e) = start_hotspot(config) {
+            break Err(e);
+        }
+        status.send_replace(NetworkStatus::Hotspot {
+            qr_data: wifi_qr_data(config.ssid(), &config.password),
+            url: url.clone(),
+        });
+
+        let Some(Credentials { ssid, password }) = credentials.recv().await else {
+            break Ok(());
+        };
+        // Joining needs the interface the hotspot is running on
+        if let Err(e) = stop_hotspot(config) {
+            warn!("Failed to stop setup hotspot: {:#}", e);
+        }
+        let interface = config.interface().to_owned();
+        let joined = tokio::task::spawn_blocking({
+            let ssid = ssid.clone();
+            move || join_network(&interface, &ssid, &password)
+        })
+        .await?;
+        match joined {
+            Ok(()) => {
+                info!(%ssid, "Joined WiFi");
+                status.send_replace(NetworkStatus::Online);
+                break Ok(());
+            }
+            Err(e) => warn!(%ssid, "Failed to join WiFi: {:#}", e),
+        }
+    };
+    server.abort();
+    result
+}
+
+async fn setup_page() -> Html<&'static str> {
+    Html(SETUP_PAGE)
+}
+
+async fn join(
+    State(credentials): State<mpsc::Sender<Credentials>>,
+    Form(entered): Form<Credentials>,
+) -> Html<&'static str> {
+    // A second submit while the first is being tried is dropped
+    let _ = credentials.try_send(entered);
+    Html(JOINING_PAGE)
+}
+
+/// Waits up to `timeout` for NetworkManager to bring up any connection
+async fn wait_online(timeout: Duration) -> bool {
+    let timeout = timeout.as_secs().to_string();
+    let result = tokio::task::spawn_blocking(move || {
+        Command::new("nm-online")
+            .args(["--quiet", "--timeout", &timeout])
+            .status()
+    })
+    .await;
+    match result {
+        Ok(Ok(status)) => status.success(),
+        Ok(Err(e)) => {
+            warn!("Failed to run nm-online: {}", e);
+            false
+        }
+        Err(e) => {
+            warn!("nm-online check failed: {}", e);
+            false
+        }
+    }
+}
+
+fn start_hotspot(config: &WifiSetupConfig) -> anyhow::Result<()> {
+    nmcli(&[
+        "device",
+        "wifi",
+        "hotspot",
+        "ifname",
+        config.interface(),
+        "con-name",
+        HOTSPOT_CONNECTION,
+        "ssid",
+        config.ssid(),
+        "password",
+        &config.password,
+    ])?;
+    info!(ssid = config.ssid(), "Setup hotspot up");
+    Ok(())
+}
+
+fn stop_hotspot(config: &WifiSetupConfig) -> anyhow::Result<()> {
+    nmcli(&["connection", "down", HOTSPOT_CONNECTION])?;
+    // The profile would otherwise linger in NetworkManager's list of known networks
+    nmcli(&["connection", "delete", HOTSPOT_CONNECTION])?;
+    info!(interface = config.interface(), "Setup hotspot down");
+    Ok(())
+}
+
+/// Connects and saves the network, NetworkManager rejoins it on every boot from then on
+fn join_network(interface: &str, ssid: &str, password: &str) -> anyhow::Result<()> {
+    let mut args = vec!["device", "wifi", "connect", ssid];
+    if !password.is_empty() {
+        args.extend(["password", password]);
+    }
+    args.extend(["ifname", interface]);
+    nmcli(&args)
+}
+
+/// Runs `nmcli`, the arguments aren't logged as they may hold a password
+fn nmcli(args: &[&str]) -> anyhow::Result<()> {
+    let output = Command::new("nmcli")
+        .args(args)
+        .output()
+        .context("Failed to run nmcli")?;
+    ensure!(
+        output.status.success(),
+        "nmcli {} failed: {}",
+        args.first().copied().unwrap_or_default(),
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(())
+}
+
+/// `WIFI:` payload phone cameras offer to join, special characters are backslash-escaped
+fn wifi_qr_data(ssid: &str, password: &str) -> String {
+    fn escape(value: &str) -> String {
+        value
+            .chars()
+            .flat_map(|c| match c {
+                '\\' | ';' | ',' | ':' | '"' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect()
+    }
+    format!("WIFI:T:WPA;S:{};P:{};;", escape(ssid), escape(password))
+}
@@ -0,0 +1,179 @@
+use crate::audit::AuditLog;
+use crate::federation;
+use crate::lnurl;
+use crate::wallet::Wallet;
+use fedimint_core::anyhow::{self, Context, bail, ensure};
+use fedimint_core::invite_code::InviteCode;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use tracing::info;
+
+/// Environment variable with the Lightning address (`user@domain`) remaining funds are swept to
+/// before a factory reset. Resetting a machine with funds on it is refused if it is unset.
+pub const SWEEP_ADDRESS_ENV: &str = "CANDYPI_SWEEP_ADDRESS";
+
+/// Part of the balance held back to pay gateway and federation fees when sweeping
+const SWEEP_FEE_RESERVE_PERCENT: u64 = 2;
+const SWEEP_MIN_FEE_RESERVE_MSATS: u64 = 10_000;
+/// Balances below this can't pay for their own sweep and are lost with the wallet
+const SWEEP_DUST_MSATS: u64 = SWEEP_MIN_FEE_RESERVE_MSATS + 1000;
+/// The fee is only known once paid, what the reserve didn't need is swept in further rounds
+const SWEEP_ROUNDS: usize = 3;
+
+/// First half of a factory reset: sweeps all funds to the configured Lightning address. The
+/// wallet has to be shut down before [`finish_factory_reset`] deletes its database.
+pub async fn begin_factory_reset(ln: &Wallet, audit_log: &AuditLog) -> anyhow::Result<()> {
+    ensure!(
+        ln.holds_funds(),
+        "Factory resets need a Fedimint or Cashu wallet, nothing is stored on the machine"
+    );
+    audit_log.record("factory_reset_started");
+    audit_log.flush();
+
+    sweep_funds(ln).await
+}
+
+/// Second half of a factory reset: securely deletes the wallet's `datadir` with its seed, leaving
+/// the machine in its first-run state. The next start joins `next_federation`, or the configured
+/// federation if `None`. That is decided before anything is deleted, an interrupted wipe must
+/// not leave the machine rejoining the federation it was meant to leave.
+pub fn finish_factory_reset(
+    datadir: &Path,
+    next_federation: Option<&InviteCode>,
+    audit_log: &AuditLog,
+) -> anyhow::Result<()> {
+    let federation = federation::default_path();
+    match next_federation {
+        Some(invite) => {
+            federation::save(&federation, invite).context("Failed to save the invite code")?
+        }
+        None => match fs::remove_file(&federation) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to delete {}", federation.display()));
+            }
+        },
+    }
+
+    secure_delete_dir(datadir)
+        .with_context(|| format!("Failed to delete datadir {}", datadir.display()))?;
+
+    audit_log.record("factory_reset_completed");
+    audit_log.flush();
+    info!("Factory reset completed");
+    Ok(())
+}
+
+/// Sweeps until only dust is left, fails rather than leaving more behind for the wipe
+async fn sweep_funds(ln: &Wallet) -> anyhow::Result<()> {
+    let mut balance_msats = ln.balance_msat().await?;
+    if balance_msats < SWEEP_DUST_MSATS {
+        info!(
+            "Balance of {} msat is too small to sweep, discarding it",
+            balance_msats
+        );
+        return Ok(());
+    }
+
+    let Ok(address) = std::env::var(SWEEP_ADDRESS_ENV) else {
+        bail!(
+            "Wallet holds {} msat but {} is not set, refusing to wipe",
+            balance_msats,
+            SWEEP_ADDRESS_ENV
+        );
+    };
+
+    for _ in 0..SWEEP_ROUNDS {
+        if balance_msats < SWEEP_DUST_MSATS {
+            break;
+        }
+        let sweep_msats = sweep_amount(balance_msats);
+        info!("Sweeping {} msat to {}", sweep_msats, address);
+        let invoice = lnurl::invoice_from_lightning_address(&address, sweep_msats).await?;
+        ln.pay_invoice(&invoice)
+            .await
+            .context("Failed to sweep funds")?;
+        balance_msats = ln.balance_msat().await?;
+    }
+
+    ensure!(
+        balance_msats < SWEEP_DUST_MSATS,
+        "{} msat are left after sweeping, refusing to wipe",
+        balance_msats
+    );
+    Ok(())
+}
+
+/// What can be swept out of `balance_msats` while keeping enough for the fees
+fn sweep_amount(balance_msats: u64) -> u64 {
+    let fee_reserve_msats =
+        (balance_msats * SWEEP_FEE_RESERVE_PERCENT / 100).max(SWEEP_MIN_FEE_RESERVE_MSATS);
+    // Cashu mints only melt whole sats
+    balance_msats.saturating_sub(fee_reserve_msats) / 1000 * 1000
+}
+
+/// Overwrites every file with zeros before deleting it. SD cards remap blocks internally, so
+/// this is best effort, but it keeps the secret out of trivially recoverable free space.
+fn secure_delete_dir(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Not following symlinks, they are only unlinked so nothing outside `dir` gets wiped
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            secure_delete_dir(&entry.path())?;
+        } else if file_type.is_file() {
+            overwrite_file(&entry.path())?;
+        }
+    }
+
+    fs::remove_dir_all(dir)
+}
+
+fn overwrite_file(path: &Path) -> io::Result<()> {
+    let zeros = [0u8; 4096];
+    let mut remaining = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+
+    while remaining > 0 {
+        let chunk = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn wipes_only_inside_the_dir() {
+        let outside = tempdir().unwrap();
+        let secret = outside.path().join("secret");
+        fs::write(&secret, "keep me").unwrap();
+
+        let datadir = tempdir().unwrap();
+        let wallet = datadir.path().join("wallet");
+        fs::create_dir(&wallet).unwrap();
+        fs::write(wallet.join("seed"), "seed words").unwrap();
+        symlink(&secret, wallet.join("linked-file")).unwrap();
+        symlink(outside.path(), wallet.join("linked-dir")).unwrap();
+
+        secure_delete_dir(&wallet).unwrap();
+        assert!(!wallet.exists());
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "keep me");
+    }
+
+    #[test]
+    fn sweeps_all_but_the_fee_reserve() {
+        assert_eq!(sweep_amount(SWEEP_DUST_MSATS), 1000);
+        assert_eq!(sweep_amount(SWEEP_DUST_MSATS - 1), 0);
+        assert_eq!(sweep_amount(1_000_000), 980_000);
+    }
+}
@@ -0,0 +1,52 @@
+//! Draws every screen with the default theme and compares it against the reference images in
+//! `tests/screens`. After an intended layout change, run the tests with
+//! `CANDYPI_UPDATE_SCREENS=1` to rewrite the images and review them in the diff.
+#![cfg(feature = "simulate")]
+
+use candypi::screen::{Display, DisplayOrientation, Panel};
+use candypi::simulator::{self, SimulatedDisplay};
+use candypi::theme::Theme;
+use std::path::Path;
+
+const UPDATE_ENV: &str = "CANDYPI_UPDATE_SCREENS";
+
+#[test]
+fn screens_match_reference_images() {
+    let reference_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/screens");
+    let update = std::env::var(UPDATE_ENV).is_ok_and(|value| value == "1");
+    // Differing screens are kept here for a look
+    let actual_dir = std::env::temp_dir().join("candypi-screens");
+
+    let theme = Theme::default();
+    let status_bar = simulator::sample_status_bar();
+    let simulated = SimulatedDisplay::new();
+    let mut display = Display::new(
+        Panel::Simulated(simulated.clone()),
+        DisplayOrientation::default(),
+    );
+
+    let mut differing = Vec::new();
+    for screen in simulator::sample_screens() {
+        screen.draw(&mut display, &status_bar, &theme).unwrap();
+        let file = format!("{}.png", screen.name());
+        if update {
+            simulated.save(&reference_dir.join(&file)).unwrap();
+            continue;
+        }
+
+        let reference = image::open(reference_dir.join(&file))
+            .unwrap_or_else(|e| panic!("No reference image {file}, run with {UPDATE_ENV}=1: {e}"))
+            .to_rgb8();
+        if simulated.image() != reference {
+            std::fs::create_dir_all(&actual_dir).unwrap();
+            simulated.save(&actual_dir.join(&file)).unwrap();
+            differing.push(file);
+        }
+    }
+    assert!(
+        differing.is_empty(),
+        "Screens differ from their reference images, see {}: {:?}",
+        actual_dir.display(),
+        differing
+    );
+}